@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE renditions (
+    -- The file that this is a rendition of
+    file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    -- Length of the longest side that the rendition was generated for
+    size INTEGER NOT NULL,
+    -- Filename of the rendition in the file storage folder
+    filename TEXT NOT NULL,
+    PRIMARY KEY (file_id, size)
+)
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Dimensions of the file as it is displayed, after the EXIF orientation is applied.
+-- NULL until the renditions of the file have been generated
+ALTER TABLE files ADD COLUMN width INTEGER;
+ALTER TABLE files ADD COLUMN height INTEGER;
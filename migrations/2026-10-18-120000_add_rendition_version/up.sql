@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- The version of the code that generated the rendition
+ALTER TABLE renditions ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Filenames of files that are stored alongside the main file, like the RAW
+-- version of a JPEG
+ALTER TABLE files ADD COLUMN sidecars TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- A hash of the image content which is similar for images that look similar
+ALTER TABLE files ADD COLUMN perceptual_hash BIGINT;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Hex encoded SHA-256 of the original file
+ALTER TABLE files ADD COLUMN content_hash TEXT;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE sync_conflicts (
+    id SERIAL PRIMARY KEY,
+    -- The file that the conflicting changes affected
+    file_id INTEGER NOT NULL,
+    -- Json encoded `conflicts::ConflictResolution`
+    json_data TEXT NOT NULL,
+    detected_at TIMESTAMP NOT NULL,
+    -- True once the user has replaced the kept change with the discarded one
+    overridden BOOLEAN NOT NULL DEFAULT FALSE
+)
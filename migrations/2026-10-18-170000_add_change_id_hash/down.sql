@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+-- The old ids came from a hash which is not available in SQL, so they can not
+-- be restored. The new ids are truncated to fit in the old column instead
+ALTER TABLE changes ALTER COLUMN id TYPE INTEGER USING (id >> 32)::INTEGER;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE changes ALTER COLUMN id TYPE BIGINT;
+
+-- Recompute the ids of existing changes with the hash used by `changelog::change_id`:
+-- the first 8 bytes of the SHA-256 of `<timestamp in microseconds>\n<affected_file>\n<json_data>`
+UPDATE changes SET id = (
+    'x' || left(encode(sha256(convert_to(
+        (extract(epoch FROM timestamp) * 1000000)::BIGINT::TEXT || E'\n'
+            || affected_file::TEXT || E'\n'
+            || json_data,
+        'UTF8'
+    )), 'hex'), 16)
+)::BIT(64)::BIGINT;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE instance;
+ALTER TABLE syncpoints DROP COLUMN clock;
+ALTER TABLE changes DROP COLUMN origin;
+ALTER TABLE changes DROP COLUMN clock;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+
+-- Existing changes and syncpoints use their timestamp in microseconds as the
+-- counter of their logical clock, see `changelog::timestamp_clock`. This gives
+-- them the same clock on all instances and keeps their order
+ALTER TABLE changes ADD COLUMN clock BIGINT;
+UPDATE changes SET clock = (extract(epoch FROM timestamp) * 1000000)::BIGINT;
+ALTER TABLE changes ALTER COLUMN clock SET NOT NULL;
+-- The instance that created the change, empty for changes created before this
+ALTER TABLE changes ADD COLUMN origin TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE syncpoints ADD COLUMN clock BIGINT;
+UPDATE syncpoints SET clock = (extract(epoch FROM last_change) * 1000000)::BIGINT;
+ALTER TABLE syncpoints ALTER COLUMN clock SET NOT NULL;
+
+-- A single row containing a random id for this instance
+CREATE TABLE instance (
+    id TEXT PRIMARY KEY
+);
+INSERT INTO instance VALUES (md5(random()::TEXT || clock_timestamp()::TEXT));
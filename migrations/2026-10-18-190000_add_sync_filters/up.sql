@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE sync_filters (
+    -- The url that is used to reach the peer the filter applies to
+    foreign_url TEXT PRIMARY KEY,
+    -- Search query in the same format as /search, for example "of work"
+    query TEXT NOT NULL
+)
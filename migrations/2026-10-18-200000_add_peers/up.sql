@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE peers (
+    id SERIAL PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE,
+    -- Name shown to the user
+    name TEXT NOT NULL,
+    -- Secret used to sign requests to the peer, FLASH_SYNC_SECRET is used if NULL
+    secret TEXT,
+    -- Seconds between automatic syncs, the peer is only synced by hand if NULL
+    sync_interval INTEGER,
+    last_sync TIMESTAMP,
+    last_attempt TIMESTAMP,
+    -- The error of the last attempt if it failed
+    last_error TEXT
+);
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE sync_jobs (
+    -- The job id used by /sync/progress
+    id BIGINT PRIMARY KEY,
+    -- 'sync' for syncs started here, 'apply' for changes sent by a peer
+    kind TEXT NOT NULL,
+    -- The url used to reach the other instance
+    peer TEXT NOT NULL,
+    started_at TIMESTAMP NOT NULL,
+    finished_at TIMESTAMP,
+    -- 'running', 'done' or 'failed'
+    status TEXT NOT NULL,
+    changes_sent INTEGER NOT NULL DEFAULT 0,
+    changes_received INTEGER NOT NULL DEFAULT 0,
+    files_sent INTEGER NOT NULL DEFAULT 0,
+    files_received INTEGER NOT NULL DEFAULT 0,
+    -- Every error in the chain of the error that stopped the job
+    error TEXT
+);
+
+CREATE INDEX sync_jobs_started_at ON sync_jobs (started_at);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE peers DROP COLUMN lazy_originals;
+ALTER TABLE files DROP COLUMN original_accessed;
+ALTER TABLE files ALTER COLUMN is_uploaded SET DEFAULT false;
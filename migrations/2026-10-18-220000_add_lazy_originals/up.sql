@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- is_uploaded was never used, it now marks files whose original is stored on
+-- this instance
+UPDATE files SET is_uploaded = true;
+ALTER TABLE files ALTER COLUMN is_uploaded SET DEFAULT true;
+-- When the original of a file was last requested. Only set for originals that
+-- were fetched from a peer on demand, which are removed when the cache is full
+ALTER TABLE files ADD COLUMN original_accessed TIMESTAMP;
+-- Only store thumbnails of the files added by the peer
+ALTER TABLE peers ADD COLUMN lazy_originals BOOLEAN NOT NULL DEFAULT false;
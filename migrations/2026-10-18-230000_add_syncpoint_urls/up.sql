@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- The url of the instance that the syncpoint was created with or first
+-- received from. Used to find every instance the change log is shared with
+ALTER TABLE syncpoints ADD COLUMN foreign_url TEXT;
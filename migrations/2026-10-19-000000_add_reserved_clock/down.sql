@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX syncpoints_clock;
+DROP INDEX changes_clock;
+ALTER TABLE instance DROP COLUMN reserved_clock;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- The largest logical clock counter reserved by a sync. Changes made while the
+-- sync runs get larger counters so they are not hidden by its syncpoint
+ALTER TABLE instance ADD COLUMN reserved_clock BIGINT NOT NULL DEFAULT 0;
+
+-- The largest clock counter is looked up for every new change
+CREATE INDEX changes_clock ON changes (clock);
+CREATE INDEX syncpoints_clock ON syncpoints (clock);
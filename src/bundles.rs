@@ -0,0 +1,732 @@
+use iron::prelude::*;
+use iron::status;
+use persistent;
+
+use chrono::{NaiveDateTime, Timelike, Utc};
+use serde_json;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+
+use changelog::{Change, ChangeType, SyncPoint, UpdateType, timestamp_clock};
+use conflicts::ConflictPolicies;
+use file_database::{self, FileDatabase};
+use file_handler::{ensure_content_hash, stored_size};
+use file_util::content_hash;
+use foreign_server::{ForeignServer, ChangeData, FileDetails};
+use library_digest::LibraryDigest;
+use request_helpers::{get_get_variable, get_optional_get_variable, to_json_with_result};
+use settings::Settings;
+use sync::{last_common_syncpoint, receive_from_foreign};
+use sync_auth::authenticate_admin_request;
+use sync_control;
+use sync_handshake::Handshake;
+use sync_jobs::{record_job, JobKind, TransferCounts};
+use sync_progress as sp;
+use sync_push::FilePart;
+
+use error::{Result, ErrorKind, ResultExt, describe_chain};
+
+/// The version of the bundle format. Bundles with other versions are rejected
+/// since they may not be read correctly
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// The file in a bundle which describes its content
+const MANIFEST_FILE: &str = "manifest.json";
+/// The folder in a bundle where originals, sidecars and thumbnails are stored
+const PARTS_FOLDER: &str = "files";
+
+/**
+  Describes the changes and files in a bundle
+*/
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub version: u32,
+    /// The id of the instance the bundle was exported from
+    pub instance_id: String,
+    pub exported_at: NaiveDateTime,
+    /// All syncpoints of the exporting instance
+    pub syncpoints: Vec<SyncPoint>,
+    /// Syncpoint after every exported change, which the importing instance
+    /// records. Changes made after the export have larger clocks
+    #[serde(default)]
+    pub export_syncpoint: Option<SyncPoint>,
+    /// The export syncpoint of the bundle that this one was exported `since`,
+    /// if the exporting instance imported that bundle. The importing instance
+    /// records it since both instances have each other's changes up to it
+    #[serde(default)]
+    pub imported_syncpoint: Option<SyncPoint>,
+    pub changes: Vec<Change>,
+    /// Details of the files affected by the changes which still exist
+    pub files: HashMap<i32, FileDetails>,
+    /// The hex encoded SHA-256 of every file in the parts folder, by filename
+    pub checksums: BTreeMap<String, String>
+}
+
+/**
+  Only the version of a manifest, which is read before the rest since other
+  versions may use another format
+*/
+#[derive(Deserialize)]
+struct ManifestVersion {
+    version: u32
+}
+
+/**
+  A directory with changes and files exported from another instance, used to
+  sync with instances that can't be reached over the network. It stands in for
+  the exporting instance while the changes are applied
+*/
+pub struct Bundle {
+    directory: PathBuf,
+    pub manifest: Manifest
+}
+
+impl Bundle {
+    /**
+      Opens the bundle in `directory` and checks that it has a supported
+      version and that none of its files are missing or modified
+    */
+    pub fn open(directory: &Path) -> Result<Self> {
+        let manifest_path = directory.join(MANIFEST_FILE);
+        let mut content = String::new();
+        File::open(&manifest_path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .chain_err(|| ErrorKind::CorruptBundle(format!("Failed to read {:?}", manifest_path)))?;
+
+        let version = serde_json::from_str::<ManifestVersion>(&content)
+            .chain_err(|| ErrorKind::CorruptBundle("The manifest has no version".into()))?
+            .version;
+        if version != BUNDLE_VERSION {
+            bail!(ErrorKind::UnsupportedBundleVersion(version));
+        }
+
+        let manifest = serde_json::from_str::<Manifest>(&content)
+            .chain_err(|| ErrorKind::CorruptBundle("The manifest is invalid".into()))?;
+
+        let parts = directory.join(PARTS_FOLDER);
+        for (filename, checksum) in &manifest.checksums {
+            // The files must be in the parts folder
+            if Path::new(filename).file_name() != Some(OsStr::new(filename)) {
+                bail!(ErrorKind::CorruptBundle(format!("Invalid filename {}", filename)));
+            }
+
+            let actual = content_hash(&parts.join(filename))
+                .chain_err(|| ErrorKind::CorruptBundle(format!("{} is missing", filename)))?;
+            if &actual != checksum {
+                bail!(ErrorKind::CorruptBundle(format!("{} was modified", filename)));
+            }
+        }
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            manifest
+        })
+    }
+
+    /**
+      Returns the path of a part of a file, or `None` if it isn't in the bundle
+    */
+    fn part_path(&self, file_id: i32, part: FilePart) -> Option<PathBuf> {
+        let filename = part.filename(file_id);
+        if self.manifest.checksums.contains_key(&filename) {
+            Some(self.directory.join(PARTS_FOLDER).join(filename))
+        }
+        else {
+            None
+        }
+    }
+
+    fn read_part(&self, file_id: i32, part: FilePart) -> Result<Vec<u8>> {
+        match self.part_path(file_id, part) {
+            Some(path) => Ok(fs::read(path)?),
+            None => bail!(ErrorKind::NotInBundle(file_id))
+        }
+    }
+
+    fn copy_part(&self, file_id: i32, part: FilePart, destination: &Path, on_progress: &dyn Fn(u64))
+        -> Result<()>
+    {
+        match self.part_path(file_id, part) {
+            Some(path) => {
+                on_progress(fs::copy(path, destination)?);
+                Ok(())
+            }
+            None => bail!(ErrorKind::NotInBundle(file_id))
+        }
+    }
+}
+
+impl ForeignServer for Bundle {
+    fn get_syncpoints(&self) -> Result<Vec<SyncPoint>> {
+        Ok(self.manifest.syncpoints.clone())
+    }
+    fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
+        Ok(self.manifest.changes.iter()
+            .filter(|change| match *starting_syncpoint {
+                Some(ref syncpoint) => change.clock.counter > syncpoint.clock,
+                None => true
+            })
+            .cloned()
+            .collect()
+        )
+    }
+    fn get_file_details(&self, id: i32) -> Result<FileDetails> {
+        match self.manifest.files.get(&id) {
+            Some(details) => Ok(details.clone()),
+            None => bail!(ErrorKind::NotInBundle(id))
+        }
+    }
+    fn send_changes(&mut self, _changes: &ChangeData, _own_port: u16, _push_id: Option<usize>)
+        -> Result<usize>
+    {
+        bail!("Changes can't be sent to bundles")
+    }
+    fn get_needed_files(&self, _changes: &ChangeData, _own_port: u16) -> Result<Vec<i32>> {
+        bail!("Changes can't be sent to bundles")
+    }
+    fn push_file_part(&self, _push_id: usize, _file_id: i32, _part: FilePart, _offset: u64, _content: &[u8])
+        -> Result<()>
+    {
+        bail!("Files can't be pushed to bundles")
+    }
+    fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+        self.copy_part(id, FilePart::Content, destination, on_progress)
+    }
+    fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+        -> Result<()>
+    {
+        self.copy_part(id, FilePart::Sidecar(index), destination, on_progress)
+    }
+    fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
+        match self.part_path(id, FilePart::Thumbnail) {
+            Some(_) => Ok(Some(self.read_part(id, FilePart::Thumbnail)?)),
+            None => Ok(None)
+        }
+    }
+    fn get_sync_updates(&self, _job_id: usize) -> Result<Box<dyn Iterator<Item=Result<sp::SyncUpdate>>>> {
+        bail!("Bundles don't have sync jobs")
+    }
+    fn add_syncpoint(&self, _syncpoint: &SyncPoint) -> Result<()> {
+        bail!("Syncpoints can't be added to bundles")
+    }
+    fn get_library_digest(&self, _prefix: &str) -> Result<LibraryDigest> {
+        bail!("Library digests can't be requested from bundles")
+    }
+    fn cancel_job(&self, _job_id: usize) -> Result<()> {
+        bail!("Bundles don't have sync jobs")
+    }
+    fn handshake(&self) -> Result<Handshake> {
+        bail!("Bundles can't be synced with")
+    }
+}
+
+/**
+  Fails if there already is a bundle in `directory`
+*/
+fn check_export_directory(directory: &Path) -> Result<()> {
+    if directory.join(MANIFEST_FILE).exists() {
+        bail!(ErrorKind::BundleExists(directory.to_string_lossy().into()));
+    }
+    Ok(())
+}
+
+/**
+  Exports the changes of this instance and the files they added to a bundle in
+  `directory`. If `since` is a bundle exported by the instance that will import
+  this one, only the changes after the last syncpoint the instances have in
+  common are exported, otherwise all of them are.
+
+  The exported syncpoint is not recorded here since the importing instance may
+  have changes before it which this instance doesn't have yet. It is recorded
+  once the importing instance sends a bundle back which was exported `since`
+  this one.
+
+  Originals that are stored on other instances are left out. The manifest is
+  written last so that an interrupted export can't be imported
+*/
+pub fn export_bundle(
+    fdb: &FileDatabase,
+    directory: &Path,
+    since: Option<&Bundle>,
+    (job_id, progress_tx): &sp::LocalTxType
+) -> Result<TransferCounts> {
+    check_export_directory(directory)?;
+
+    // Created and reserved before the changes are read so that changes made
+    // during the export are after it. Like other syncpoints it is stored in
+    // whole seconds
+    let now = Utc::now().naive_utc().with_nanosecond(0).expect("Zero is a valid nanosecond");
+    let export_syncpoint = SyncPoint {
+        last_change: now,
+        clock: ::std::cmp::max(fdb.current_clock()? + 1, timestamp_clock(now))
+    };
+    fdb.reserve_clock(export_syncpoint.clock)?;
+
+    let syncpoints = fdb.get_syncpoints()?;
+    let imported_syncpoint = since
+        .and_then(|bundle| bundle.manifest.export_syncpoint.clone())
+        .filter(|syncpoint| syncpoints.contains(syncpoint));
+    let starting_syncpoint = since.and_then(|bundle| {
+        last_common_syncpoint(&syncpoints, &bundle.manifest.syncpoints)
+    });
+    let changes = match starting_syncpoint {
+        Some(ref syncpoint) => fdb.get_changes_after_clock(syncpoint.clock)?,
+        None => fdb.get_all_changes()?
+    }
+        .into_iter()
+        .filter(|change| change.clock.counter <= export_syncpoint.clock)
+        .collect::<Vec<_>>();
+
+    let parts = directory.join(PARTS_FOLDER);
+    fs::create_dir_all(&parts)?;
+
+    let affected = changes.iter().map(|change| change.affected_file).collect::<BTreeSet<_>>();
+    // Replaced content is exported like the content of added files
+    let added = changes.iter()
+        .filter(|change| {
+            matches!(change.change_type, ChangeType::FileAdded | ChangeType::Update(UpdateType::ContentChanged(_)))
+        })
+        .map(|change| change.affected_file)
+        .collect::<BTreeSet<_>>();
+
+    let mut files = HashMap::new();
+    let mut checksums = BTreeMap::new();
+    let mut files_sent = 0;
+    let mut files_left = added.len();
+    for id in affected {
+        sync_control::check_job(*job_id)?;
+
+        // Files that were removed since are not needed
+        let mut file = match fdb.get_file_with_id(id) {
+            Some(file) => file,
+            None => continue
+        };
+        ensure_content_hash(&mut file, fdb)?;
+
+        let mut details = FileDetails::from(&file);
+        details.size = stored_size(&file, fdb)?;
+        files.insert(id, details);
+
+        if added.contains(&id) {
+            files_left -= 1;
+            progress_tx.send((*job_id, sp::SyncUpdate::ExportingFile(files_left)))
+                .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
+            if export_parts(fdb, &file, &parts, &mut checksums)? {
+                files_sent += 1;
+            }
+        }
+    }
+
+    let manifest = Manifest {
+        version: BUNDLE_VERSION,
+        instance_id: fdb.get_instance_id()?,
+        exported_at: now,
+        syncpoints,
+        export_syncpoint: Some(export_syncpoint),
+        imported_syncpoint,
+        changes,
+        files,
+        checksums
+    };
+    let changes_sent = manifest.changes.len();
+
+    let temporary_path = directory.join(format!("{}.part", MANIFEST_FILE));
+    fs::write(&temporary_path, serde_json::to_string(&manifest)?)?;
+    fs::rename(&temporary_path, directory.join(MANIFEST_FILE))?;
+
+    Ok(TransferCounts {
+        changes_sent,
+        files_sent,
+        .. TransferCounts::default()
+    })
+}
+
+/**
+  Applies the changes in a bundle that are newer than the last syncpoint this
+  instance has in common with the exporting instance. The syncpoints of the
+  bundle are recorded afterwards, so exporting a bundle `since` this one only
+  exports the changes of this instance that the exporting one doesn't have
+*/
+pub fn import_bundle(
+    fdb: &FileDatabase,
+    bundle: &Bundle,
+    fetch_workers: usize,
+    conflict_policies: &ConflictPolicies,
+    progress_reporter: &sp::LocalTxType
+) -> Result<TransferCounts> {
+    let counts = receive_from_foreign(fdb, bundle, fetch_workers, conflict_policies, progress_reporter)?;
+
+    let known = fdb.get_syncpoints()?;
+    let manifest = &bundle.manifest;
+    for syncpoint in manifest.imported_syncpoint.iter().chain(manifest.export_syncpoint.iter()) {
+        if !known.contains(syncpoint) {
+            fdb.add_syncpoint(syncpoint)?;
+        }
+    }
+
+    Ok(counts)
+}
+
+/**
+  Copies the original, sidecars and thumbnail of a file to the parts folder of
+  a bundle. Returns false if the original is stored on another instance
+*/
+fn export_parts(
+    fdb: &FileDatabase,
+    file: &file_database::File,
+    parts_folder: &Path,
+    checksums: &mut BTreeMap<String, String>
+) -> Result<bool> {
+    let mut parts = vec!();
+    if file.is_uploaded {
+        parts.push((FilePart::Content, file.filename.clone()));
+        for (index, sidecar) in file.sidecars.iter().enumerate() {
+            parts.push((FilePart::Sidecar(index), sidecar.clone()));
+        }
+    }
+    if let Some(ref thumbnail) = file.thumbnail_path {
+        parts.push((FilePart::Thumbnail, thumbnail.clone()));
+    }
+
+    let storage = fdb.get_file_save_path();
+    for (part, filename) in parts {
+        let part_filename = part.filename(file.id);
+        let destination = parts_folder.join(&part_filename);
+        fs::copy(storage.join(&filename), &destination)
+            .chain_err(|| format!("Failed to export {}", filename))?;
+
+        checksums.insert(part_filename, content_hash(&destination)?);
+    }
+    Ok(file.is_uploaded)
+}
+
+/**
+  Runs `job` in the background and stores it in the sync history with the
+  bundle path as the peer. Returns the id of the job
+*/
+fn start_bundle_job<F>(
+    settings: Settings,
+    kind: JobKind,
+    path: &Path,
+    progress_tx: &sp::TxType,
+    job: F
+) -> usize
+    where F: FnOnce(&FileDatabase, &sp::LocalTxType) -> Result<TransferCounts> + Send + 'static
+{
+    let job_id = sp::new_job_id();
+    let path = path.to_string_lossy().to_string();
+
+    let progress_tx = progress_tx.clone();
+    progress_tx.send((job_id, sp::SyncUpdate::Started))
+        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
+    thread::spawn(move || {
+        let fdb = FileDatabase::new(&settings.database_url, settings.get_file_storage_path());
+
+        let result = fdb.and_then(|fdb| {
+            let history_days = settings.get_sync_history_days();
+            let limits = settings.get_job_limits();
+            record_job(&fdb, job_id, kind, &path, history_days, &limits, || {
+                job(&fdb, &(job_id, progress_tx.clone()))
+            })
+        });
+
+        let update = match result {
+            Ok(_) => sp::SyncUpdate::Done,
+            Err(e) => sp::SyncUpdate::Error(describe_chain(&e))
+        };
+        progress_tx.send((job_id, update))
+            .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+    });
+
+    job_id
+}
+
+/**
+  Returns the directory in the bundle folder `root` for a `path` sent with a
+  request. Only relative paths without `..` are accepted so that requests
+  can't read or write anything outside of it
+*/
+fn bundle_directory(root: Option<PathBuf>, path: &str) -> Result<PathBuf> {
+    let root = match root {
+        Some(root) => root,
+        None => bail!(ErrorKind::BundlePathMissing)
+    };
+
+    let relative = Path::new(path);
+    let confined = relative.components().all(|component| matches!(component, Component::Normal(_)));
+    if path.is_empty() || !confined {
+        bail!(ErrorKind::InvalidBundlePath(path.to_string()));
+    }
+    Ok(root.join(relative))
+}
+
+pub fn export_request_handler(request: &mut Request, progress_tx: &sp::TxType)
+    -> IronResult<Response>
+{
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+
+    let root = settings.get_bundle_path();
+    let directory = bundle_directory(root.clone(), &get_get_variable(request, "path")?)?;
+    let since = match get_optional_get_variable(request, "since")? {
+        Some(path) => Some(Bundle::open(&bundle_directory(root, &path)?)?),
+        None => None
+    };
+    check_export_directory(&directory)?;
+
+    let path = directory.clone();
+    let job_id = start_bundle_job(
+        (*settings).clone(),
+        JobKind::Export,
+        &path,
+        progress_tx,
+        move |fdb, progress| export_bundle(fdb, &directory, since.as_ref(), progress)
+    );
+
+    Ok(Response::with((status::Ok, to_json_with_result(job_id)?)))
+}
+
+pub fn import_request_handler(request: &mut Request, progress_tx: &sp::TxType)
+    -> IronResult<Response>
+{
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+
+    let path = get_get_variable(request, "path")?;
+    let directory = bundle_directory(settings.get_bundle_path(), &path)?;
+    let bundle = Bundle::open(&directory)?;
+
+    let fetch_workers = settings.get_sync_workers();
+    let conflict_policies = settings.get_conflict_policies();
+    let job_id = start_bundle_job(
+        (*settings).clone(),
+        JobKind::Import,
+        &directory,
+        progress_tx,
+        move |fdb, progress| {
+            import_bundle(fdb, &bundle, fetch_workers, &conflict_policies, progress)
+        }
+    );
+
+    Ok(Response::with((status::Ok, to_json_with_result(job_id)?)))
+}
+
+
+#[cfg(test)]
+mod bundle_tests {
+    use super::*;
+
+    use changelog::ChangeCreationPolicy;
+    use conflicts::ConflictPolicies;
+    use file_database::db_test_helpers;
+    use file_database::db_test_helpers::{add_stored_file, year};
+    use file_handler::remove_file;
+
+    fn empty_directory(fdb: &FileDatabase, name: &str) -> PathBuf {
+        let directory = fdb.get_file_save_path().join(name);
+        fs::remove_dir_all(&directory).ok();
+        directory
+    }
+
+    #[test]
+    fn bundles_are_exported_and_imported() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let directory = empty_directory(&fdb, "bundle_export");
+        let since_directory = empty_directory(&fdb, "bundle_since");
+
+        add_stored_file(&fdb, 47, &["things".into()], year(2017));
+        fdb.add_syncpoint(&SyncPoint::from_timestamp(year(2018))).unwrap();
+        add_stored_file(&fdb, 48, &[], year(2019));
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let counts = export_bundle(&fdb, &directory, None, &(0, tx.clone())).unwrap();
+        assert_eq!(counts.changes_sent, 3);
+        assert_eq!(counts.files_sent, 2);
+        assert_matches!(
+            export_bundle(&fdb, &directory, None, &(0, tx.clone())),
+            Err(::error::Error(ErrorKind::BundleExists(_), _))
+        );
+
+        // The instance that imports the bundle has the syncpoint, so it only
+        // needs the file added after it
+        let since = Bundle::open(&directory).unwrap();
+        // Changes made after the export come after its syncpoint, even if
+        // their timestamp is older
+        let export_clock = since.manifest.export_syncpoint.as_ref().unwrap().clock;
+        assert!(fdb.next_clock(year(2019)).unwrap().counter > export_clock);
+        let counts = export_bundle(&fdb, &since_directory, Some(&since), &(0, tx)).unwrap();
+        assert_eq!(counts.changes_sent, 1);
+        let bundle = Bundle::open(&since_directory).unwrap();
+        assert_eq!(bundle.manifest.files.keys().collect::<Vec<_>>(), vec!(&48));
+
+        // The bundle is imported by an instance without the files
+        remove_file(47, &fdb, &ChangeCreationPolicy::No).unwrap();
+        remove_file(48, &fdb, &ChangeCreationPolicy::No).unwrap();
+        fdb.reset();
+
+        let bundle = Bundle::open(&directory).unwrap();
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let counts = import_bundle(&fdb, &bundle, 2, &ConflictPolicies::default(), &(0, tx))
+            .unwrap();
+        assert_eq!(counts.changes_received, 3);
+        assert_eq!(counts.files_received, 2);
+
+        let storage = fdb.get_file_save_path();
+        let file = fdb.get_file_with_id(47).expect("The file was not imported");
+        assert_eq!(file.tags, mapvec!(String::from: "things"));
+        assert_eq!(fs::read(storage.join(&file.filename)).unwrap(), vec!(1, 2, 3));
+        assert_eq!(fs::read(storage.join(file.thumbnail_path.unwrap())).unwrap(), vec!(4));
+        assert!(fdb.get_file_with_id(48).is_some());
+
+        // The exporting instance doesn't have the changes of this instance yet,
+        // so only the syncpoint of the export is recorded
+        assert_eq!(
+            fdb.get_syncpoints().unwrap(),
+            bundle.manifest.export_syncpoint.into_iter().collect::<Vec<_>>()
+        );
+
+        remove_file(47, &fdb, &ChangeCreationPolicy::No).unwrap();
+        remove_file(48, &fdb, &ChangeCreationPolicy::No).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        fs::remove_dir_all(&since_directory).unwrap();
+    }
+
+    #[test]
+    fn bundles_are_synced_back_and_forth() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let foreign = db_test_helpers::get_foreign_database();
+        let foreign = foreign.lock().unwrap();
+        fdb.reset();
+        foreign.reset();
+
+        let to_foreign = empty_directory(&fdb, "bundle_to_foreign");
+        let to_local = empty_directory(&fdb, "bundle_to_local");
+        let to_foreign_again = empty_directory(&fdb, "bundle_to_foreign_again");
+        let policies = ConflictPolicies::default();
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+
+        add_stored_file(&fdb, 60, &["local".into()], year(2017));
+        add_stored_file(&foreign, 61, &["foreign".into()], year(2017));
+
+        export_bundle(&fdb, &to_foreign, None, &(0, tx.clone())).unwrap();
+        let from_local = Bundle::open(&to_foreign).unwrap();
+        let counts = import_bundle(&foreign, &from_local, 2, &policies, &(0, tx.clone())).unwrap();
+        assert_eq!(counts.changes_received, 2);
+
+        export_bundle(&foreign, &to_local, Some(&from_local), &(0, tx.clone())).unwrap();
+        let from_foreign = Bundle::open(&to_local).unwrap();
+        assert_eq!(
+            from_foreign.manifest.imported_syncpoint,
+            from_local.manifest.export_syncpoint
+        );
+        import_bundle(&fdb, &from_foreign, 2, &policies, &(0, tx.clone())).unwrap();
+
+        for db in &[&*fdb, &*foreign] {
+            assert_eq!(db.get_file_with_id(60).unwrap().tags, mapvec!(String::from: "local"));
+            assert_eq!(db.get_file_with_id(61).unwrap().tags, mapvec!(String::from: "foreign"));
+        }
+        // Both instances have each others changes up to the first export
+        let first_export = from_local.manifest.export_syncpoint.clone().unwrap();
+        assert!(fdb.get_syncpoints().unwrap().contains(&first_export));
+        assert!(foreign.get_syncpoints().unwrap().contains(&first_export));
+
+        // Only the changes made since are exported in the next round
+        let file = fdb.get_file_with_id(61).unwrap();
+        fdb.change_file_tags(
+            &file,
+            &["foreign".into(), "changed".into()],
+            &ChangeCreationPolicy::Yes(year(2019))
+        ).unwrap();
+
+        let counts = export_bundle(&fdb, &to_foreign_again, Some(&from_foreign), &(0, tx.clone()))
+            .unwrap();
+        assert_eq!(counts.changes_sent, 1);
+        let bundle = Bundle::open(&to_foreign_again).unwrap();
+        let counts = import_bundle(&foreign, &bundle, 2, &policies, &(0, tx)).unwrap();
+        assert_eq!(counts.changes_received, 1);
+        assert_eq!(
+            foreign.get_file_with_id(61).unwrap().tags,
+            mapvec!(String::from: "foreign", "changed")
+        );
+
+        for id in &[60, 61] {
+            remove_file(*id, &fdb, &ChangeCreationPolicy::No).unwrap();
+            remove_file(*id, &foreign, &ChangeCreationPolicy::No).unwrap();
+        }
+        for directory in &[to_foreign, to_local, to_foreign_again] {
+            fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn bundle_paths_are_confined_to_the_bundle_folder() {
+        let root = PathBuf::from("/tmp/bundles");
+
+        assert_eq!(
+            bundle_directory(Some(root.clone()), "2019/camera").unwrap(),
+            root.join("2019/camera")
+        );
+        for path in &["", "/etc", "../outside", "2019/../../outside"] {
+            assert_matches!(
+                bundle_directory(Some(root.clone()), path).map_err(|e| e.0),
+                Err(ErrorKind::InvalidBundlePath(_))
+            );
+        }
+        assert_matches!(
+            bundle_directory(None, "2019").map_err(|e| e.0),
+            Err(ErrorKind::BundlePathMissing)
+        );
+    }
+
+    #[test]
+    fn modified_bundles_are_rejected() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let directory = empty_directory(&fdb, "bundle_modified");
+        add_stored_file(&fdb, 49, &[], year(2018));
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        export_bundle(&fdb, &directory, None, &(0, tx)).unwrap();
+        remove_file(49, &fdb, &ChangeCreationPolicy::No).unwrap();
+        assert!(Bundle::open(&directory).is_ok());
+
+        let content = directory.join(PARTS_FOLDER).join(FilePart::Content.filename(49));
+        fs::write(&content, vec!(1, 2, 4)).unwrap();
+        assert_matches!(
+            Bundle::open(&directory).err(),
+            Some(::error::Error(ErrorKind::CorruptBundle(_), _))
+        );
+        fs::remove_file(&content).unwrap();
+        assert_matches!(
+            Bundle::open(&directory).err(),
+            Some(::error::Error(ErrorKind::CorruptBundle(_), _))
+        );
+
+        let manifest_path = directory.join(MANIFEST_FILE);
+        let manifest = fs::read_to_string(&manifest_path).unwrap()
+            .replace(&format!("\"version\":{}", BUNDLE_VERSION), "\"version\":100");
+        fs::write(&manifest_path, manifest).unwrap();
+        assert_matches!(
+            Bundle::open(&directory).err(),
+            Some(::error::Error(ErrorKind::UnsupportedBundleVersion(100), _))
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
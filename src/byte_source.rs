@@ -1,4 +1,6 @@
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
@@ -7,12 +9,15 @@ use error::{Result, ErrorKind, ResultExt};
 #[derive(Clone, Debug)]
 pub enum ByteSource {
     File(PathBuf),
-    Memory(Vec<u8>)
+    Memory(Vec<u8>),
+    /// A file which is no longer needed once its content has been saved. It
+    /// is moved to its destination instead of being copied
+    TempFile(PathBuf)
 }
 
 pub fn vec_from_byte_source(source: ByteSource) -> Result<Vec<u8>> {
     match source {
-        ByteSource::File(path) => {
+        ByteSource::File(path) | ByteSource::TempFile(path) => {
             let mut file = File::open(&path)
                 .chain_err(||ErrorKind::ByteSourceExpansionFailed)?;
             let mut buffer = vec!();
@@ -25,15 +30,58 @@ pub fn vec_from_byte_source(source: ByteSource) -> Result<Vec<u8>> {
     }
 }
 
+/**
+  Writes the content of `source` to `path`. The content is written to a
+  temporary file first which is renamed once it is complete, so `path` never
+  contains a partially written file. File content is streamed rather than
+  read into memory
+*/
 pub fn write_byte_source_to_file(source: ByteSource, path: &Path) -> Result<()> {
-    let content = vec_from_byte_source(source)?;
+    let partial_path = partial_path(path);
 
-    let mut file = File::create(path)?;
-    file.write_all(&content)?;
+    match source {
+        ByteSource::TempFile(source_path) => {
+            // Renaming fails if the files are on different file systems
+            if fs::rename(&source_path, path).is_ok() {
+                return Ok(());
+            }
+            copy_file(&source_path, &partial_path)?;
+            fs::remove_file(&source_path)?;
+        }
+        ByteSource::File(source_path) => {
+            copy_file(&source_path, &partial_path)?;
+        }
+        ByteSource::Memory(content) => {
+            let mut file = File::create(&partial_path)?;
+            file.write_all(&content)?;
+        }
+    }
 
+    fs::rename(&partial_path, path)?;
     Ok(())
 }
 
+fn copy_file(source: &Path, destination: &Path) -> Result<()> {
+    let mut source = File::open(source)
+        .chain_err(||ErrorKind::ByteSourceExpansionFailed)?;
+    let mut destination = File::create(destination)?;
+    io::copy(&mut source, &mut destination)?;
+
+    Ok(())
+}
+
+/**
+  Returns the path that content which is going to be stored at `path` is
+  written to before it is complete
+*/
+fn partial_path(path: &Path) -> PathBuf {
+    let mut filename = path.file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    filename.push(".part");
+    path.with_file_name(filename)
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -58,4 +106,33 @@ mod tests {
                 .collect::<Vec<_>>()
         );
     }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("flash_{}_{}", name, ::rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn byte_sources_are_written_to_files() {
+        let dir = temp_dir("byte_source_write");
+
+        write_byte_source_to_file(ByteSource::Memory(vec!(1, 2, 3)), &dir.join("memory")).unwrap();
+        assert_eq!(fs::read(dir.join("memory")).unwrap(), vec!(1, 2, 3));
+
+        write_byte_source_to_file(ByteSource::File(dir.join("memory")), &dir.join("copy")).unwrap();
+        assert_eq!(fs::read(dir.join("copy")).unwrap(), vec!(1, 2, 3));
+        assert!(dir.join("memory").exists());
+
+        write_byte_source_to_file(ByteSource::TempFile(dir.join("copy")), &dir.join("moved")).unwrap();
+        assert_eq!(fs::read(dir.join("moved")).unwrap(), vec!(1, 2, 3));
+        assert!(!dir.join("copy").exists());
+
+        // No partially written files are left behind
+        let mut files = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!("memory", "moved"));
+    }
 }
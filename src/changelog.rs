@@ -1,9 +1,8 @@
 use chrono::NaiveDateTime;
 
-use std::hash::{Hasher, Hash};
-use std::collections::hash_map::DefaultHasher;
 use std::convert::From;
 use serde_json;
+use sha2::{Sha256, Digest};
 
 use schema::{changes, syncpoints};
 
@@ -16,7 +15,9 @@ use std::cmp::Ordering;
 pub enum UpdateType {
     TagAdded(String),
     TagRemoved(String),
-    CreationDateChanged(NaiveDateTime)
+    CreationDateChanged(NaiveDateTime),
+    /// The content of the file was replaced by content with the specified hash
+    ContentChanged(String)
 }
 
 
@@ -27,26 +28,119 @@ pub enum ChangeType {
     Update(UpdateType)
 }
 
+/**
+  The names of the change types this instance can apply, which are sent to
+  peers so they don't send changes that can't be applied
+*/
+pub const CHANGE_TYPES: &[&str] = &[
+    "FileAdded",
+    "FileRemoved",
+    "TagAdded",
+    "TagRemoved",
+    "CreationDateChanged",
+    "ContentChanged"
+];
+
+/**
+  The change types that are left out when syncing with peers that can't apply
+  them instead of refusing the sync. Peers without `ContentChanged` keep the
+  old content of the file
+*/
+pub const OPTIONAL_CHANGE_TYPES: &[&str] = &["ContentChanged"];
+
+impl ChangeType {
+    /**
+      The name of the change type in `CHANGE_TYPES`
+    */
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChangeType::FileAdded => "FileAdded",
+            ChangeType::FileRemoved => "FileRemoved",
+            ChangeType::Update(UpdateType::TagAdded(_)) => "TagAdded",
+            ChangeType::Update(UpdateType::TagRemoved(_)) => "TagRemoved",
+            ChangeType::Update(UpdateType::CreationDateChanged(_)) => "CreationDateChanged",
+            ChangeType::Update(UpdateType::ContentChanged(_)) => "ContentChanged"
+        }
+    }
+}
+
+/**
+  Hybrid logical clock of a change. Changes and syncpoints are ordered by
+  `counter` instead of their wall clock timestamps, which can't be trusted
+  when the clocks of the peers differ.
+
+  The counter of a new change is larger than the counter of every change and
+  syncpoint that the instance knows about, so changes made after a sync always
+  come after its syncpoint. It is kept at least as large as the timestamp of
+  the change in microseconds to stay close to the wall clock when it is
+  correct. Changes from before logical clocks were added use that timestamp
+  as their counter.
+
+  `instance` is the id of the instance that created the change. It breaks ties
+  between changes with the same counter from different instances
+*/
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Hash, Default)]
+pub struct LogicalClock {
+    pub counter: i64,
+    pub instance: String
+}
+
+impl LogicalClock {
+    /**
+      Returns the clock used for changes which were not created by an instance
+      with logical clocks
+    */
+    pub fn from_timestamp(timestamp: NaiveDateTime) -> Self {
+        Self {
+            counter: timestamp_clock(timestamp),
+            instance: String::new()
+        }
+    }
+}
+
+/**
+  Returns the smallest counter a logical clock for something which happened at
+  `timestamp` may have, the timestamp in microseconds since the unix epoch
+*/
+pub fn timestamp_clock(timestamp: NaiveDateTime) -> i64 {
+    timestamp.and_utc().timestamp_micros()
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Hash)]
 pub struct Change {
-    pub id: i32,
+    pub id: i64,
     pub change_type: ChangeType,
     pub affected_file: i32,
-    pub timestamp: NaiveDateTime
+    pub timestamp: NaiveDateTime,
+    /// Conflicts that were logged before logical clocks were added contain
+    /// changes without clocks
+    #[serde(default)]
+    pub clock: LogicalClock
 }
 
 impl Change {
+    /**
+      Creates a change with a clock derived from `timestamp`. Changes that are
+      recorded by a `FileDatabase` get their clock from `with_clock`
+    */
     pub fn new(timestamp: NaiveDateTime, affected_file: i32, change_type: ChangeType) -> Change {
-        let mut hasher = DefaultHasher::default();
-        timestamp.hash(&mut hasher);
-        affected_file.hash(&mut hasher);
-        change_type.hash(&mut hasher);
-        let id = hasher.finish() as i32;
         Change {
-            id: id,
+            id: change_id(timestamp, affected_file, &change_type),
             timestamp,
             affected_file,
-            change_type
+            change_type,
+            clock: LogicalClock::from_timestamp(timestamp)
+        }
+    }
+
+    /**
+      Replaces the logical clock of the change. The clock is not part of the id
+      since peers have to agree on the id of a change before they know its clock
+    */
+    pub fn with_clock(self, clock: LogicalClock) -> Change {
+        Change {
+            clock,
+            .. self
         }
     }
 
@@ -55,11 +149,39 @@ impl Change {
             id: db_entry.id,
             affected_file: db_entry.affected_file,
             timestamp: db_entry.timestamp,
-            change_type: serde_json::from_str(&db_entry.json_data)?
+            change_type: serde_json::from_str(&db_entry.json_data)?,
+            clock: LogicalClock {
+                counter: db_entry.clock,
+                instance: db_entry.origin.clone()
+            }
         })
     }
 }
 
+/**
+  Computes the id of a change. All peers have to agree on the id of a change
+  so it is derived from a hash which doesn't depend on the rust version.
+
+  The id is the first 8 bytes, read as a big endian signed integer, of the
+  SHA-256 of `<timestamp>\n<affected_file>\n<change_type>` where the timestamp
+  is in microseconds since the unix epoch and the change type is encoded as
+  json. Timestamps are stored with microsecond precision in the database so
+  anything finer is ignored.
+
+  The `add_change_id_hash` migration computes the same ids in SQL
+*/
+pub fn change_id(timestamp: NaiveDateTime, affected_file: i32, change_type: &ChangeType) -> i64 {
+    let micros = timestamp.and_utc().timestamp_micros();
+    let change_type = serde_json::to_string(change_type)
+        .expect("Change types can always be serialized");
+
+    let digest = Sha256::digest(format!("{}\n{}\n{}", micros, affected_file, change_type).as_bytes());
+
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(bytes)
+}
+
 fn type_order_int(change_type: &ChangeType) -> u8 {
     match *change_type {
         ChangeType::FileAdded => 0,
@@ -68,21 +190,22 @@ fn type_order_int(change_type: &ChangeType) -> u8 {
     }
 }
 /**
-  Sorts a given vector of changes first according to timestamps and if there
-  are conflicts it ensures that additions happen before updates which happens
-  before removals
+  Sorts a given vector of changes first according to their logical clocks, then
+  timestamps and if there are conflicts it ensures that additions happen before
+  updates which happens before removals. Changes with the same counter from
+  different instances are ordered by instance id last
 */
 pub fn sorted_changes(changes: &[Change]) -> Vec<Change> {
 
     let mut result = changes.to_vec();
 
     result.sort_by(|change1, change2| {
-        match change1.timestamp.cmp(&change2.timestamp) {
-            Ordering::Equal => {
+        change1.clock.counter.cmp(&change2.clock.counter)
+            .then_with(|| change1.timestamp.cmp(&change2.timestamp))
+            .then_with(|| {
                 type_order_int(&change1.change_type).cmp(&type_order_int(&change2.change_type))
-            }
-            not_equal => not_equal
-        }
+            })
+            .then_with(|| change1.clock.instance.cmp(&change2.clock.instance))
     });
 
 
@@ -91,10 +214,12 @@ pub fn sorted_changes(changes: &[Change]) -> Vec<Change> {
 
 #[derive(Queryable)]
 pub struct ChangeDbEntry {
-    id: i32,
+    id: i64,
     timestamp: NaiveDateTime,
     json_data: String,
     affected_file: i32,
+    clock: i64,
+    origin: String,
 }
 
 impl<'a> From<&'a Change> for ChangeDbEntry {
@@ -103,7 +228,9 @@ impl<'a> From<&'a Change> for ChangeDbEntry {
             id: other.id,
             json_data: serde_json::to_string(&other.change_type).unwrap(),
             affected_file: other.affected_file,
-            timestamp: other.timestamp
+            timestamp: other.timestamp,
+            clock: other.clock.counter,
+            origin: other.clock.instance.clone()
         }
     }
 }
@@ -111,10 +238,12 @@ impl<'a> From<&'a Change> for ChangeDbEntry {
 #[derive(Insertable)]
 #[table_name="changes"]
 pub struct InsertableChange<'a> {
-    id: i32,
+    id: i64,
     json_data: &'a str,
     affected_file: i32,
-    timestamp: NaiveDateTime
+    timestamp: NaiveDateTime,
+    clock: i64,
+    origin: &'a str
 }
 
 impl<'a> From<&'a ChangeDbEntry> for InsertableChange<'a> {
@@ -123,16 +252,49 @@ impl<'a> From<&'a ChangeDbEntry> for InsertableChange<'a> {
             id: other.id,
             json_data: &other.json_data,
             affected_file: other.affected_file,
-            timestamp: other.timestamp
+            timestamp: other.timestamp,
+            clock: other.clock,
+            origin: &other.origin
         }
     }
 }
 
 
-#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Clone, Debug, PartialOrd, Ord, Eq)]
+/**
+  A point where two instances were synced. Changes with a larger clock
+  counter than `clock` were made after the sync
+*/
+#[derive(Queryable, Insertable, Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
 #[table_name="syncpoints"]
 pub struct SyncPoint {
-    pub last_change: NaiveDateTime
+    pub last_change: NaiveDateTime,
+    pub clock: i64
+}
+
+impl SyncPoint {
+    /**
+      Creates a syncpoint with a clock derived from its timestamp, like the
+      syncpoints which were created before logical clocks were added
+    */
+    pub fn from_timestamp(last_change: NaiveDateTime) -> Self {
+        Self {
+            last_change,
+            clock: timestamp_clock(last_change)
+        }
+    }
+}
+
+impl Ord for SyncPoint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.clock.cmp(&other.clock)
+            .then_with(|| self.last_change.cmp(&other.last_change))
+    }
+}
+
+impl PartialOrd for SyncPoint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 #[derive(Clone)]
@@ -148,6 +310,23 @@ mod changelog_tests {
     use super::*;
     use chrono::NaiveDate;
 
+    #[test]
+    fn change_ids_are_stable() {
+        let timestamp = NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0);
+
+        // Computed independently from the format described in `change_id`
+        assert_eq!(Change::new(timestamp, 1, ChangeType::FileAdded).id, 5531115995610367147);
+
+        assert_ne!(
+            Change::new(timestamp, 1, ChangeType::FileAdded).id,
+            Change::new(timestamp, 1, ChangeType::FileRemoved).id
+        );
+        assert_ne!(
+            Change::new(timestamp, 1, ChangeType::FileAdded).id,
+            Change::new(timestamp, 2, ChangeType::FileAdded).id
+        );
+    }
+
     #[test]
     fn change_sorting() {
         let change_20160401 = Change::new(
@@ -0,0 +1,228 @@
+use iron::prelude::*;
+use iron::status;
+use persistent;
+
+use std::collections::{HashMap, HashSet};
+
+use changelog::{Change, ChangeType, UpdateType, SyncPoint, sorted_changes};
+use file_database::FileDatabase;
+use foreign_server::{ForeignServer, HttpForeignServer};
+use request_helpers::{to_json_with_result, setup_db_connection};
+use settings::Settings;
+use sync::last_common_syncpoint;
+use sync_auth::authenticate_admin_request;
+
+use error::{Result, ResultExt};
+
+/**
+  The result of compacting the change log
+*/
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Compaction {
+    /// Changes up to this syncpoint were compacted. `None` if there is no
+    /// syncpoint shared with every peer
+    pub syncpoint: Option<SyncPoint>,
+    pub removed_changes: usize
+}
+
+/**
+  The changes which decide the current state of a file
+*/
+#[derive(Default)]
+struct FileHistory {
+    added: Option<i64>,
+    /// The last change that added each of the current tags
+    tags: HashMap<String, i64>,
+    date: Option<i64>,
+    content: Option<i64>
+}
+
+/**
+  Returns the ids of the changes in `changes` which can be removed without
+  changing the result of applying them. What is left is the `FileAdded`
+  change of every file that was not removed, the last `TagAdded` change of
+  each of its current tags and its last `CreationDateChanged` and
+  `ContentChanged` changes
+*/
+pub fn superseded_changes(changes: &[Change]) -> Vec<i64> {
+    let mut files: HashMap<i32, FileHistory> = HashMap::new();
+    for change in sorted_changes(changes) {
+        let file_id = change.affected_file;
+        match change.change_type {
+            ChangeType::FileAdded => {
+                files.entry(file_id).or_default().added = Some(change.id);
+            }
+            ChangeType::Update(UpdateType::TagAdded(tag)) => {
+                files.entry(file_id).or_default().tags.insert(tag, change.id);
+            }
+            ChangeType::Update(UpdateType::TagRemoved(tag)) => {
+                files.entry(file_id).or_default().tags.remove(&tag);
+            }
+            ChangeType::Update(UpdateType::CreationDateChanged(_)) => {
+                files.entry(file_id).or_default().date = Some(change.id);
+            }
+            ChangeType::Update(UpdateType::ContentChanged(_)) => {
+                files.entry(file_id).or_default().content = Some(change.id);
+            }
+            ChangeType::FileRemoved => {
+                files.remove(&file_id);
+            }
+        }
+    }
+
+    let needed = files.values()
+        .flat_map(|history| {
+            history.added.into_iter()
+                .chain(history.tags.values().cloned())
+                .chain(history.date)
+                .chain(history.content)
+        })
+        .collect::<HashSet<_>>();
+
+    changes.iter()
+        .map(|change| change.id)
+        .filter(|id| !needed.contains(id))
+        .collect()
+}
+
+/**
+  Returns the oldest of the last syncpoints shared with each of `peers`. Every
+  peer has every change up to that syncpoint, so they are never sent again.
+  Returns `None` if there are no peers or one of them shares no syncpoint
+*/
+pub fn compaction_syncpoint(fdb: &FileDatabase, peers: &[&dyn ForeignServer])
+    -> Result<Option<SyncPoint>>
+{
+    let local = fdb.get_syncpoints()?;
+
+    let mut oldest: Option<SyncPoint> = None;
+    for peer in peers {
+        let remote = peer.get_syncpoints()
+            .chain_err(|| "Failed to get syncpoints from a peer")?;
+
+        match last_common_syncpoint(&local, &remote) {
+            Some(common) => {
+                if oldest.as_ref().is_none_or(|oldest| common.clock < oldest.clock) {
+                    oldest = Some(common);
+                }
+            }
+            None => return Ok(None)
+        }
+    }
+    Ok(oldest)
+}
+
+/**
+  Removes the changes up to `syncpoint` which are superseded by later changes
+  in the change log. Returns the amount of removed changes
+*/
+pub fn compact_changes(fdb: &FileDatabase, syncpoint: &SyncPoint) -> Result<usize> {
+    let changes = fdb.get_changes_until_clock(syncpoint.clock)?;
+
+    let superseded = superseded_changes(&changes);
+    fdb.remove_changes(&superseded)?;
+
+    Ok(superseded.len())
+}
+
+/**
+  Compacts the changes up to the oldest syncpoint shared with all registered
+  peers and all instances that syncpoints were shared with. Instances that are
+  not registered as peers are contacted with the sync secret of this instance.
+  Nothing is compacted if one of them can't be reached
+*/
+pub fn compact_for_peers(fdb: &FileDatabase, settings: &Settings) -> Result<Compaction> {
+    let mut known = fdb.get_peers()?.into_iter()
+        .map(|peer| (peer.url, peer.secret))
+        .collect::<HashMap<_, _>>();
+    for url in fdb.get_syncpoint_urls()? {
+        known.entry(url).or_insert(None);
+    }
+
+    let mut servers = vec!();
+    for (url, secret) in known {
+        servers.push(HttpForeignServer::from_settings(url, secret, settings)?);
+    }
+    let servers = servers.iter().map(|server| server as &dyn ForeignServer).collect::<Vec<_>>();
+
+    let syncpoint = compaction_syncpoint(fdb, &servers)?;
+    let removed_changes = match syncpoint {
+        Some(ref syncpoint) => compact_changes(fdb, syncpoint)?,
+        None => 0
+    };
+
+    Ok(Compaction{syncpoint, removed_changes})
+}
+
+
+pub fn compact_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+    let fdb = setup_db_connection(request)?;
+
+    let compaction = compact_for_peers(&fdb, &settings)?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(compaction)?)))
+}
+
+
+#[cfg(test)]
+mod compaction_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    fn change(day: u32, file: i32, change_type: ChangeType) -> Change {
+        Change::new(NaiveDate::from_ymd(2018, 1, day).and_hms(0, 0, 0), file, change_type)
+    }
+
+    fn tag_added(day: u32, file: i32, tag: &str) -> Change {
+        change(day, file, ChangeType::Update(UpdateType::TagAdded(tag.into())))
+    }
+
+    fn tag_removed(day: u32, file: i32, tag: &str) -> Change {
+        change(day, file, ChangeType::Update(UpdateType::TagRemoved(tag.into())))
+    }
+
+    #[test]
+    fn only_the_current_state_is_kept() {
+        let date = NaiveDate::from_ymd(2010, 1, 1).and_hms(0, 0, 0);
+        let changes = vec!(
+            change(1, 1, ChangeType::FileAdded),
+            tag_added(2, 1, "a"),
+            tag_removed(3, 1, "a"),
+            tag_added(4, 1, "a"),
+            tag_added(5, 1, "b"),
+            tag_removed(6, 1, "b"),
+            change(7, 1, ChangeType::Update(UpdateType::CreationDateChanged(date))),
+            change(8, 1, ChangeType::Update(UpdateType::CreationDateChanged(date))),
+            change(1, 2, ChangeType::FileAdded),
+            tag_added(2, 2, "a"),
+            change(3, 2, ChangeType::FileRemoved),
+        );
+
+        let superseded = superseded_changes(&changes);
+        let kept = changes.iter()
+            .filter(|change| !superseded.contains(&change.id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        assert_eq!(kept, vec!(changes[0].clone(), changes[3].clone(), changes[7].clone()));
+    }
+
+    #[test]
+    fn only_the_last_content_change_is_kept() {
+        let replaced = |day, hash: &str| {
+            change(day, 1, ChangeType::Update(UpdateType::ContentChanged(hash.into())))
+        };
+        let changes = vec!(
+            change(1, 1, ChangeType::FileAdded),
+            replaced(2, "a"),
+            replaced(3, "b"),
+            replaced(4, "a"),
+        );
+
+        assert_eq!(superseded_changes(&changes), vec!(changes[1].id, changes[2].id));
+    }
+}
@@ -0,0 +1,907 @@
+use iron::prelude::*;
+use iron::status;
+
+use persistent;
+
+use chrono::{NaiveDateTime, Utc};
+use serde_json;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use byte_source::ByteSource;
+use changelog::{Change, ChangeType, UpdateType, ChangeCreationPolicy, LogicalClock};
+use file_database::FileDatabase;
+use file_handler::{remove_file, replace_file_content, ThumbnailStrategy};
+use file_util::ThumbnailSettings;
+use foreign_server::ForeignServer;
+use request_helpers::{to_json_with_result, get_get_i64, setup_db_connection};
+use schema::sync_conflicts;
+use settings::Settings;
+use sync::{download_with_retries, with_retries};
+use sync_auth::authenticate_admin_request;
+use sync_control;
+
+use error::{Result, ErrorKind, ResultExt};
+
+/// The folder in the file storage where content that lost a conflict is kept
+/// until the conflict is overridden
+const DISCARDED_CONTENT_FOLDER: &str = "discarded_content";
+
+/// How a tag that was added on one side and removed on the other is resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagConflictPolicy {
+    /// The change with the later logical clock is kept
+    LastWriterWins,
+    /// The tag is kept since it was added on one of the sides
+    Union
+}
+
+/// How a file that was removed on one side and edited on the other is resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemovalConflictPolicy {
+    /// The file is kept if the edit has a later logical clock than the removal
+    LastWriterWins,
+    /// The file is always kept
+    Keep
+}
+
+/**
+  The policies used to resolve conflicting changes during a sync. Conflicting
+  creation dates always use last writer wins
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConflictPolicies {
+    pub tags: TagConflictPolicy,
+    pub removals: RemovalConflictPolicy
+}
+
+impl Default for ConflictPolicies {
+    fn default() -> Self {
+        Self {
+            tags: TagConflictPolicy::LastWriterWins,
+            removals: RemovalConflictPolicy::LastWriterWins
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Local,
+    Foreign
+}
+
+impl Side {
+    fn other(self) -> Side {
+        match self {
+            Side::Local => Side::Foreign,
+            Side::Foreign => Side::Local
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConflictKind {
+    /// The tag was added on one side and removed on the other
+    Tag(String),
+    /// The creation date was changed to different dates
+    CreationDate,
+    /// The content of the file was replaced with different content
+    Content,
+    /// The file was removed on one side and edited on the other
+    Removal
+}
+
+/**
+  Two conflicting changes to the same file and which of them was kept
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConflictResolution {
+    pub file_id: i32,
+    pub kind: ConflictKind,
+    /// The last conflicting change made on this server
+    pub local_change: Change,
+    /// The last conflicting change made on the foreign server
+    pub foreign_change: Change,
+    /// The side whose change was kept
+    pub kept: Side
+}
+
+impl ConflictResolution {
+    pub fn discarded_change(&self) -> &Change {
+        match self.kept {
+            Side::Local => &self.foreign_change,
+            Side::Foreign => &self.local_change
+        }
+    }
+}
+
+/**
+  A conflict that was resolved during a sync, stored for the user to review
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub id: i32,
+    pub detected_at: NaiveDateTime,
+    /// True if the user has replaced the kept change with the discarded one
+    pub overridden: bool,
+    pub resolution: ConflictResolution
+}
+
+impl Conflict {
+    pub fn from_db_entry(db_entry: &ConflictDbEntry) -> Result<Self> {
+        Ok(Self {
+            id: db_entry.id,
+            detected_at: db_entry.detected_at,
+            overridden: db_entry.overridden,
+            resolution: serde_json::from_str(&db_entry.json_data)?
+        })
+    }
+}
+
+#[derive(Queryable)]
+pub struct ConflictDbEntry {
+    id: i32,
+    // Only stored to allow looking up the conflicts of a file
+    _file_id: i32,
+    json_data: String,
+    detected_at: NaiveDateTime,
+    overridden: bool
+}
+
+#[derive(Insertable)]
+#[table_name="sync_conflicts"]
+pub struct NewConflict {
+    file_id: i32,
+    json_data: String,
+    detected_at: NaiveDateTime
+}
+
+impl NewConflict {
+    pub fn new(resolution: &ConflictResolution, detected_at: NaiveDateTime) -> Self {
+        Self {
+            file_id: resolution.file_id,
+            json_data: serde_json::to_string(resolution).unwrap(),
+            detected_at
+        }
+    }
+}
+
+/**
+  The changes made on each side of a sync since the last common syncpoint
+*/
+pub struct SyncedChanges {
+    /// Changes made on this server
+    pub local_changes: Vec<Change>,
+    /// Changes made on the foreign server
+    pub remote_changes: Vec<Change>,
+    /// Files that were removed on either side
+    pub removed_files: Vec<i32>
+}
+
+/**
+  The changes that a sync applies on each side once conflicts are resolved
+*/
+pub struct ResolvedChanges {
+    /// Changes to apply on the foreign server
+    pub local_changes: Vec<Change>,
+    /// Changes to apply on this server
+    pub remote_changes: Vec<Change>,
+    pub removed_files: Vec<i32>,
+    pub conflicts: Vec<ConflictResolution>
+}
+
+/**
+  Finds changes made on both sides since the last sync which conflict and
+  drops the ones that are discarded by `policies`.
+
+  Files that are kept even though they were removed on one side are added
+  back on that side with changes at `now` with the logical clock `clock`, which
+  should come after all the changes on both sides. `local_tags` and
+  `foreign_tags` look up the current tags of a file on each side for this
+*/
+pub fn resolve_conflicts<L, F>(
+    changes: SyncedChanges,
+    policies: &ConflictPolicies,
+    local_tags: L,
+    foreign_tags: F,
+    now: NaiveDateTime,
+    clock: &LogicalClock
+) -> Result<ResolvedChanges>
+    where L: Fn(i32) -> Result<Vec<String>>,
+          F: Fn(i32) -> Result<Vec<String>>
+{
+    let SyncedChanges { local_changes, remote_changes, removed_files } = changes;
+    let mut resolved = ResolvedChanges {
+        local_changes,
+        remote_changes,
+        removed_files,
+        conflicts: vec!()
+    };
+
+    resolve_removal_conflicts(&mut resolved, Side::Local, policies.removals, foreign_tags, now, clock)?;
+    resolve_removal_conflicts(&mut resolved, Side::Foreign, policies.removals, local_tags, now, clock)?;
+    resolve_field_conflicts(&mut resolved, policies.tags);
+
+    Ok(resolved)
+}
+
+fn is_update(change: &Change) -> bool {
+    matches!(change.change_type, ChangeType::Update(_))
+}
+
+fn is_tag_addition(change: &Change) -> bool {
+    matches!(change.change_type, ChangeType::Update(UpdateType::TagAdded(_)))
+}
+
+/**
+  Returns the latest change in `changes` that matches `predicate` by logical
+  clock. Changes later in the list win if their clocks are equal
+*/
+fn last_change<P>(changes: &[Change], predicate: P) -> Option<&Change>
+    where P: Fn(&Change) -> bool
+{
+    changes.iter()
+        .filter(|change| predicate(change))
+        .fold(None, |last, change| match last {
+            Some(last) if last.clock > change.clock => Some(last),
+            _ => Some(change)
+        })
+}
+
+/**
+  Resolves conflicts between files removed on `removed_on` and edited on the
+  other side. `edited_tags` looks up the tags on the side that edited the file
+  which are restored if the file is kept.
+
+  Files added on the editing side since the last sync were never shared, so
+  edits to them don't conflict with the removal
+*/
+fn resolve_removal_conflicts<T>(
+    resolved: &mut ResolvedChanges,
+    removed_on: Side,
+    policy: RemovalConflictPolicy,
+    edited_tags: T,
+    now: NaiveDateTime,
+    clock: &LogicalClock
+) -> Result<()>
+    where T: Fn(i32) -> Result<Vec<String>>
+{
+    let conflicts = {
+        let (removing, editing) = match removed_on {
+            Side::Local => (&resolved.local_changes, &resolved.remote_changes),
+            Side::Foreign => (&resolved.remote_changes, &resolved.local_changes)
+        };
+
+        let mut conflicts: Vec<(Change, Change)> = vec!();
+        for change in removing.iter().filter(|change| change.change_type == ChangeType::FileRemoved) {
+            let file_id = change.affected_file;
+            let removed_or_added_on_other_side = editing.iter()
+                .any(|c| c.affected_file == file_id && !is_update(c));
+            if removed_or_added_on_other_side || conflicts.iter().any(|(r, _)| r.affected_file == file_id) {
+                continue;
+            }
+
+            if let Some(edit) = last_change(editing, |c| c.affected_file == file_id && is_update(c)) {
+                let removal = last_change(removing, |c| {
+                    c.affected_file == file_id && c.change_type == ChangeType::FileRemoved
+                }).unwrap();
+                conflicts.push((removal.clone(), edit.clone()));
+            }
+        }
+        conflicts
+    };
+
+    for (removal, edit) in conflicts {
+        let file_id = removal.affected_file;
+        let keep = policy == RemovalConflictPolicy::Keep || edit.clock > removal.clock;
+
+        if keep {
+            resolved.removed_files.retain(|id| *id != file_id);
+
+            let mut restore = vec!(Change::new(now, file_id, ChangeType::FileAdded).with_clock(clock.clone()));
+            for tag in edited_tags(file_id)? {
+                restore.push(
+                    Change::new(now, file_id, ChangeType::Update(UpdateType::TagAdded(tag)))
+                        .with_clock(clock.clone())
+                );
+            }
+
+            // The side that removed the file gets it back in the state of the other
+            // side and none of the changes it made to the file are sent
+            let (applied_on_removing, sent_from_removing) = match removed_on {
+                Side::Local => (&mut resolved.remote_changes, &mut resolved.local_changes),
+                Side::Foreign => (&mut resolved.local_changes, &mut resolved.remote_changes)
+            };
+            applied_on_removing.retain(|change| change.affected_file != file_id);
+            applied_on_removing.extend(restore);
+            sent_from_removing.retain(|change| change.affected_file != file_id);
+        }
+
+        let (local_change, foreign_change) = match removed_on {
+            Side::Local => (removal, edit),
+            Side::Foreign => (edit, removal)
+        };
+        resolved.conflicts.push(ConflictResolution {
+            file_id,
+            kind: ConflictKind::Removal,
+            local_change,
+            foreign_change,
+            kept: if keep { removed_on.other() } else { removed_on }
+        });
+    }
+
+    Ok(())
+}
+
+/**
+  Returns the field of a file that a change modifies if it can conflict with
+  changes made on the other side
+*/
+fn conflict_field(change: &Change) -> Option<ConflictKind> {
+    match change.change_type {
+        ChangeType::Update(UpdateType::TagAdded(ref tag)) |
+        ChangeType::Update(UpdateType::TagRemoved(ref tag)) => Some(ConflictKind::Tag(tag.clone())),
+        ChangeType::Update(UpdateType::CreationDateChanged(_)) => Some(ConflictKind::CreationDate),
+        ChangeType::Update(UpdateType::ContentChanged(_)) => Some(ConflictKind::Content),
+        _ => None
+    }
+}
+
+fn kept_side(kind: &ConflictKind, local: &Change, foreign: &Change, tag_policy: TagConflictPolicy)
+    -> Side
+{
+    match (kind, tag_policy) {
+        (&ConflictKind::Tag(_), TagConflictPolicy::Union) => {
+            if is_tag_addition(local) { Side::Local } else { Side::Foreign }
+        }
+        _ => {
+            if local.clock != foreign.clock {
+                if local.clock > foreign.clock { Side::Local } else { Side::Foreign }
+            }
+            // Ties keep the tag, or the local change if no tag is involved
+            else if is_tag_addition(foreign) {
+                Side::Foreign
+            }
+            else {
+                Side::Local
+            }
+        }
+    }
+}
+
+/**
+  Resolves tags, creation dates and content that were changed to different
+  values on each side. Only the last change to a field on each side is
+  compared, so edits that end up with the same value do not conflict
+*/
+fn resolve_field_conflicts(resolved: &mut ResolvedChanges, tag_policy: TagConflictPolicy) {
+    let mut conflicts: Vec<ConflictResolution> = vec!();
+
+    for change in &resolved.local_changes {
+        let file_id = change.affected_file;
+        let kind = match conflict_field(change) {
+            Some(kind) => kind,
+            None => continue
+        };
+        let already_found = conflicts.iter()
+            .any(|conflict| conflict.file_id == file_id && conflict.kind == kind);
+        if already_found || resolved.removed_files.contains(&file_id) {
+            continue;
+        }
+
+        let same_field = |c: &Change| c.affected_file == file_id && conflict_field(c).as_ref() == Some(&kind);
+        let local = last_change(&resolved.local_changes, same_field).unwrap();
+        let foreign = match last_change(&resolved.remote_changes, same_field) {
+            Some(foreign) => foreign,
+            None => continue
+        };
+        if local.change_type == foreign.change_type {
+            continue;
+        }
+
+        conflicts.push(ConflictResolution {
+            file_id,
+            kept: kept_side(&kind, local, foreign, tag_policy),
+            kind,
+            local_change: local.clone(),
+            foreign_change: foreign.clone()
+        });
+    }
+
+    for conflict in &conflicts {
+        // The discarded changes are not applied on the side whose change was kept
+        let discarded = match conflict.kept {
+            Side::Local => &mut resolved.remote_changes,
+            Side::Foreign => &mut resolved.local_changes
+        };
+        discarded.retain(|change| {
+            change.affected_file != conflict.file_id || conflict_field(change) != Some(conflict.kind.clone())
+        });
+    }
+
+    resolved.conflicts.extend(conflicts);
+}
+
+
+/**
+  Returns where the content with the hash `content_hash` is kept after it lost
+  a conflict
+*/
+pub fn discarded_content_path(storage_path: &Path, content_hash: &str) -> PathBuf {
+    storage_path.join(DISCARDED_CONTENT_FOLDER).join(content_hash)
+}
+
+/**
+  Keeps the content that loses the content conflicts in `conflicts` so that
+  overriding them can restore it. Content of this instance is copied before it
+  is replaced, and content of the foreign server is downloaded before the
+  foreign server replaces it. This has to be done before any changes are
+  applied on either side.
+
+  Content that is only stored as a thumbnail on the losing side can't be kept
+*/
+pub fn keep_discarded_content(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    conflicts: &[ConflictResolution],
+    job_id: usize
+) -> Result<()> {
+    let storage_path = fdb.get_file_save_path();
+    let retry_policy = sync_control::retry_policy(job_id);
+    for conflict in conflicts {
+        let hash = match conflict.discarded_change().change_type {
+            ChangeType::Update(UpdateType::ContentChanged(ref hash)) => hash,
+            _ => continue
+        };
+        let destination = discarded_content_path(&storage_path, hash);
+        if destination.exists() {
+            continue;
+        }
+        fs::create_dir_all(storage_path.join(DISCARDED_CONTENT_FOLDER))?;
+
+        let file_id = conflict.file_id;
+        match conflict.kept {
+            Side::Foreign => {
+                let file = fdb.get_file_with_id_result(file_id)?;
+                let original_stored = file.is_uploaded && file.original_accessed.is_none();
+                if original_stored && file.content_hash.as_ref() == Some(hash) {
+                    fs::copy(storage_path.join(&file.filename), &destination)?;
+                }
+            }
+            Side::Local => {
+                let details = with_retries(&retry_policy, Some(job_id), || foreign_server.get_file_details(file_id))
+                    .chain_err(|| "Failed to get file details")?;
+                if details.original_stored && details.content_hash.as_ref() == Some(hash) {
+                    // Moved into place once it is complete so that only
+                    // verified content is restored
+                    let download_path = destination.with_extension("download");
+                    download_with_retries(
+                        foreign_server,
+                        file_id,
+                        &download_path,
+                        &details.content_hash,
+                        &retry_policy,
+                        Some(job_id),
+                        &|_| {}
+                    )?;
+                    fs::rename(download_path, &destination)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn conflicts_request_handler(request: &mut Request) -> IronResult<Response> {
+    let fdb = setup_db_connection(request)?;
+
+    let conflicts = fdb.get_sync_conflicts()?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(conflicts)?)))
+}
+
+pub fn override_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+    let fdb = setup_db_connection(request)?;
+
+    let conflict_id = get_get_i64(request, "conflict_id")?;
+
+    handle_override_request(
+        &fdb,
+        conflict_id as i32,
+        &settings.get_thumbnail_settings(),
+        Utc::now().naive_utc()
+    )?;
+
+    Ok(Response::with((status::Ok, "Done")))
+}
+
+/**
+  Replaces the kept change of a conflict with the discarded one. The new state
+  is recorded as a change at `now` which is sent to other servers on the next
+  sync. Discarded content is restored from where `keep_discarded_content`
+  kept it, and gets new thumbnails according to `thumbnail_settings`
+*/
+fn handle_override_request(
+    fdb: &FileDatabase,
+    conflict_id: i32,
+    thumbnail_settings: &ThumbnailSettings,
+    now: NaiveDateTime
+) -> Result<()> {
+    let conflict = fdb.get_sync_conflict(conflict_id)?;
+    if conflict.overridden {
+        bail!(ErrorKind::ConflictAlreadyOverridden(conflict_id));
+    }
+
+    let resolution = &conflict.resolution;
+    let file_id = resolution.file_id;
+    let policy = ChangeCreationPolicy::Yes(now);
+
+    match resolution.discarded_change().change_type {
+        ChangeType::FileRemoved => remove_file(file_id, fdb, &policy)?,
+        // The file was removed on both servers so the edit can't be applied
+        _ if resolution.kind == ConflictKind::Removal => {
+            bail!(ErrorKind::ConflictNotOverridable(conflict_id))
+        }
+        ChangeType::Update(UpdateType::ContentChanged(ref hash)) => {
+            let path = discarded_content_path(&fdb.get_file_save_path(), hash);
+            if !path.exists() {
+                bail!(ErrorKind::ConflictNotOverridable(conflict_id));
+            }
+
+            let file = fdb.get_file_with_id(file_id)
+                .ok_or(ErrorKind::NoSuchFileInDatabase(file_id))?;
+            if !file.is_uploaded || file.original_accessed.is_some() {
+                bail!(ErrorKind::OriginalNotStored(file_id));
+            }
+
+            replace_file_content(
+                fdb,
+                &file,
+                Some(ByteSource::TempFile(path)),
+                hash,
+                ThumbnailStrategy::Generate(thumbnail_settings.clone()),
+                &policy
+            )?;
+        }
+        ChangeType::Update(ref update) => {
+            let file = fdb.get_file_with_id(file_id)
+                .ok_or(ErrorKind::NoSuchFileInDatabase(file_id))?;
+
+            match *update {
+                UpdateType::TagAdded(ref tag) => {
+                    let mut tags = file.tags.clone();
+                    if !tags.contains(tag) {
+                        tags.push(tag.clone());
+                    }
+                    fdb.change_file_tags(&file, &tags, &policy)?;
+                }
+                UpdateType::TagRemoved(ref tag) => {
+                    let tags = file.tags.iter()
+                        .filter(|t| *t != tag)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    fdb.change_file_tags(&file, &tags, &policy)?;
+                }
+                UpdateType::CreationDateChanged(date) => {
+                    fdb.set_file_timestamp(&file, date, &policy)?;
+                }
+                UpdateType::ContentChanged(_) => unreachable!()
+            }
+        }
+        ChangeType::FileAdded => bail!(ErrorKind::ConflictNotOverridable(conflict_id))
+    }
+
+    fdb.set_sync_conflict_overridden(conflict_id)
+}
+
+
+#[cfg(test)]
+mod conflict_tests {
+    use super::*;
+
+    use changelog::timestamp_clock;
+
+    use file_database::FileDatabase;
+    use file_database::db_test_helpers::date;
+    use error::Error;
+
+    fn tag_added(day: u32, file_id: i32, tag: &str) -> Change {
+        Change::new(date(day), file_id, ChangeType::Update(UpdateType::TagAdded(tag.into())))
+    }
+
+    fn tag_removed(day: u32, file_id: i32, tag: &str) -> Change {
+        Change::new(date(day), file_id, ChangeType::Update(UpdateType::TagRemoved(tag.into())))
+    }
+
+    fn content_changed(day: u32, file_id: i32, hash: &str) -> Change {
+        Change::new(date(day), file_id, ChangeType::Update(UpdateType::ContentChanged(hash.into())))
+    }
+
+    fn removed(day: u32, file_id: i32) -> Change {
+        Change::new(date(day), file_id, ChangeType::FileRemoved)
+    }
+
+    fn resolve(local: Vec<Change>, remote: Vec<Change>, policies: &ConflictPolicies) -> ResolvedChanges {
+        let mut removed_files = vec!();
+        for change in local.iter().chain(remote.iter()) {
+            if change.change_type == ChangeType::FileRemoved {
+                removed_files.push(change.affected_file);
+            }
+        }
+
+        resolve_conflicts(
+            SyncedChanges {
+                local_changes: local,
+                remote_changes: remote,
+                removed_files
+            },
+            policies,
+            |_| Ok(vec!("local".into())),
+            |_| Ok(vec!("foreign".into())),
+            date(31),
+            &LogicalClock::from_timestamp(date(31))
+        ).unwrap()
+    }
+
+    #[test]
+    fn the_last_tag_change_wins() {
+        let resolved = resolve(
+            vec!(tag_added(2, 1, "a"), tag_removed(1, 2, "b")),
+            vec!(tag_removed(1, 1, "a"), tag_added(2, 2, "b")),
+            &ConflictPolicies::default()
+        );
+
+        assert_eq!(resolved.local_changes, vec!(tag_added(2, 1, "a")));
+        assert_eq!(resolved.remote_changes, vec!(tag_added(2, 2, "b")));
+        assert_eq!(
+            resolved.conflicts.iter().map(|c| (c.file_id, c.kept)).collect::<Vec<_>>(),
+            vec!((1, Side::Local), (2, Side::Foreign))
+        );
+        assert_eq!(resolved.conflicts[0].kind, ConflictKind::Tag("a".into()));
+        assert_eq!(resolved.conflicts[0].discarded_change(), &tag_removed(1, 1, "a"));
+    }
+
+    #[test]
+    fn logical_clocks_decide_which_change_is_last() {
+        // The clock of the remote instance is ahead of its timestamps, so its
+        // change comes after the local one even though it is dated earlier
+        let remote_change = tag_removed(1, 1, "a").with_clock(LogicalClock {
+            counter: timestamp_clock(date(5)),
+            instance: "remote".into()
+        });
+        let resolved = resolve(
+            vec!(tag_added(2, 1, "a")),
+            vec!(remote_change.clone()),
+            &ConflictPolicies::default()
+        );
+
+        assert_eq!(resolved.conflicts.len(), 1);
+        assert_eq!(resolved.conflicts[0].kept, Side::Foreign);
+        assert_eq!(resolved.remote_changes, vec!(remote_change));
+    }
+
+    #[test]
+    fn union_keeps_added_tags() {
+        let policies = ConflictPolicies{tags: TagConflictPolicy::Union, ..ConflictPolicies::default()};
+        let resolved = resolve(
+            vec!(tag_added(1, 1, "a")),
+            vec!(tag_removed(2, 1, "a")),
+            &policies
+        );
+
+        assert_eq!(resolved.conflicts.len(), 1);
+        assert_eq!(resolved.conflicts[0].kept, Side::Local);
+        assert_eq!(resolved.remote_changes, vec!());
+    }
+
+    #[test]
+    fn matching_edits_do_not_conflict() {
+        let local = vec!(tag_added(1, 1, "a"), tag_removed(1, 1, "b"), tag_added(2, 1, "b"));
+        let remote = vec!(tag_added(3, 1, "a"), tag_added(1, 1, "c"), tag_added(1, 1, "b"));
+        let resolved = resolve(local.clone(), remote.clone(), &ConflictPolicies::default());
+
+        assert_eq!(resolved.conflicts, vec!());
+        assert_eq!(resolved.local_changes, local);
+        assert_eq!(resolved.remote_changes, remote);
+    }
+
+    #[test]
+    fn the_last_creation_date_wins() {
+        let changed = |day, new_day| {
+            Change::new(date(day), 1, ChangeType::Update(UpdateType::CreationDateChanged(date(new_day))))
+        };
+        let resolved = resolve(
+            vec!(changed(1, 10), changed(3, 11)),
+            vec!(changed(2, 12)),
+            &ConflictPolicies::default()
+        );
+
+        assert_eq!(resolved.conflicts.len(), 1);
+        assert_eq!(resolved.conflicts[0].kind, ConflictKind::CreationDate);
+        assert_eq!(resolved.conflicts[0].kept, Side::Local);
+        assert_eq!(resolved.remote_changes, vec!());
+    }
+
+    #[test]
+    fn the_last_content_change_wins() {
+        let resolved = resolve(
+            vec!(content_changed(1, 1, "a"), tag_added(3, 1, "b")),
+            vec!(content_changed(2, 1, "c")),
+            &ConflictPolicies::default()
+        );
+
+        assert_eq!(resolved.conflicts.len(), 1);
+        assert_eq!(resolved.conflicts[0].kind, ConflictKind::Content);
+        assert_eq!(resolved.conflicts[0].kept, Side::Foreign);
+        assert_eq!(resolved.local_changes, vec!(tag_added(3, 1, "b")));
+        assert_eq!(resolved.remote_changes, vec!(content_changed(2, 1, "c")));
+
+        // Both sides ending up with the same content is not a conflict
+        let resolved = resolve(
+            vec!(content_changed(1, 1, "a")),
+            vec!(content_changed(2, 1, "a")),
+            &ConflictPolicies::default()
+        );
+        assert_eq!(resolved.conflicts, vec!());
+    }
+
+    #[test]
+    fn files_edited_after_removal_are_restored() {
+        let resolved = resolve(
+            vec!(tag_added(2, 1, "a")),
+            vec!(tag_added(1, 1, "b"), removed(1, 1)),
+            &ConflictPolicies::default()
+        );
+
+        assert_eq!(resolved.removed_files, Vec::<i32>::new());
+        assert_eq!(resolved.conflicts.len(), 1);
+        assert_eq!(resolved.conflicts[0].kind, ConflictKind::Removal);
+        assert_eq!(resolved.conflicts[0].kept, Side::Local);
+        // The foreign server gets the file back with the local tags
+        assert_eq!(resolved.local_changes, vec!(
+            Change::new(date(31), 1, ChangeType::FileAdded),
+            tag_added(31, 1, "local")
+        ));
+        assert_eq!(resolved.remote_changes, vec!());
+    }
+
+    #[test]
+    fn removals_after_edits_win() {
+        let local = vec!(removed(2, 1));
+        let remote = vec!(tag_added(1, 1, "a"));
+        let resolved = resolve(local.clone(), remote.clone(), &ConflictPolicies::default());
+
+        assert_eq!(resolved.removed_files, vec!(1));
+        assert_eq!(resolved.conflicts.len(), 1);
+        assert_eq!(resolved.conflicts[0].kept, Side::Local);
+        assert_eq!(resolved.local_changes, local);
+        assert_eq!(resolved.remote_changes, remote);
+
+        let keep = ConflictPolicies{removals: RemovalConflictPolicy::Keep, ..ConflictPolicies::default()};
+        let resolved = resolve(local, remote, &keep);
+
+        assert_eq!(resolved.removed_files, Vec::<i32>::new());
+        assert_eq!(resolved.conflicts[0].kept, Side::Foreign);
+        assert_eq!(resolved.local_changes, vec!());
+        assert_eq!(resolved.remote_changes, vec!(
+            Change::new(date(31), 1, ChangeType::FileAdded),
+            tag_added(31, 1, "foreign")
+        ));
+    }
+
+    #[test]
+    fn files_added_since_the_last_sync_do_not_conflict_with_removals() {
+        let resolved = resolve(
+            vec!(Change::new(date(2), 1, ChangeType::FileAdded), tag_added(2, 1, "a")),
+            vec!(removed(1, 1)),
+            &ConflictPolicies::default()
+        );
+
+        assert_eq!(resolved.conflicts, vec!());
+        assert_eq!(resolved.removed_files, vec!(1));
+    }
+
+    db_test!(conflicts_can_be_overridden(fdb) {
+        fdb.add_new_file(1, "1.jpg", None, &["a".into()], 0, &ChangeCreationPolicy::No);
+
+        let resolution = ConflictResolution {
+            file_id: 1,
+            kind: ConflictKind::Tag("a".into()),
+            local_change: tag_added(2, 1, "a"),
+            foreign_change: tag_removed(1, 1, "a"),
+            kept: Side::Local
+        };
+        fdb.add_sync_conflict(&resolution, date(3)).unwrap();
+
+        let conflicts = fdb.get_sync_conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].resolution, resolution);
+        assert_eq!(conflicts[0].overridden, false);
+
+        handle_override_request(fdb, conflicts[0].id, &ThumbnailSettings::default(), date(4)).unwrap();
+
+        assert_eq!(fdb.get_file_with_id(1).unwrap().tags, Vec::<String>::new());
+        let clock = LogicalClock {
+            counter: timestamp_clock(date(4)),
+            instance: fdb.get_instance_id().unwrap()
+        };
+        assert_eq!(
+            fdb.get_changes_after_clock(timestamp_clock(date(3))).unwrap(),
+            vec!(tag_removed(4, 1, "a").with_clock(clock))
+        );
+        assert!(fdb.get_sync_conflict(conflicts[0].id).unwrap().overridden);
+
+        assert_matches!(
+            handle_override_request(fdb, conflicts[0].id, &ThumbnailSettings::default(), date(5)),
+            Err(Error(ErrorKind::ConflictAlreadyOverridden(_), _))
+        );
+        assert_matches!(
+            handle_override_request(fdb, conflicts[0].id + 1, &ThumbnailSettings::default(), date(5)),
+            Err(Error(ErrorKind::NoSuchConflict(_), _))
+        );
+    });
+
+    db_test!(discarded_content_is_restored(fdb) {
+        let storage = fdb.get_file_save_path();
+        fs::copy("test/media/512x512.png", storage.join("1.png")).unwrap();
+        fdb.add_new_file(1, "1.png", None, &[], 0, &ChangeCreationPolicy::No);
+
+        let discarded_path = discarded_content_path(&storage, "a");
+        fs::create_dir_all(discarded_path.parent().unwrap()).unwrap();
+        fs::copy("test/media/10x10.png", &discarded_path).unwrap();
+
+        fdb.add_sync_conflict(&ConflictResolution {
+            file_id: 1,
+            kind: ConflictKind::Content,
+            local_change: content_changed(1, 1, "a"),
+            foreign_change: content_changed(2, 1, "b"),
+            kept: Side::Foreign
+        }, date(3)).unwrap();
+
+        let conflict_id = fdb.get_sync_conflicts().unwrap()[0].id;
+        handle_override_request(fdb, conflict_id, &ThumbnailSettings::default(), date(4)).unwrap();
+
+        let file = fdb.get_file_with_id(1).unwrap();
+        assert_eq!(file.content_hash, Some("a".into()));
+        assert_eq!((file.width, file.height), (Some(10), Some(10)));
+        assert_eq!(
+            fs::read(storage.join(&file.filename)).unwrap(),
+            fs::read("test/media/10x10.png").unwrap()
+        );
+        assert!(file.thumbnail_path.is_some());
+        assert!(!discarded_path.exists());
+
+        assert!(fdb.get_sync_conflict(conflict_id).unwrap().overridden);
+        let clock = LogicalClock {
+            counter: timestamp_clock(date(4)),
+            instance: fdb.get_instance_id().unwrap()
+        };
+        assert_eq!(
+            fdb.get_changes_after_clock(timestamp_clock(date(3))).unwrap(),
+            vec!(content_changed(4, 1, "a").with_clock(clock))
+        );
+    });
+
+    db_test!(content_that_was_not_kept_can_not_be_restored(fdb) {
+        fdb.add_new_file(1, "1.jpg", None, &[], 0, &ChangeCreationPolicy::No);
+
+        fdb.add_sync_conflict(&ConflictResolution {
+            file_id: 1,
+            kind: ConflictKind::Content,
+            local_change: content_changed(1, 1, "a"),
+            foreign_change: content_changed(2, 1, "b"),
+            kept: Side::Foreign
+        }, date(3)).unwrap();
+
+        let conflict_id = fdb.get_sync_conflicts().unwrap()[0].id;
+        assert_matches!(
+            handle_override_request(fdb, conflict_id, &ThumbnailSettings::default(), date(4)),
+            Err(Error(ErrorKind::ConflictNotOverridable(_), _))
+        );
+        assert!(!fdb.get_sync_conflict(conflict_id).unwrap().overridden);
+    });
+}
@@ -0,0 +1,206 @@
+use iron::prelude::*;
+use iron::status;
+
+use chrono::Utc;
+
+use changelog::ChangeCreationPolicy;
+use file_database::{FileDatabase, File};
+use file_handler::remove_file;
+use file_util::hash_distance;
+use request_helpers::{to_json_with_result, get_get_i64, get_optional_get_i64, setup_db_connection};
+use search::SavedSearchQuery;
+use sync_auth::authenticate_admin_request;
+
+use error::{Result, ErrorKind};
+
+/// The amount of bits two perceptual hashes may differ by if no limit is specified
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+/// The amount of bits in a perceptual hash
+const HASH_BITS: u32 = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SimilarFile {
+    pub id: i32,
+    pub thumbnail_path: Option<String>,
+    /// How similar the file is to the first file of the cluster. 1.0 means
+    /// that their perceptual hashes are identical
+    pub similarity: f32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuplicateCluster {
+    /// The files in the cluster, starting with the oldest one
+    pub files: Vec<SimilarFile>
+}
+
+/**
+  Converts the distance between two perceptual hashes into a score
+  between 0 and 1
+*/
+pub fn similarity(distance: u32) -> f32 {
+    1. - distance as f32 / HASH_BITS as f32
+}
+
+/**
+  Groups files whose perceptual hashes differ by at most `max_distance` bits.
+
+  Each cluster is started by the first file in `files` which isn't similar
+  to an earlier one, and later files join the cluster whose first file they
+  are the most similar to. Files without a perceptual hash and clusters with
+  a single file are left out
+*/
+pub fn find_duplicate_clusters(files: Vec<File>, max_distance: u32) -> Vec<DuplicateCluster> {
+    let mut clusters: Vec<(u64, DuplicateCluster)> = vec!();
+
+    for file in files {
+        let hash = match file.perceptual_hash {
+            Some(hash) => hash as u64,
+            None => continue
+        };
+
+        let closest = clusters.iter()
+            .enumerate()
+            .map(|(index, &(first_hash, _))| (index, hash_distance(first_hash, hash)))
+            .filter(|&(_, distance)| distance <= max_distance)
+            .min_by_key(|&(_, distance)| distance);
+
+        match closest {
+            Some((index, distance)) => {
+                clusters[index].1.files.push(SimilarFile {
+                    id: file.id,
+                    thumbnail_path: file.thumbnail_path,
+                    similarity: similarity(distance)
+                });
+            }
+            None => {
+                clusters.push((hash, DuplicateCluster {
+                    files: vec!(SimilarFile {
+                        id: file.id,
+                        thumbnail_path: file.thumbnail_path,
+                        similarity: 1.
+                    })
+                }));
+            }
+        }
+    }
+
+    clusters.into_iter()
+        .map(|(_, cluster)| cluster)
+        .filter(|cluster| cluster.files.len() > 1)
+        .collect()
+}
+
+pub fn duplicates_request_handler(request: &mut Request) -> IronResult<Response> {
+    let fdb = setup_db_connection(request)?;
+
+    let max_distance = get_max_distance(request)?;
+
+    let clusters = handle_duplicates_request(&fdb, max_distance);
+
+    Ok(Response::with((status::Ok, to_json_with_result(clusters)?)))
+}
+
+/**
+  Reads the optional `max_distance` variable of the request
+*/
+fn get_max_distance(request: &mut Request) -> Result<u32> {
+    match get_optional_get_i64(request, "max_distance")? {
+        Some(distance) if distance >= 0 && distance <= HASH_BITS as i64 => Ok(distance as u32),
+        Some(_) => {
+            let expected = format!("a number between 0 and {}", HASH_BITS);
+            bail!(ErrorKind::InvalidVariableType("max_distance".into(), expected))
+        }
+        None => Ok(DEFAULT_MAX_DISTANCE)
+    }
+}
+
+fn handle_duplicates_request(fdb: &FileDatabase, max_distance: u32) -> Vec<DuplicateCluster> {
+    // Files are ordered by creation date which makes the original the first file of its cluster
+    find_duplicate_clusters(fdb.search_files(SavedSearchQuery::empty()), max_distance)
+}
+
+pub fn removal_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let file_id = get_get_i64(request, "file_id")?;
+
+    handle_removal_request(&fdb, file_id as i32)?;
+
+    Ok(Response::with((status::Ok, "Done")))
+}
+
+fn handle_removal_request(fdb: &FileDatabase, file_id: i32) -> Result<()> {
+    remove_file(file_id, fdb, &ChangeCreationPolicy::Yes(Utc::now().naive_utc()))
+}
+
+
+#[cfg(test)]
+mod duplicate_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    fn file_with_hash(id: i32, perceptual_hash: Option<i64>) -> File {
+        File {
+            id,
+            filename: format!("{}.jpg", id),
+            thumbnail_path: None,
+            creation_date: NaiveDate::from_ymd(2016, 1, 1).and_hms(0, 0, 0),
+            is_uploaded: true,
+            tags: vec!(),
+            width: None,
+            height: None,
+            sidecars: vec!(),
+            perceptual_hash,
+            content_hash: None,
+            original_accessed: None
+        }
+    }
+
+    fn cluster_ids(clusters: &[DuplicateCluster]) -> Vec<Vec<i32>> {
+        clusters.iter()
+            .map(|cluster| cluster.files.iter().map(|file| file.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn similar_files_are_clustered() {
+        let files = vec!(
+            file_with_hash(1, Some(0b0000)),
+            file_with_hash(2, Some(!0)),
+            file_with_hash(3, Some(0b0011)),
+            file_with_hash(4, None),
+            file_with_hash(5, Some(!0b0001)),
+            file_with_hash(6, Some(0xffff_0000)),
+        );
+
+        let clusters = find_duplicate_clusters(files, 2);
+
+        assert_eq!(cluster_ids(&clusters), vec!(vec!(1, 3), vec!(2, 5)));
+        assert_eq!(clusters[0].files[0].similarity, 1.);
+        assert_eq!(clusters[0].files[1].similarity, similarity(2));
+        assert_eq!(clusters[1].files[1].similarity, similarity(1));
+    }
+
+    #[test]
+    fn files_join_the_closest_cluster() {
+        let files = vec!(
+            file_with_hash(1, Some(0b0000_0000)),
+            file_with_hash(2, Some(0b1111_0000)),
+            file_with_hash(3, Some(0b1110_0000)),
+        );
+
+        let clusters = find_duplicate_clusters(files, 3);
+
+        assert_eq!(cluster_ids(&clusters), vec!(vec!(2, 3)));
+    }
+
+    #[test]
+    fn similarity_scores() {
+        assert_eq!(similarity(0), 1.);
+        assert_eq!(similarity(32), 0.5);
+        assert_eq!(similarity(64), 0.);
+    }
+}
@@ -35,6 +35,14 @@ error_chain! {
             description("The specified path does not have an extension")
             display("Path {:?} does not have an extension", path)
         }
+        ExtensionMismatch(path: PathBuf, expected: String) {
+            description("The new content of a file must have the same extension as the old content")
+            display("Path {:?} does not have the extension {}", path, expected)
+        }
+        FileNotSaved(path: PathBuf) {
+            description("The content of files that are not saved can't be replaced")
+            display("File {:?} is not saved", path)
+        }
 
         // Errors relating to url variable parsing 
         NoSuchVariable(name: String) {
@@ -92,7 +100,11 @@ error_chain! {
             description("The database did not contain a file with the specified id")
             display("The database did not contain a file with id {}", file_id)
         }
-        ChangeIDCollision(change_id: i32) {
+        NoSuchSidecar(file_id: i32, index: usize) {
+            description("The file did not have the requested sidecar")
+            display("File {} has no sidecar with index {}", file_id, index)
+        }
+        ChangeIDCollision(change_id: i64) {
             description("An ID collision occured when insertin change")
             display(
                 "An ID collision occured when inserting a change with id id {}",
@@ -111,23 +123,184 @@ error_chain! {
             display("HTTP request returned status {}. Response: {}", code.as_u16(), body)
         }
 
+        FileNotPushed(file_id: i32) {
+            description("A file that is needed to apply changes was not pushed")
+            display("File {} was not pushed by the foreign server", file_id)
+        }
+        PushedChunkMissing(file_id: i32, offset: u64, stored: u64) {
+            description("A chunk of a pushed file was sent before the chunks preceding it")
+            display("Got a chunk of file {} at offset {} but only {} bytes were pushed", file_id, offset, stored)
+        }
+        PushedChunkTooLarge(size: u64, limit: u64) {
+            description("A chunk of a pushed file was larger than the push chunk size")
+            display("Pushed chunk of {} bytes is larger than the limit of {} bytes", size, limit)
+        }
+
+        OriginalNotStored(file_id: i32) {
+            description("The original of the file is stored on another instance")
+            display("The original of file {} is not stored on this instance", file_id)
+        }
+        OriginalUnavailable(file_id: i32) {
+            description("No peer could provide the original of the file")
+            display("None of the peers could provide the original of file {}", file_id)
+        }
+        OriginalBeingFetched(file_id: i32) {
+            description("The original of the file is already being fetched")
+            display("The original of file {} is already being fetched", file_id)
+        }
+
+        ForeignSyncFailed(message: String) {
+            description("The foreign server failed to apply the changes")
+            display("The foreign server failed to apply the changes: {}", message)
+        }
+
         NoSuchJobId(id: usize) {
             description("No such job ID")
             display("No job with id {}", id)
         }
+        JobCancelled(id: usize) {
+            description("The sync job was cancelled")
+            display("Job {} was cancelled", id)
+        }
+        JobTimedOut(id: usize) {
+            description("The sync job took longer than FLASH_SYNC_TIMEOUT_MINUTES")
+            display("Job {} timed out", id)
+        }
+
+        ContentHashMismatch(file_id: i32, expected: String, actual: String) {
+            description("A downloaded file did not have the expected content hash")
+            display("File {} was downloaded with hash {} but {} was expected", file_id, actual, expected)
+        }
+
+        NoSuchConflict(id: i32) {
+            description("No sync conflict with the specified id")
+            display("No sync conflict with id {}", id)
+        }
+        ConflictAlreadyOverridden(id: i32) {
+            description("The sync conflict has already been overridden")
+            display("Sync conflict {} has already been overridden", id)
+        }
+        ConflictNotOverridable(id: i32) {
+            description("The discarded change of the sync conflict can no longer be applied")
+            display("Sync conflict {} can not be overridden since the file was removed or its content was not kept", id)
+        }
+
+        // Sync authentication errors
+        SyncSecretMissing {
+            description("Sync is disabled because no sync secret is configured")
+            display("Sync is disabled because FLASH_SYNC_SECRET is not set")
+        }
+        UnauthenticatedSyncRequest(reason: String) {
+            description("A sync request was not correctly signed")
+            display("Rejected unauthenticated sync request: {}", reason)
+        }
+        UnauthenticatedSyncResponse(reason: String) {
+            description("A response from a peer was not correctly signed")
+            display("Rejected unauthenticated response from the peer: {}", reason)
+        }
+        IncompatibleProtocolVersion(own: u32, peer: u32) {
+            description("The peer uses an incompatible version of the sync protocol")
+            display("This instance uses sync protocol version {} but the peer uses version {}", own, peer)
+        }
+        UnsupportedChangeTypes(types: Vec<String>) {
+            description("The peer can't apply some of the changes")
+            display("The peer can't apply changes of type {}", types.join(", "))
+        }
+        UnsupportedUrlScheme(scheme: String) {
+            description("Foreign servers can only be reached over http or https")
+            display("Unsupported url scheme {}, expected http or https", scheme)
+        }
+        NoSuchPeer(id: i32) {
+            description("The database did not contain a peer with the specified id")
+            display("No peer with id {}", id)
+        }
+        InvalidSyncFilter(query: String) {
+            description("Sync filters must be searches for tags or dates")
+            display("Invalid sync filter '{}', expected a search for tags or dates", query)
+        }
+        InvalidDigestPrefix(prefix: String) {
+            description("Library digests are requested for prefixes of hex encoded file ids")
+            display("Invalid digest prefix '{}', expected at most 8 lowercase hex digits", prefix)
+        }
+
+        // Bundle errors
+        UnsupportedBundleVersion(version: u32) {
+            description("The bundle was exported with an unsupported version of the bundle format")
+            display("Bundle format version {} is not supported", version)
+        }
+        CorruptBundle(reason: String) {
+            description("The bundle is incomplete or was modified after it was exported")
+            display("Corrupt bundle: {}", reason)
+        }
+        BundleExists(path: String) {
+            description("There already is a bundle in the directory")
+            display("There already is a bundle in {}", path)
+        }
+        NotInBundle(file_id: i32) {
+            description("A file that is needed to apply changes is not in the bundle")
+            display("File {} is not in the bundle", file_id)
+        }
+        BundlePathMissing {
+            description("Bundles are disabled because no bundle folder is configured")
+            display("Bundles are disabled because FLASH_BUNDLE_PATH is not set")
+        }
+        InvalidBundlePath(path: String) {
+            description("Bundle paths must be relative paths inside the bundle folder")
+            display("Invalid bundle path '{}', expected a relative path without '..'", path)
+        }
+
+        BackupLibraryMissing {
+            description("Backups are disabled because no backup library is configured")
+            display("Backups are disabled because FLASH_BACKUP_DATABASE_URL is not set")
+        }
     }
 }
 
+/**
+  Describes an error and every error that caused it, one per line
+*/
+pub fn describe_chain(error: &Error) -> String {
+    error.iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join("\nCaused by: ")
+}
+
 impl ErrorKind {
     fn iron_status(&self) -> status::Status {
         match *self {
             ErrorKind::NoSuchVariable(_) |
             ErrorKind::InvalidVariableType(_, _) |
+            ErrorKind::UnsupportedUrlScheme(_) |
+            ErrorKind::InvalidSyncFilter(_) |
+            ErrorKind::InvalidDigestPrefix(_) |
+            ErrorKind::ExtensionMismatch(_, _) |
+            ErrorKind::FileNotSaved(_) |
+            ErrorKind::UnsupportedBundleVersion(_) |
+            ErrorKind::CorruptBundle(_) |
+            ErrorKind::BundleExists(_) |
+            ErrorKind::InvalidBundlePath(_) |
+            ErrorKind::ConflictAlreadyOverridden(_) |
+            ErrorKind::ConflictNotOverridable(_) |
+            ErrorKind::PushedChunkMissing(_, _, _) |
             ErrorKind::NoUrlEncodedQuery => status::Status::BadRequest,
+            ErrorKind::PushedChunkTooLarge(_, _) => status::Status::PayloadTooLarge,
+            ErrorKind::UnauthenticatedSyncRequest(_) => status::Status::Unauthorized,
+            ErrorKind::SyncSecretMissing |
+            ErrorKind::BundlePathMissing |
+            ErrorKind::BackupLibraryMissing => status::Status::Forbidden,
+            ErrorKind::IncompatibleProtocolVersion(_, _) |
+            ErrorKind::UnsupportedChangeTypes(_) => status::Status::Conflict,
             ErrorKind::UnknownAction(_) |
             ErrorKind::NoSuchList(_) |
             ErrorKind::NoSuchFileInList(_, _) |
-            ErrorKind::NoSuchFileInDatabase(_) => status::Status::NotFound,
+            ErrorKind::NoSuchFileInDatabase(_) |
+            ErrorKind::NoSuchSidecar(_, _) |
+            ErrorKind::NoSuchConflict(_) |
+            ErrorKind::NoSuchJobId(_) |
+            ErrorKind::OriginalUnavailable(_) |
+            ErrorKind::NoSuchPeer(_) => status::Status::NotFound,
+            ErrorKind::OriginalBeingFetched(_) => status::Status::ServiceUnavailable,
             _ => status::Status::InternalServerError
         }
     }
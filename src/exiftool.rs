@@ -5,7 +5,8 @@ extern crate chrono;
 
 use self::regex::Regex;
 
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::io::Write;
 
 error_chain! {
     foreign_links {
@@ -26,6 +27,60 @@ error_chain! {
             description("Exiftool returned invalid UTF-8")
             display("Invalid UTF-8 returned from exiftool")
         }
+        UnknownOrientation(data: String) {
+            description("The orientation tag had an unexpected value")
+            display("Unexpected orientation in exif data: {}", data)
+        }
+    }
+}
+
+
+/**
+  The transformation that has to be applied to the stored image data for it to
+  be displayed the right way up, as described by the EXIF Orientation tag
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Normal,
+    MirrorHorizontal,
+    Rotate180,
+    MirrorVertical,
+    MirrorHorizontalRotate270,
+    Rotate90,
+    MirrorHorizontalRotate90,
+    Rotate270,
+}
+
+impl Orientation {
+    /**
+      Parses the human readable orientation value printed by exiftool
+    */
+    pub fn from_exiftool_value(value: &str) -> Option<Orientation> {
+        match value {
+            "Horizontal (normal)" => Some(Orientation::Normal),
+            "Mirror horizontal" => Some(Orientation::MirrorHorizontal),
+            "Rotate 180" => Some(Orientation::Rotate180),
+            "Mirror vertical" => Some(Orientation::MirrorVertical),
+            "Mirror horizontal and rotate 270 CW" => Some(Orientation::MirrorHorizontalRotate270),
+            "Rotate 90 CW" => Some(Orientation::Rotate90),
+            "Mirror horizontal and rotate 90 CW" => Some(Orientation::MirrorHorizontalRotate90),
+            "Rotate 270 CW" => Some(Orientation::Rotate270),
+            _ => None
+        }
+    }
+
+    /**
+      Returns true if the width and height of the stored image are swapped
+      when it is displayed
+    */
+    pub fn swaps_dimensions(&self) -> bool {
+        matches!(
+            *self,
+            Orientation::MirrorHorizontalRotate270
+                | Orientation::Rotate90
+                | Orientation::MirrorHorizontalRotate90
+                | Orientation::Rotate270
+        )
     }
 }
 
@@ -72,6 +127,31 @@ impl ExifData {
         Self::from_exiftool_string(&command_output)
     }
 
+    /**
+      Reads the exif data of a file that is stored in memory by passing it to
+      exiftool through stdin
+    */
+    pub fn from_bytes(data: &[u8]) -> Result<ExifData> {
+        let mut child = Command::new("exiftool")
+            .arg("-d")
+            .arg(DATE_FORMAT)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // Stdin is closed when it goes out of scope which tells exiftool that
+        // all data has been sent
+        {
+            let mut stdin = child.stdin.take().expect("Exiftool stdin was not piped");
+            stdin.write_all(data)?;
+        }
+
+        let command_output = String::from_utf8(child.wait_with_output()?.stdout)?;
+
+        Self::from_exiftool_string(&command_output)
+    }
+
     pub fn get_tag(&self, name: &str) -> Option<&str> {
         match self.tags.get(name) {
             Some(tag) => Some(tag),
@@ -93,6 +173,17 @@ impl ExifData {
             None => Err(ErrorKind::NoSuchTag(String::from(target_tag)).into()),
         }
     }
+
+    pub fn get_orientation(&self) -> Result<Orientation> {
+        let target_tag = "Orientation";
+        match self.get_tag(target_tag) {
+            Some(value) => {
+                Orientation::from_exiftool_value(value)
+                    .ok_or_else(|| ErrorKind::UnknownOrientation(String::from(value)).into())
+            }
+            None => Err(ErrorKind::NoSuchTag(String::from(target_tag)).into()),
+        }
+    }
 }
 
 
@@ -115,6 +206,30 @@ mod exif_data_tests {
         assert_eq!(data.get_creation_date().unwrap(), expected_date);
     }
 
+    #[test]
+    fn orientation_parsing() {
+        let data = ExifData::from_exiftool_string(
+                "Orientation                     : Rotate 90 CW\n"
+            ).unwrap();
+        assert_eq!(data.get_orientation().unwrap(), Orientation::Rotate90);
+        assert!(Orientation::Rotate90.swaps_dimensions());
+
+        let data = ExifData::from_exiftool_string(
+                "Orientation                     : Horizontal (normal)\n"
+            ).unwrap();
+        assert_eq!(data.get_orientation().unwrap(), Orientation::Normal);
+        assert!(!Orientation::Normal.swaps_dimensions());
+
+        let data = ExifData::from_exiftool_string("Orientation : Sideways\n").unwrap();
+        assert_matches!(
+            data.get_orientation(),
+            Err(Error(ErrorKind::UnknownOrientation(_), _))
+        );
+
+        let data = ExifData::from_exiftool_string("").unwrap();
+        assert_matches!(data.get_orientation(), Err(Error(ErrorKind::NoSuchTag(_), _)));
+    }
+
 
 
     #[test]
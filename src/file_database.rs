@@ -8,8 +8,10 @@ use diesel;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::expression::{not};
+use diesel::expression::dsl::max;
 
-use schema::{files, syncpoints, changes};
+use schema::{files, syncpoints, changes, renditions, instance, peers};
+use schema::{sync_conflicts, sync_filters, sync_jobs};
 
 use chrono::NaiveDateTime;
 
@@ -17,7 +19,7 @@ use chrono::NaiveDateTime;
 use std::path::PathBuf;
 
 use search;
-use error::{Result, ErrorKind};
+use error::{Error, Result, ErrorKind};
 use changelog::{
     Change,
     ChangeDbEntry,
@@ -26,8 +28,14 @@ use changelog::{
     ChangeCreationPolicy,
     ChangeType,
     UpdateType,
-    sorted_changes
+    LogicalClock,
+    sorted_changes,
+    timestamp_clock
 };
+use conflicts::{Conflict, ConflictDbEntry, ConflictResolution, NewConflict};
+use sync_filters::SyncFilter;
+use peers::{Peer, NewPeer};
+use sync_jobs::{SyncJob, NewSyncJob, TransferCounts, STATUS_DONE, STATUS_FAILED, STATUS_RUNNING};
 
 
 /**
@@ -44,9 +52,33 @@ pub struct File {
 
     pub creation_date: NaiveDateTime,
 
+    // True if the original file and its sidecars are stored on this instance.
+    // Files synced from peers with lazy originals only have their thumbnail
+    // until the original is requested
     pub is_uploaded: bool,
 
     pub tags: Vec<String>,
+
+    // The size of the file as it is displayed. Only known once its
+    // renditions have been generated
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+
+    // Files that are stored alongside the main file, for example the RAW
+    // version of a JPEG
+    pub sidecars: Vec<String>,
+
+    // Hash of the image content used to find visually similar files. Only
+    // known once its renditions have been generated
+    pub perceptual_hash: Option<i64>,
+
+    // Hex encoded SHA-256 of the file content. Computed in the background
+    // after the file is saved
+    pub content_hash: Option<String>,
+
+    // When the original was last requested if it was fetched from a peer on
+    // demand. Such originals are removed again when the cache is full
+    pub original_accessed: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable)]
@@ -77,15 +109,33 @@ impl<'a> NewFile<'a> {
             filename,
             thumbnail_path,
             creation_date,
-            is_uploaded: false,
+            is_uploaded: true,
             tags,
         }
     }
 }
 
 
+/**
+  A resized copy of a file, generated from the original to avoid sending the
+  full file to clients that only need a smaller version
+*/
+#[derive(Queryable, Insertable, Clone, PartialEq, Debug)]
+#[table_name = "renditions"]
+pub struct Rendition {
+    pub file_id: i32,
+    // The length of the longest side that the rendition was generated for
+    pub size: i32,
+    // The name of the rendition in the file storage folder
+    pub filename: String,
+    // The `file_util::RENDITION_VERSION` that generated the rendition
+    pub version: i32,
+}
+
+
 pub struct FileDatabase {
     connection: PgConnection,
+    url: String,
     file_save_path: PathBuf,
 }
 
@@ -96,10 +146,19 @@ impl FileDatabase {
         // If the destination folder does not exist, it should be created
         Ok(FileDatabase {
             connection,
+            url: url.to_owned(),
             file_save_path,
         })
     }
 
+    /**
+      Opens a new connection to the same database. Used by worker threads
+      which can not share the connection of the original database
+    */
+    pub fn reconnect(&self) -> Result<FileDatabase> {
+        FileDatabase::new(&self.url, self.file_save_path.clone())
+    }
+
     /**
       Adds a new file entry to the "database". It is given a new unique ID and the
       file is added to the tags which it should be part of. If some of those tags don't
@@ -157,32 +216,30 @@ impl FileDatabase {
             .set(files::tags.eq(tags))
             .get_result(&self.connection);
 
-        if let ChangeCreationPolicy::Yes(timestamp) = change_policy {
-            // Filter removed tags and add changes for them
-            let removed_tags =
-                file.tags.iter()
-                    .filter(|tag| !tags.contains(tag))
-                    .collect::<Vec<_>>();
-
-            let added_tags =
-                tags.iter()
-                    .filter(|tag| !file.tags.contains(tag))
-                    .collect::<Vec<_>>();
-
-            for tag in removed_tags {
-                self.add_change(&Change::new(
-                        *timestamp,
-                        file.id,
-                        ChangeType::Update(UpdateType::TagRemoved(tag.to_string()))
-                    ))?;
-            }
-            for tag in added_tags {
-                self.add_change(&Change::new(
-                        *timestamp,
-                        file.id,
-                        ChangeType::Update(UpdateType::TagAdded(tag.to_string()))
-                    ))?;
-            }
+        // Filter removed tags and add changes for them
+        let removed_tags =
+            file.tags.iter()
+                .filter(|tag| !tags.contains(tag))
+                .collect::<Vec<_>>();
+
+        let added_tags =
+            tags.iter()
+                .filter(|tag| !file.tags.contains(tag))
+                .collect::<Vec<_>>();
+
+        for tag in removed_tags {
+            self.handle_change_creation_policy(
+                change_policy,
+                file.id,
+                ChangeType::Update(UpdateType::TagRemoved(tag.to_string()))
+            )?;
+        }
+        for tag in added_tags {
+            self.handle_change_creation_policy(
+                change_policy,
+                file.id,
+                ChangeType::Update(UpdateType::TagAdded(tag.to_string()))
+            )?;
         }
 
         match result {
@@ -191,6 +248,129 @@ impl FileDatabase {
         }
     }
 
+    /**
+      Changes the thumbnail of the specified file without creating a change
+      since thumbnails are generated separately on each server
+    */
+    pub fn set_thumbnail_path(&self, file_id: i32, thumbnail_path: Option<&str>) -> Result<()> {
+        diesel::update(files::table.find(file_id))
+            .set(files::thumbnail_path.eq(thumbnail_path))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Sets the filenames of the sidecars of the specified file
+    */
+    pub fn set_sidecars(&self, file_id: i32, sidecars: &[String]) -> Result<()> {
+        diesel::update(files::table.find(file_id))
+            .set(files::sidecars.eq(sidecars))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Stores the displayed size of the specified file
+    */
+    pub fn set_file_dimensions(&self, file_id: i32, width: i32, height: i32) -> Result<()> {
+        diesel::update(files::table.find(file_id))
+            .set((files::width.eq(width), files::height.eq(height)))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Stores the SHA-256 of the content of the specified file
+    */
+    pub fn set_content_hash(&self, file_id: i32, hash: &str) -> Result<()> {
+        diesel::update(files::table.find(file_id))
+            .set(files::content_hash.eq(hash))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Stores the SHA-256 of new content of the specified file, which replaced
+      its previous content. Creates a change if the `change_policy` says to do so
+    */
+    pub fn set_file_content_hash(
+        &self,
+        file: &File,
+        hash: &str,
+        change_policy: &ChangeCreationPolicy
+    ) -> Result<()> {
+        self.set_content_hash(file.id, hash)?;
+
+        self.handle_change_creation_policy(
+            change_policy,
+            file.id,
+            ChangeType::Update(UpdateType::ContentChanged(hash.to_string()))
+        )
+    }
+
+    /**
+      Stores the perceptual hash of the specified file
+    */
+    pub fn set_perceptual_hash(&self, file_id: i32, hash: i64) -> Result<()> {
+        diesel::update(files::table.find(file_id))
+            .set(files::perceptual_hash.eq(hash))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Stores whether the original of the specified file is stored on this
+      instance. `accessed` is the time the original was last requested if it
+      was fetched on demand, or `None` if it should be kept
+    */
+    pub fn set_original_stored(
+        &self,
+        file_id: i32,
+        stored: bool,
+        accessed: Option<NaiveDateTime>
+    ) -> Result<()> {
+        diesel::update(files::table.find(file_id))
+            .set((
+                files::is_uploaded.eq(stored),
+                files::original_accessed.eq(accessed)
+            ))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Updates the time that the specified original was last requested if it
+      was fetched on demand
+    */
+    pub fn touch_cached_original(&self, file_id: i32, time: NaiveDateTime) -> Result<()> {
+        diesel::update(
+                files::table.find(file_id).filter(files::original_accessed.is_not_null())
+            )
+            .set(files::original_accessed.eq(Some(time)))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Returns the files with originals that were fetched on demand, the most
+      recently requested first
+    */
+    pub fn get_cached_originals(&self) -> Result<Vec<File>> {
+        Ok(files::table
+            .filter(files::is_uploaded.eq(true))
+            .filter(files::original_accessed.is_not_null())
+            .order(files::original_accessed.desc())
+            .load(&self.connection)?
+        )
+    }
+
     pub fn update_file_without_creating_change(&self, file: &File) -> Result<File> {
         Ok(diesel::update(files::table.find(file.id))
             .set(file)
@@ -220,9 +400,11 @@ impl FileDatabase {
                 diesel::result::DatabaseErrorKind::UniqueViolation,
                 _
             )) => {
-                // Find the old item in the database
+                // Find the old item in the database. The same change may be
+                // received again from another peer which stored it with
+                // another clock, the clock it was first stored with is kept
                 let in_db = self.get_change_with_id(change.id)?;
-                if in_db != *change {
+                if in_db.with_clock(change.clock.clone()) != *change {
                     Err(ErrorKind::ChangeIDCollision(change.id).into())
                 }
                 else {
@@ -312,25 +494,107 @@ impl FileDatabase {
         }
     }
 
+    /**
+      Returns the files with ids from `first` to `last`, sorted by id
+    */
+    pub fn get_files_in_id_range(&self, first: i32, last: i32) -> Result<Vec<File>> {
+        Ok(files::table
+            .filter(files::id.ge(first))
+            .filter(files::id.le(last))
+            .order(files::id)
+            .get_results(&self.connection)?
+        )
+    }
+
     pub fn get_syncpoints(&self) -> Result<Vec<SyncPoint>> {
         Ok(syncpoints::table
-            .select(syncpoints::last_change)
+            .select((syncpoints::last_change, syncpoints::clock))
             .load(&self.connection)?
-            .into_iter()
-            .map(|last_change| SyncPoint{last_change})
-            .collect()
         )
     }
 
-    pub fn get_changes_after_timestamp(&self, timestamp: &NaiveDateTime) 
-        -> Result<Vec<Change>>
-    {
+    /**
+      Returns the changes with a logical clock counter larger than `clock`
+    */
+    pub fn get_changes_after_clock(&self, clock: i64) -> Result<Vec<Change>> {
         Ok(Self::changes_from_db_entries(
             &changes::table
-                .filter(changes::timestamp.gt(timestamp))
+                .filter(changes::clock.gt(clock))
                 .get_results(&self.connection)?
         )?)
     }
+
+    /**
+      Returns the changes with a logical clock counter of at most `clock`
+    */
+    pub fn get_changes_until_clock(&self, clock: i64) -> Result<Vec<Change>> {
+        Self::changes_from_db_entries(
+            &changes::table
+                .filter(changes::clock.le(clock))
+                .get_results(&self.connection)?
+        )
+    }
+
+    /**
+      Removes the changes with the specified ids from the change log
+    */
+    pub fn remove_changes(&self, ids: &[i64]) -> Result<()> {
+        diesel::delete(changes::table.filter(changes::id.eq_any(ids)))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Returns the largest logical clock counter of all changes and syncpoints
+      in the database and the counters reserved with `reserve_clock`, or 0 if
+      there are none
+    */
+    pub fn current_clock(&self) -> Result<i64> {
+        let changes_max = changes::table
+            .select(max(changes::clock))
+            .first::<Option<i64>>(&self.connection)?;
+        let syncpoints_max = syncpoints::table
+            .select(max(syncpoints::clock))
+            .first::<Option<i64>>(&self.connection)?;
+        let reserved = instance::table
+            .select(instance::reserved_clock)
+            .first::<i64>(&self.connection)?;
+
+        Ok(changes_max.into_iter().chain(syncpoints_max).chain(Some(reserved)).max().unwrap_or(0))
+    }
+
+    /**
+      Makes sure that changes created from now on get a clock counter larger
+      than `clock`. Syncs reserve the clock of their syncpoint before reading
+      the changes so that changes made while they run are not hidden by it
+    */
+    pub fn reserve_clock(&self, clock: i64) -> Result<()> {
+        diesel::update(instance::table.filter(instance::reserved_clock.lt(clock)))
+            .set(instance::reserved_clock.eq(clock))
+            .execute(&self.connection)?;
+        Ok(())
+    }
+
+    /**
+      Returns the logical clock of a change made at `timestamp` by this instance
+    */
+    pub fn next_clock(&self, timestamp: NaiveDateTime) -> Result<LogicalClock> {
+        Ok(LogicalClock {
+            counter: ::std::cmp::max(self.current_clock()? + 1, timestamp_clock(timestamp)),
+            instance: self.get_instance_id()?
+        })
+    }
+
+    /**
+      Returns the random id of this instance which is created by the migrations
+    */
+    pub fn get_instance_id(&self) -> Result<String> {
+        Ok(instance::table
+            .select(instance::id)
+            .first(&self.connection)?
+        )
+    }
     pub fn get_all_changes(&self) -> Result<Vec<Change>>{
         Ok(Self::changes_from_db_entries(
             &changes::table
@@ -338,7 +602,7 @@ impl FileDatabase {
         )?)
     }
 
-    pub fn get_change_with_id(&self, id: i32) -> Result<Change> {
+    pub fn get_change_with_id(&self, id: i64) -> Result<Change> {
         let entry = changes::table.find(id).get_result(&self.connection)?;
         Ok(Change::from_db_entry(&entry)?)
     }
@@ -351,6 +615,321 @@ impl FileDatabase {
         Ok(sorted_changes(&changes))
     }
 
+    /**
+      Replaces the renditions stored for a file with the specified ones
+    */
+    pub fn set_renditions(&self, file_id: i32, new_renditions: &[Rendition]) -> Result<()> {
+        self.connection.transaction::<_, Error, _>(|| {
+            diesel::delete(renditions::table.filter(renditions::file_id.eq(file_id)))
+                .execute(&self.connection)?;
+
+            if !new_renditions.is_empty() {
+                diesel::insert(new_renditions)
+                    .into(renditions::table)
+                    .execute(&self.connection)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /**
+      Returns the renditions of the specified file sorted by size, smallest first
+    */
+    pub fn get_renditions(&self, file_id: i32) -> Result<Vec<Rendition>> {
+        Ok(renditions::table
+            .filter(renditions::file_id.eq(file_id))
+            .order(renditions::size)
+            .load(&self.connection)?
+        )
+    }
+
+    /**
+      Stores a conflict that was resolved during a sync
+    */
+    pub fn add_sync_conflict(&self, resolution: &ConflictResolution, detected_at: NaiveDateTime)
+        -> Result<()>
+    {
+        diesel::insert(&NewConflict::new(resolution, detected_at))
+            .into(sync_conflicts::table)
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Returns all conflicts that have been resolved during syncs, newest first
+    */
+    pub fn get_sync_conflicts(&self) -> Result<Vec<Conflict>> {
+        let db_entries = sync_conflicts::table
+            .order(sync_conflicts::id.desc())
+            .load::<ConflictDbEntry>(&self.connection)?;
+
+        let mut conflicts = vec!();
+        for db_entry in &db_entries {
+            conflicts.push(Conflict::from_db_entry(db_entry)?);
+        }
+        Ok(conflicts)
+    }
+
+    pub fn get_sync_conflict(&self, id: i32) -> Result<Conflict> {
+        let result = sync_conflicts::table.find(id).get_result::<ConflictDbEntry>(&self.connection);
+
+        match result {
+            Ok(db_entry) => Conflict::from_db_entry(&db_entry),
+            Err(diesel::result::Error::NotFound) => bail!(ErrorKind::NoSuchConflict(id)),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn set_sync_conflict_overridden(&self, id: i32) -> Result<()> {
+        diesel::update(sync_conflicts::table.find(id))
+            .set(sync_conflicts::overridden.eq(true))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Stores the filter used when syncing with `filter.foreign_url`, replacing
+      any previous filter for that peer
+    */
+    pub fn set_sync_filter(&self, filter: &SyncFilter) -> Result<()> {
+        self.connection.transaction::<_, Error, _>(|| {
+            self.remove_sync_filter(&filter.foreign_url)?;
+
+            diesel::insert(filter)
+                .into(sync_filters::table)
+                .execute(&self.connection)?;
+
+            Ok(())
+        })
+    }
+
+    pub fn remove_sync_filter(&self, foreign_url: &str) -> Result<()> {
+        diesel::delete(sync_filters::table.find(foreign_url))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Returns the query of the filter used when syncing with `foreign_url`
+    */
+    pub fn get_sync_filter(&self, foreign_url: &str) -> Result<Option<String>> {
+        let result = sync_filters::table
+            .find(foreign_url)
+            .get_result::<SyncFilter>(&self.connection);
+
+        match result {
+            Ok(filter) => Ok(Some(filter.query)),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn get_sync_filters(&self) -> Result<Vec<SyncFilter>> {
+        Ok(sync_filters::table
+            .order(sync_filters::foreign_url)
+            .load(&self.connection)?
+        )
+    }
+
+    pub fn add_peer(&self, peer: &NewPeer) -> Result<Peer> {
+        Ok(diesel::insert(peer)
+            .into(peers::table)
+            .get_result(&self.connection)?
+        )
+    }
+
+    pub fn get_peers(&self) -> Result<Vec<Peer>> {
+        Ok(peers::table
+            .order(peers::id)
+            .load(&self.connection)?
+        )
+    }
+
+    pub fn get_peer(&self, id: i32) -> Result<Peer> {
+        let result = peers::table.find(id).get_result::<Peer>(&self.connection);
+
+        match result {
+            Ok(peer) => Ok(peer),
+            Err(diesel::result::Error::NotFound) => bail!(ErrorKind::NoSuchPeer(id)),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn get_peer_with_url(&self, url: &str) -> Result<Option<Peer>> {
+        let result = peers::table
+            .filter(peers::url.eq(url))
+            .get_result::<Peer>(&self.connection);
+
+        match result {
+            Ok(peer) => Ok(Some(peer)),
+            Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    /**
+      Stores the settings of a peer. The sync state is only changed by
+      `record_peer_sync`
+    */
+    pub fn update_peer(&self, peer: &Peer) -> Result<()> {
+        diesel::update(peers::table.find(peer.id))
+            .set((
+                peers::url.eq(&peer.url),
+                peers::name.eq(&peer.name),
+                peers::secret.eq(&peer.secret),
+                peers::sync_interval.eq(peer.sync_interval),
+                peers::lazy_originals.eq(peer.lazy_originals)
+            ))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    pub fn remove_peer(&self, id: i32) -> Result<()> {
+        let removed = diesel::delete(peers::table.find(id))
+            .execute(&self.connection)?;
+
+        if removed == 0 {
+            bail!(ErrorKind::NoSuchPeer(id));
+        }
+        Ok(())
+    }
+
+    /**
+      Returns the secrets of all peers which have their own secret
+    */
+    pub fn get_peer_secrets(&self) -> Result<Vec<String>> {
+        let secrets = peers::table
+            .select(peers::secret)
+            .load::<Option<String>>(&self.connection)?;
+
+        Ok(secrets.into_iter().flatten().collect())
+    }
+
+    /**
+      Records a sync attempt with the peer at `url` which finished at `time`.
+      `error` is the reason the sync failed. Does nothing if `url` does not
+      belong to a peer
+    */
+    pub fn record_peer_sync(&self, url: &str, time: NaiveDateTime, error: Option<String>)
+        -> Result<()>
+    {
+        let peer = peers::table.filter(peers::url.eq(url));
+
+        match error {
+            Some(error) => diesel::update(peer)
+                .set((
+                    peers::last_attempt.eq(Some(time)),
+                    peers::last_error.eq(Some(error))
+                ))
+                .execute(&self.connection)?,
+            None => diesel::update(peer)
+                .set((
+                    peers::last_attempt.eq(Some(time)),
+                    peers::last_sync.eq(Some(time)),
+                    peers::last_error.eq(None::<String>)
+                ))
+                .execute(&self.connection)?
+        };
+
+        Ok(())
+    }
+
+    /**
+      Stores why the peer could not be synced without counting it as an
+      attempt, so it is tried again on the next check
+    */
+    pub fn record_peer_error(&self, id: i32, error: &str) -> Result<()> {
+        diesel::update(peers::table.find(id))
+            .set(peers::last_error.eq(Some(error)))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    pub fn add_sync_job(&self, job: &NewSyncJob) -> Result<()> {
+        diesel::insert(job)
+            .into(sync_jobs::table)
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Stores the result of a job. The job failed if `error` is specified
+    */
+    pub fn finish_sync_job(
+        &self,
+        id: usize,
+        finished_at: NaiveDateTime,
+        counts: &TransferCounts,
+        error: Option<String>
+    ) -> Result<()> {
+        let status = if error.is_some() {STATUS_FAILED} else {STATUS_DONE};
+
+        diesel::update(sync_jobs::table.find(id as i64))
+            .set((
+                sync_jobs::finished_at.eq(Some(finished_at)),
+                sync_jobs::status.eq(status),
+                sync_jobs::changes_sent.eq(counts.changes_sent as i32),
+                sync_jobs::changes_received.eq(counts.changes_received as i32),
+                sync_jobs::files_sent.eq(counts.files_sent as i32),
+                sync_jobs::files_received.eq(counts.files_received as i32),
+                sync_jobs::error.eq(error)
+            ))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Marks jobs that are still running as failed. Used on startup since jobs
+      are stopped when the server exits
+    */
+    pub fn fail_running_sync_jobs(&self, finished_at: NaiveDateTime) -> Result<()> {
+        diesel::update(sync_jobs::table.filter(sync_jobs::status.eq(STATUS_RUNNING)))
+            .set((
+                sync_jobs::finished_at.eq(Some(finished_at)),
+                sync_jobs::status.eq(STATUS_FAILED),
+                sync_jobs::error.eq(Some("The server stopped before the job was done"))
+            ))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
+    /**
+      Returns the `limit` most recently started sync jobs, newest first
+    */
+    pub fn get_sync_jobs(&self, limit: i64) -> Result<Vec<SyncJob>> {
+        Ok(sync_jobs::table
+            .order(sync_jobs::started_at.desc())
+            .limit(limit)
+            .load(&self.connection)?
+        )
+    }
+
+    pub fn get_sync_job(&self, id: usize) -> Result<SyncJob> {
+        let result = sync_jobs::table.find(id as i64).get_result::<SyncJob>(&self.connection);
+
+        match result {
+            Ok(job) => Ok(job),
+            Err(diesel::result::Error::NotFound) => bail!(ErrorKind::NoSuchJobId(id)),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn remove_sync_jobs_before(&self, time: NaiveDateTime) -> Result<()> {
+        diesel::delete(sync_jobs::table.filter(sync_jobs::started_at.lt(time)))
+            .execute(&self.connection)?;
+
+        Ok(())
+    }
+
     pub fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()> {
         diesel::insert(syncpoint)
             .into(syncpoints::table)
@@ -359,6 +938,41 @@ impl FileDatabase {
         Ok(())
     }
 
+    /**
+      Records that the syncpoints in `shared` are shared with the instance at
+      `foreign_url`. Syncpoints that are already attributed to an instance
+      keep their url
+    */
+    pub fn set_syncpoint_url(&self, shared: &[SyncPoint], foreign_url: &str) -> Result<()> {
+        for syncpoint in shared {
+            diesel::update(
+                    syncpoints::table
+                        .filter(syncpoints::last_change.eq(syncpoint.last_change))
+                        .filter(syncpoints::clock.eq(syncpoint.clock))
+                        .filter(syncpoints::foreign_url.is_null())
+                )
+                .set(syncpoints::foreign_url.eq(foreign_url))
+                .execute(&self.connection)?;
+        }
+        Ok(())
+    }
+
+    /**
+      Returns the urls of the instances which share syncpoints with this one
+    */
+    pub fn get_syncpoint_urls(&self) -> Result<Vec<String>> {
+        Ok(syncpoints::table
+            .select(syncpoints::foreign_url)
+            .filter(syncpoints::foreign_url.is_not_null())
+            .distinct()
+            .order(syncpoints::foreign_url)
+            .load::<Option<String>>(&self.connection)?
+            .into_iter()
+            .flatten()
+            .collect()
+        )
+    }
+
     /**
       Returns the path to the folder where files should be stored
 
@@ -381,7 +995,8 @@ impl FileDatabase {
             change_type: ChangeType,
         ) -> Result<()> {
         if let ChangeCreationPolicy::Yes(date) = *policy {
-            self.add_change(&Change::new(date, affected_file, change_type))
+            let clock = self.next_clock(date)?;
+            self.add_change(&Change::new(date, affected_file, change_type).with_clock(clock))
         } else {
             Ok(())
         }
@@ -389,6 +1004,9 @@ impl FileDatabase {
 
     #[cfg(test)]
     pub fn reset(&self) {
+        diesel::delete(renditions::table)
+            .execute(&self.connection)
+            .unwrap();
         diesel::delete(files::table)
             .execute(&self.connection)
             .unwrap();
@@ -398,6 +1016,22 @@ impl FileDatabase {
         diesel::delete(syncpoints::table)
             .execute(&self.connection)
             .unwrap();
+        diesel::delete(sync_conflicts::table)
+            .execute(&self.connection)
+            .unwrap();
+        diesel::delete(sync_filters::table)
+            .execute(&self.connection)
+            .unwrap();
+        diesel::delete(peers::table)
+            .execute(&self.connection)
+            .unwrap();
+        diesel::delete(sync_jobs::table)
+            .execute(&self.connection)
+            .unwrap();
+        diesel::update(instance::table)
+            .set(instance::reserved_clock.eq(0))
+            .execute(&self.connection)
+            .unwrap();
     }
 }
 
@@ -406,6 +1040,10 @@ impl FileDatabase {
 pub mod db_test_helpers {
     use file_database::*;
 
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    use changelog::ChangeCreationPolicy;
+
     use std::sync::Mutex;
     use std::sync::Arc;
 
@@ -423,6 +1061,17 @@ pub mod db_test_helpers {
             .expect("DATABASE_TEST_URL must be set. Perhaps .env is missing?")
     }
 
+    /**
+      The url of a second database, used as the foreign instance in tests that
+      sync two databases
+    */
+    fn foreign_db_url() -> String {
+        dotenv().ok();
+
+        env::var("DATABASE_FOREIGN_TEST_URL")
+            .expect("DATABASE_FOREIGN_TEST_URL must be set. Perhaps .env is missing?")
+    }
+
     pub fn get_test_storage_path() -> String {
         dotenv().ok();
         env::var("TEST_FILE_STORAGE_PATH").expect(
@@ -430,9 +1079,7 @@ pub mod db_test_helpers {
         )
     }
 
-    fn create_db() -> FileDatabase {
-        let test_file_storage_path = get_test_storage_path();
-
+    fn create_db(url: &str, test_file_storage_path: String) -> FileDatabase {
         match fs::create_dir(test_file_storage_path.clone()) {
             Ok(_) => {}
             Err(e) => {
@@ -443,7 +1090,7 @@ pub mod db_test_helpers {
         };
 
         let fdb = FileDatabase::new(
-            &db_url(),
+            url,
             PathBuf::from(test_file_storage_path),
         );
 
@@ -456,13 +1103,26 @@ pub mod db_test_helpers {
         // Most functions that modify the database already want
         // `Arc<Mutex<fdb>>` so it has to have two layers of mutex
         static ref FDB: Arc<Mutex<FileDatabase>>
-                = Arc::new(Mutex::new(create_db()));
+                = Arc::new(Mutex::new(create_db(&db_url(), get_test_storage_path())));
+        // Lock `FDB` before this one when both are used to avoid deadlocks
+        static ref FOREIGN_FDB: Arc<Mutex<FileDatabase>> = Arc::new(Mutex::new(create_db(
+            &foreign_db_url(),
+            format!("{}_foreign", get_test_storage_path())
+        )));
     }
 
     pub fn get_database() -> Arc<Mutex<FileDatabase>> {
         FDB.clone()
     }
 
+    /**
+      Returns a database with its own storage path which is separate from the
+      one returned by `get_database`
+    */
+    pub fn get_foreign_database() -> Arc<Mutex<FileDatabase>> {
+        FOREIGN_FDB.clone()
+    }
+
     pub fn run_test<F: Fn(&FileDatabase)>(test: F) {
         let fdb = FDB.lock().unwrap();
         fdb.reset();
@@ -482,6 +1142,22 @@ pub mod db_test_helpers {
         fdb.search_files(file_query)
     }
 
+    /**
+      Returns all changes in the database with the clocks that `Change::new`
+      gives them, to compare them with changes created without knowing the
+      logical clocks that the database assigns
+    */
+    pub fn get_changes_with_timestamp_clocks(fdb: &FileDatabase) -> Vec<Change> {
+        fdb.get_all_changes()
+            .expect("Failed to get changes from database")
+            .into_iter()
+            .map(|change| {
+                let clock = LogicalClock::from_timestamp(change.timestamp);
+                change.with_clock(clock)
+            })
+            .collect()
+    }
+
     pub fn get_file_paths_with_tags(fdb: &FileDatabase, tags: Vec<String>, negated: Vec<String>) 
         -> Vec<String>
     {
@@ -501,6 +1177,38 @@ pub mod db_test_helpers {
 
         result
     }
+
+    /**
+      Adds a file with a thumbnail whose original is stored in the storage folder
+    */
+    pub fn add_stored_file(fdb: &FileDatabase, id: i32, tags: &[String], date: NaiveDateTime) {
+        let storage = fdb.get_file_save_path();
+        fs::write(storage.join(format!("{}.jpg", id)), vec!(1, 2, 3)).unwrap();
+        fs::write(storage.join(format!("thumb_{}.jpg", id)), vec!(4)).unwrap();
+
+        fdb.add_new_file(
+            id,
+            &format!("{}.jpg", id),
+            Some(&format!("thumb_{}.jpg", id)),
+            tags,
+            0,
+            &ChangeCreationPolicy::Yes(date)
+        );
+    }
+
+    /**
+      Midnight on the specified day of January 2018
+    */
+    pub fn date(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2018, 1, day).and_hms(0, 0, 0)
+    }
+
+    /**
+      Midnight on the first of January of the specified year
+    */
+    pub fn year(year: i32) -> NaiveDateTime {
+        NaiveDate::from_ymd(year, 1, 1).and_hms(0, 0, 0)
+    }
 }
 
 /*
@@ -880,11 +1588,43 @@ mod db_tests {
         let file = fdb.get_file_with_id(2);
         assert_matches!(file, None);
     }}
+
+    db_test!{peer_syncs_are_recorded(fdb) {
+        let peer = fdb.add_peer(&NewPeer{
+            url: "localhost:3000".into(),
+            name: "NAS".into(),
+            secret: Some("s3cret".into()),
+            sync_interval: Some(3600),
+            lazy_originals: false
+        }).unwrap();
+        let time = NaiveDate::from_ymd(2018, 1, 1).and_hms(0, 0, 0);
+
+        fdb.record_peer_sync("localhost:3000", time, Some("Failed".into())).unwrap();
+        let failed = fdb.get_peer(peer.id).unwrap();
+        assert_eq!(failed.last_attempt, Some(time));
+        assert_eq!(failed.last_sync, None);
+        assert_eq!(failed.last_error, Some("Failed".into()));
+
+        let later = NaiveDate::from_ymd(2018, 1, 2).and_hms(0, 0, 0);
+        fdb.record_peer_sync("localhost:3000", later, None).unwrap();
+        let synced = fdb.get_peer(peer.id).unwrap();
+        assert_eq!(synced.last_sync, Some(later));
+        assert_eq!(synced.last_error, None);
+
+        // Syncs with urls that are not peers are not recorded
+        fdb.record_peer_sync("localhost:4000", later, None).unwrap();
+        assert_eq!(fdb.get_peers().unwrap(), vec!(synced));
+        assert_eq!(fdb.get_peer_secrets().unwrap(), vec!(String::from("s3cret")));
+
+        fdb.remove_peer(peer.id).unwrap();
+        assert_matches!(fdb.get_peer(peer.id), Err(Error(ErrorKind::NoSuchPeer(_), _)));
+    }}
 }
 
 #[cfg(test)]
 mod change_tests {
     use super::*;
+    use super::db_test_helpers::get_changes_with_timestamp_clocks;
 
     use changelog::ChangeCreationPolicy;
 
@@ -917,7 +1657,7 @@ mod change_tests {
                 &ChangeCreationPolicy::Yes(second_file_timestamp)
             );
 
-        let changes = fdb.get_all_changes().unwrap();
+        let changes = get_changes_with_timestamp_clocks(fdb);
         assert_eq!(changes.len(), 3); // Two additions, one tag addition
 
         assert_eq!(changes[0], Change::new(
@@ -960,7 +1700,7 @@ mod change_tests {
             &ChangeCreationPolicy::Yes(timestamp)
         ).expect("File tag change failed");
 
-        let changes = fdb.get_all_changes().expect("Failed to get changes from database");
+        let changes = get_changes_with_timestamp_clocks(fdb);
         assert_eq!(changes.len(), 2);
 
 
@@ -997,7 +1737,7 @@ mod change_tests {
             &ChangeCreationPolicy::Yes(change_timestamp)
         ).expect("Failed to set file timestamp");
 
-        let changes = fdb.get_all_changes().expect("FAiled to get changes from database");
+        let changes = get_changes_with_timestamp_clocks(fdb);
         assert_eq!(changes.len(), 1);
 
 
@@ -1007,4 +1747,90 @@ mod change_tests {
             ChangeType::Update(UpdateType::CreationDateChanged(new_timestamp)),
         )));
     }}
+
+    db_test!{changes_get_clocks_after_everything_known(fdb) {
+        let timestamp = NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0);
+        let future_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2030,1,1).and_hms(0,0,0));
+
+        fdb.add_new_file(1, "file1", None, &[], 0, &ChangeCreationPolicy::Yes(timestamp));
+        let first = fdb.get_all_changes().unwrap()[0].clock.clone();
+        assert_eq!(first.counter, timestamp_clock(timestamp));
+        assert_eq!(first.instance, fdb.get_instance_id().unwrap());
+
+        // Changes made after a syncpoint come after it even if the syncpoint is
+        // from a peer with a clock that is ahead
+        fdb.add_syncpoint(&future_syncpoint).unwrap();
+        fdb.add_new_file(2, "file2", None, &[], 0, &ChangeCreationPolicy::Yes(timestamp));
+
+        let changes = fdb.get_changes_after_clock(future_syncpoint.clock).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].affected_file, 2);
+        assert_eq!(changes[0].clock.counter, future_syncpoint.clock + 1);
+        assert_eq!(fdb.current_clock().unwrap(), future_syncpoint.clock + 1);
+    }}
+
+    db_test!{changes_get_clocks_after_reserved_clocks(fdb) {
+        let timestamp = NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0);
+        let reserved = timestamp_clock(NaiveDate::from_ymd(2030,1,1).and_hms(0,0,0));
+
+        // A sync reserves the clock of its syncpoint before it is added
+        fdb.reserve_clock(reserved).unwrap();
+        fdb.add_new_file(1, "file1", None, &[], 0, &ChangeCreationPolicy::Yes(timestamp));
+        assert_eq!(fdb.get_all_changes().unwrap()[0].clock.counter, reserved + 1);
+
+        // Smaller reservations don't move the clock back
+        fdb.reserve_clock(reserved - 10).unwrap();
+        assert_eq!(fdb.current_clock().unwrap(), reserved + 1);
+    }}
+
+    db_test!{syncpoints_remember_the_first_instance_they_were_shared_with(fdb) {
+        let first = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0));
+        let second = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017,1,2).and_hms(0,0,0));
+        let bundled = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017,1,3).and_hms(0,0,0));
+        fdb.add_syncpoint(&first).unwrap();
+        fdb.add_syncpoint(&second).unwrap();
+        fdb.add_syncpoint(&bundled).unwrap();
+        assert_eq!(fdb.get_syncpoint_urls().unwrap(), Vec::<String>::new());
+
+        fdb.set_syncpoint_url(::std::slice::from_ref(&first), "b").unwrap();
+        fdb.set_syncpoint_url(&[first, second], "a").unwrap();
+
+        assert_eq!(fdb.get_syncpoint_urls().unwrap(), mapvec!(String::from: "a", "b"));
+        assert_eq!(fdb.get_syncpoints().unwrap().len(), 3);
+    }}
+
+    // Ensures that the migration to 64 bit change ids computes the same ids as
+    // `changelog::change_id`
+    db_test!{migrated_change_ids_match_computed_ids(fdb) {
+        use diesel::connection::SimpleConnection;
+
+        let changes = vec!(
+            Change::new(NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0), 1, ChangeType::FileAdded),
+            Change::new(
+                NaiveDate::from_ymd(2017,1,1).and_hms_micro(10,5,3,123456),
+                1,
+                ChangeType::Update(UpdateType::TagAdded("smörgås \"tag\"".into()))
+            ),
+            Change::new(
+                NaiveDate::from_ymd(1960,6,1).and_hms_micro(0,0,0,1),
+                2,
+                ChangeType::Update(UpdateType::CreationDateChanged(
+                    NaiveDate::from_ymd(1960,1,1).and_hms(0,0,0)
+                ))
+            ),
+            Change::new(NaiveDate::from_ymd(2018,1,1).and_hms(0,0,0), 2, ChangeType::FileRemoved),
+        );
+
+        for change in &changes {
+            fdb.add_change(change).unwrap();
+        }
+
+        fdb.connection.batch_execute(
+            include_str!("../migrations/2026-10-18-170000_add_change_id_hash/up.sql")
+        ).unwrap();
+
+        for change in &changes {
+            assert_eq!(fdb.get_change_with_id(change.id).unwrap(), *change);
+        }
+    }}
 }
@@ -1,40 +1,93 @@
-use std::path::{PathBuf, Path};
+use std::path::{Path, PathBuf};
 
 use std::sync::mpsc::{channel, Receiver};
 
 use file_database::{FileDatabase, File};
 
-use error::{Result, ErrorKind, ResultExt};
+use error::{Result, ErrorKind, ResultExt, describe_chain};
 
 
 use std::thread;
 
 use std::fs;
-use std::io::prelude::*;
+use std::io;
+use std::collections::BTreeSet;
 
 use changelog::ChangeCreationPolicy;
 
-use byte_source::{ByteSource, write_byte_source_to_file, vec_from_byte_source};
+use byte_source::{ByteSource, write_byte_source_to_file};
 
-use file_util::generate_thumbnail;
+use file_util::{
+    generate_thumbnail,
+    generate_image_details,
+    get_image_dimensions,
+    rendition_filename,
+    content_hash,
+    ImageDetails,
+    ThumbnailSettings
+};
 
-// TODO: Remove if unused
 #[derive(Debug)]
 pub struct FileSavingWorkerResults {
     pub file: Receiver<Result<()>>,
     pub thumbnail: Option<Receiver<Result<()>>>,
 }
 
+impl FileSavingWorkerResults {
+    /**
+      Waits until the file is written to disk and fails if it could not be.
+      The renditions are left to finish in the background. Failures to
+      generate them are only reported since the thumbnail job generates
+      missing thumbnails later
+    */
+    pub fn wait_for_file(self) -> Result<()> {
+        if let Some(thumbnail) = self.thumbnail {
+            thread::spawn(move || {
+                let result = thumbnail.recv().chain_err(|| "The thumbnail worker crashed")
+                    .and_then(|result| result);
+                if let Err(e) = result {
+                    println!("Warning: Failed to generate thumbnail: {}", describe_chain(&e));
+                }
+            });
+        }
+
+        self.file.recv().chain_err(|| "The file saving worker crashed")?
+    }
+}
+
 
 pub enum ThumbnailStrategy {
     None,
-    Generate,
+    Generate(ThumbnailSettings),
     FromByteSource(ByteSource)
 }
 
+/**
+  A file that is stored alongside the main file of an item, for example the
+  RAW version of a JPEG
+*/
+pub struct Sidecar {
+    pub content: ByteSource,
+    pub extension: String,
+}
+
+/**
+  Returns the filename of the sidecar with the specified index for the file
+  with the specified ID
+*/
+fn sidecar_filename(id: i32, index: usize, extension: &str) -> String {
+    if extension.is_empty() {
+        format!("{}_{}", id, index)
+    }
+    else {
+        format!("{}_{}.{}", id, index, extension)
+    }
+}
+
 #[allow(too_many_arguments)] //TODO
 pub fn save_file(
         source_content: ByteSource,
+        sidecars: Vec<Sidecar>,
         thumbnail_strategy: ThumbnailStrategy,
         id: i32,
         tags: &[String],
@@ -54,31 +107,24 @@ pub fn save_file(
     let new_file_path = destination_dir.join(PathBuf::from(filename.clone()));
 
     // Save the thumbnail to disk
-    let (thumbnail_filename, thumbnail_worker_result) =
-        if let ThumbnailStrategy::None = thumbnail_strategy {
-            (None, None)
-        }
-        else {
+    let (thumbnail_filename, rendition_settings) = match thumbnail_strategy {
+        ThumbnailStrategy::None => (None, None),
+        ThumbnailStrategy::Generate(settings) => {
+            (Some(rendition_filename(id, settings.thumbnail_size())), Some(settings))
+        },
+        ThumbnailStrategy::FromByteSource(data) => {
             let thumbnail_filename = format!("thumb_{}.jpg", id);
             let thumbnail_path = destination_dir.join(PathBuf::from(thumbnail_filename.clone()));
 
-            let thumbnail_worker_result = match thumbnail_strategy {
-                ThumbnailStrategy::Generate => {
-                    Some(generate_thumbnail(source_content.clone(), &thumbnail_path))
-                },
-                ThumbnailStrategy::FromByteSource(data) => {
-                    write_byte_source_to_file(data, &thumbnail_path)
-                        .chain_err(|| "Failed to write thumbnail to disk")?;
-                    None
-                },
-                ThumbnailStrategy::None => panic!("Unreachable statement")
-            };
-
-            (Some(thumbnail_filename), thumbnail_worker_result)
-        };
+            write_byte_source_to_file(data, &thumbnail_path)
+                .chain_err(|| "Failed to write thumbnail to disk")?;
+
+            (Some(thumbnail_filename), None)
+        }
+    };
 
     //Store the file in the database
-    let saved_file = {
+    let mut saved_file = {
         fdb.add_new_file(
             id,
             &filename,
@@ -89,12 +135,63 @@ pub fn save_file(
         )
     };
 
+    let sidecars = sidecars.into_iter()
+        .enumerate()
+        .map(|(index, sidecar)| {
+            (sidecar_filename(id, index, &sidecar.extension), sidecar.content)
+        })
+        .collect::<Vec<_>>();
+    if !sidecars.is_empty() {
+        let sidecar_filenames = sidecars.iter()
+            .map(|(filename, _)| filename.clone())
+            .collect::<Vec<_>>();
+        fdb.set_sidecars(id, &sidecar_filenames)?;
+        saved_file.sidecars = sidecar_filenames;
+    }
+
+    // Temporary files are moved into place right away which is cheap and lets
+    // the renditions be generated from the stored file
+    let (source_content, content_to_write) = match source_content {
+        ByteSource::TempFile(path) => {
+            write_byte_source_to_file(ByteSource::TempFile(path), &new_file_path)
+                .chain_err(|| "Failed to move file into storage")?;
+            (ByteSource::File(new_file_path.clone()), None)
+        }
+        other => (other.clone(), Some(other))
+    };
+
+    // The renditions are generated after the file is added since they
+    // are stored with a reference to it
+    let thumbnail_worker_result = match rendition_settings {
+        Some(settings) => {
+            let rendition_fdb = fdb.reconnect()?;
+            Some(generate_thumbnail(
+                source_content.clone(),
+                &destination_dir,
+                id,
+                &settings,
+                move |details| store_image_details(&rendition_fdb, id, &details)
+            ))
+        }
+        None => None
+    };
+
     // Spawn a thread to copy the files to their destinations
     let save_result_rx = {
         let (tx, rx) = channel();
+        let hash_fdb = fdb.reconnect()?;
 
         thread::spawn(move || {
-            let save_result = save_file_to_disk(&new_file_path, source_content);
+            let save_result = content_to_write
+                .map(|content| write_byte_source_to_file(content, &new_file_path))
+                .unwrap_or(Ok(()))
+                .and_then(|_| {
+                    for (filename, content) in sidecars {
+                        write_byte_source_to_file(content, &destination_dir.join(filename))?;
+                    }
+                    Ok(())
+                })
+                .and_then(|_| hash_fdb.set_content_hash(id, &content_hash(&new_file_path)?));
 
             // We ignore any failures to send the file save result since
             // it most likely means that the caller of the save function
@@ -116,13 +213,191 @@ pub fn save_file(
     ))
 }
 
-fn save_file_to_disk(destination_path: &Path, content: ByteSource) -> Result<()>  {
-    let mut file = fs::File::create(destination_path)?;
+/**
+  Adds a file whose original is stored on another instance. Only the
+  thumbnail is stored, the original and its sidecars are fetched by
+  `originals::fetch_original` once they are requested
+*/
+pub fn save_remote_file(
+        thumbnail: Option<ByteSource>,
+        sidecar_extensions: &[String],
+        id: i32,
+        fdb: &FileDatabase,
+        file_extension: &str,
+        file_timestamp: u64,
+        content_hash: Option<&str>
+    )
+    -> Result<File>
+{
+    let destination_dir = fdb.get_file_save_path();
+
+    let thumbnail_filename = match thumbnail {
+        Some(data) => {
+            let thumbnail_filename = format!("thumb_{}.jpg", id);
+            write_byte_source_to_file(data, &destination_dir.join(&thumbnail_filename))
+                .chain_err(|| "Failed to write thumbnail to disk")?;
+            Some(thumbnail_filename)
+        }
+        None => None
+    };
+
+    let mut saved_file = fdb.add_new_file(
+        id,
+        &format!("{}.{}", id, file_extension),
+        thumbnail_filename.as_deref(),
+        &[],
+        file_timestamp,
+        &ChangeCreationPolicy::No
+    );
+    fdb.set_original_stored(id, false, None)?;
+    saved_file.is_uploaded = false;
+
+    let sidecar_filenames = sidecar_extensions.iter()
+        .enumerate()
+        .map(|(index, extension)| sidecar_filename(id, index, extension))
+        .collect::<Vec<_>>();
+    if !sidecar_filenames.is_empty() {
+        fdb.set_sidecars(id, &sidecar_filenames)?;
+        saved_file.sidecars = sidecar_filenames;
+    }
+
+    if let Some(hash) = content_hash {
+        fdb.set_content_hash(id, hash)?;
+        saved_file.content_hash = Some(hash.to_string());
+    }
+
+    Ok(saved_file)
+}
+
+/**
+  Stores the information gathered while generating renditions for a file
+*/
+fn store_image_details(fdb: &FileDatabase, id: i32, details: &ImageDetails) -> Result<()> {
+    fdb.set_renditions(id, &details.renditions)?;
+    fdb.set_file_dimensions(id, details.width as i32, details.height as i32)?;
+    fdb.set_perceptual_hash(id, details.perceptual_hash as i64)
+}
+
+/**
+  Generates new renditions for a file that is already stored and removes
+  the thumbnails that they replace
+*/
+pub fn regenerate_thumbnails(fdb: &FileDatabase, file: &File, settings: &ThumbnailSettings)
+    -> Result<()>
+{
+    let storage_path = fdb.get_file_save_path();
+    let old_renditions = fdb.get_renditions(file.id)?;
+
+    let details = generate_image_details(
+            ByteSource::File(storage_path.join(&file.filename)),
+            file.id,
+            settings,
+            &storage_path
+        )
+        .chain_err(|| ErrorKind::ThumbnailGenerationFailed)?;
+
+    store_image_details(fdb, file.id, &details)?;
+    fdb.set_thumbnail_path(file.id, Some(&rendition_filename(file.id, settings.thumbnail_size())))?;
+
+    // Old renditions with the same size have been overwritten, the rest are no
+    // longer referenced
+    let outdated = old_renditions.into_iter()
+        .map(|rendition| rendition.filename)
+        .chain(file.thumbnail_path.clone())
+        .filter(|filename| !details.renditions.iter().any(|r| &r.filename == filename))
+        .collect::<BTreeSet<_>>();
+
+    for filename in outdated {
+        remove_if_exists(&storage_path.join(filename))?;
+    }
+
+    Ok(())
+}
+
+/**
+  Replaces the content of a stored file, for example with an edited version or
+  a losslessly rotated JPEG, and stores `content_hash` which is the hash of the
+  new content. `content` is `None` if the original is stored on another
+  instance.
+
+  The thumbnail is replaced according to `thumbnail_strategy`. Renditions of
+  the old content are removed unless they are generated again, so
+  `/thumbnails/regenerate` picks the file up.
+
+  Creates a change if the `change_policy` says to do so
+*/
+pub fn replace_file_content(
+    fdb: &FileDatabase,
+    file: &File,
+    content: Option<ByteSource>,
+    content_hash: &str,
+    thumbnail_strategy: ThumbnailStrategy,
+    change_policy: &ChangeCreationPolicy
+) -> Result<()> {
+    let storage_path = fdb.get_file_save_path();
+    let path = storage_path.join(&file.filename);
+
+    let content_stored = content.is_some();
+    if let Some(content) = content {
+        write_byte_source_to_file(content, &path)
+            .chain_err(|| "Failed to replace file content")?;
+    }
+
+    // The hash is stored before the thumbnails are replaced so that it always
+    // matches the content even if that fails
+    fdb.set_file_content_hash(file, content_hash, change_policy)?;
+
+    match thumbnail_strategy {
+        ThumbnailStrategy::Generate(settings) => regenerate_thumbnails(fdb, file, &settings)?,
+        ThumbnailStrategy::FromByteSource(data) => {
+            let thumbnail_filename = format!("thumb_{}.jpg", file.id);
+            write_byte_source_to_file(data, &storage_path.join(&thumbnail_filename))
+                .chain_err(|| "Failed to write thumbnail to disk")?;
+            fdb.set_thumbnail_path(file.id, Some(&thumbnail_filename))?;
+
+            remove_renditions(fdb, file.id, Some(&thumbnail_filename))?;
+        }
+        ThumbnailStrategy::None => {
+            remove_renditions(fdb, file.id, None)?;
+            if let Some(ref thumbnail_path) = file.thumbnail_path {
+                remove_if_exists(&storage_path.join(thumbnail_path))?;
+            }
+            fdb.set_thumbnail_path(file.id, None)?;
+        }
+    }
+
+    // Rotating an image swaps its dimensions
+    if content_stored {
+        if let Ok((width, height)) = get_image_dimensions(&path) {
+            fdb.set_file_dimensions(file.id, width as i32, height as i32)?;
+        }
+    }
 
-    file.write_all(&vec_from_byte_source(content)?)?;
     Ok(())
 }
 
+/**
+  Removes the renditions of a file from the database and the file system,
+  except the one called `keep`
+*/
+fn remove_renditions(fdb: &FileDatabase, file_id: i32, keep: Option<&str>) -> Result<()> {
+    let storage_path = fdb.get_file_save_path();
+
+    for rendition in fdb.get_renditions(file_id)? {
+        if Some(rendition.filename.as_str()) != keep {
+            remove_if_exists(&storage_path.join(&rendition.filename))?;
+        }
+    }
+    fdb.set_renditions(file_id, &[])
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).chain_err(|| ErrorKind::FileRemovalFailed(path.to_string_lossy().into()))
+    }
+}
 
 /**
   Drops a file from the database and removes it from the file system.
@@ -133,23 +408,84 @@ pub fn remove_file(file_id: i32, fdb: &FileDatabase, change_policy: &ChangeCreat
     // Fetch the file details from the database
     let file = fdb.get_file_with_id_result(file_id)
         .chain_err(|| ErrorKind::FileDatabaseRemovalFailed(file_id))?;
+    let renditions = fdb.get_renditions(file_id)?;
 
     // Drop the file from the database
     fdb.drop_file(file_id, &change_policy)?;
 
-    let full_path = fdb.get_file_save_path().join(file.filename);
-    let full_thumb_path = file.thumbnail_path.map( |filename| {
+    let full_path = fdb.get_file_save_path().join(&file.filename);
+    let full_thumb_path = file.thumbnail_path.as_ref().map( |filename| {
         fdb.get_file_save_path().join(filename)
     });
 
-    fs::remove_file(full_path.clone())
-        .chain_err(|| ErrorKind::FileRemovalFailed(full_path.to_string_lossy().into()))?;
+    // Originals that are stored on another instance were never written here
+    if file.is_uploaded {
+        fs::remove_file(full_path.clone())
+            .chain_err(|| ErrorKind::FileRemovalFailed(full_path.to_string_lossy().into()))?;
+    }
 
     if let Some(path) = full_thumb_path {
         fs::remove_file(path.clone())
             .chain_err(|| ErrorKind::FileRemovalFailed(path.to_string_lossy().into()))?;
     }
 
+    if file.is_uploaded {
+        remove_sidecars(&file, fdb)?;
+    }
+
+    // The thumbnail is usually one of the renditions, so it has already been removed
+    for rendition in renditions {
+        if Some(&rendition.filename) == file.thumbnail_path.as_ref() {
+            continue;
+        }
+
+        let path = fdb.get_file_save_path().join(rendition.filename);
+        fs::remove_file(path.clone())
+            .chain_err(|| ErrorKind::FileRemovalFailed(path.to_string_lossy().into()))?;
+    }
+
+    Ok(())
+}
+
+/**
+  Removes the sidecars of a file from the file system
+*/
+pub fn remove_sidecars(file: &File, fdb: &FileDatabase) -> Result<()> {
+    for filename in &file.sidecars {
+        let path = fdb.get_file_save_path().join(filename);
+        fs::remove_file(path.clone())
+            .chain_err(|| ErrorKind::FileRemovalFailed(path.to_string_lossy().into()))?;
+    }
+    Ok(())
+}
+
+/**
+  Returns the amount of bytes a file and its sidecars take up in storage.
+  Thumbnails and renditions are left out. Returns `None` if the original is
+  stored on another instance
+*/
+pub fn stored_size(file: &File, fdb: &FileDatabase) -> Result<Option<u64>> {
+    if !file.is_uploaded {
+        return Ok(None);
+    }
+
+    let mut size = 0;
+    for filename in Some(&file.filename).into_iter().chain(file.sidecars.iter()) {
+        size += fs::metadata(fdb.get_file_save_path().join(filename))?.len();
+    }
+    Ok(Some(size))
+}
+
+/**
+  Computes the content hash of files saved by older versions which don't have
+  one yet. Files whose original is stored on another instance are left as is
+*/
+pub fn ensure_content_hash(file: &mut File, fdb: &FileDatabase) -> Result<()> {
+    if file.content_hash.is_none() && file.is_uploaded {
+        let hash = content_hash(&fdb.get_file_save_path().join(&file.filename))?;
+        fdb.set_content_hash(file.id, &hash)?;
+        file.content_hash = Some(hash);
+    }
     Ok(())
 }
 
@@ -1,9 +1,10 @@
 use std::path::{Path, PathBuf};
+use std::collections::HashMap;
 
 use iron::typemap::Key;
 use std::option::Option;
 
-use file_util::get_files_in_dir;
+use file_util::{get_files_in_dir, group_sidecars};
 
 use file_database;
 
@@ -37,25 +38,41 @@ pub enum FileListSource {
 pub struct FileList {
     files: Vec<FileLocation>,
     source: FileListSource,
+    /// Other versions of the unsaved files in the list, found when the list was created
+    sidecars: HashMap<PathBuf, Vec<PathBuf>>,
 }
 
 impl FileList {
     pub fn from_locations(files: Vec<FileLocation>, source: FileListSource) -> FileList {
-        FileList { files, source }
+        FileList { files, source, sidecars: HashMap::new() }
+    }
+
+    pub fn with_sidecars(self, sidecars: HashMap<PathBuf, Vec<PathBuf>>) -> FileList {
+        FileList { sidecars, ..self }
     }
 
     pub fn from_directory(path: PathBuf, file_read_path: &Path) -> FileList {
         let full_dir_path = file_read_path.join(&path);
         let file_paths = get_files_in_dir(&full_dir_path);
 
-        let files = file_paths
+        // Only the main file of files that are versions of the same photo is
+        // listed. The rest are found again when it is saved
+        let mut sidecars = HashMap::new();
+        let files = group_sidecars(file_paths)
             .into_iter()
-            .map(FileLocation::Unsaved)
+            .map(|mut group| {
+                let primary = group.remove(0);
+                if !group.is_empty() {
+                    sidecars.insert(primary.clone(), group);
+                }
+                FileLocation::Unsaved(primary)
+            })
             .collect();
 
         FileList {
             files,
             source: FileListSource::Folder(path),
+            sidecars,
         }
     }
 
@@ -93,6 +110,17 @@ impl FileList {
     pub fn get_source(&self) -> &FileListSource {
         &self.source
     }
+
+    /**
+      Returns the sidecars of an unsaved file in the list as they were when the
+      list was created
+    */
+    pub fn get_sidecars(&self, path: &Path) -> Vec<PathBuf> {
+        self.sidecars.get(path).cloned().unwrap_or_default()
+    }
+    pub fn get_all_sidecars(&self) -> &HashMap<PathBuf, Vec<PathBuf>> {
+        &self.sidecars
+    }
 }
 
 /**
@@ -232,4 +260,23 @@ mod file_list_tests {
             &FileLocation::Unsaved(PathBuf::from("test3"))
         );
     }
+
+    #[test]
+    fn sidecars_are_remembered_by_directory_lists() {
+        let dir = ::std::env::temp_dir()
+            .join(format!("flash_list_sidecars_{}", ::rand::random::<u32>()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        for file in &["a.jpg", "a.NEF", "b.png"] {
+            ::std::fs::write(dir.join(file), b"content").unwrap();
+        }
+
+        let list = FileList::from_directory(dir.clone(), Path::new(""));
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.get_sidecars(&dir.join("a.jpg")), vec!(dir.join("a.NEF")));
+        assert_eq!(list.get_sidecars(&dir.join("b.png")), Vec::<PathBuf>::new());
+
+        // The directory is not read again when the sidecars are requested
+        ::std::fs::remove_file(dir.join("a.NEF")).unwrap();
+        assert_eq!(list.get_sidecars(&dir.join("a.jpg")), vec!(dir.join("a.NEF")));
+    }
 }
@@ -2,7 +2,7 @@
 use serde_json;
 
 use iron::*;
-use persistent::Write;
+use persistent::{Read, Write};
 
 use std::path::{Path, PathBuf};
 
@@ -12,22 +12,34 @@ use std::sync::Arc;
 use chrono::{NaiveDateTime, Utc};
 
 use file_database;
-use file_database::FileDatabase;
+use file_database::{FileDatabase, Rendition};
 use file_list::{FileListList, FileLocation};
 use file_list_worker;
 use persistent_file_list;
 use file_util::{
+    content_hash,
     get_semi_unique_identifier,
     get_file_timestamp,
-    sanitize_tag_names
+    get_image_dimensions,
+    find_sidecars,
+    sanitize_tag_names,
+    ThumbnailSettings
+};
+use request_helpers::{get_get_variable, get_optional_get_i64, setup_db_connection};
+use settings::Settings;
+use file_handler::{
+    replace_file_content,
+    save_file,
+    FileSavingWorkerResults,
+    ThumbnailStrategy,
+    Sidecar
 };
-use request_helpers::{get_get_variable, setup_db_connection};
-use file_handler::{save_file, FileSavingWorkerResults, ThumbnailStrategy};
 use byte_source::ByteSource;
 use changelog;
 use changelog::ChangeCreationPolicy;
 
 use file_list_response;
+use originals::ensure_original;
 
 use error::{Result, ErrorKind, Error, ResultExt};
 
@@ -36,7 +48,8 @@ enum FileAction {
     GetFile,
     GetFilename,
     GetThumbnail,
-    Save
+    Save,
+    Replace
 }
 
 impl FileAction {
@@ -48,6 +61,7 @@ impl FileAction {
             "get_filename" => Some(FileAction::GetFilename),
             "get_thumbnail" => Some(FileAction::GetThumbnail),
             "save" => Some(FileAction::Save),
+            "replace" => Some(FileAction::Replace),
             _ => None
         }
     }
@@ -64,6 +78,11 @@ struct FileData {
     thumbnail_path: String,
     tags: Vec<String>,
     creation_date: NaiveDateTime,
+    // The displayed size of the image if it is known
+    width: Option<u32>,
+    height: Option<u32>,
+    // Other versions of the same photo that are stored with the file
+    sidecars: Vec<String>,
 }
 
 impl FileData {
@@ -73,16 +92,27 @@ impl FileData {
             thumbnail_path: source.thumbnail_path.unwrap_or_else(|| String::from("")),
             tags: source.tags,
             creation_date: source.creation_date,
+            width: source.width.map(|width| width as u32),
+            height: source.height.map(|height| height as u32),
+            sidecars: source.sidecars,
         }
     }
 
-    fn from_path(source: &Path) -> Result<FileData> {
+    fn from_path(source: &Path, sidecars: &[PathBuf]) -> Result<FileData> {
+        // Files that are not images do not have dimensions
+        let dimensions = get_image_dimensions(source).ok();
+
         Ok(FileData {
             file_path: String::from(source.to_string_lossy()),
             thumbnail_path: String::from(source.to_string_lossy()),
             tags: vec![],
             creation_date: get_file_timestamp(&source)
-                .chain_err(|| "Failed to read file timestamp")?
+                .chain_err(|| "Failed to read file timestamp")?,
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            sidecars: sidecars.iter()
+                .map(|path| String::from(path.to_string_lossy()))
+                .collect(),
         })
     }
 }
@@ -125,23 +155,35 @@ fn file_request_handler(request: &mut Request, action: &FileAction) -> IronResul
     let (list_id, file_index) = read_request_list_id_index(request)?;
     let fdb = setup_db_connection(request)?;
 
-    let file_location = {
+    let (file_location, sidecars) = {
         let mutex = request.get::<Write<FileListList>>().unwrap();
         let file_list_list = mutex.lock().unwrap();
 
-        get_file_list_object(&*file_list_list, list_id, file_index)?
+        let file_location = get_file_list_object(&file_list_list, list_id, file_index)?;
+        let sidecars = match file_location {
+            FileLocation::Unsaved(ref path) => file_list_list.get(list_id)
+                .map(|list| list.get_sidecars(path))
+                .unwrap_or_default(),
+            FileLocation::Database(_) => vec!()
+        };
+        (file_location, sidecars)
     };
 
     let file_storage_folder = fdb.get_file_save_path();
 
     match *action {
         FileAction::GetData => {
-            let file_data = file_data_from_file_location(&file_location)?;
+            let file_data = file_data_from_file_location(&fdb, &file_location, &sidecars)?;
             Ok(Response::with(
                 (status::Ok, serde_json::to_string(&file_data).unwrap()),
             ))
         }
         FileAction::GetFile => {
+            if let FileLocation::Database(ref entry) = file_location {
+                let settings = request.get::<Read<Settings>>().unwrap();
+                ensure_original(&fdb, &settings, entry.id)?;
+            }
+
             let path = get_file_location_path(&file_storage_folder, &file_location);
             Ok(Response::with((status::Ok, path)))
         }
@@ -155,23 +197,53 @@ fn file_request_handler(request: &mut Request, action: &FileAction) -> IronResul
             Ok(Response::with((status::Ok, path)))
         }
         FileAction::GetThumbnail => {
-            let path = get_file_list_thumbnail(&file_storage_folder, &file_location);
+            let path = match get_optional_get_i64(request, "size")? {
+                Some(size) => {
+                    get_file_list_rendition(&fdb, &file_location, size as i32)?
+                }
+                None => get_file_list_thumbnail(&file_storage_folder, &file_location)
+            };
             Ok(Response::with((status::Ok, path)))
         }
         FileAction::Save => {
             let tags = get_tags_from_request(request)?;
             let current_time = NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0);
-            match handle_save_request(&fdb, &file_location, &tags, current_time)? {
-                FileSaveRequestResult::NewDatabaseEntry(new_location, _) |
-                FileSaveRequestResult::UpdatedDatabaseEntry(new_location) => {
-                    let mut file_list_list = request.get::<Write<FileListList>>().unwrap();
-                    update_file_list(&mut file_list_list, list_id, file_index, &new_location);
+            let thumbnail_settings = request.get::<Read<Settings>>().unwrap()
+                .get_thumbnail_settings();
+            let new_location = match handle_save_request(&fdb, &file_location, &tags, current_time, &thumbnail_settings)? {
+                FileSaveRequestResult::NewDatabaseEntry(new_location, worker_results) => {
+                    worker_results.wait_for_file()?;
+                    new_location
+                }
+                FileSaveRequestResult::UpdatedDatabaseEntry(new_location) => new_location
+            };
 
-                    send_file_list_save_command(request);
+            let mut file_list_list = request.get::<Write<FileListList>>().unwrap();
+            update_file_list(&mut file_list_list, list_id, file_index, &new_location);
 
-                    Ok(Response::with((status::Ok, "\"ok\"")))
-                }
-            }
+            send_file_list_save_command(request);
+
+            Ok(Response::with((status::Ok, "\"ok\"")))
+        }
+        FileAction::Replace => {
+            let settings = request.get::<Read<Settings>>().unwrap();
+            let path = settings.get_file_read_path().join(get_get_variable(request, "path")?);
+            let current_time = NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0);
+
+            let new_location = handle_replace_request(
+                &fdb,
+                &settings,
+                &file_location,
+                &path,
+                current_time
+            )?;
+
+            let mut file_list_list = request.get::<Write<FileListList>>().unwrap();
+            update_file_list(&mut file_list_list, list_id, file_index, &new_location);
+
+            send_file_list_save_command(request);
+
+            Ok(Response::with((status::Ok, "\"ok\"")))
         }
     }
 }
@@ -256,13 +328,14 @@ fn handle_save_request(
     db: &FileDatabase,
     file_location: &FileLocation,
     tags: &[String],
-    change_timestamp: NaiveDateTime
+    change_timestamp: NaiveDateTime,
+    thumbnail_settings: &ThumbnailSettings
 ) -> Result<FileSaveRequestResult> {
     match *file_location {
         FileLocation::Unsaved(ref path) => {
             let file_timestamp = get_file_timestamp(path)?;
 
-            match save_new_file(db, path, tags, file_timestamp, change_timestamp) {
+            match save_new_file(db, path, tags, file_timestamp, change_timestamp, thumbnail_settings) {
                 Ok((db_entry, save_result_rx)) => {
                     Ok(FileSaveRequestResult::NewDatabaseEntry(
                         FileLocation::Database(db_entry),
@@ -285,6 +358,50 @@ fn handle_save_request(
     }
 }
 
+/**
+  Replaces the content of a saved file with the file at `path`, for example an
+  edited or rotated version of it. Returns the updated `FileLocation`
+*/
+fn handle_replace_request(
+    db: &FileDatabase,
+    settings: &Settings,
+    file_location: &FileLocation,
+    path: &Path,
+    change_timestamp: NaiveDateTime
+) -> Result<FileLocation> {
+    let file_id = match *file_location {
+        FileLocation::Database(ref entry) => entry.id,
+        FileLocation::Unsaved(ref path) => bail!(ErrorKind::FileNotSaved(path.clone()))
+    };
+
+    let extension_of = |path: &Path| {
+        path.extension().map(|extension| extension.to_string_lossy().to_string())
+    };
+    let extension = extension_of(Path::new(&db.get_file_with_id_result(file_id)?.filename))
+        .unwrap_or_default();
+    if extension_of(path).as_ref() != Some(&extension) {
+        bail!(ErrorKind::ExtensionMismatch(path.to_owned(), extension));
+    }
+
+    // The sidecars of files from peers with lazy originals are fetched along
+    // with the original. Since the content only exists here from now on, the
+    // original is no longer cached
+    ensure_original(db, settings, file_id)?;
+    db.set_original_stored(file_id, true, None)?;
+
+    let file = db.get_file_with_id_result(file_id)?;
+    replace_file_content(
+        db,
+        &file,
+        Some(ByteSource::File(path.to_owned())),
+        &content_hash(path)?,
+        ThumbnailStrategy::Generate(settings.get_thumbnail_settings()),
+        &ChangeCreationPolicy::Yes(change_timestamp)
+    )?;
+
+    Ok(FileLocation::Database(db.get_file_with_id_result(file_id)?))
+}
+
 /**
   Saves a specified file in the `Filedatabase`
 */
@@ -293,7 +410,8 @@ fn save_new_file(
     original_path: &Path,
     tags: &[String],
     file_timestamp: NaiveDateTime,
-    current_time: NaiveDateTime
+    current_time: NaiveDateTime,
+    thumbnail_settings: &ThumbnailSettings
 ) -> Result<(file_database::File, FileSavingWorkerResults)> {
     let file_identifier = get_semi_unique_identifier();
 
@@ -305,9 +423,21 @@ fn save_new_file(
     }?;
 
 
+    // Other versions of the same photo are stored as part of the same file
+    let sidecars = find_sidecars(original_path)
+        .into_iter()
+        .map(|path| Sidecar {
+            extension: path.extension()
+                .map(|extension| extension.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            content: ByteSource::File(path),
+        })
+        .collect();
+
     save_file(
         file,
-        ThumbnailStrategy::Generate,
+        sidecars,
+        ThumbnailStrategy::Generate(thumbnail_settings.clone()),
         file_identifier,
         tags,
         db,
@@ -334,11 +464,20 @@ fn update_stored_file_tags(
 /**
   Returns a `FileData` struct for the specified file location
 */
-fn file_data_from_file_location(file: &FileLocation) -> Result<FileData> {
+fn file_data_from_file_location(
+    fdb: &FileDatabase,
+    file: &FileLocation,
+    sidecars: &[PathBuf]
+) -> Result<FileData> {
     // Lock the file list and try to fetch the file
     Ok(match *file {
-        FileLocation::Unsaved(ref path) => FileData::from_path(path)?,
-        FileLocation::Database(ref db_entry) => FileData::from_database(db_entry.clone()),
+        FileLocation::Unsaved(ref path) => FileData::from_path(path, sidecars)?,
+        FileLocation::Database(ref db_entry) => {
+            // The entry in the file list is not updated when the dimensions
+            // are stored after the file was saved so the database is checked first
+            let db_entry = fdb.get_file_with_id(db_entry.id).unwrap_or_else(|| db_entry.clone());
+            FileData::from_database(db_entry)
+        }
     })
 }
 
@@ -368,6 +507,39 @@ fn get_file_list_thumbnail(storage_folder: &Path, file: &FileLocation) -> PathBu
     }
 }
 
+/**
+  Returns the path to the smallest version of a `FileLocation` that is at least
+  `size` pixels along its longest side. Falls back to the original file if no
+  rendition is large enough, or to the thumbnail if the original is stored on
+  another instance
+*/
+fn get_file_list_rendition(fdb: &FileDatabase, file: &FileLocation, size: i32)
+    -> Result<PathBuf>
+{
+    let storage_folder = fdb.get_file_save_path();
+    match *file {
+        FileLocation::Unsaved(ref path) => Ok(path.clone()),
+        FileLocation::Database(ref db_entry) => {
+            let renditions = fdb.get_renditions(db_entry.id)?;
+
+            Ok(match best_rendition(&renditions, size) {
+                Some(rendition) => storage_folder.join(&rendition.filename),
+                None if !db_entry.is_uploaded => get_file_list_thumbnail(&storage_folder, file),
+                None => storage_folder.join(&db_entry.filename)
+            })
+        }
+    }
+}
+
+/**
+  Returns the smallest rendition that is at least `size` pixels large
+*/
+fn best_rendition(renditions: &[Rendition], size: i32) -> Option<&Rendition> {
+    renditions.iter()
+        .filter(|rendition| rendition.size >= size)
+        .min_by_key(|rendition| rendition.size)
+}
+
 /**
   Returns a `FileLocation` from a `FileListList`, a list id and a file id
 */
@@ -414,6 +586,12 @@ mod file_request_tests {
             creation_date: NaiveDate::from_ymd(2016,1,1).and_hms(0,0,0),
             is_uploaded: true,
             tags: vec![],
+            width: None,
+            height: None,
+            sidecars: vec![],
+            perceptual_hash: None,
+            content_hash: None,
+            original_accessed: None,
         }
     }
 
@@ -490,6 +668,22 @@ mod file_request_tests {
                         , FileLocation::Database(_))
     }
 
+    #[test]
+    fn best_rendition_is_smallest_large_enough() {
+        let renditions = vec!(
+            Rendition{file_id: 0, size: 200, filename: "thumb_0_200.jpg".into(), version: 1},
+            Rendition{file_id: 0, size: 2048, filename: "thumb_0_2048.jpg".into(), version: 1},
+            Rendition{file_id: 0, size: 1024, filename: "thumb_0_1024.jpg".into(), version: 1},
+        );
+
+        assert_eq!(best_rendition(&renditions, 100).map(|r| r.size), Some(200));
+        assert_eq!(best_rendition(&renditions, 200).map(|r| r.size), Some(200));
+        assert_eq!(best_rendition(&renditions, 800).map(|r| r.size), Some(1024));
+        assert_eq!(best_rendition(&renditions, 1500).map(|r| r.size), Some(2048));
+        assert_eq!(best_rendition(&renditions, 4000), None);
+        assert_eq!(best_rendition(&[], 100), None);
+    }
+
 
     // TODO: Use db_test! instead of this test runner
     #[test]
@@ -518,7 +712,8 @@ mod file_request_tests {
                     &PathBuf::from("test"),
                     &tags,
                     NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0),
-                    NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0)
+                    NaiveDate::from_ymd(2017,1,1).and_hms(0,0,0),
+                    &ThumbnailSettings::default()
                 ),
                 Err(Error(ErrorKind::NoFileExtension(_), _))
             );
@@ -533,7 +728,8 @@ mod file_request_tests {
                 &src_path,
                 &tags,
                 NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0),
-                NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0)
+                NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0),
+                &ThumbnailSettings::default()
             )
             .unwrap();
 
@@ -569,7 +765,8 @@ mod file_request_tests {
                 fdb,
                 &FileLocation::Unsaved(old_path),
                 &tags,
-                timestamp
+                timestamp,
+                &ThumbnailSettings::default()
             );
 
             assert_matches!(result, Ok(_));
@@ -631,7 +828,8 @@ mod file_request_tests {
                     &fdb,
                     &FileLocation::Database(old_location),
                     &tags,
-                    timestamp
+                    timestamp,
+                    &ThumbnailSettings::default()
                 );
 
             assert_matches!(result, Ok(_));
@@ -682,7 +880,8 @@ mod file_request_tests {
                 fdb,
                 &FileLocation::Unsaved(old_path),
                 &vec!(),
-                change_timestamp
+                change_timestamp,
+                &ThumbnailSettings::default()
             );
 
             assert_matches!(result, Ok(_));
@@ -724,8 +923,7 @@ mod file_request_tests {
         }
 
         // Make sure a change was added with the correct
-        let changes = fdb.get_all_changes()
-            .expect("Failed to get changes from database ");
+        let changes = file_database::db_test_helpers::get_changes_with_timestamp_clocks(fdb);
 
         assert_eq!(changes.len(), 1);
         assert_eq!(changes[0], Change::new(change_timestamp, saved_entry.id, ChangeType::FileAdded));
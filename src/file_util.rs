@@ -3,9 +3,14 @@ extern crate rand;
 extern crate immeta;
 
 use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 
 use std::fs::File;
 use std::fs;
+use std::io;
+use std::io::Write;
+use std::cmp;
 
 use std::thread;
 
@@ -16,44 +21,102 @@ use error::{Result, ResultExt, ErrorKind};
 use chrono::NaiveDateTime;
 
 use exiftool;
-use exiftool::{ExifData};
+use exiftool::{ExifData, Orientation};
 use byte_source::{ByteSource, vec_from_byte_source};
+use file_database::Rendition;
+use util::to_hex;
+
+use sha2::{Sha256, Digest};
+
+use self::image::GenericImage;
 
 use std::sync::mpsc;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /**
-  Length or width, depending on which is the longest of a generated thumbnail
+  Version of the rendition generation code. Renditions generated by an older
+  version are regenerated by the thumbnail job. Should be increased whenever
+  generation changes in a way that makes old renditions outdated
+*/
+pub const RENDITION_VERSION: i32 = 1;
+
+/**
+  Describes which renditions are generated when a file is saved
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThumbnailSettings {
+    // Length of the longest side of each rendition
+    pub sizes: Vec<u32>,
+    // JPEG quality of the generated renditions, 1-100
+    pub quality: u8,
+}
+
+impl Default for ThumbnailSettings {
+    fn default() -> Self {
+        Self {
+            sizes: vec!(200, 1024, 2048),
+            quality: 85,
+        }
+    }
+}
+
+impl ThumbnailSettings {
+    /**
+      The size of the rendition which is used as the thumbnail of a file
+    */
+    pub fn thumbnail_size(&self) -> u32 {
+        self.sizes.iter().cloned().min().unwrap_or(200)
+    }
+}
+
+/**
+  Returns the filename of the rendition of the specified size for the file
+  with the specified ID
 */
-const THUMBNAIL_SIZE: u32 = 200;
+pub fn rendition_filename(id: i32, size: u32) -> String {
+    format!("thumb_{}_{}.jpg", id, size)
+}
 
 /**
-  Starts a thread that generates a thumbnail from the specified source. It is
-  stored in `destination_path`
+  Information about an image that is gathered while generating its renditions
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageDetails {
+    pub renditions: Vec<Rendition>,
+    // The size of the image after the EXIF orientation has been applied
+    pub width: u32,
+    pub height: u32,
+    pub perceptual_hash: u64,
+}
+
+/**
+  Starts a thread that generates the renditions described by `settings` from
+  the specified source. They are stored in `destination_dir` and details about
+  the image are passed to `on_done` once they have all been written.
 
   Returns a channel which can be used to listen for errors that occured during
   generation
  */
-
-pub fn generate_thumbnail(
+pub fn generate_thumbnail<F>(
     source: ByteSource,
-    destination_path: &Path,
-) -> mpsc::Receiver<Result<()>> {
-    let destination_path = destination_path.to_owned();
+    destination_dir: &Path,
+    id: i32,
+    settings: &ThumbnailSettings,
+    on_done: F
+) -> mpsc::Receiver<Result<()>>
+    where F: FnOnce(ImageDetails) -> Result<()> + Send + 'static
+{
+    let destination_dir = destination_dir.to_owned();
+    let settings = settings.clone();
 
     let (tx, rx) = mpsc::channel();
 
     thread::spawn(move || {
         let handler = || -> Result<()> {
-            let file_content = vec_from_byte_source(source)?;
-            let img = image::load_from_memory(&file_content)?;
+            let details = generate_image_details(source, id, &settings, &destination_dir)?;
 
-            let thumb_data = generate_thumbnail_from_generic_image(&img, THUMBNAIL_SIZE);
-
-            let fout = &mut File::create(&destination_path)?;
-            thumb_data.save(fout, image::PNG)?;
-            Ok(())
+            on_done(details)
         };
 
         let generation_result = handler()
@@ -67,6 +130,193 @@ pub fn generate_thumbnail(
     rx
 }
 
+/**
+  Generates the renditions described by `settings` from the specified source
+  and stores them in `destination_dir`.
+
+  The EXIF orientation of the source is applied to all renditions.
+*/
+pub fn generate_image_details(
+    source: ByteSource,
+    id: i32,
+    settings: &ThumbnailSettings,
+    destination_dir: &Path
+) -> Result<ImageDetails> {
+    let orientation = read_orientation(&source);
+
+    let file_content = vec_from_byte_source(source)?;
+    let img = apply_orientation(image::load_from_memory(&file_content)?, orientation);
+
+    let renditions = generate_renditions(&img, id, settings, destination_dir)?;
+
+    let (width, height) = img.dimensions();
+    let perceptual_hash = perceptual_hash(&img);
+    Ok(ImageDetails{renditions, width, height, perceptual_hash})
+}
+
+/**
+  Computes a difference hash of an image. Each bit says if a pixel in a
+  9x8 grayscale version of the image is darker than its right neighbour,
+  which makes the hash survive resizing and re-encoding. Images that look
+  similar have hashes with a small hamming distance between them.
+*/
+pub fn perceptual_hash(img: &image::DynamicImage) -> u64 {
+    let small = image::imageops::resize(&img.to_luma(), 9, 8, image::FilterType::Triangle);
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).data[0] < small.get_pixel(x + 1, y).data[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/**
+  Returns the amount of bits that differ between two perceptual hashes
+*/
+pub fn hash_distance(first: u64, second: u64) -> u32 {
+    (first ^ second).count_ones()
+}
+
+/**
+  Returns the sizes of the renditions that are generated for an image whose
+  longest side is `longest_side` pixels, largest first.
+
+  Images are never scaled up, sizes larger than the original are skipped
+  except for the thumbnail size which is always generated.
+*/
+pub fn rendition_sizes(settings: &ThumbnailSettings, longest_side: u32) -> Vec<u32> {
+    let thumbnail_size = settings.thumbnail_size();
+
+    let mut sizes = settings.sizes.iter()
+        .cloned()
+        .filter(|size| *size < longest_side || *size == thumbnail_size)
+        .collect::<Vec<_>>();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes.dedup();
+    sizes
+}
+
+/**
+  Resizes `img` to each of the sizes in `settings` and writes the results to
+  `destination_dir` as JPEG.
+*/
+fn generate_renditions(
+    img: &image::DynamicImage,
+    id: i32,
+    settings: &ThumbnailSettings,
+    destination_dir: &Path
+) -> Result<Vec<Rendition>> {
+    let longest_side = {
+        let (width, height) = img.dimensions();
+        cmp::max(width, height)
+    };
+
+    // Generating from largest to smallest lets each rendition be resized
+    // from the previous one instead of the full original
+    let mut current = img.clone();
+    let mut result = vec!();
+    for size in rendition_sizes(settings, longest_side) {
+        if size < longest_side {
+            current = generate_thumbnail_from_generic_image(&current, size);
+        }
+
+        let filename = rendition_filename(id, size);
+        let fout = &mut File::create(destination_dir.join(&filename))?;
+        encode_jpeg(&current, settings.quality, fout)?;
+
+        result.push(Rendition{
+            file_id: id,
+            size: size as i32,
+            filename,
+            version: RENDITION_VERSION
+        });
+    }
+
+    Ok(result)
+}
+
+/**
+  Encodes `img` as a JPEG with the specified quality
+*/
+fn encode_jpeg<W: Write>(img: &image::DynamicImage, quality: u8, target: &mut W) -> Result<()> {
+    let rgb = img.to_rgb();
+    let (width, height) = rgb.dimensions();
+
+    image::jpeg::JPEGEncoder::new_with_quality(target, quality)
+        .encode(&rgb, width, height, image::ColorType::RGB(8))?;
+
+    Ok(())
+}
+
+
+/**
+  Reads the EXIF orientation of an image. If the orientation can not be read,
+  the image is assumed to be stored the right way up since a thumbnail in the
+  wrong orientation is better than no thumbnail at all
+*/
+fn read_orientation(source: &ByteSource) -> Orientation {
+    let exif_data = match *source {
+        ByteSource::File(ref path) | ByteSource::TempFile(ref path) => {
+            ExifData::from_file(&path.to_string_lossy())
+        }
+        ByteSource::Memory(ref data) => ExifData::from_bytes(data),
+    };
+
+    match exif_data.and_then(|exif_data| exif_data.get_orientation()) {
+        Ok(orientation) => orientation,
+        Err(exiftool::Error(exiftool::ErrorKind::NoSuchTag(_), _)) => Orientation::Normal,
+        Err(e) => {
+            println!("Failed to read image orientation: {}", e);
+            Orientation::Normal
+        }
+    }
+}
+
+/**
+  Rotates and flips an image stored with the specified orientation so that it
+  is the right way up
+*/
+fn apply_orientation(img: image::DynamicImage, orientation: Orientation) -> image::DynamicImage {
+    match orientation {
+        Orientation::Normal => img,
+        Orientation::MirrorHorizontal => img.fliph(),
+        Orientation::Rotate180 => img.rotate180(),
+        Orientation::MirrorVertical => img.flipv(),
+        Orientation::MirrorHorizontalRotate270 => img.fliph().rotate270(),
+        Orientation::Rotate90 => img.rotate90(),
+        Orientation::MirrorHorizontalRotate90 => img.fliph().rotate90(),
+        Orientation::Rotate270 => img.rotate270(),
+    }
+}
+
+/**
+  Reads the size that the specified image file will have when displayed
+  without decoding the whole image
+*/
+pub fn get_image_dimensions(path: &Path) -> Result<(u32, u32)> {
+    let dimensions = immeta::load_from_file(path)
+        .chain_err(|| "Failed to read image metadata")?
+        .dimensions();
+
+    if read_orientation(&ByteSource::File(path.to_owned())).swaps_dimensions() {
+        Ok((dimensions.height, dimensions.width))
+    }
+    else {
+        Ok((dimensions.width, dimensions.height))
+    }
+}
+
+/**
+  Checks if the header of the specified image file can be read
+*/
+pub fn is_readable_image(path: &Path) -> bool {
+    immeta::load_from_file(path).is_ok()
+}
 
 /**
   Takes a `image::GenericImage` and generates a thumbnail image from that
@@ -75,7 +325,7 @@ fn generate_thumbnail_from_generic_image(
     src: &image::DynamicImage,
     max_size: u32,
 ) -> image::DynamicImage {
-    src.resize(max_size, max_size, image::FilterType::Nearest)
+    src.resize(max_size, max_size, image::FilterType::Lanczos3)
 }
 
 /**
@@ -155,6 +405,142 @@ pub fn get_files_in_dir(dir: &PathBuf) -> Vec<PathBuf> {
     result
 }
 
+/**
+  Extensions of files that are preferred as the main file of an item consisting
+  of several files, most preferred first. Files with other extensions are
+  ranked after these
+*/
+const PRIMARY_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "tif", "tiff"];
+
+/**
+  The largest difference in seconds between the capture times of two files with
+  the same name for them to be considered versions of the same photo
+*/
+const SIDECAR_TIME_TOLERANCE: i64 = 2;
+
+/**
+  Ranks how well suited a file is to be the main file of an item, lower is better
+*/
+fn primary_rank(path: &Path) -> usize {
+    let extension = path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    PRIMARY_EXTENSIONS.iter()
+        .position(|candidate| *candidate == extension)
+        .unwrap_or_else(|| PRIMARY_EXTENSIONS.len())
+}
+
+/**
+  Returns true if two capture times are close enough for the files to be
+  versions of the same photo
+*/
+fn capture_times_match(first: &NaiveDateTime, second: &NaiveDateTime) -> bool {
+    (*first - *second).num_seconds().abs() <= SIDECAR_TIME_TOLERANCE
+}
+
+/**
+  Splits `paths` into groups of files that are versions of the same photo, like
+  `DSC_0001.JPG` and `DSC_0001.NEF`. Files are grouped if they are in the same
+  directory, have the same name apart from the extension and were captured at
+  the same time.
+
+  The first path in each group is the main file which should be displayed, the
+  rest are sidecars. Groups are ordered by where their first file was in `paths`
+*/
+pub fn group_sidecars(paths: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    // Group by directory and file stem, remembering the order in which groups
+    // were first seen
+    let mut order = vec!();
+    let mut candidates: HashMap<(Option<PathBuf>, Option<OsString>), Vec<PathBuf>> = HashMap::new();
+    for path in paths {
+        let key = (path.parent().map(Path::to_path_buf), path.file_stem().map(OsStr::to_os_string));
+
+        candidates.entry(key.clone())
+            .or_insert_with(|| {
+                order.push(key);
+                vec!()
+            })
+            .push(path);
+    }
+
+    let mut result = vec!();
+    for key in order {
+        let mut group = candidates.remove(&key).unwrap_or_default();
+
+        // Timestamps are only read when there is something to group
+        if group.len() == 1 {
+            result.push(group);
+            continue;
+        }
+
+        group.sort_by_key(|path| (primary_rank(path), path.clone()));
+
+        // Directories and files without a readable timestamp are never grouped
+        let mut timestamps = group.into_iter()
+            .map(|path| {
+                let timestamp = if path.is_file() {get_file_timestamp(&path).ok()} else {None};
+                (path, timestamp)
+            })
+            .collect::<Vec<_>>();
+
+        // Pick the best file which can be grouped as the main file
+        let primary_index = timestamps.iter()
+            .position(|(_, timestamp)| timestamp.is_some())
+            .unwrap_or(0);
+        let (primary, primary_timestamp) = timestamps.remove(primary_index);
+
+        let mut new_group = vec!(primary);
+        let mut separate = vec!();
+        for (path, timestamp) in timestamps {
+            match (primary_timestamp, timestamp) {
+                (Some(ref primary_time), Some(ref time))
+                    if capture_times_match(primary_time, time) => new_group.push(path),
+                _ => separate.push(vec!(path))
+            }
+        }
+
+        result.push(new_group);
+        result.append(&mut separate);
+    }
+
+    result
+}
+
+/**
+  Finds the sidecars of the specified file by looking for other versions of it
+  in the same directory. Returns an empty list if the file is itself a sidecar
+  of another file
+*/
+pub fn find_sidecars(primary: &Path) -> Vec<PathBuf> {
+    let directory = match primary.parent() {
+        Some(directory) => directory.to_owned(),
+        None => return vec!()
+    };
+
+    let same_stem = get_files_in_dir(&directory)
+        .into_iter()
+        .filter(|path| path.file_stem() == primary.file_stem())
+        .collect();
+
+    group_sidecars(same_stem)
+        .into_iter()
+        .find(|group| group[0] == primary)
+        .map(|group| group.into_iter().skip(1).collect())
+        .unwrap_or_default()
+}
+
+/**
+  Computes the hex encoded SHA-256 of a file without reading all of it into memory
+*/
+pub fn content_hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
 /**
   Returns a list of all subdirectories of a dir
 */
@@ -209,6 +595,105 @@ mod thumbnail_tests {
         assert!(thumbnail.dimensions() == (150, 300));
     }
 
+    #[test]
+    fn perceptual_hash_survives_resizing() {
+        // Smooth waves on top of a ramp to make sure the image isn't symmetric
+        let img = image::DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(640, 480, |x, y| {
+            let wave = (x as f32 / 70.).sin() * (y as f32 / 50.).cos();
+            let value = (wave * 60. + x as f32 / 5.) as u8 + 30;
+            image::Rgb([value, value, value])
+        }));
+
+        let hash = super::perceptual_hash(&img);
+        let resized = super::generate_thumbnail_from_generic_image(&img, 200);
+        let flipped = img.fliph();
+
+        assert!(super::hash_distance(hash, super::perceptual_hash(&resized)) <= 4);
+        assert!(super::hash_distance(hash, super::perceptual_hash(&flipped)) > 10);
+    }
+
+    #[test]
+    fn orientation_is_applied() {
+        use exiftool::Orientation;
+
+        let red = image::Rgba([255, 0, 0, 255]);
+        let blue = image::Rgba([0, 0, 255, 255]);
+
+        // A red pixel to the left of a blue one
+        let mut img = image::DynamicImage::new_rgba8(2, 1);
+        img.put_pixel(0, 0, red);
+        img.put_pixel(1, 0, blue);
+
+        let expected = vec!(
+            (Orientation::Normal, vec!((0, 0, red), (1, 0, blue))),
+            (Orientation::MirrorHorizontal, vec!((0, 0, blue), (1, 0, red))),
+            (Orientation::Rotate180, vec!((0, 0, blue), (1, 0, red))),
+            (Orientation::MirrorVertical, vec!((0, 0, red), (1, 0, blue))),
+            (Orientation::MirrorHorizontalRotate270, vec!((0, 0, red), (0, 1, blue))),
+            (Orientation::Rotate90, vec!((0, 0, red), (0, 1, blue))),
+            (Orientation::MirrorHorizontalRotate90, vec!((0, 0, blue), (0, 1, red))),
+            (Orientation::Rotate270, vec!((0, 0, blue), (0, 1, red))),
+        );
+
+        for (orientation, pixels) in expected {
+            let oriented = super::apply_orientation(img.clone(), orientation);
+
+            if orientation.swaps_dimensions() {
+                assert_eq!(oriented.dimensions(), (1, 2), "{:?}", orientation);
+            }
+            else {
+                assert_eq!(oriented.dimensions(), (2, 1), "{:?}", orientation);
+            }
+            for (x, y, colour) in pixels {
+                assert_eq!(oriented.get_pixel(x, y), colour, "{:?}", orientation);
+            }
+        }
+    }
+
+    #[test]
+    fn renditions_are_generated_without_upscaling() {
+        let destination_dir = ::std::env::temp_dir().join("flash_rendition_test");
+        ::std::fs::create_dir_all(&destination_dir).unwrap();
+
+        let settings = super::ThumbnailSettings {
+            sizes: vec!(100, 1000, 300),
+            quality: 80
+        };
+        let img = image::DynamicImage::new_rgba8(500, 250);
+
+        let renditions = super::generate_renditions(&img, 5, &settings, &destination_dir)
+            .expect("Failed to generate renditions");
+
+        assert_eq!(
+            renditions.iter().map(|r| r.size).collect::<Vec<_>>(),
+            vec!(300, 100)
+        );
+        let generated = image::open(destination_dir.join("thumb_5_300.jpg")).unwrap();
+        assert_eq!(generated.dimensions(), (300, 150));
+        let generated = image::open(destination_dir.join("thumb_5_100.jpg")).unwrap();
+        assert_eq!(generated.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn thumbnail_rendition_is_always_generated() {
+        let destination_dir = ::std::env::temp_dir().join("flash_rendition_test");
+        ::std::fs::create_dir_all(&destination_dir).unwrap();
+
+        let img = image::DynamicImage::new_rgba8(50, 20);
+
+        let renditions = super::generate_renditions(
+                &img,
+                6,
+                &super::ThumbnailSettings::default(),
+                &destination_dir
+            )
+            .expect("Failed to generate renditions");
+
+        assert_eq!(renditions.len(), 1);
+        assert_eq!(renditions[0].filename, "thumb_6_200.jpg");
+        let generated = image::open(destination_dir.join("thumb_6_200.jpg")).unwrap();
+        assert_eq!(generated.dimensions(), (50, 20));
+    }
 }
 
 
@@ -282,6 +767,62 @@ mod util_tests {
         assert_eq!(timestamp, NaiveDate::from_ymd(2016, 12, 16).and_hms(21, 34, 26));
     }
 
+    /**
+      Creates a new directory in the temp dir containing files with the specified names
+    */
+    fn create_test_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("flash_{}_{}", name, ::rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            fs::write(dir.join(file), b"content").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn files_with_different_names_are_not_grouped() {
+        let dir = create_test_dir("ungrouped", &["a.jpg", "b.nef", "c.png"]);
+
+        let paths = vec!(dir.join("a.jpg"), dir.join("b.nef"), dir.join("c.png"));
+        let groups = group_sidecars(paths.clone());
+
+        assert_eq!(groups, paths.into_iter().map(|path| vec!(path)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sidecar_grouping_works() {
+        let dir = create_test_dir("sidecars", &["a.NEF", "a.jpg", "b.png", "c.nef"]);
+
+        let groups = group_sidecars(vec!(
+            dir.join("a.NEF"),
+            dir.join("a.jpg"),
+            dir.join("b.png"),
+            dir.join("c.nef")
+        ));
+
+        assert_eq!(groups, vec!(
+            vec!(dir.join("a.jpg"), dir.join("a.NEF")),
+            vec!(dir.join("b.png")),
+            vec!(dir.join("c.nef"))
+        ));
+
+        assert_eq!(find_sidecars(&dir.join("a.jpg")), vec!(dir.join("a.NEF")));
+        assert_eq!(find_sidecars(&dir.join("a.NEF")), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn sidecar_grouping_requires_matching_times() {
+        let dir = create_test_dir("sidecar_times", &["a.jpg", "a.nef"]);
+        let old_time = SystemTime::now() - ::std::time::Duration::from_secs(3600);
+        File::options().write(true).open(dir.join("a.nef")).unwrap()
+            .set_modified(old_time)
+            .unwrap();
+
+        let groups = group_sidecars(vec!(dir.join("a.jpg"), dir.join("a.nef")));
+
+        assert_eq!(groups, vec!(vec!(dir.join("a.jpg")), vec!(dir.join("a.nef"))));
+    }
+
     #[test]
     // TODO Test disabled for now. Find a way to reliably test this
     fn filesystem_timestamp_test() {
@@ -8,10 +8,36 @@ use serde_json;
 use serde;
 
 use reqwest;
+use chrono::Utc;
+use sha2::{Sha256, Digest};
 
 use std::str::from_utf8;
-
-use sync_progress::SyncStatus;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use sync_progress::{SyncUpdate, read_events};
+use sync_auth::{
+    sign_request,
+    signed_host,
+    verify_response,
+    canonical_path,
+    check_protocol_version,
+    SignedContent,
+    TIMESTAMP_HEADER,
+    SIGNATURE_HEADER,
+    PROTOCOL_VERSION_HEADER,
+    PROTOCOL_VERSION,
+    SIGNED_HOST_VERSION
+};
+use util::to_hex;
+use settings::Settings;
+use sync_push::FilePart;
+use library_digest::LibraryDigest;
+use sync_handshake::Handshake;
 
 /**
   Struct of information about a file which can be requested from a `ForeginServer`
@@ -19,7 +45,24 @@ use sync_progress::SyncStatus;
 #[derive(Clone, Serialize, Deserialize)]
 pub struct FileDetails {
     pub extension: String,
-    pub timestamp: NaiveDateTime
+    pub timestamp: NaiveDateTime,
+    /// Extensions of the sidecars of the file, in the order they are stored in
+    #[serde(default)]
+    pub sidecars: Vec<String>,
+    /// Hex encoded SHA-256 of the file content. Older versions don't send it
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// Size of the file and its sidecars in bytes. Older versions don't send it
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// The current tags of the file, used to restore files that were removed
+    /// on the other side
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// If the original is stored by the instance, not just its thumbnail or a
+    /// copy fetched on demand. Older versions don't send it
+    #[serde(default)]
+    pub original_stored: bool
 }
 
 impl<'a> From<&'a ::file_database::File> for FileDetails {
@@ -29,9 +72,23 @@ impl<'a> From<&'a ::file_database::File> for FileDetails {
             .map(|e| e.to_string_lossy().to_string())
             .unwrap_or_else(|| "".to_string());
 
+        let sidecars = file.sidecars.iter()
+            .map(|sidecar| {
+                ::std::path::Path::new(sidecar)
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            })
+            .collect();
+
         FileDetails {
             extension,
-            timestamp: file.creation_date
+            timestamp: file.creation_date,
+            sidecars,
+            content_hash: file.content_hash.clone(),
+            size: None,
+            tags: file.tags.clone(),
+            original_stored: file.is_uploaded && file.original_accessed.is_none()
         }
     }
 }
@@ -44,21 +101,80 @@ impl<'a> From<&'a ::file_database::File> for FileDetails {
 #[derive(Serialize, Deserialize)]
 pub struct ChangeData {
     pub changes: Vec<Change>,
-    pub removed_files: Vec<i32>
+    pub removed_files: Vec<i32>,
+    /// Details of the files affected by the changes. Only sent when the files
+    /// are pushed since the foreign server can't request them
+    #[serde(default)]
+    pub file_details: HashMap<i32, FileDetails>,
+    /// The clock counter of the syncpoint the sync creates, which the changes
+    /// are stored with. Missing if the peer uses a version older than 8, the
+    /// changes keep their clocks then
+    #[serde(default)]
+    pub clock: Option<i64>
 }
 
 /**
   Trait for communicating with another flash server
 */
-pub trait ForeignServer {
+pub trait ForeignServer: Sync {
     fn get_syncpoints(&self) -> Result<Vec<SyncPoint>>;
     fn get_changes(&self, starting_timestamp: &Option<SyncPoint>) -> Result<Vec<Change>>;
     fn get_file_details(&self, id: i32) -> Result<FileDetails>;
-    fn send_changes(&mut self, change: &ChangeData, own_port: u16) -> Result<usize>;
-    fn get_file(&self, id: i32) -> Result<Vec<u8>>;
+    /**
+      Sends changes to be applied by the foreign server and returns the id of
+      the job that applies them. If `push_id` is specified, the files are
+      taken from the parts that were pushed with that id, otherwise they are
+      fetched from this instance on `own_port`
+    */
+    fn send_changes(&mut self, change: &ChangeData, own_port: u16, push_id: Option<usize>)
+        -> Result<usize>;
+    /**
+      Returns the files which the foreign server has to receive to apply the changes
+    */
+    fn get_needed_files(&self, change: &ChangeData, own_port: u16) -> Result<Vec<i32>>;
+    /**
+      Sends a chunk of a part of a file to the foreign server which is kept
+      until the changes with the same `push_id` are sent. `offset` is the
+      position of the chunk in the part, chunks are sent in order starting at 0.
+      Sending a chunk again replaces everything that was sent after its offset
+    */
+    fn push_file_part(&self, push_id: usize, file_id: i32, part: FilePart, offset: u64, content: &[u8])
+        -> Result<()>;
+    /**
+      Downloads the content of a file to `destination`. If `destination` already
+      contains the start of the file from an earlier attempt, only the rest of it
+      is fetched and appended. `on_progress` is called with the amount of bytes
+      that were received each time a part of the file has been written
+    */
+    fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()>;
+    /**
+      Downloads the sidecar with the specified index of a file to `destination`
+      like `download_file`
+    */
+    fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+        -> Result<()>;
     fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>>;
-    fn get_sync_status(&self, job_id: usize) -> Result<SyncStatus>;
+    /**
+      Follows the updates of a sync job on the foreign server. The updates end
+      once the job is done or failed
+    */
+    fn get_sync_updates(&self, job_id: usize) -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>>;
     fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()>;
+    /**
+      Returns the digest of the files on the foreign server whose hex encoded
+      ids start with `prefix`
+    */
+    fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest>;
+    /**
+      Cancels a job on the foreign server, used when nobody waits for the job
+      any more because the sync that started it was stopped
+    */
+    fn cancel_job(&self, job_id: usize) -> Result<()>;
+    /**
+      Returns the protocol versions, change types and features that the
+      foreign server supports
+    */
+    fn handshake(&self) -> Result<Handshake>;
 }
 
 
@@ -67,7 +183,25 @@ pub trait ForeignServer {
 //                      Http implementation
 ////////////////////////////////////////////////////////////////////////////////
 
-const FOREIGN_SCHEME: &str = "http";
+/// The scheme used for foreign urls which don't specify one
+const DEFAULT_SCHEME: &str = "http";
+
+/**
+  Splits a url like `https://example.com:3000` into its scheme and the rest of
+  the url. Urls without a scheme use `DEFAULT_SCHEME`
+*/
+pub fn split_scheme(url: &str) -> Result<(String, String)> {
+    match url.find("://") {
+        Some(index) => {
+            let scheme = url[..index].to_lowercase();
+            if scheme != "http" && scheme != "https" {
+                bail!(ErrorKind::UnsupportedUrlScheme(scheme));
+            }
+            Ok((scheme, url[index + 3..].trim_end_matches('/').to_string()))
+        }
+        None => Ok((DEFAULT_SCHEME.to_string(), url.to_string()))
+    }
+}
 
 fn construct_url(scheme: &str, dns: &str, path: &[String], query: &[(String, String)]) -> String {
     let mut result = String::new();
@@ -94,22 +228,33 @@ fn construct_url(scheme: &str, dns: &str, path: &[String], query: &[(String, Str
     result
 }
 /**
-  Sends a request to the foreign server and parses the result as json for `T`
+  Sends an unsigned request and parses the result as json for `T`. Requests to
+  foreign servers are sent through `HttpForeignServer` which signs them
 */
+#[cfg(test)]
 fn send_request<T: serde::de::DeserializeOwned>(full_url: &str, body: &str) -> Result<T> {
     let bytes = send_request_for_bytes(full_url, body)?;
 
     Ok(serde_json::from_str::<T>(from_utf8(&bytes)?)?)
 }
 
+#[cfg(test)]
 fn send_request_for_bytes(full_url: &str, body: &str) -> Result<Vec<u8>> {
     let client = reqwest::Client::new();
 
-    let mut response_body = vec!();
-    let mut response = client.get(full_url)
+    let response = client.get(full_url)
         .body(body.to_string())
         .send()?;
 
+    read_response(response)
+}
+
+/**
+  Reads the body of a response, returning an error if the request was not
+  successful
+*/
+fn read_response(mut response: reqwest::Response) -> Result<Vec<u8>> {
+    let mut response_body = vec!();
     response.copy_to(&mut response_body)?;
 
     let status = response.status();
@@ -123,15 +268,169 @@ fn send_request_for_bytes(full_url: &str, body: &str) -> Result<Vec<u8>> {
     Ok(response_body)
 }
 
+/**
+  Returns the value of the `SIGNATURE_HEADER` of a response
+*/
+fn response_signature(response: &reqwest::Response) -> Option<String> {
+    response.headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/**
+  Reads the complete length of a file from the content range header of a
+  416 Range Not Satisfiable response
+*/
+fn complete_length(response: &reqwest::Response) -> Option<u64> {
+    response.headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim_start_matches("bytes */").parse().ok())
+}
+
+/**
+  Creates a http client which trusts the certificate at `ca_certificate` in
+  addition to the system certificates. This allows peers to use self-signed
+  certificates. Requests fail if connecting, or a single read or write, takes
+  longer than `timeout`
+*/
+fn build_client(ca_certificate: Option<&Path>, timeout: Duration) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout);
+
+    if let Some(path) = ca_certificate {
+        let mut pem = vec!();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut pem))
+            .chain_err(|| format!("Failed to read CA certificate {}", path.to_string_lossy()))?;
+
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/**
+  Writer which reports the amount of bytes that were written through it
+*/
+struct ProgressWriter<'a, W> {
+    inner: W,
+    on_progress: &'a dyn Fn(u64)
+}
+
+impl<'a, W: Write> Write for ProgressWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        (self.on_progress)(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/**
+  Writer which hashes the bytes that are written through it, used to check
+  the signature of responses that are streamed to disk
+*/
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/**
+  A response to a signed request along with the signature of the request,
+  which the signature of the response covers
+*/
+struct SignedResponse {
+    response: reqwest::Response,
+    request_signature: String
+}
+
 pub struct HttpForeignServer {
+    scheme: String,
     url: String,
+    secret: String,
+    client: reqwest::Client,
+    /// The url the foreign server can reach this instance on, if it is known
+    own_url: Option<String>,
 }
 
 impl HttpForeignServer {
-    pub fn new(url: String) -> Self {
-        Self {
+    /**
+      Creates a connection to the flash instance at `url`, which may start
+      with `http://` or `https://`. All requests are signed with `secret`.
+      If `ca_certificate` is specified, it is trusted when connecting over https.
+      Requests that make no progress for `request_timeout` fail
+    */
+    pub fn new(
+        url: String,
+        secret: String,
+        ca_certificate: Option<&Path>,
+        request_timeout: Duration
+    ) -> Result<Self> {
+        let (scheme, url) = split_scheme(&url)?;
+
+        Ok(Self {
+            scheme,
             url,
+            secret,
+            client: build_client(ca_certificate, request_timeout)?,
+            own_url: None,
+        })
+    }
+
+    /**
+      Creates a connection using the CA certificate, request timeout and sync
+      url in `settings`. Requests are signed with `secret` if it is specified,
+      which is used for peers that have their own secret, otherwise with the
+      sync secret in `settings`
+    */
+    pub fn from_settings(url: String, secret: Option<String>, settings: &Settings)
+        -> Result<Self>
+    {
+        match secret.or_else(|| settings.get_sync_secret()) {
+            Some(secret) => {
+                let ca_certificate = settings.get_sync_ca_certificate();
+                let server = Self::new(
+                    url,
+                    secret,
+                    ca_certificate.as_deref(),
+                    settings.get_sync_request_timeout()
+                )?;
+                Ok(Self{own_url: settings.get_sync_url(), ..server})
+            }
+            None => bail!(ErrorKind::SyncSecretMissing)
+        }
+    }
+
+    /**
+      Checks that the foreign server is reachable. `/ping` is not signed so
+      this works even if the secrets don't match
+    */
+    pub fn ping(&self) -> Result<()> {
+        let url = self.construct_url(&[String::from("ping")], &[]);
+
+        let response = self.client.get(&url).send()?;
+        let body = read_response(response)?;
+        if body != b"pong" {
+            bail!(ErrorKind::ForeignHttpError(url));
         }
+        Ok(())
     }
 
     /**
@@ -140,7 +439,158 @@ impl HttpForeignServer {
     fn get_file_sync_url(&self, file_id: i32, action: &str) -> String {
         let file_path = vec!(String::from("sync"), action.to_string());
         let query = vec!((String::from("file_id"), format!("{}", file_id)));
-        construct_url("http", &self.url, &file_path, &query)
+        self.construct_url(&file_path, &query)
+    }
+
+    fn construct_url(&self, path: &[String], query: &[(String, String)]) -> String {
+        construct_url(&self.scheme, &self.url, path, query)
+    }
+
+    /**
+      Returns the query variables which tell the foreign server how to reach
+      this instance. The foreign server falls back to the address the request
+      came from and `own_port` if no url is known
+    */
+    fn identity_query(&self, own_port: u16) -> Vec<(String, String)> {
+        let mut query = vec!((String::from("port"), format!("{}", own_port)));
+        if let Some(ref own_url) = self.own_url {
+            query.push((String::from("url"), own_url.clone()));
+        }
+        query
+    }
+
+    /**
+      Sends a signed request to the foreign server and parses the result as json for `T`
+    */
+    fn send_request<T: serde::de::DeserializeOwned>(&self, full_url: &str, body: &str) -> Result<T> {
+        let bytes = self.send_request_for_bytes(full_url, body)?;
+
+        Ok(serde_json::from_str::<T>(from_utf8(&bytes)?)?)
+    }
+
+    /**
+      Sends a request to the foreign server which is signed with the sync secret
+    */
+    fn send_request_for_bytes(&self, full_url: &str, body: &str) -> Result<Vec<u8>> {
+        self.read_signed_response(self.send_signed_request(full_url, body.as_bytes(), None)?)
+    }
+
+    /**
+      Reads the body of a response, returning an error if the request was not
+      successful or the response was not signed with the secret
+    */
+    fn read_signed_response(&self, signed: SignedResponse) -> Result<Vec<u8>> {
+        let signature = response_signature(&signed.response);
+        let body = read_response(signed.response)?;
+
+        verify_response(
+            &self.secret,
+            &signed.request_signature,
+            &to_hex(&Sha256::digest(&body)),
+            signature.as_deref()
+        )?;
+        Ok(body)
+    }
+
+    /**
+      Downloads the content at `url` to `destination` like
+      `ForeignServer::download_file`, resuming from the bytes that are
+      already in `destination`
+    */
+    fn download(&self, url: &str, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+        let existing_length = fs::metadata(destination).map(|metadata| metadata.len()).unwrap_or(0);
+        let range_start = if existing_length > 0 {Some(existing_length)} else {None};
+
+        let SignedResponse {mut response, request_signature} =
+            self.send_signed_request(url, &[], range_start)?;
+
+        // The amount of bytes in the destination from earlier attempts
+        let (file, kept_length) = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => {
+                (OpenOptions::new().append(true).open(destination)?, existing_length)
+            }
+            // The server sent the whole file
+            reqwest::StatusCode::OK => (File::create(destination)?, 0),
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+                if complete_length(&response) == Some(existing_length) =>
+            {
+                // The whole file was downloaded by an earlier attempt
+                return Ok(());
+            }
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+                // The partial file doesn't match the file on the server, start over
+                fs::remove_file(destination)?;
+                return self.download(url, destination, on_progress);
+            }
+            status => {
+                let mut body = vec!();
+                response.copy_to(&mut body)?;
+                bail!(ErrorKind::WrongHttpStatusCode(status, String::from_utf8_lossy(&body).into()))
+            }
+        };
+
+        let signature = response_signature(&response);
+        let mut writer = HashingWriter {
+            inner: ProgressWriter{inner: file, on_progress},
+            hasher: Sha256::new()
+        };
+        response.copy_to(&mut writer)?;
+
+        let body_hash = to_hex(&writer.hasher.finalize());
+        let verified = verify_response(&self.secret, &request_signature, &body_hash, signature.as_deref());
+        if verified.is_err() {
+            // The bytes from earlier attempts are kept, the content hash of
+            // the complete file is checked once it is downloaded
+            writer.inner.inner.set_len(kept_length)?;
+        }
+        verified
+    }
+
+    /**
+      Sends a signed request without reading the response. If `range_start` is
+      specified, only the content after that many bytes is requested.
+
+      Fails if the foreign server uses a version of the sync protocol which
+      can't verify the signature, see `SIGNED_HOST_VERSION`
+    */
+    fn send_signed_request(&self, full_url: &str, body: &[u8], range_start: Option<u64>)
+        -> Result<SignedResponse>
+    {
+        let url = reqwest::Url::parse(full_url)
+            .chain_err(|| ErrorKind::ForeignHttpError(full_url.to_string()))?;
+
+        let timestamp = Utc::now().timestamp();
+        let signature = sign_request(
+            &self.secret,
+            &SignedContent {
+                method: "GET",
+                host: Some(&signed_host(&url)),
+                path: &canonical_path(url.path(), url.query()),
+                timestamp,
+                body
+            }
+        );
+
+        let mut request = self.client.get(url)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, signature.clone())
+            .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION.to_string())
+            .body(body.to_vec());
+
+        if let Some(start) = range_start {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", start));
+        }
+
+        let response = request.send()?;
+
+        let version = check_protocol_version(
+            response.headers().get(PROTOCOL_VERSION_HEADER).map(|value| value.as_bytes())
+        )?;
+        if version < SIGNED_HOST_VERSION {
+            bail!(ErrorKind::IncompatibleProtocolVersion(PROTOCOL_VERSION, version));
+        }
+
+        Ok(SignedResponse {response, request_signature: signature})
     }
 }
 
@@ -148,44 +598,83 @@ impl HttpForeignServer {
 impl ForeignServer for HttpForeignServer {
     fn get_syncpoints(&self) -> Result<Vec<SyncPoint>> {
         let syncpoint_path = vec!(String::from("sync"), String::from("syncpoints"));
-        let url = construct_url(FOREIGN_SCHEME, &self.url, &syncpoint_path, &[]);
+        let url = self.construct_url(&syncpoint_path, &[]);
 
-        Ok(send_request(&url, "")?)
+        self.send_request(&url, "")
     }
     fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
         let change_path = vec!(String::from("sync"), String::from("changes"));
 
-        let starting_timestamp = match *starting_syncpoint {
-            Some(SyncPoint{last_change}) => last_change.timestamp(),
-            None => 0
+        // All changes are requested if there is no syncpoint
+        let query = match *starting_syncpoint {
+            Some(ref syncpoint) => vec!(
+                (String::from("starting_clock"), format!("{}", syncpoint.clock))
+            ),
+            None => vec!()
         };
 
-        let query = vec!(
-            (String::from("starting_timestamp"), format!("{}", starting_timestamp))
-        );
-
-        let url = construct_url(FOREIGN_SCHEME, &self.url, &change_path, &query);
+        let url = self.construct_url(&change_path, &query);
 
-        Ok(send_request(&url, "")?)
+        self.send_request(&url, "")
     }
     fn get_file_details(&self, id: i32) -> Result<FileDetails> {
         let url = self.get_file_sync_url(id, "file_details");
 
-        send_request::<FileDetails>(&url, "")
+        self.send_request::<FileDetails>(&url, "")
     }
-    fn send_changes(&mut self, changes: &ChangeData, own_port: u16) -> Result<usize> {
+    fn send_changes(&mut self, changes: &ChangeData, own_port: u16, push_id: Option<usize>)
+        -> Result<usize>
+    {
         let path = vec!(String::from("sync"), String::from("apply_changes"));
-        let query = vec!((String::from("port"), format!("{}", own_port)));
-        let url = construct_url(FOREIGN_SCHEME, &self.url, &path, &query);
+        let mut query = self.identity_query(own_port);
+        if let Some(push_id) = push_id {
+            query.push((String::from("push_id"), format!("{}", push_id)));
+        }
+        let url = self.construct_url(&path, &query);
+
+        let body = serde_json::to_string(changes)?;
+
+        self.send_request::<usize>(&url, &body)
+    }
+    fn get_needed_files(&self, changes: &ChangeData, own_port: u16) -> Result<Vec<i32>> {
+        let path = vec!(String::from("sync"), String::from("needed_files"));
+        let query = self.identity_query(own_port);
+        let url = self.construct_url(&path, &query);
 
         let body = serde_json::to_string(changes)?;
 
-        Ok(send_request::<usize>(&url, &body)?)
+        self.send_request(&url, &body)
     }
-    fn get_file(&self, id: i32) -> Result<Vec<u8>> {
+    fn push_file_part(&self, push_id: usize, file_id: i32, part: FilePart, offset: u64, content: &[u8])
+        -> Result<()>
+    {
+        let path = vec!(String::from("sync"), String::from("push"));
+        let mut query = vec!(
+            (String::from("push_id"), format!("{}", push_id)),
+            (String::from("file_id"), format!("{}", file_id)),
+            (String::from("offset"), format!("{}", offset))
+        );
+        query.append(&mut part.query());
+        let url = self.construct_url(&path, &query);
+
+        self.read_signed_response(self.send_signed_request(&url, content, None)?)?;
+        Ok(())
+    }
+    fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
         let url = self.get_file_sync_url(id, "file");
+        self.download(&url, destination, on_progress)
+    }
+    fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+        -> Result<()>
+    {
+        let path = vec!(String::from("sync"), String::from("sidecar"));
+        let query = vec!(
+            (String::from("file_id"), format!("{}", id)),
+            (String::from("index"), format!("{}", index))
+        );
+        let url = self.construct_url(&path, &query);
 
-        Ok(send_request_for_bytes(&url, "")?)
+        self.download(&url, destination, on_progress)
     }
     /**
       Gets the thumbnail of the file with the specified ID. If the content returned
@@ -194,19 +683,32 @@ impl ForeignServer for HttpForeignServer {
     fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
         let url = self.get_file_sync_url(id, "thumbnail");
 
-        let content = send_request_for_bytes(&url, "")?;
+        let content = self.send_request_for_bytes(&url, "")?;
         match content.len() {
             0 => Ok(None),
             _ => Ok(Some(content))
         }
     }
 
-    fn get_sync_status(&self, job_id: usize) -> Result<SyncStatus> {
-        let path = vec!(String::from("sync"), String::from("progress"));
+    fn get_sync_updates(&self, job_id: usize) -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>> {
+        let path = vec!(
+            String::from("sync"),
+            String::from("progress"),
+            String::from("events")
+        );
         let query = vec!((String::from("job_id"), format!("{}", job_id)));
-        let url = construct_url(FOREIGN_SCHEME, &self.url, &path, &query);
+        let url = self.construct_url(&path, &query);
+
+        // The events only report the progress of the job, so they are not signed
+        let mut response = self.send_signed_request(&url, &[], None)?.response;
+        let status = response.status();
+        if status != reqwest::StatusCode::OK {
+            let mut body = vec!();
+            response.copy_to(&mut body)?;
+            bail!(ErrorKind::WrongHttpStatusCode(status, String::from_utf8_lossy(&body).into()))
+        }
 
-        send_request(&url, "")
+        Ok(Box::new(read_events(BufReader::new(response))))
     }
 
     fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()>{
@@ -216,15 +718,44 @@ impl ForeignServer for HttpForeignServer {
             String::from("add")
         );
 
-        let url = construct_url(FOREIGN_SCHEME, &self.url, &path, &vec!());
+        // The url is advertised so the foreign server knows who the syncpoint
+        // is shared with
+        let query = self.own_url.iter()
+            .map(|own_url| (String::from("url"), own_url.clone()))
+            .collect::<Vec<_>>();
+        let url = self.construct_url(&path, &query);
 
         let encoded = serde_json::to_string(syncpoint)
             .chain_err(|| "Failed to encode syncpoint")?;
-        send_request_for_bytes(&url, &encoded)
+        self.send_request_for_bytes(&url, &encoded)
             .chain_err(|| "Foreign failed to apply syncpoint")?;
 
         Ok(())
     }
+
+    fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+        let path = vec!(String::from("sync"), String::from("digest"));
+        let query = vec!((String::from("prefix"), prefix.to_string()));
+        let url = self.construct_url(&path, &query);
+
+        self.send_request(&url, "")
+    }
+
+    fn cancel_job(&self, job_id: usize) -> Result<()> {
+        let path = vec!(String::from("sync"), String::from("jobs"), String::from("cancel"));
+        let query = vec!((String::from("job_id"), format!("{}", job_id)));
+        let url = self.construct_url(&path, &query);
+
+        self.send_request_for_bytes(&url, "")?;
+        Ok(())
+    }
+
+    fn handshake(&self) -> Result<Handshake> {
+        let path = vec!(String::from("sync"), String::from("handshake"));
+        let url = self.construct_url(&path, &[]);
+
+        self.send_request(&url, "")
+    }
 }
 
 
@@ -274,6 +805,64 @@ mod http_tests {
         assert_eq!(url, "https://httpbin.org/path/to?var=val&test=something");
     }
 
+    #[test]
+    fn url_schemes_are_split() {
+        assert_eq!(
+            split_scheme("localhost:3000").unwrap(),
+            ("http".to_string(), "localhost:3000".to_string())
+        );
+        assert_eq!(
+            split_scheme("HTTPS://example.com/").unwrap(),
+            ("https".to_string(), "example.com".to_string())
+        );
+        assert_matches!(split_scheme("ftp://example.com"), Err(_));
+    }
+
+    #[test]
+    fn advertised_urls_are_sent_along_with_the_port() {
+        let server = HttpForeignServer::new(
+            "localhost:3000".into(),
+            "secret".into(),
+            None,
+            Duration::from_secs(30)
+        ).unwrap();
+        assert_eq!(server.identity_query(3001), vec!(("port".to_string(), "3001".to_string())));
+
+        let server = HttpForeignServer{own_url: Some("https://flash.example.com".into()), ..server};
+        let url = server.construct_url(&[String::from("sync")], &server.identity_query(3001));
+        let parsed = reqwest::Url::parse(&url).unwrap();
+        assert_eq!(
+            parsed.query_pairs().into_owned().collect::<Vec<_>>(),
+            vec!(
+                ("port".to_string(), "3001".to_string()),
+                ("url".to_string(), "https://flash.example.com".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn ca_certificates_are_loaded() {
+        let certificate = ::std::path::PathBuf::from("test/files/sync_test_ca.pem");
+        assert!(
+            HttpForeignServer::new(
+                "https://localhost:3443".into(),
+                "secret".into(),
+                Some(&certificate),
+                Duration::from_secs(30)
+            ).is_ok()
+        );
+
+        let missing = ::std::path::PathBuf::from("test/files/missing.pem");
+        assert!(
+            HttpForeignServer::new(
+                "https://localhost:3443".into(),
+                "secret".into(),
+                Some(&missing),
+                Duration::from_secs(30)
+            ).is_err()
+        );
+    }
+
     #[test]
     fn constructed_urls_are_valid() {
         let url = construct_url(
@@ -303,6 +892,9 @@ mod sync_integration {
     use sync_progress::SyncStatus;
     use sync_progress::SyncUpdate;
 
+    /// The sync secret that test/run_sync_test_server.sh starts the servers with
+    const SYNC_SECRET: &str = "flash_sync_test_secret";
+
     /**
       Runs the foreign server by launching the test script and kills it when
       it goes out of scope. This ensures that the server does not keep running
@@ -458,27 +1050,22 @@ mod sync_integration {
     }
 
     fn sync_with_foreign(url: &str, foreign_url: &str) -> usize {
-        let request_url = construct_url(
-                "http",
-                url,
-                &vec!("sync".into(), "sync".into()),
-                &vec!(("foreign_url".into(), foreign_url.into()))
+        // Syncs can only be started with requests signed with the sync secret
+        let server = HttpForeignServer::new(url.into(), SYNC_SECRET.into(), None, Duration::from_secs(30))
+            .expect("Failed to create the foreign server");
+        let request_url = server.construct_url(
+                &["sync".into(), "sync".into()],
+                &[("foreign_url".into(), foreign_url.into())]
             );
 
         println!("{}", request_url);
 
-        send_request::<usize>(&request_url, "").expect("Sync failed")
+        server.send_request::<usize>(&request_url, "").expect("Sync failed")
     }
 
     fn get_syncpoints(url: &str) -> Result<Vec<SyncPoint>> {
-        let request_url = construct_url(
-            "http",
-            url,
-            &vec!("sync".into(), "syncpoints".into()),
-            &vec!()
-        );
-
-        send_request(&request_url, "")
+        HttpForeignServer::new(url.into(), SYNC_SECRET.into(), None, Duration::from_secs(30))?
+            .get_syncpoints()
     }
 
     fn check_job_status(initiating_url: &str, initiating_job_id: usize, foreign_url: &str)
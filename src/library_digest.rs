@@ -0,0 +1,437 @@
+use iron::prelude::*;
+use iron::status;
+use persistent;
+
+use serde_json;
+use sha2::{Sha256, Digest};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use file_database::FileDatabase;
+use foreign_server::{ForeignServer, FileDetails};
+use peers;
+use request_helpers::{
+    get_get_variable,
+    get_optional_get_variable,
+    to_json_with_result,
+    setup_db_connection
+};
+use settings::Settings;
+use sync_auth::{authenticate_request, authenticate_admin_request, signed_response};
+use util::to_hex;
+
+use error::{Result, ErrorKind, ResultExt};
+
+/// The amount of hex digits used for file ids, which is also the depth of
+/// the digest tree
+const ID_DIGITS: usize = 8;
+
+/// Buckets with at most this many files list the digests of all their files
+/// so that they can be compared without walking further down the tree
+const MAX_LISTED_FILES: usize = 64;
+
+/**
+  Digest of the id, content hash, tags and creation date of a file
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileDigest {
+    pub id: i32,
+    pub digest: String,
+    /// The content hash of the file has not been computed yet, so the digest
+    /// differs from that of the same file on instances which have it
+    #[serde(default)]
+    pub unhashed: bool
+}
+
+impl FileDigest {
+    pub fn new(id: i32, details: &FileDetails) -> Self {
+        let mut tags = details.tags.clone();
+        tags.sort();
+
+        // Encoded as json to keep tags containing separators apart
+        let content = serde_json::to_string(
+            &(id, &details.content_hash, tags, details.timestamp.timestamp())
+        ).expect("Failed to encode file digest");
+
+        let mut hasher = Sha256::new();
+        hasher.update(content.as_bytes());
+
+        Self {
+            id,
+            digest: to_hex(&hasher.finalize()),
+            unhashed: details.content_hash.is_none()
+        }
+    }
+}
+
+/**
+  Digest of the files whose hex encoded ids start with `prefix`
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BucketDigest {
+    pub prefix: String,
+    pub digest: String
+}
+
+/**
+  A node in the digest tree of a library. The files are put in buckets by the
+  hex digits of their ids, and the digest of each bucket is the hash of the
+  digests of the buckets below it
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryDigest {
+    pub prefix: String,
+    /// `None` if there are no files in the bucket
+    pub digest: Option<String>,
+    /// The buckets one digit further down that contain files
+    pub buckets: Vec<BucketDigest>,
+    /// The digests of the files if there are at most `MAX_LISTED_FILES`
+    pub files: Option<Vec<FileDigest>>
+}
+
+/**
+  How a file differs between this instance and a foreign one
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Divergence {
+    OnlyLocal,
+    OnlyForeign,
+    /// The content, tags or creation date differ
+    Different,
+    /// The digests differ but one of the sides has not computed the content
+    /// hash of the file yet, which `/thumbnails/regenerate` does
+    NotHashed
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DivergingFile {
+    pub id: i32,
+    pub divergence: Divergence
+}
+
+/**
+  Returns the hex encoded id of a file. Ids are random so about half of them
+  are negative, those are encoded as their unsigned bits and end up in the
+  buckets from `8` to `f`
+*/
+fn hex_id(id: i32) -> String {
+    format!("{:0width$x}", id as u32, width = ID_DIGITS)
+}
+
+pub fn has_prefix(id: i32, prefix: &str) -> bool {
+    hex_id(id).starts_with(prefix)
+}
+
+/**
+  Splits files sorted by id into buckets by their digit at `depth`
+*/
+fn split_buckets(files: &[FileDigest], depth: usize) -> Vec<(String, &[FileDigest])> {
+    let mut buckets = vec!();
+    let mut start = 0;
+    while start < files.len() {
+        let prefix = hex_id(files[start].id)[..depth + 1].to_string();
+        let end = start + files[start..].iter()
+            .take_while(|file| has_prefix(file.id, &prefix))
+            .count();
+
+        buckets.push((prefix, &files[start..end]));
+        start = end;
+    }
+    buckets
+}
+
+fn tree_digest(files: &[FileDigest], depth: usize) -> String {
+    // There is only one file with a complete id
+    if depth == ID_DIGITS {
+        return files[0].digest.clone();
+    }
+
+    let mut hasher = Sha256::new();
+    for (prefix, bucket) in split_buckets(files, depth) {
+        hasher.update(prefix.as_bytes());
+        hasher.update(tree_digest(bucket, depth + 1).as_bytes());
+    }
+    to_hex(&hasher.finalize())
+}
+
+/**
+  Builds the digest of the bucket `prefix` from the digests of the files in it
+  which must be sorted by their unsigned ids, see `hex_id`
+*/
+pub fn bucket_digest(prefix: &str, files: &[FileDigest]) -> LibraryDigest {
+    let depth = prefix.len();
+
+    let buckets = if depth < ID_DIGITS {
+        split_buckets(files, depth).into_iter()
+            .map(|(prefix, bucket)| BucketDigest {
+                digest: tree_digest(bucket, depth + 1),
+                prefix
+            })
+            .collect()
+    }
+    else {
+        vec!()
+    };
+
+    LibraryDigest {
+        prefix: prefix.to_string(),
+        digest: if files.is_empty() {None} else {Some(tree_digest(files, depth))},
+        buckets,
+        files: if files.len() <= MAX_LISTED_FILES {Some(files.to_vec())} else {None}
+    }
+}
+
+/**
+  Returns the digest of the files in this library whose ids start with `prefix`.
+  Content hashes are not computed here since that reads whole files, files
+  without one are marked as `unhashed`
+*/
+pub fn library_digest(fdb: &FileDatabase, prefix: &str) -> Result<LibraryDigest> {
+    let is_hex = prefix.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase());
+    if prefix.len() > ID_DIGITS || !is_hex {
+        bail!(ErrorKind::InvalidDigestPrefix(prefix.to_string()));
+    }
+
+    let shift = 4 * (ID_DIGITS - prefix.len());
+    let first = u64::from_str_radix(prefix, 16).unwrap_or(0) << shift;
+    let last = first + (1 << shift) - 1;
+
+    // The ids of a bucket are consecutive as unsigned numbers, which are two
+    // ranges in the database if the bucket contains both signed halves
+    let halves = [
+        (0, u64::from(i32::MAX as u32)),
+        (u64::from(i32::MIN as u32), u64::from(u32::MAX))
+    ];
+
+    let mut digests = vec!();
+    for &(start, end) in &halves {
+        if first > end || last < start {
+            continue;
+        }
+
+        let range_first = first.max(start) as u32 as i32;
+        let range_last = last.min(end) as u32 as i32;
+        for file in fdb.get_files_in_id_range(range_first, range_last)? {
+            digests.push(FileDigest::new(file.id, &FileDetails::from(&file)));
+        }
+    }
+
+    Ok(bucket_digest(prefix, &digests))
+}
+
+/**
+  Finds the files which differ between this instance and `foreign_server`.
+  Only the buckets whose digests differ are compared further
+*/
+pub fn find_diverging_files(fdb: &FileDatabase, foreign_server: &dyn ForeignServer)
+    -> Result<Vec<DivergingFile>>
+{
+    let mut diverging = vec!();
+    compare_buckets(fdb, foreign_server, "", &mut diverging)?;
+    Ok(diverging)
+}
+
+fn compare_buckets(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    prefix: &str,
+    diverging: &mut Vec<DivergingFile>
+) -> Result<()> {
+    let local = library_digest(fdb, prefix)?;
+    let foreign = foreign_server.get_library_digest(prefix)
+        .chain_err(|| "Failed to get library digest from foreign server")?;
+
+    if local.digest == foreign.digest {
+        return Ok(());
+    }
+
+    if let (Some(local_files), Some(foreign_files)) = (&local.files, &foreign.files) {
+        diverging.append(&mut compare_files(local_files, foreign_files));
+        return Ok(());
+    }
+
+    let local_buckets = bucket_map(&local.buckets);
+    let foreign_buckets = bucket_map(&foreign.buckets);
+    let prefixes = local_buckets.keys().chain(foreign_buckets.keys()).collect::<BTreeSet<_>>();
+    for prefix in prefixes {
+        if local_buckets.get(prefix) != foreign_buckets.get(prefix) {
+            compare_buckets(fdb, foreign_server, prefix, diverging)?;
+        }
+    }
+    Ok(())
+}
+
+fn bucket_map(buckets: &[BucketDigest]) -> BTreeMap<&str, &str> {
+    buckets.iter()
+        .map(|bucket| (bucket.prefix.as_str(), bucket.digest.as_str()))
+        .collect()
+}
+
+fn compare_files(local: &[FileDigest], foreign: &[FileDigest]) -> Vec<DivergingFile> {
+    let local = local.iter().map(|file| (file.id, file)).collect::<BTreeMap<_, _>>();
+    let foreign = foreign.iter().map(|file| (file.id, file)).collect::<BTreeMap<_, _>>();
+
+    let ids = local.keys().chain(foreign.keys()).cloned().collect::<BTreeSet<_>>();
+    ids.into_iter()
+        .filter_map(|id| {
+            let divergence = match (local.get(&id), foreign.get(&id)) {
+                (Some(_), None) => Divergence::OnlyLocal,
+                (None, Some(_)) => Divergence::OnlyForeign,
+                (Some(local), Some(foreign)) if local.digest != foreign.digest => {
+                    if local.unhashed || foreign.unhashed {
+                        Divergence::NotHashed
+                    }
+                    else {
+                        Divergence::Different
+                    }
+                }
+                _ => return None
+            };
+            Some(DivergingFile{id, divergence})
+        })
+        .collect()
+}
+
+
+pub fn digest_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
+    let prefix = get_optional_get_variable(request, "prefix")?.unwrap_or_default();
+
+    let fdb = setup_db_connection(request)?;
+
+    let digest = library_digest(&fdb, &prefix)?;
+
+    Ok(signed_response(request, to_json_with_result(digest)?))
+}
+
+pub fn verify_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+
+    let foreign_url = get_get_variable(request, "foreign_url")?;
+
+    let fdb = setup_db_connection(request)?;
+    let foreign_server = peers::connect(&fdb, foreign_url, &settings)?;
+
+    let diverging = find_diverging_files(&fdb, &foreign_server)?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(diverging)?)))
+}
+
+
+#[cfg(test)]
+mod library_digest_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    use changelog::ChangeCreationPolicy;
+
+    fn digest(id: i32, tag: &str) -> FileDigest {
+        FileDigest::new(id, &FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2018, 1, 1).and_hms(0, 0, 0),
+            sidecars: vec!(),
+            content_hash: Some("abc".into()),
+            size: None,
+            tags: vec!(tag.into()),
+            original_stored: true
+        })
+    }
+
+    #[test]
+    fn buckets_are_split_by_id_digits() {
+        let files = vec!(digest(1, "a"), digest(2, "a"), digest(0x10, "a"), digest(0x100, "a"));
+
+        let root = bucket_digest("", &files);
+        assert_eq!(root.buckets.len(), 1);
+        assert_eq!(root.buckets[0].prefix, "0");
+
+        let bucket = bucket_digest("00000", &files);
+        let prefixes = bucket.buckets.iter().map(|b| b.prefix.as_str()).collect::<Vec<_>>();
+        assert_eq!(prefixes, vec!("000000", "000001"));
+
+        let bucket = bucket_digest("000000", &files[..3]);
+        let prefixes = bucket.buckets.iter().map(|b| b.prefix.as_str()).collect::<Vec<_>>();
+        assert_eq!(prefixes, vec!("0000000", "0000001"));
+
+        let leaf = bucket_digest("00000001", &files[..1]);
+        assert_eq!(leaf.digest, Some(files[0].digest.clone()));
+        assert!(leaf.buckets.is_empty());
+
+        assert_eq!(bucket_digest("1", &[]).digest, None);
+    }
+
+    #[test]
+    fn digests_change_with_the_files() {
+        let files = vec!(digest(1, "a"), digest(2, "a"));
+        let changed = vec!(digest(1, "a"), digest(2, "b"));
+
+        assert_ne!(bucket_digest("", &files).digest, bucket_digest("", &changed).digest);
+        assert_ne!(bucket_digest("", &files).digest, bucket_digest("", &files[..1]).digest);
+        assert_eq!(bucket_digest("", &files).digest, bucket_digest("", &files.clone()).digest);
+    }
+
+    #[test]
+    fn large_buckets_do_not_list_files() {
+        let files = (0..MAX_LISTED_FILES as i32 + 1).map(|id| digest(id, "a")).collect::<Vec<_>>();
+
+        assert_eq!(bucket_digest("", &files).files, None);
+        assert_eq!(bucket_digest("", &files[1..]).files.map(|f| f.len()), Some(MAX_LISTED_FILES));
+    }
+
+    db_test!(negative_ids_are_in_the_upper_buckets(fdb) {
+        for id in &[3, -5, i32::MIN, i32::MAX] {
+            fdb.add_new_file(*id, &format!("{}.jpg", id), None, &[], 0, &ChangeCreationPolicy::No);
+            fdb.set_content_hash(*id, "abc").unwrap();
+        }
+
+        let ids = |prefix: &str| {
+            library_digest(fdb, prefix).unwrap().files.unwrap().iter()
+                .map(|file| file.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(""), vec!(3, i32::MAX, i32::MIN, -5));
+        assert_eq!(ids("7"), vec!(i32::MAX));
+        assert_eq!(ids("8"), vec!(i32::MIN));
+        assert_eq!(ids("f"), vec!(-5));
+        assert_eq!(ids("fffffffb"), vec!(-5));
+
+        let root = library_digest(fdb, "").unwrap();
+        let prefixes = root.buckets.iter().map(|b| b.prefix.as_str()).collect::<Vec<_>>();
+        assert_eq!(prefixes, vec!("0", "7", "8", "f"));
+    });
+
+    #[test]
+    fn file_differences_are_found() {
+        let local = vec!(digest(1, "a"), digest(2, "a"), digest(3, "a"));
+        let foreign = vec!(digest(2, "a"), digest(3, "b"), digest(4, "a"));
+
+        assert_eq!(
+            compare_files(&local, &foreign),
+            vec!(
+                DivergingFile{id: 1, divergence: Divergence::OnlyLocal},
+                DivergingFile{id: 3, divergence: Divergence::Different},
+                DivergingFile{id: 4, divergence: Divergence::OnlyForeign},
+            )
+        );
+    }
+
+    db_test!(files_without_content_hash_are_not_hashed(fdb) {
+        // The content is missing, so hashing it would fail
+        fdb.add_new_file(1, "1.jpg", None, &[], 0, &ChangeCreationPolicy::No);
+
+        let local = library_digest(fdb, "").unwrap().files.unwrap();
+        assert!(local[0].unhashed);
+        assert_eq!(fdb.get_file_with_id(1).unwrap().content_hash, None);
+
+        let mut foreign = digest(1, "a");
+        foreign.digest = "other".into();
+        assert_eq!(
+            compare_files(&local, &[foreign]),
+            vec!(DivergingFile{id: 1, divergence: Divergence::NotHashed})
+        );
+    });
+}
@@ -0,0 +1,329 @@
+use iron::prelude::*;
+use iron::status;
+use persistent;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use changelog::{Change, SyncPoint};
+use file_database::FileDatabase;
+use foreign_server::{ForeignServer, ChangeData, FileDetails};
+use library_digest::{self, LibraryDigest};
+use request_helpers::to_json_with_result;
+use settings::Settings;
+use sync::{apply_changes_with_filter, sync_with_foreign, ApplyOptions, SyncOptions, TransferMode};
+use sync_handlers::{
+    handle_file_request,
+    handle_sidecar_request,
+    handle_thumbnail_request,
+    handle_file_detail_request
+};
+use sync_auth::authenticate_admin_request;
+use sync_handshake::{Handshake, own_handshake};
+use sync_jobs::{record_job, JobKind};
+use sync_progress as sp;
+use sync_push::FilePart;
+
+use error::{Result, ErrorKind, describe_chain};
+
+/**
+  A foreign server backed by another database and storage path in the same
+  process, for example a backup library on a second disk. Nothing is sent
+  over HTTP, and changes sent to it are applied before `send_changes` returns
+*/
+pub struct LocalForeignServer {
+    fdb: Mutex<FileDatabase>,
+    /// The database of the instance that syncs with this server. The files of
+    /// the changes it sends are copied from its storage path
+    origin: Option<Mutex<FileDatabase>>,
+    fetch_workers: usize,
+    /// The final update of every job that applied changes
+    jobs: Mutex<HashMap<usize, sp::SyncUpdate>>
+}
+
+impl LocalForeignServer {
+    pub fn new(fdb: FileDatabase) -> Self {
+        Self {
+            fdb: Mutex::new(fdb),
+            origin: None,
+            fetch_workers: 1,
+            jobs: Mutex::new(HashMap::new())
+        }
+    }
+
+    /**
+      Allows changes to be sent by the instance using `origin`, fetching the
+      files of the changes with `fetch_workers` threads
+    */
+    pub fn receiving_from(mut self, origin: FileDatabase, fetch_workers: usize) -> Self {
+        self.origin = Some(Mutex::new(origin));
+        self.fetch_workers = fetch_workers;
+        self
+    }
+
+    /**
+      Applies `changes` to this server, fetching files from `origin`
+    */
+    fn apply_changes(&self, origin: &LocalForeignServer, changes: &ChangeData, job_id: usize)
+        -> Result<usize>
+    {
+        // Nobody follows the progress of the job so the updates are dropped
+        let (progress_tx, progress_rx) = sync_channel(32);
+        thread::spawn(move || for _ in progress_rx {});
+
+        let fdb = self.fdb.lock().unwrap();
+        apply_changes_with_filter(
+            &fdb,
+            origin,
+            &changes.changes,
+            &changes.removed_files,
+            &ApplyOptions {receive_clock: changes.clock, ..ApplyOptions::unfiltered(self.fetch_workers)},
+            &(job_id, progress_tx)
+        )
+    }
+}
+
+impl ForeignServer for LocalForeignServer {
+    fn get_syncpoints(&self) -> Result<Vec<SyncPoint>> {
+        self.fdb.lock().unwrap().get_syncpoints()
+    }
+    fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
+        let fdb = self.fdb.lock().unwrap();
+        match *starting_syncpoint {
+            Some(ref syncpoint) => fdb.get_changes_after_clock(syncpoint.clock),
+            None => fdb.get_all_changes()
+        }
+    }
+    fn get_file_details(&self, id: i32) -> Result<FileDetails> {
+        handle_file_detail_request(&self.fdb.lock().unwrap(), id)
+    }
+    fn send_changes(&mut self, changes: &ChangeData, _own_port: u16, _push_id: Option<usize>)
+        -> Result<usize>
+    {
+        let origin = match self.origin {
+            Some(ref origin) => LocalForeignServer::new(origin.lock().unwrap().reconnect()?),
+            None => bail!("Changes can't be sent to a local server without an origin")
+        };
+
+        let job_id = sp::new_job_id();
+        let update = match self.apply_changes(&origin, changes, job_id) {
+            Ok(_) => sp::SyncUpdate::Done,
+            Err(e) => sp::SyncUpdate::Error(describe_chain(&e))
+        };
+        self.jobs.lock().unwrap().insert(job_id, update);
+
+        Ok(job_id)
+    }
+    fn get_needed_files(&self, _changes: &ChangeData, _own_port: u16) -> Result<Vec<i32>> {
+        bail!("Local servers copy files from their origin, files can't be pushed to them")
+    }
+    fn push_file_part(&self, _push_id: usize, _file_id: i32, _part: FilePart, _offset: u64, _content: &[u8])
+        -> Result<()>
+    {
+        bail!("Local servers copy files from their origin, files can't be pushed to them")
+    }
+    fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+        let path = handle_file_request(&self.fdb.lock().unwrap(), id)?;
+        on_progress(fs::copy(path, destination)?);
+        Ok(())
+    }
+    fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+        -> Result<()>
+    {
+        let path = handle_sidecar_request(&self.fdb.lock().unwrap(), id, index)?;
+        on_progress(fs::copy(path, destination)?);
+        Ok(())
+    }
+    fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
+        handle_thumbnail_request(&self.fdb.lock().unwrap(), id)
+    }
+    fn get_sync_updates(&self, job_id: usize) -> Result<Box<dyn Iterator<Item=Result<sp::SyncUpdate>>>> {
+        match self.jobs.lock().unwrap().get(&job_id) {
+            Some(update) => Ok(Box::new(vec!(Ok(update.clone())).into_iter())),
+            None => bail!(ErrorKind::NoSuchJobId(job_id))
+        }
+    }
+    fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()> {
+        self.fdb.lock().unwrap().add_syncpoint(syncpoint)
+    }
+    fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+        library_digest::library_digest(&self.fdb.lock().unwrap(), prefix)
+    }
+    fn cancel_job(&self, _job_id: usize) -> Result<()> {
+        // Changes are applied before `send_changes` returns so no job is ever running
+        Ok(())
+    }
+    fn handshake(&self) -> Result<Handshake> {
+        own_handshake(&self.fdb.lock().unwrap())
+    }
+}
+
+
+/**
+  Syncs this instance with its backup library in the background. Returns the
+  id of the job
+*/
+fn start_backup(settings: Settings, progress_tx: &sp::TxType) -> Result<usize> {
+    let (backup_url, backup_path) = match settings.get_backup_library() {
+        Some(library) => library,
+        None => bail!(ErrorKind::BackupLibraryMissing)
+    };
+
+    let job_id = sp::new_job_id();
+
+    let progress_tx = progress_tx.clone();
+    progress_tx.send((job_id, sp::SyncUpdate::Started))
+        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
+    thread::spawn(move || {
+        let fdb = FileDatabase::new(&settings.database_url, settings.get_file_storage_path());
+
+        let result = fdb.and_then(|fdb| {
+            let peer = backup_path.to_string_lossy().to_string();
+            let history_days = settings.get_sync_history_days();
+            let limits = settings.get_job_limits();
+            record_job(&fdb, job_id, JobKind::Backup, &peer, history_days, &limits, || {
+                let backup = FileDatabase::new(&backup_url, backup_path.clone())?;
+                let mut foreign = LocalForeignServer::new(backup)
+                    .receiving_from(fdb.reconnect()?, settings.get_sync_workers());
+
+                let options = SyncOptions {
+                    transfer_mode: TransferMode::Callback(settings.get_port()),
+                    conflict_policies: &settings.get_conflict_policies(),
+                    apply: ApplyOptions::unfiltered(settings.get_sync_workers())
+                };
+                sync_with_foreign(&fdb, &mut foreign, &options, &(job_id, progress_tx.clone()))
+            })
+        });
+
+        if let Err(e) = result {
+            progress_tx.send((job_id, sp::SyncUpdate::Error(describe_chain(&e))))
+                .expect("Failed to send error from backup job
+                        to sync progress manager. Did it crash?");
+        }
+    });
+
+    Ok(job_id)
+}
+
+pub fn backup_request_handler(request: &mut Request, progress_tx: &sp::TxType)
+    -> IronResult<Response>
+{
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+
+    let job_id = start_backup((*settings).clone(), progress_tx)?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(job_id)?)))
+}
+
+
+#[cfg(test)]
+mod local_foreign_server_tests {
+    use super::*;
+
+    use changelog::ChangeCreationPolicy;
+    use conflicts::ConflictPolicies;
+    use file_database::db_test_helpers;
+    use file_database::db_test_helpers::{add_stored_file, year};
+    use file_handler::remove_file;
+    use sync_jobs::TransferCounts;
+
+    fn file_states(fdb: &FileDatabase) -> Vec<(i32, Vec<String>, Vec<u8>)> {
+        let mut files = db_test_helpers::get_files_with_tags(fdb, vec!(), vec!()).into_iter()
+            .map(|file| {
+                let mut tags = file.tags;
+                tags.sort();
+                let content = fs::read(fdb.get_file_save_path().join(file.filename)).unwrap();
+                (file.id, tags, content)
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    fn sync(fdb: &FileDatabase, foreign: &FileDatabase) -> Result<TransferCounts> {
+        let mut server = LocalForeignServer::new(foreign.reconnect()?)
+            .receiving_from(fdb.reconnect()?, 2);
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let options = SyncOptions {
+            transfer_mode: TransferMode::Callback(0),
+            conflict_policies: &ConflictPolicies::default(),
+            apply: ApplyOptions::unfiltered(2)
+        };
+        sync_with_foreign(fdb, &mut server, &options, &(0, tx))
+    }
+
+    #[test]
+    fn two_databases_are_synced_in_process() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let foreign = db_test_helpers::get_foreign_database();
+        let foreign = foreign.lock().unwrap();
+        fdb.reset();
+        foreign.reset();
+
+        add_stored_file(&fdb, 50, &["local".into()], year(2018));
+        add_stored_file(&foreign, 51, &["foreign".into()], year(2018));
+
+        let counts = sync(&fdb, &foreign).unwrap();
+        assert_eq!(counts.files_sent, 1);
+        assert_eq!(counts.files_received, 1);
+
+        let expected = vec!(
+            (50, mapvec!(String::from: "local"), vec!(1, 2, 3)),
+            (51, mapvec!(String::from: "foreign"), vec!(1, 2, 3)),
+        );
+        assert_eq!(file_states(&fdb), expected);
+        assert_eq!(file_states(&foreign), expected);
+        assert_eq!(fdb.get_syncpoints().unwrap(), foreign.get_syncpoints().unwrap());
+
+        // Only the changes since the last sync are sent
+        let file = foreign.get_file_with_id(50).unwrap();
+        foreign.change_file_tags(
+            &file,
+            &["local".into(), "changed".into()],
+            &ChangeCreationPolicy::Yes(year(2019))
+        ).unwrap();
+
+        let counts = sync(&fdb, &foreign).unwrap();
+        assert_eq!(counts, TransferCounts{ changes_received: 1, .. TransferCounts::default() });
+        assert_eq!(
+            fdb.get_file_with_id(50).unwrap().tags,
+            mapvec!(String::from: "local", "changed")
+        );
+
+        for id in &[50, 51] {
+            remove_file(*id, &fdb, &ChangeCreationPolicy::No).unwrap();
+            remove_file(*id, &foreign, &ChangeCreationPolicy::No).unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_changes_fail_the_sync() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let foreign = db_test_helpers::get_foreign_database();
+        let foreign = foreign.lock().unwrap();
+        fdb.reset();
+        foreign.reset();
+
+        // The original can't be copied to the foreign server
+        add_stored_file(&fdb, 52, &[], year(2018));
+        fs::remove_file(fdb.get_file_save_path().join("52.jpg")).unwrap();
+
+        let error = describe_chain(&sync(&fdb, &foreign).unwrap_err());
+        assert!(error.contains("The foreign server failed to apply the changes"), "{}", error);
+        assert_eq!(fdb.get_syncpoints().unwrap(), vec!());
+        assert_eq!(foreign.get_syncpoints().unwrap(), vec!());
+
+        remove_file(52, &fdb, &ChangeCreationPolicy::No).ok();
+        remove_file(52, &foreign, &ChangeCreationPolicy::No).ok();
+    }
+}
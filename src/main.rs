@@ -14,6 +14,8 @@ extern crate regex;
 #[macro_use]
 extern crate error_chain;
 extern crate itertools;
+extern crate hmac;
+extern crate sha2;
 extern crate rand;
 extern crate reqwest;
 
@@ -65,6 +67,21 @@ mod file_handler;
 mod byte_source;
 mod foreign_server;
 mod misc_handlers;
+mod thumbnail_job;
+mod duplicates;
+mod sync_auth;
+mod sync_handshake;
+mod conflicts;
+mod sync_filters;
+mod peers;
+mod sync_jobs;
+mod sync_control;
+mod sync_push;
+mod originals;
+mod compaction;
+mod library_digest;
+mod bundles;
+mod local_foreign_server;
 
 mod fix_timestamps;
 mod db_fixes;
@@ -144,6 +161,11 @@ fn main() {
             &settings.database_url,
             settings.get_file_storage_path()
         ).unwrap();
+        // Jobs that were running when the server stopped will never finish
+        db.fail_running_sync_jobs(chrono::Utc::now().naive_utc())
+            .expect("Failed to update interrupted sync jobs");
+        sync_push::remove_stale_pushes(&settings.get_file_storage_path(), sync_push::PUSH_MAX_AGE)
+            .expect("Failed to remove stale pushes");
         persistent_file_list::read_file_list_list(&file_list_save_path, &db).unwrap()
     };
 
@@ -154,8 +176,13 @@ fn main() {
     let (sync_tx, sync_rx, sync_storage) = sync_progress::setup_progress_datastructures();
     sync_progress::run_sync_tracking_thread(sync_rx, sync_storage.clone());
 
+    let thumbnail_job_storage = thumbnail_job::setup_storage();
+    let thumbnail_job_storage1 = thumbnail_job_storage.clone();
+
     let port = settings.get_port();
 
+    peers::run_sync_scheduler(settings.clone(), port, sync_tx.clone());
+
     let mut mount = Mount::new();
 
     let sync_tx1 = sync_tx.clone();
@@ -165,14 +192,52 @@ fn main() {
     mount.mount("/list", file_request_handlers::file_list_request_handler);
     mount.mount("/search", search_handler::handle_file_search);
     mount.mount("sync/sync", sync_handler);
+    mount.mount("sync/preview", sync_handlers::preview_handler);
+    mount.mount("sync/conflicts", conflicts::conflicts_request_handler);
+    mount.mount("sync/conflicts/override", conflicts::override_request_handler);
+    mount.mount("sync/filters", sync_filters::filters_request_handler);
+    mount.mount("sync/filters/set", sync_filters::set_filter_handler);
+    mount.mount("sync/filters/remove", sync_filters::remove_filter_handler);
+    mount.mount("sync/peers", peers::peers_request_handler);
+    mount.mount("sync/peers/add", peers::add_peer_handler);
+    mount.mount("sync/peers/update", peers::update_peer_handler);
+    mount.mount("sync/peers/remove", peers::remove_peer_handler);
+    mount.mount("sync/jobs", sync_jobs::jobs_request_handler);
+    mount.mount("sync/jobs/job", sync_jobs::job_request_handler);
+    mount.mount("sync/jobs/cancel", sync_control::cancel_request_handler);
+    mount.mount("sync/compact", compaction::compact_request_handler);
+    mount.mount("sync/verify", library_digest::verify_request_handler);
+    let sync_tx2 = sync_tx.clone();
+    mount.mount("sync/bundle/export", move |r: &mut Request| bundles::export_request_handler(r, &sync_tx2));
+    let sync_tx3 = sync_tx.clone();
+    mount.mount("sync/bundle/import", move |r: &mut Request| bundles::import_request_handler(r, &sync_tx3));
+    let sync_tx4 = sync_tx.clone();
+    mount.mount("sync/backup", move |r: &mut Request| local_foreign_server::backup_request_handler(r, &sync_tx4));
+    mount.mount("sync/needed_files", sync_push::needed_files_handler);
+    mount.mount("sync/push", sync_push::push_handler);
     mount.mount("sync/syncpoints", sync_handlers::syncpoint_request_handler);
     mount.mount("sync/syncpoints/add", sync_handlers::syncpoint_add_handler);
+    mount.mount("sync/digest", library_digest::digest_request_handler);
+    mount.mount("sync/handshake", sync_handshake::handshake_request_handler);
     mount.mount("sync/file_details", sync_handlers::file_detail_handler);
     mount.mount("sync/file", sync_handlers::file_request_handler);
+    mount.mount("sync/sidecar", sync_handlers::sidecar_request_handler);
     mount.mount("sync/thumbnail", sync_handlers::thumbnail_request_handler);
     mount.mount("sync/changes", sync_handlers::change_request_handler);
     mount.mount("sync/apply_changes", move |r: &mut Request| sync_handlers::change_application_handler(r, &sync_tx));
-    mount.mount("sync/progress", move |r: &mut Request| sync_progress::progress_request_handler(r, &sync_storage));
+    let sync_storage1 = sync_storage.clone();
+    mount.mount("sync/progress", move |r: &mut Request| sync_progress::progress_request_handler(r, &sync_storage1));
+    mount.mount("sync/progress/events", move |r: &mut Request| {
+        sync_progress::events_request_handler(r, &sync_storage)
+    });
+    mount.mount("thumbnails/regenerate", move |r: &mut Request| {
+        thumbnail_job::regeneration_request_handler(r, &thumbnail_job_storage1)
+    });
+    mount.mount("thumbnails/progress", move |r: &mut Request| {
+        thumbnail_job::progress_request_handler(r, &thumbnail_job_storage)
+    });
+    mount.mount("duplicates", duplicates::duplicates_request_handler);
+    mount.mount("duplicates/remove", duplicates::removal_request_handler);
     mount.mount("subdirectories", move |request: &mut Request| {
         misc_handlers::subdirectory_request_handler(request, &file_read_path)}
     );
@@ -182,6 +247,7 @@ fn main() {
     chain.link(Write::<file_list::FileListList>::both(file_list_list));
     chain.link(Write::<file_list_worker::Commander>::both(file_list_worker_commander));
     chain.link(Read::<settings::Settings>::both(settings));
+    chain.link_after(sync_auth::ProtocolVersionHeader);
 
     let url = format!("0.0.0.0:{}", port);
     match Iron::new(chain).http(url) {
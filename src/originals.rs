@@ -0,0 +1,262 @@
+use chrono::Utc;
+
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Mutex;
+
+use file_database::{FileDatabase, File};
+use file_handler::{remove_sidecars, stored_size};
+use foreign_server::{ForeignServer, HttpForeignServer};
+use settings::Settings;
+use sync::{download_with_retries, with_retries};
+use sync_control::RetryPolicy;
+
+use error::{Result, ErrorKind, ResultExt, describe_chain};
+
+lazy_static! {
+    /// Files whose originals are being fetched. Requests for them are turned
+    /// away which stops peers with lazy originals from asking each other for
+    /// the same file forever
+    static ref FETCHING: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+}
+
+/**
+  Marks the original of a file as being fetched until it is dropped
+*/
+struct FetchGuard(i32);
+
+impl FetchGuard {
+    fn claim(file_id: i32) -> Result<Self> {
+        if !FETCHING.lock().unwrap().insert(file_id) {
+            bail!(ErrorKind::OriginalBeingFetched(file_id));
+        }
+        Ok(FetchGuard(file_id))
+    }
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        FETCHING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/**
+  Makes sure that the original of a file is stored on this instance. Originals
+  of files from peers with lazy originals are fetched from the first registered
+  peer that has them, and are removed again once they haven't been requested
+  for a while and the cache is full
+*/
+pub fn ensure_original(fdb: &FileDatabase, settings: &Settings, file_id: i32) -> Result<()> {
+    let now = Utc::now().naive_utc();
+
+    if fdb.get_file_with_id_result(file_id)?.is_uploaded {
+        return fdb.touch_cached_original(file_id, now);
+    }
+
+    let _guard = FetchGuard::claim(file_id)?;
+
+    // The original may have been fetched while the guard was claimed
+    let file = fdb.get_file_with_id_result(file_id)?;
+    if file.is_uploaded {
+        return fdb.touch_cached_original(file_id, now);
+    }
+
+    let mut sources = vec!();
+    for peer in fdb.get_peers()? {
+        match HttpForeignServer::from_settings(peer.url.clone(), peer.secret.clone(), settings) {
+            Ok(server) => sources.push(server),
+            Err(e) => println!("Warning: Failed to connect to {}: {}", peer.name, e)
+        }
+    }
+    let sources = sources.iter().map(|server| server as &dyn ForeignServer).collect::<Vec<_>>();
+
+    fetch_original(fdb, &file, &sources, &settings.get_sync_retry_policy())?;
+    fdb.set_original_stored(file_id, true, Some(now))?;
+
+    evict_cached_originals(fdb, settings.get_original_cache_size(), file_id)
+        .chain_err(|| "Failed to remove cached originals")?;
+    Ok(())
+}
+
+/**
+  Downloads the original of `file` and its sidecars from the first of
+  `sources` that has them, retrying failed downloads according to
+  `retry_policy`. The database is not updated
+*/
+pub fn fetch_original(
+    fdb: &FileDatabase,
+    file: &File,
+    sources: &[&dyn ForeignServer],
+    retry_policy: &RetryPolicy
+) -> Result<()> {
+    for source in sources {
+        match download_original(fdb, file, *source, retry_policy) {
+            Ok(()) => return Ok(()),
+            Err(e) => println!(
+                "Warning: Failed to fetch the original of file {}: {}",
+                file.id,
+                describe_chain(&e)
+            )
+        }
+    }
+    bail!(ErrorKind::OriginalUnavailable(file.id))
+}
+
+fn download_original(
+    fdb: &FileDatabase,
+    file: &File,
+    foreign_server: &dyn ForeignServer,
+    retry_policy: &RetryPolicy
+) -> Result<()> {
+    let storage_path = fdb.get_file_save_path();
+
+    // Downloaded next to the final file so that it is only moved into place
+    // once it is complete
+    let download_path = storage_path.join(format!("{}.download", file.filename));
+    download_with_retries(
+        foreign_server,
+        file.id,
+        &download_path,
+        &file.content_hash,
+        retry_policy,
+        None,
+        &|_| {}
+    )?;
+
+    for (index, filename) in file.sidecars.iter().enumerate() {
+        let sidecar_download_path = storage_path.join(format!("{}.download", filename));
+        if sidecar_download_path.exists() {
+            fs::remove_file(&sidecar_download_path)?;
+        }
+        with_retries(retry_policy, None, || {
+            foreign_server.download_sidecar(file.id, index, &sidecar_download_path, &|_| {})
+        })
+            .chain_err(|| "Failed to get sidecar")?;
+        fs::rename(&sidecar_download_path, storage_path.join(filename))?;
+    }
+
+    fs::rename(&download_path, storage_path.join(&file.filename))?;
+    Ok(())
+}
+
+/**
+  Removes the originals that were fetched on demand and haven't been requested
+  for the longest time until the rest take up at most `limit` bytes. The
+  original of `keep` is never removed.
+
+  Returns the ids of the files whose originals were removed
+*/
+pub fn evict_cached_originals(fdb: &FileDatabase, limit: u64, keep: i32) -> Result<Vec<i32>> {
+    let mut used = 0;
+    let mut evicted = vec!();
+    for file in fdb.get_cached_originals()? {
+        used += stored_size(&file, fdb)?.unwrap_or(0);
+
+        if used > limit && file.id != keep {
+            remove_original(fdb, &file)?;
+            evicted.push(file.id);
+        }
+    }
+    Ok(evicted)
+}
+
+/**
+  Removes the original of a file and its sidecars, keeping the thumbnail and
+  everything in the database
+*/
+pub fn remove_original(fdb: &FileDatabase, file: &File) -> Result<()> {
+    fdb.set_original_stored(file.id, false, None)?;
+
+    let path = fdb.get_file_save_path().join(&file.filename);
+    fs::remove_file(&path)
+        .chain_err(|| ErrorKind::FileRemovalFailed(path.to_string_lossy().into()))?;
+
+    remove_sidecars(file, fdb)
+}
+
+
+#[cfg(test)]
+mod original_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+    use std::collections::HashMap;
+
+    use byte_source::ByteSource;
+    use file_database::db_test_helpers;
+    use file_handler::save_remote_file;
+    use foreign_server::FileDetails;
+    use sync_push::{PushedFiles, FilePart, store_pushed_part, push_directory};
+
+    use std::time::Duration;
+
+    fn save_remote(fdb: &FileDatabase, id: i32, sidecars: &[String]) -> File {
+        save_remote_file(
+            Some(ByteSource::Memory(vec!(7))),
+            sidecars,
+            id,
+            fdb,
+            "jpg",
+            0,
+            None
+        ).unwrap()
+    }
+
+    #[test]
+    fn originals_are_fetched_and_evicted() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        // The ids are not used by other tests which leave their files in the storage folder
+        let storage = fdb.get_file_save_path();
+        let first = save_remote(&fdb, 41, &["nef".into()]);
+        let second = save_remote(&fdb, 42, &[]);
+        assert!(!first.is_uploaded);
+        assert!(!storage.join(&first.filename).exists());
+        assert_eq!(fs::read(storage.join(first.thumbnail_path.clone().unwrap())).unwrap(), vec!(7));
+
+        store_pushed_part(&storage, 10, 41, FilePart::Content, 0, &[1, 2, 3]).unwrap();
+        store_pushed_part(&storage, 10, 41, FilePart::Sidecar(0), 0, &[4, 5]).unwrap();
+        store_pushed_part(&storage, 10, 42, FilePart::Content, 0, &[6, 7, 8]).unwrap();
+        let peer = PushedFiles::new(HashMap::<i32, FileDetails>::new(), push_directory(&storage, 10));
+
+        // Peers without the file are skipped
+        let empty = PushedFiles::new(HashMap::new(), push_directory(&storage, 11));
+        let retries = RetryPolicy {
+            retries: 1,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0)
+        };
+        fetch_original(&fdb, &first, &[&empty, &peer], &retries).unwrap();
+        fetch_original(&fdb, &second, &[&peer], &retries).unwrap();
+        assert_matches!(
+            fetch_original(&fdb, &second, &[&empty], &retries),
+            Err(::error::Error(ErrorKind::OriginalUnavailable(42), _))
+        );
+
+        let first = fdb.get_file_with_id(41).unwrap();
+        assert_eq!(fs::read(storage.join(&first.filename)).unwrap(), vec!(1, 2, 3));
+        assert_eq!(fs::read(storage.join(&first.sidecars[0])).unwrap(), vec!(4, 5));
+
+        fdb.set_original_stored(41, true, Some(NaiveDate::from_ymd(2018, 1, 1).and_hms(0, 0, 0)))
+            .unwrap();
+        fdb.set_original_stored(42, true, Some(NaiveDate::from_ymd(2018, 1, 2).and_hms(0, 0, 0)))
+            .unwrap();
+
+        // Both originals fit
+        assert!(evict_cached_originals(&fdb, 8, 42).unwrap().is_empty());
+
+        // The first file was requested longest ago
+        assert_eq!(evict_cached_originals(&fdb, 4, 42).unwrap(), vec!(41));
+        let first = fdb.get_file_with_id(41).unwrap();
+        assert!(!first.is_uploaded);
+        assert_eq!(first.original_accessed, None);
+        assert!(!storage.join(&first.filename).exists());
+        assert!(!storage.join(&first.sidecars[0]).exists());
+        assert!(fdb.get_file_with_id(42).unwrap().is_uploaded);
+
+        // The file that was just fetched is kept even if it is too large
+        assert!(evict_cached_originals(&fdb, 0, 42).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+use iron::prelude::*;
+use iron::status;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use std::thread;
+
+use file_database::FileDatabase;
+use foreign_server::{HttpForeignServer, split_scheme};
+use request_helpers::{
+    to_json_with_result,
+    from_json_with_result,
+    get_get_i64,
+    get_get_variable,
+    get_optional_get_i64,
+    get_optional_get_variable,
+    setup_db_connection
+};
+use schema::peers;
+use settings::Settings;
+use sync_auth::authenticate_admin_request;
+use sync_handlers::run_sync_job;
+use sync_progress as sp;
+
+use error::{Result, ResultExt, ErrorKind, describe_chain};
+
+
+/// Seconds between checks for peers that should be synced
+const SCHEDULER_INTERVAL: u64 = 60;
+
+/**
+  Another flash instance which this instance syncs with
+*/
+#[derive(Queryable, Serialize, Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub id: i32,
+    pub url: String,
+    pub name: String,
+    /// Secret used to sign requests to the peer instead of `FLASH_SYNC_SECRET`.
+    /// It is never sent to clients
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    /// Seconds between automatic syncs. Peers without an interval are only
+    /// synced when requested
+    pub sync_interval: Option<i32>,
+    pub last_sync: Option<NaiveDateTime>,
+    pub last_attempt: Option<NaiveDateTime>,
+    /// The error of the last sync attempt if it failed
+    pub last_error: Option<String>,
+    /// Only store the thumbnails of files added by the peer. Their originals
+    /// are fetched when they are requested
+    pub lazy_originals: bool
+}
+
+impl Peer {
+    /**
+      Returns true if the peer should be synced automatically at `now`
+    */
+    pub fn is_due(&self, now: &NaiveDateTime) -> bool {
+        match (self.sync_interval, self.last_attempt) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(interval), Some(last_attempt)) =>
+                last_attempt + Duration::seconds(interval as i64) <= *now
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="peers"]
+pub struct NewPeer {
+    pub url: String,
+    pub name: String,
+    pub secret: Option<String>,
+    pub sync_interval: Option<i32>,
+    pub lazy_originals: bool
+}
+
+/**
+  Creates a connection to the flash instance at `url`. If `url` belongs to a
+  peer with its own secret, that secret is used
+*/
+pub fn connect(fdb: &FileDatabase, url: String, settings: &Settings) -> Result<HttpForeignServer> {
+    let secret = fdb.get_peer_with_url(&url)?.and_then(|peer| peer.secret);
+
+    HttpForeignServer::from_settings(url, secret, settings)
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                  Scheduler
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  Starts a thread which syncs with every peer that has a sync interval once the
+  interval has passed since the last attempt. Peers that don't answer a ping
+  are skipped and tried again on the next check.
+
+  Checks that fail are reported through `progress_tx` as failed jobs
+*/
+pub fn run_sync_scheduler(settings: Settings, own_port: u16, progress_tx: sp::TxType) {
+    thread::spawn(move || {
+        loop {
+            if let Err(e) = sync_due_peers(&settings, own_port, &progress_tx) {
+                let job_id = sp::new_job_id();
+                let error = format!("Failed to run scheduled syncs: {}", describe_chain(&e));
+                for update in [sp::SyncUpdate::Started, sp::SyncUpdate::Error(error)] {
+                    progress_tx.send((job_id, update))
+                        .expect("Failed to send error from the sync scheduler
+                                to sync progress manager. Did it crash?");
+                }
+            }
+
+            thread::sleep(::std::time::Duration::from_secs(SCHEDULER_INTERVAL));
+        }
+    });
+}
+
+fn sync_due_peers(settings: &Settings, own_port: u16, progress_tx: &sp::TxType) -> Result<()> {
+    let fdb = FileDatabase::new(&settings.database_url, settings.get_file_storage_path())?;
+
+    let now = Utc::now().naive_utc();
+    let due_peers = fdb.get_peers()?
+        .into_iter()
+        .filter(|peer| peer.is_due(&now))
+        .collect::<Vec<_>>();
+
+    for peer in due_peers {
+        let foreign = HttpForeignServer::from_settings(peer.url.clone(), peer.secret.clone(), settings)
+            .and_then(|foreign| foreign.ping().map(|_| foreign));
+
+        let foreign = match foreign {
+            Ok(foreign) => foreign,
+            Err(e) => {
+                // The attempt is not recorded so the peer is tried again on the next check
+                let error = format!("Skipped scheduled sync, the peer did not answer: {}", describe_chain(&e));
+                fdb.record_peer_error(peer.id, &error)?;
+                continue;
+            }
+        };
+
+        // The result is stored in the peer by run_sync_job
+        run_sync_job(
+            settings,
+            &peer.url,
+            foreign,
+            own_port,
+            &(sp::new_job_id(), progress_tx.clone())
+        ).ok();
+    }
+    Ok(())
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                  Request handlers
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn peers_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let peers = fdb.get_peers()?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(peers)?)))
+}
+
+/**
+  Peers can only be changed through requests signed with `FLASH_SYNC_SECRET`
+  since requests signed with the secret of a peer are trusted
+*/
+pub fn add_peer_handler(request: &mut Request) -> IronResult<Response> {
+    let body = authenticate_admin_request(request)?;
+    let secret = read_peer_secret(body)?.filter(|secret| !secret.is_empty());
+
+    let fdb = setup_db_connection(request)?;
+
+    let url = get_get_variable(request, "url")?;
+    let name = get_optional_get_variable(request, "name")?.unwrap_or_else(|| url.clone());
+    let sync_interval = get_sync_interval(request)?.filter(|interval| *interval != 0);
+    let lazy_originals = get_lazy_originals(request)?.unwrap_or(false);
+
+    split_scheme(&url)?;
+
+    let peer = fdb.add_peer(&NewPeer{url, name, secret, sync_interval, lazy_originals})?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(peer.id)?)))
+}
+
+pub fn update_peer_handler(request: &mut Request) -> IronResult<Response> {
+    let body = authenticate_admin_request(request)?;
+    let secret = read_peer_secret(body)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let mut peer = fdb.get_peer(get_get_i64(request, "peer_id")? as i32)?;
+
+    if let Some(url) = get_optional_get_variable(request, "url")? {
+        split_scheme(&url)?;
+        peer.url = url;
+    }
+    if let Some(name) = get_optional_get_variable(request, "name")? {
+        peer.name = name;
+    }
+    if let Some(secret) = secret {
+        // An empty secret goes back to using FLASH_SYNC_SECRET
+        peer.secret = Some(secret).filter(|secret| !secret.is_empty());
+    }
+    if let Some(sync_interval) = get_sync_interval(request)? {
+        // An interval of 0 turns off automatic syncs
+        peer.sync_interval = Some(sync_interval).filter(|interval| *interval != 0);
+    }
+    if let Some(lazy_originals) = get_lazy_originals(request)? {
+        peer.lazy_originals = lazy_originals;
+    }
+
+    fdb.update_peer(&peer)?;
+
+    Ok(Response::with((status::Ok, "Done")))
+}
+
+pub fn remove_peer_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let peer_id = get_get_i64(request, "peer_id")? as i32;
+
+    fdb.remove_peer(peer_id)?;
+
+    Ok(Response::with((status::Ok, "Done")))
+}
+
+/**
+  The body of requests that add or update peers. The secret is sent in the
+  signed body rather than in the url so it doesn't end up in logs
+*/
+#[derive(Deserialize)]
+struct PeerSecret {
+    secret: Option<String>
+}
+
+/**
+  Reads the `secret` of a peer from a request body, which is either empty or
+  a jsonified `PeerSecret`
+*/
+fn read_peer_secret(body: Vec<u8>) -> Result<Option<String>> {
+    if body.is_empty() {
+        return Ok(None);
+    }
+
+    let body = String::from_utf8(body).chain_err(|| "Peer secret was not valid utf-8")?;
+    Ok(from_json_with_result::<PeerSecret>(&body)?.secret)
+}
+
+/**
+  Reads the `sync_interval` in seconds from a request. 0 means that the peer
+  should not be synced automatically
+*/
+fn get_sync_interval(request: &mut Request) -> Result<Option<i32>> {
+    match get_optional_get_i64(request, "sync_interval")? {
+        Some(interval) if interval >= 0 && interval <= i64::from(i32::MAX) =>
+            Ok(Some(interval as i32)),
+        Some(_) => bail!(ErrorKind::InvalidVariableType(
+            "sync_interval".into(),
+            "a positive amount of seconds".into()
+        )),
+        None => Ok(None)
+    }
+}
+
+/**
+  Reads `lazy_originals` from a request, which is either `true` or `false`
+*/
+fn get_lazy_originals(request: &mut Request) -> Result<Option<bool>> {
+    match get_optional_get_variable(request, "lazy_originals")? {
+        Some(value) => match value.as_str() {
+            "true" => Ok(Some(true)),
+            "false" => Ok(Some(false)),
+            _ => bail!(ErrorKind::InvalidVariableType(
+                "lazy_originals".into(),
+                "true or false".into()
+            ))
+        },
+        None => Ok(None)
+    }
+}
+
+
+#[cfg(test)]
+mod peer_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    fn peer(sync_interval: Option<i32>, last_attempt: Option<NaiveDateTime>) -> Peer {
+        Peer {
+            id: 1,
+            url: "localhost:3000".into(),
+            name: "NAS".into(),
+            secret: None,
+            sync_interval,
+            last_sync: None,
+            last_attempt,
+            last_error: None,
+            lazy_originals: false
+        }
+    }
+
+    #[test]
+    fn peers_are_due_after_their_interval() {
+        let now = NaiveDate::from_ymd(2018, 1, 1).and_hms(12, 0, 0);
+        let hour_ago = NaiveDate::from_ymd(2018, 1, 1).and_hms(11, 0, 0);
+
+        assert!(!peer(None, None).is_due(&now));
+        assert!(!peer(None, Some(hour_ago)).is_due(&now));
+        assert!(peer(Some(3600), None).is_due(&now));
+        assert!(peer(Some(3600), Some(hour_ago)).is_due(&now));
+        assert!(!peer(Some(3601), Some(hour_ago)).is_due(&now));
+    }
+
+    #[test]
+    fn secrets_are_not_serialized() {
+        let mut peer = peer(None, None);
+        peer.secret = Some("s3cret".into());
+
+        assert!(!to_json_with_result(&peer).unwrap().contains("s3cret"));
+    }
+
+    #[test]
+    fn secrets_are_read_from_the_body() {
+        assert_eq!(read_peer_secret(vec!()).unwrap(), None);
+        assert_eq!(read_peer_secret(b"{}".to_vec()).unwrap(), None);
+        assert_eq!(
+            read_peer_secret(br#"{"secret": "s3cret"}"#.to_vec()).unwrap(),
+            Some("s3cret".into())
+        );
+        assert_eq!(read_peer_secret(br#"{"secret": ""}"#.to_vec()).unwrap(), Some("".into()));
+        assert!(read_peer_secret(b"s3cret".to_vec()).is_err());
+    }
+
+    db_test!(unreachable_peers_are_tried_again(fdb) {
+        let now = NaiveDate::from_ymd(2018, 1, 1).and_hms(12, 0, 0);
+        let added = fdb.add_peer(&NewPeer {
+            url: "localhost:1".into(),
+            name: "NAS".into(),
+            secret: None,
+            sync_interval: Some(3600),
+            lazy_originals: false
+        }).unwrap();
+
+        fdb.record_peer_error(added.id, "Skipped scheduled sync").unwrap();
+
+        let peer = fdb.get_peer(added.id).unwrap();
+        assert_eq!(peer.last_error, Some("Skipped scheduled sync".into()));
+        assert_eq!(peer.last_attempt, None);
+        assert!(peer.is_due(&now));
+    });
+}
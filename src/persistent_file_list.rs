@@ -4,6 +4,7 @@ use file_list::{FileList, FileLocation, FileListSource, FileListList};
 use file_database;
 
 use std::path::{PathBuf, Path};
+use std::collections::HashMap;
 
 use std::fs;
 use std::io::{Write, Read};
@@ -20,6 +21,8 @@ pub enum SaveableFileLocation {
 pub struct SaveableFileList {
     pub source: FileListSource,
     pub files: Vec<SaveableFileLocation>,
+    #[serde(default)]
+    pub sidecars: HashMap<PathBuf, Vec<PathBuf>>,
 }
 
 /**
@@ -37,6 +40,7 @@ fn saveable_file_list(list: &FileList) -> SaveableFileList {
     SaveableFileList {
         source: list.get_source().clone(),
         files,
+        sidecars: list.get_all_sidecars().clone(),
     }
 }
 
@@ -66,6 +70,7 @@ fn list_from_saveable(
         .collect();
 
     FileList::from_locations(files, saveable_list.source)
+        .with_sidecars(saveable_list.sidecars)
 }
 
 
@@ -1,4 +1,4 @@
-use error::{Result, ErrorKind};
+use error::{Error, Result, ErrorKind};
 use file_database::FileDatabase;
 use settings::Settings;
 use persistent::{Read};
@@ -27,6 +27,19 @@ pub fn get_get_variable(request: &mut Request, name: &str) -> Result<String> {
     }
 }
 
+/**
+  Fetches a single optional GET variable from the request. Returns `Ok(None)`
+  if the variable was not specified or there is no query
+*/
+pub fn get_optional_get_variable(request: &mut Request, name: &str) -> Result<Option<String>> {
+    match get_get_variable(request, name) {
+        Ok(val) => Ok(Some(val)),
+        Err(Error(ErrorKind::NoSuchVariable(_), _)) |
+        Err(Error(ErrorKind::NoUrlEncodedQuery, _)) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
 /**
   Fetches a single number from the GET variables of the requests.
 */
@@ -40,6 +53,19 @@ pub fn get_get_i64(request: &mut Request, name: &str) -> Result<i64> {
     }
 }
 
+/**
+  Fetches a single optional number from the GET variables of the request.
+  Returns `Ok(None)` if the variable was not specified or there is no query
+*/
+pub fn get_optional_get_i64(request: &mut Request, name: &str) -> Result<Option<i64>> {
+    match get_get_i64(request, name) {
+        Ok(val) => Ok(Some(val)),
+        Err(Error(ErrorKind::NoSuchVariable(_), _)) |
+        Err(Error(ErrorKind::NoUrlEncodedQuery, _)) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
 /**
   Fetches a single number from the GET variables of the requests.
 */
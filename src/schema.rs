@@ -1,10 +1,12 @@
 //infer_schema!("dotenv:DATABASE_URL");
 table! {
     changes (id) {
-        id -> Int4,
+        id -> Int8,
         timestamp -> Timestamp,
         json_data -> Text,
         affected_file -> Int4,
+        clock -> Int8,
+        origin -> Text,
     }
 }
 
@@ -16,6 +18,45 @@ table! {
         creation_date -> Timestamp,
         is_uploaded -> Bool,
         tags -> Array<Text>,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        sidecars -> Array<Text>,
+        perceptual_hash -> Nullable<Int8>,
+        content_hash -> Nullable<Text>,
+        original_accessed -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    sync_conflicts (id) {
+        id -> Int4,
+        file_id -> Int4,
+        json_data -> Text,
+        detected_at -> Timestamp,
+        overridden -> Bool,
+    }
+}
+
+table! {
+    sync_filters (foreign_url) {
+        foreign_url -> Text,
+        query -> Text,
+    }
+}
+
+table! {
+    sync_jobs (id) {
+        id -> Int8,
+        kind -> Text,
+        peer -> Text,
+        started_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+        status -> Text,
+        changes_sent -> Int4,
+        changes_received -> Int4,
+        files_sent -> Int4,
+        files_received -> Int4,
+        error -> Nullable<Text>,
     }
 }
 
@@ -23,6 +64,38 @@ table! {
     syncpoints (id) {
         id -> Int4,
         last_change -> Timestamp,
+        clock -> Int8,
+        foreign_url -> Nullable<Text>,
     }
 }
 
+table! {
+    instance (id) {
+        id -> Text,
+        reserved_clock -> Int8,
+    }
+}
+
+table! {
+    peers (id) {
+        id -> Int4,
+        url -> Text,
+        name -> Text,
+        secret -> Nullable<Text>,
+        sync_interval -> Nullable<Int4>,
+        last_sync -> Nullable<Timestamp>,
+        last_attempt -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        lazy_originals -> Bool,
+    }
+}
+
+
+table! {
+    renditions (file_id, size) {
+        file_id -> Int4,
+        size -> Int4,
+        filename -> Text,
+        version -> Int4,
+    }
+}
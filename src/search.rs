@@ -50,6 +50,25 @@ impl SavedSearchQuery {
             negated_tags: util::merge_vectors(&self.negated_tags, &other.negated_tags),
         }
     }
+
+    /**
+      Checks if a file with the specified tags and creation date would be
+      found by this query. Mirrors the database query in `search_files`
+    */
+    pub fn matches(&self, tags: &[String], creation_date: &NaiveDateTime) -> bool {
+        let has_all = |wanted: &[String]| wanted.iter().all(|tag| tags.contains(tag));
+
+        let tags_match = has_all(&self.tags)
+            && (self.negated_tags.is_empty() || !has_all(&self.negated_tags));
+
+        let in_intervals = self.date_constraints.intervals.iter()
+            .all(|interval| interval.start <= *creation_date && *creation_date <= interval.end);
+
+        let constraints_match = self.date_constraints.constraints.iter()
+            .all(|constraint_function| constraint_function(creation_date));
+
+        tags_match && in_intervals && constraints_match
+    }
 }
 
 /**
@@ -4,12 +4,32 @@ use std::env;
 use iron::typemap::Key;
 
 use std::path::PathBuf;
+use std::time::Duration;
+
+use file_util::ThumbnailSettings;
+use conflicts::{ConflictPolicies, TagConflictPolicy, RemovalConflictPolicy};
+use sync_control::{JobLimits, RetryPolicy};
 
 #[derive(Clone)]
 pub struct Settings {
     file_storage_path: PathBuf,
     port: u16,
     file_read_path: PathBuf,
+    thumbnail_settings: ThumbnailSettings,
+    thumbnail_workers: usize,
+    sync_workers: usize,
+    conflict_policies: ConflictPolicies,
+    sync_history_days: i64,
+    sync_timeout: Option<Duration>,
+    sync_request_timeout: Duration,
+    sync_retry_policy: RetryPolicy,
+    sync_push: bool,
+    original_cache_size: u64,
+    sync_secret: Option<String>,
+    sync_url: Option<String>,
+    sync_ca_certificate: Option<PathBuf>,
+    backup_library: Option<(String, PathBuf)>,
+    bundle_path: Option<PathBuf>,
     pub database_url: String
 }
 
@@ -39,10 +59,183 @@ impl Settings {
             PathBuf::from(as_str)
         };
 
+        let thumbnail_settings = {
+            let default = ThumbnailSettings::default();
+
+            let sizes = env::var("FLASH_THUMBNAIL_SIZES")
+                .map(|as_str| {
+                    as_str.split(',')
+                        .map(|size| size.trim().parse::<u32>().ok().filter(|size| *size > 0))
+                        .collect::<Option<Vec<_>>>()
+                        .expect("FLASH_THUMBNAIL_SIZES must be a comma separated list of positive integers")
+                })
+                .unwrap_or(default.sizes);
+
+            let quality = env::var("FLASH_THUMBNAIL_QUALITY")
+                .map(|as_str| {
+                    as_str.parse::<u8>()
+                        .ok()
+                        .filter(|quality| *quality >= 1 && *quality <= 100)
+                        .expect("FLASH_THUMBNAIL_QUALITY must be an integer between 1 and 100")
+                })
+                .unwrap_or(default.quality);
+
+            ThumbnailSettings { sizes, quality }
+        };
+
+        let thumbnail_workers = env::var("FLASH_THUMBNAIL_WORKERS")
+            .unwrap_or_else(|_| "2".to_owned())
+            .parse::<usize>()
+            .ok()
+            .filter(|workers| *workers > 0)
+            .expect("FLASH_THUMBNAIL_WORKERS must be a positive integer");
+
+        let sync_workers = env::var("FLASH_SYNC_WORKERS")
+            .unwrap_or_else(|_| "4".to_owned())
+            .parse::<usize>()
+            .ok()
+            .filter(|workers| *workers > 0)
+            .expect("FLASH_SYNC_WORKERS must be a positive integer");
+
+        let conflict_policies = {
+            let default = ConflictPolicies::default();
+
+            let tags = env::var("FLASH_SYNC_TAG_CONFLICTS")
+                .map(|as_str| {
+                    match as_str.as_str() {
+                        "last-writer-wins" => Some(TagConflictPolicy::LastWriterWins),
+                        "union" => Some(TagConflictPolicy::Union),
+                        _ => None
+                    }.expect("FLASH_SYNC_TAG_CONFLICTS must be last-writer-wins or union")
+                })
+                .unwrap_or(default.tags);
+
+            let removals = env::var("FLASH_SYNC_REMOVAL_CONFLICTS")
+                .map(|as_str| {
+                    match as_str.as_str() {
+                        "last-writer-wins" => Some(RemovalConflictPolicy::LastWriterWins),
+                        "keep" => Some(RemovalConflictPolicy::Keep),
+                        _ => None
+                    }.expect("FLASH_SYNC_REMOVAL_CONFLICTS must be last-writer-wins or keep")
+                })
+                .unwrap_or(default.removals);
+
+            ConflictPolicies { tags, removals }
+        };
+
+        let sync_history_days = env::var("FLASH_SYNC_HISTORY_DAYS")
+            .unwrap_or_else(|_| "90".to_owned())
+            .parse::<i64>()
+            .ok()
+            .filter(|days| *days > 0)
+            .expect("FLASH_SYNC_HISTORY_DAYS must be a positive integer");
+
+        // 0 lets sync jobs run for as long as they need
+        let sync_timeout = env::var("FLASH_SYNC_TIMEOUT_MINUTES")
+            .unwrap_or_else(|_| "720".to_owned())
+            .parse::<u64>()
+            .map(|minutes| Duration::from_secs(minutes * 60))
+            .expect("FLASH_SYNC_TIMEOUT_MINUTES must be a positive integer or 0");
+        let sync_timeout = Some(sync_timeout).filter(|timeout| *timeout > Duration::from_secs(0));
+
+        let sync_request_timeout = env::var("FLASH_SYNC_REQUEST_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "30".to_owned())
+            .parse::<u64>()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+            .expect("FLASH_SYNC_REQUEST_TIMEOUT_SECS must be a positive integer");
+
+        let sync_retry_policy = {
+            let default = RetryPolicy::default();
+
+            let retries = env::var("FLASH_SYNC_RETRIES")
+                .map(|as_str| {
+                    as_str.parse::<usize>()
+                        .expect("FLASH_SYNC_RETRIES must be a positive integer or 0")
+                })
+                .unwrap_or(default.retries);
+
+            let initial_backoff = env::var("FLASH_SYNC_BACKOFF_MS")
+                .map(|as_str| {
+                    as_str.parse::<u64>()
+                        .map(Duration::from_millis)
+                        .expect("FLASH_SYNC_BACKOFF_MS must be a positive integer")
+                })
+                .unwrap_or(default.initial_backoff);
+
+            let max_backoff = env::var("FLASH_SYNC_MAX_BACKOFF_MS")
+                .map(|as_str| {
+                    as_str.parse::<u64>()
+                        .map(Duration::from_millis)
+                        .expect("FLASH_SYNC_MAX_BACKOFF_MS must be a positive integer")
+                })
+                .unwrap_or(default.max_backoff);
+
+            RetryPolicy { retries, initial_backoff, max_backoff }
+        };
+
+        let sync_push = env::var("FLASH_SYNC_MODE")
+            .map(|as_str| {
+                match as_str.as_str() {
+                    "callback" => Some(false),
+                    "push" => Some(true),
+                    _ => None
+                }.expect("FLASH_SYNC_MODE must be callback or push")
+            })
+            .unwrap_or(false);
+
+        let original_cache_size = env::var("FLASH_ORIGINAL_CACHE_MB")
+            .unwrap_or_else(|_| "2048".to_owned())
+            .parse::<u64>()
+            .expect("FLASH_ORIGINAL_CACHE_MB must be a positive integer")
+            * 1024 * 1024;
+
+        let sync_secret = env::var("FLASH_SYNC_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty());
+
+        let sync_url = env::var("FLASH_SYNC_URL")
+            .ok()
+            .filter(|url| !url.is_empty());
+
+        let sync_ca_certificate = env::var("FLASH_SYNC_CA_CERT")
+            .ok()
+            .map(PathBuf::from);
+
+        let backup_library = env::var("FLASH_BACKUP_DATABASE_URL")
+            .ok()
+            .map(|url| {
+                let storage_path = env::var("FLASH_BACKUP_STORAGE_PATH")
+                    .expect("FLASH_BACKUP_STORAGE_PATH must be set when FLASH_BACKUP_DATABASE_URL is");
+
+                (url, PathBuf::from(storage_path))
+            });
+
+        let bundle_path = env::var("FLASH_BUNDLE_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+
         Settings {
             file_storage_path,
             port,
             file_read_path,
+            thumbnail_settings,
+            thumbnail_workers,
+            sync_workers,
+            conflict_policies,
+            sync_history_days,
+            sync_timeout,
+            sync_request_timeout,
+            sync_retry_policy,
+            sync_push,
+            original_cache_size,
+            sync_secret,
+            sync_url,
+            sync_ca_certificate,
+            backup_library,
+            bundle_path,
             database_url,
         }
     }
@@ -59,6 +252,98 @@ impl Settings {
         self.file_read_path.clone()
     }
 
+    pub fn get_thumbnail_settings(&self) -> ThumbnailSettings {
+        self.thumbnail_settings.clone()
+    }
+
+    pub fn get_thumbnail_workers(&self) -> usize {
+        self.thumbnail_workers
+    }
+
+    pub fn get_sync_workers(&self) -> usize {
+        self.sync_workers
+    }
+
+    pub fn get_conflict_policies(&self) -> ConflictPolicies {
+        self.conflict_policies.clone()
+    }
+
+    /**
+      Returns the amount of days that finished sync jobs are kept in the database
+    */
+    pub fn get_sync_history_days(&self) -> i64 {
+        self.sync_history_days
+    }
+
+    /**
+      Returns how long sync jobs may run and how their failed requests are retried
+    */
+    pub fn get_job_limits(&self) -> JobLimits {
+        JobLimits {
+            timeout: self.sync_timeout,
+            retry_policy: self.sync_retry_policy.clone()
+        }
+    }
+
+    /**
+      Returns how long a request to a peer may go without progress before it fails
+    */
+    pub fn get_sync_request_timeout(&self) -> Duration {
+        self.sync_request_timeout
+    }
+
+    pub fn get_sync_retry_policy(&self) -> RetryPolicy {
+        self.sync_retry_policy.clone()
+    }
+
+    /**
+      Returns true if files should be pushed to peers when this instance starts
+      a sync, rather than letting the peers connect back to fetch them
+    */
+    pub fn get_sync_push(&self) -> bool {
+        self.sync_push
+    }
+
+    /**
+      Returns the amount of bytes that originals fetched from peers on demand
+      may take up before the least recently requested ones are removed
+    */
+    pub fn get_original_cache_size(&self) -> u64 {
+        self.original_cache_size
+    }
+
+    pub fn get_sync_secret(&self) -> Option<String> {
+        self.sync_secret.clone()
+    }
+
+    /**
+      Returns the url that peers use to reach this instance, which is sent
+      along with changes to identify this instance
+    */
+    pub fn get_sync_url(&self) -> Option<String> {
+        self.sync_url.clone()
+    }
+
+    pub fn get_sync_ca_certificate(&self) -> Option<PathBuf> {
+        self.sync_ca_certificate.clone()
+    }
+
+    /**
+      Returns the database url and storage path of the library that
+      `/sync/backup` replicates this instance into, if one is configured
+    */
+    pub fn get_backup_library(&self) -> Option<(String, PathBuf)> {
+        self.backup_library.clone()
+    }
+
+    /**
+      Returns the folder that bundles are exported to and imported from, if
+      one is configured
+    */
+    pub fn get_bundle_path(&self) -> Option<PathBuf> {
+        self.bundle_path.clone()
+    }
+
 }
 
 impl Key for Settings {
@@ -4,20 +4,42 @@ use changelog::{
     ChangeType,
     UpdateType,
     ChangeCreationPolicy,
+    LogicalClock,
+    timestamp_clock,
 };
 
 use byte_source::{ByteSource};
 
 use file_database::{FileDatabase};
-use error::{Result, ErrorKind, ResultExt};
+use error::{Error, Result, ErrorKind, ResultExt};
 use file_handler;
-use file_handler::{remove_file, ThumbnailStrategy};
-use foreign_server::{ForeignServer, ChangeData};
+use file_handler::{remove_file, ThumbnailStrategy, Sidecar};
+use foreign_server::{ForeignServer, ChangeData, FileDetails};
 use sync_progress as sp;
+use sync_control::{self, RetryPolicy};
+use sync_handshake::{Handshake, FEATURE_PUSH, FEATURE_CANCEL};
+use conflicts::{resolve_conflicts, keep_discarded_content, ConflictPolicies, ConflictResolution, SyncedChanges};
+use search::SavedSearchQuery;
+use sync_jobs::TransferCounts;
+use sync_push::push_changes;
+
+use file_util::content_hash;
+use originals;
 
 use chrono::prelude::*;
 
 use std::thread;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::time::{Duration, Instant};
+
+/// The shortest time between two updates about the amount of received bytes
+const BYTE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
 
 
 
@@ -49,43 +71,207 @@ fn get_removed_files(changes: &[Change]) -> Vec<i32> {
         .collect()
 }
 
+/**
+  The changes made on each side since the last syncpoint they have in common,
+  with conflicting changes resolved
+*/
+struct ChangeSets {
+    last_common_syncpoint: Option<SyncPoint>,
+    local_syncpoints: Vec<SyncPoint>,
+    remote_syncpoints: Vec<SyncPoint>,
+    local_changes: Vec<Change>,
+    remote_changes: Vec<Change>,
+    /// Files removed on either side
+    removed_files: Vec<i32>,
+    conflicts: Vec<ConflictResolution>,
+    /// The logical clock counter of the new syncpoint, which is larger than the
+    /// counter of every change on both sides
+    sync_clock: i64
+}
+
+/**
+  Gathers the changes made on each side since the last sync and resolves
+  conflicts between them using `policies`. Changes that are created to
+  resolve conflicts get the timestamp `now` and the clock of the new syncpoint.
+  Failed requests are retried as part of the job `job_id`
+*/
+fn gather_change_sets(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    policies: &ConflictPolicies,
+    now: NaiveDateTime,
+    job_id: Option<usize>
+) -> Result<ChangeSets> {
+    let retry_policy = job_id.map_or_else(RetryPolicy::default, sync_control::retry_policy);
+
+    // Get the syncpoints from the local and remote servers
+    let local_syncpoints = fdb.get_syncpoints()
+        .chain_err(|| "Failed to get local syncpoints")?;
+    let remote_syncpoints = with_retries(&retry_policy, job_id, || foreign_server.get_syncpoints())
+        .chain_err(|| "Failed to get remote syncpoints")?;
+
+    // Find the highest common syncpoint
+    let sync_merge_start = last_common_syncpoint(&local_syncpoints, &remote_syncpoints);
+
+    // Fetch all remote changes that have been made on the remote server
+    let remote_changes = with_retries(&retry_policy, job_id, || foreign_server.get_changes(&sync_merge_start))
+        .chain_err(|| "Failed to get remote changes")?;
+
+    // Remote changes that were not fetched come before the common syncpoint, so
+    // the remote syncpoints and changes cover the whole remote history
+    let sync_clock = remote_syncpoints.iter().map(|point| point.clock)
+        .chain(remote_changes.iter().map(|change| change.clock.counter))
+        .chain(Some(fdb.current_clock()?))
+        .max()
+        .unwrap_or(0) + 1;
+    let sync_clock = ::std::cmp::max(sync_clock, timestamp_clock(now));
+    // Changes made from now on come after the new syncpoint and are sent by
+    // the next sync
+    fdb.reserve_clock(sync_clock)?;
+
+    // Get the changes that have been made locally since that change
+    let local_changes = match sync_merge_start {
+        Some(ref syncpoint) => fdb.get_changes_after_clock(syncpoint.clock),
+        None => fdb.get_all_changes()
+    }.chain_err(|| "Failed to get local changes")?
+        .into_iter()
+        .filter(|change| change.clock.counter <= sync_clock)
+        .collect::<Vec<_>>();
+
+    // Find all files that have been removed
+    let mut removed_files = vec!();
+    removed_files.extend_from_slice(&get_removed_files(&local_changes));
+    removed_files.extend_from_slice(&get_removed_files(&remote_changes));
+
+    let resolved = resolve_conflicts(
+        SyncedChanges {
+            local_changes,
+            remote_changes,
+            removed_files
+        },
+        policies,
+        |id| Ok(fdb.get_file_with_id_result(id)?.tags),
+        |id| Ok(with_retries(&retry_policy, job_id, || foreign_server.get_file_details(id))?.tags),
+        now,
+        &LogicalClock {
+            counter: sync_clock,
+            instance: fdb.get_instance_id()?
+        }
+    ).chain_err(|| "Failed to resolve conflicts")?;
+
+    Ok(ChangeSets {
+        last_common_syncpoint: sync_merge_start,
+        local_syncpoints,
+        remote_syncpoints,
+        local_changes: resolved.local_changes,
+        remote_changes: resolved.remote_changes,
+        removed_files: resolved.removed_files,
+        conflicts: resolved.conflicts,
+        sync_clock
+    })
+}
+
+/**
+  How the foreign server gets the files it needs from this instance during a sync
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    /// The foreign server connects to this instance, which listens on the port,
+    /// to fetch the files
+    Callback(u16),
+    /// The files are pushed to the foreign server which never connects to this
+    /// instance. This works behind NAT and reverse proxies. The port is only
+    /// used by the foreign server to identify this instance
+    Push(u16)
+}
+
+/**
+  How changes received from a foreign server are applied, see
+  `apply_changes_with_filter`
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct ApplyOptions<'a> {
+    /// The amount of files that are fetched at the same time
+    pub fetch_workers: usize,
+    /// Only changes to files that match this are applied
+    pub filter: Option<&'a SavedSearchQuery>,
+    /// Only the thumbnails of new files are fetched
+    pub lazy_originals: bool,
+    /// The clock counter the changes are stored with instead of the one they
+    /// were received with, see `apply_changes_with_filter`
+    pub receive_clock: Option<i64>
+}
+
+impl<'a> ApplyOptions<'a> {
+    /**
+      Applies all changes and fetches the originals of all files
+    */
+    pub fn unfiltered(fetch_workers: usize) -> Self {
+        Self {
+            fetch_workers,
+            filter: None,
+            lazy_originals: false,
+            receive_clock: None
+        }
+    }
+}
+
+/**
+  How a sync with a foreign server is done
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct SyncOptions<'a> {
+    pub transfer_mode: TransferMode,
+    pub conflict_policies: &'a ConflictPolicies,
+    /// How the changes from the foreign server are applied here
+    pub apply: ApplyOptions<'a>
+}
+
 pub fn sync_with_foreign(
     fdb: &FileDatabase,
-    foreign_server: &mut ForeignServer,
-    own_port: u16,
+    foreign_server: &mut dyn ForeignServer,
+    options: &SyncOptions,
     progress_reporter: &sp::LocalTxType
-) -> Result<()> {
+) -> Result<TransferCounts> {
     let (job_id, progress_tx) = progress_reporter;
 
+    let retry_policy = sync_control::retry_policy(*job_id);
+
+    let peer = with_retries(&retry_policy, Some(*job_id), || foreign_server.handshake())
+        .chain_err(|| "Failed to get the handshake of the foreign server")?;
+    peer.check_compatible()?;
+
+    // Peers without push support fetch the files themselves like before
+    let transfer_mode = match options.transfer_mode {
+        TransferMode::Push(own_port) if !peer.supports(FEATURE_PUSH) => {
+            println!("Warning: The foreign server can't receive pushed files, it fetches them instead");
+            TransferMode::Callback(own_port)
+        }
+        transfer_mode => transfer_mode
+    };
+
+    let sync_time = NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0);
+
     let (
         local_changes,
+        eviction_candidates,
         new_local_syncpoints,
         new_remote_syncpoints,
         removed_files,
-        remote_changes
+        remote_changes,
+        conflicts,
+        sync_clock
     ) = {
-        // Get the syncpoints from the local and remote servers
-        let local_syncpoints = fdb.get_syncpoints()
-            .chain_err(|| "Failed to get local syncpoints")?;
-        let remote_syncpoints = foreign_server.get_syncpoints()
-            .chain_err(|| "Failed to get remote syncpoints")?;
-
-        // Find the highest common syncpoint
-        let sync_merge_start = last_common_syncpoint(&local_syncpoints, &remote_syncpoints);
-
-        // Get the changes that have been made locally since that change
-        let local_changes = match sync_merge_start {
-            Some(ref syncpoint) => fdb.get_changes_after_timestamp(&syncpoint.last_change),
-            None => fdb.get_all_changes()
-        }.chain_err(|| "Failed to get local changes")?;
-        // Fetch all remote changes that have been made on the remote server
-        let remote_changes = foreign_server.get_changes(&sync_merge_start)
-            .chain_err(|| "Failed to get remote changes")?;
-
-        // Find all files that have been removed
-        let mut removed_files = vec!();
-        removed_files.extend_from_slice(&get_removed_files(&local_changes));
-        removed_files.extend_from_slice(&get_removed_files(&remote_changes));
+        let ChangeSets {
+            local_syncpoints,
+            remote_syncpoints,
+            local_changes,
+            remote_changes,
+            removed_files,
+            conflicts,
+            sync_clock,
+            ..
+        } = gather_change_sets(fdb, foreign_server, options.conflict_policies, sync_time, Some(*job_id))?;
 
         let mut new_local_syncpoints = remote_syncpoints.clone().into_iter()
                     .filter(|p| !local_syncpoints.contains(p))
@@ -94,9 +280,11 @@ pub fn sync_with_foreign(
                     .filter(|p| !remote_syncpoints.contains(p))
                     .collect::<Vec<_>>();
 
-        // Create a new syncpoint
+        // Create a new syncpoint. Changes made to resolve conflicts have the same
+        // clock so they are not sent again in the next sync
         let new_syncpoint = SyncPoint{
-                last_change: NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0)
+                last_change: sync_time,
+                clock: sync_clock
             };
 
         new_local_syncpoints.push(new_syncpoint.clone());
@@ -105,26 +293,87 @@ pub fn sync_with_foreign(
         progress_tx.send((*job_id, sp::SyncUpdate::GatheredData))
             .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
-        (local_changes, new_local_syncpoints, new_remote_syncpoints, removed_files, remote_changes)
+        // Nothing has been changed on either side yet
+        sync_control::check_job(*job_id)?;
+        let local_changes = peer.supported_changes(local_changes)?;
+
+        // Files that were added here since the last sync are kept even if they
+        // don't match the filter since they have not been tagged yet
+        let added_locally = local_changes.iter()
+            .filter(|change| change.change_type == ChangeType::FileAdded)
+            .map(|change| change.affected_file)
+            .collect::<Vec<_>>();
+        let eviction_candidates = local_changes.iter().chain(remote_changes.iter())
+            .map(|change| change.affected_file)
+            .filter(|file| !added_locally.contains(file))
+            .fold(vec!(), add_unique);
+
+        (
+            local_changes,
+            eviction_candidates,
+            new_local_syncpoints,
+            new_remote_syncpoints,
+            removed_files,
+            remote_changes,
+            conflicts,
+            sync_clock
+        )
+    };
+
+    let mut counts = TransferCounts {
+        changes_sent: local_changes.len(),
+        changes_received: remote_changes.len(),
+        files_sent: local_changes.iter()
+            .filter(|change| change.change_type == ChangeType::FileAdded)
+            .filter(|change| !removed_files.contains(&change.affected_file))
+            .count(),
+        files_received: 0
+    };
+
+    keep_discarded_content(fdb, foreign_server, &conflicts, *job_id)
+        .chain_err(|| "Failed to keep the content that lost conflicts")?;
+
+    let change_data = ChangeData{
+        changes: local_changes,
+        removed_files: removed_files.clone(),
+        file_details: HashMap::new(),
+        clock: Some(sync_clock)
     };
 
     // Send the changes to the remote server to apply
-    let foreign_job_id = foreign_server.send_changes(
-        &ChangeData{
-            changes: local_changes,
-            removed_files: removed_files.clone()
-        },
-        own_port
-    )
+    let foreign_job_id = match transfer_mode {
+        TransferMode::Callback(own_port) => {
+            with_retries(&retry_policy, Some(*job_id), || foreign_server.send_changes(&change_data, own_port, None))
+        }
+        TransferMode::Push(own_port) => {
+            push_changes(fdb, foreign_server, change_data, own_port, progress_reporter)
+                .map(|(foreign_job_id, pushed_files)| {
+                    counts.files_sent = pushed_files;
+                    foreign_job_id
+                })
+        }
+    }
         .chain_err(|| "Failed to send changes")?;
 
     progress_tx.send((*job_id, sp::SyncUpdate::SentToForeign(foreign_job_id)))
         .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
     // Apply changes locally
-    apply_changes(fdb, foreign_server, &remote_changes, &removed_files, progress_reporter)
+    counts.files_received = apply_changes_with_filter(
+        fdb,
+        foreign_server,
+        &remote_changes,
+        &removed_files,
+        &ApplyOptions {receive_clock: Some(sync_clock), ..options.apply},
+        progress_reporter
+    )
+        .map_err(|e| stop_foreign_job(foreign_server, &peer, foreign_job_id, *job_id, e))
         .chain_err(|| "Failed to apply changes")?;
 
+    for conflict in &conflicts {
+        fdb.add_sync_conflict(conflict, sync_time)?;
+    }
+
     progress_tx.send((*job_id, sp::SyncUpdate::AddingSyncpoint))
             .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
@@ -132,28 +381,9 @@ pub fn sync_with_foreign(
     progress_tx.send((*job_id, sp::SyncUpdate::WaitingForForeign))
             .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
-    // We will retry this a couple of times because it is possible
-    // for things to happen before the other side adds the job to its
-    // list
-    let mut error_amount = 0;
-    loop {
-        let status = foreign_server.get_sync_status(foreign_job_id);
-        match status {
-            Ok(status) => if let sp::SyncUpdate::Done = status.last_update {
-                break;
-            },
-            Err(e) => {
-                if error_amount > 5 {
-                    return Err(e)
-                }
-                else {
-                    error_amount += 1
-                }
-            }
-        }
-
-        thread::sleep(::std::time::Duration::from_secs(1));
-    }
+    wait_for_foreign_job(foreign_server, foreign_job_id, *job_id)
+        .map_err(|e| stop_foreign_job(foreign_server, &peer, foreign_job_id, *job_id, e))
+        .chain_err(|| "Failed to wait for the foreign server")?;
 
     for point in new_remote_syncpoints {
         foreign_server.add_syncpoint(&point)
@@ -167,10 +397,279 @@ pub fn sync_with_foreign(
         fdb.add_syncpoint(&point)?;
     }
 
+    // The foreign server has applied our changes by now so it has a copy of
+    // everything that is evicted
+    if let Some(filter) = options.apply.filter {
+        evict_unmatched_files(fdb, foreign_server, &eviction_candidates, filter)
+            .chain_err(|| "Failed to remove files that don't match the sync filter")?;
+    }
+
     progress_tx.send((*job_id, sp::SyncUpdate::Done))
         .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
-    Ok(())
+    Ok(counts)
+}
+
+/**
+  Follows the updates of the job `foreign_job_id` on the foreign server until
+  it is done.
+
+  Following the job is retried according to the retry policy of the local job
+  `job_id` since the foreign server may not know about the job right after
+  returning its id, and because the connection may be lost. Fails right away if
+  the job itself fails, or if the local job is stopped since the foreign server
+  may keep sending updates without ever finishing
+*/
+fn wait_for_foreign_job(foreign_server: &dyn ForeignServer, foreign_job_id: usize, job_id: usize)
+    -> Result<()>
+{
+    let retry_policy = sync_control::retry_policy(job_id);
+
+    let mut last_error = None;
+    for attempt in 0..retry_policy.retries + 1 {
+        if attempt > 0 {
+            sync_control::wait(job_id, retry_policy.backoff(attempt))?;
+        }
+
+        match foreign_server.get_sync_updates(foreign_job_id) {
+            Ok(updates) => {
+                for update in updates {
+                    sync_control::check_job(job_id)?;
+
+                    match update {
+                        Ok(sp::SyncUpdate::Done) => return Ok(()),
+                        Ok(sp::SyncUpdate::Error(e)) => bail!(ErrorKind::ForeignSyncFailed(e)),
+                        Ok(_) => {}
+                        Err(e) => {
+                            last_error = Some(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => last_error = Some(e)
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| "The foreign server stopped sending updates".into()))
+}
+
+/**
+  Cancels the job that applies the local changes on the foreign server if the
+  local job was stopped, since nobody waits for it any more. Peers that can't
+  cancel jobs finish them on their own. Returns `error` to be used with `map_err`
+*/
+fn stop_foreign_job(
+    foreign_server: &dyn ForeignServer,
+    peer: &Handshake,
+    foreign_job_id: usize,
+    job_id: usize,
+    error: Error
+) -> Error {
+    if sync_control::check_job(job_id).is_err() && peer.supports(FEATURE_CANCEL) {
+        foreign_server.cancel_job(foreign_job_id).unwrap_or_else(|e| {
+            println!("Warning: Failed to cancel job {} on the foreign server: {}", foreign_job_id, e)
+        });
+    }
+    error
+}
+
+/**
+  Applies the changes made on `foreign_server` since the last syncpoint in
+  common without sending any changes back, for foreign servers like bundles
+  which can't receive changes. No syncpoint is added since the foreign server
+  doesn't get the local changes, so they are still sent by the next sync
+*/
+pub fn receive_from_foreign(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    fetch_workers: usize,
+    conflict_policies: &ConflictPolicies,
+    progress_reporter: &sp::LocalTxType
+) -> Result<TransferCounts> {
+    let (job_id, progress_tx) = progress_reporter;
+
+    let sync_time = NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0);
+
+    let ChangeSets {
+        remote_changes,
+        removed_files,
+        conflicts,
+        ..
+    } = gather_change_sets(fdb, foreign_server, conflict_policies, sync_time, Some(*job_id))?;
+
+    progress_tx.send((*job_id, sp::SyncUpdate::GatheredData))
+        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
+    keep_discarded_content(fdb, foreign_server, &conflicts, *job_id)
+        .chain_err(|| "Failed to keep the content that lost conflicts")?;
+
+    let files_received = apply_changes_with_filter(
+        fdb,
+        foreign_server,
+        &remote_changes,
+        &removed_files,
+        &ApplyOptions::unfiltered(fetch_workers),
+        progress_reporter
+    )
+        .chain_err(|| "Failed to apply changes")?;
+
+    for conflict in &conflicts {
+        fdb.add_sync_conflict(conflict, sync_time)?;
+    }
+
+    Ok(TransferCounts {
+        changes_received: remote_changes.len(),
+        files_received,
+        .. TransferCounts::default()
+    })
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagChange {
+    pub file_id: i32,
+    pub tag: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DateChange {
+    pub file_id: i32,
+    pub date: NaiveDateTime
+}
+
+/**
+  The changes a sync would make to one of the servers
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SyncSidePreview {
+    pub files_added: Vec<i32>,
+    pub files_removed: Vec<i32>,
+    pub tags_added: Vec<TagChange>,
+    pub tags_removed: Vec<TagChange>,
+    pub dates_changed: Vec<DateChange>,
+    /// Files whose content was replaced
+    pub contents_changed: Vec<i32>,
+    /// Size of the added and replaced files and their sidecars. Files from
+    /// servers which don't report their size are not counted
+    pub bytes_to_transfer: u64
+}
+
+/**
+  Summary of what `sync_with_foreign` would do
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyncPreview {
+    pub last_common_syncpoint: Option<SyncPoint>,
+    /// Changes that would be made to this server
+    pub local: SyncSidePreview,
+    /// Changes that would be made to the foreign server
+    pub foreign: SyncSidePreview,
+    /// Conflicting changes and how they would be resolved. The sides above
+    /// only contain the changes that are kept
+    pub conflicts: Vec<ConflictResolution>
+}
+
+/**
+  Works out what syncing with `foreign_server` would do without changing
+  anything on either server
+*/
+pub fn preview_sync(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    conflict_policies: &ConflictPolicies
+) -> Result<SyncPreview> {
+    let now = NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0);
+    let change_sets = gather_change_sets(fdb, foreign_server, conflict_policies, now, None)?;
+
+    // Files that are added here are downloaded from the foreign server
+    let local = preview_side(
+        &change_sets.remote_changes,
+        &change_sets.removed_files,
+        |id| fdb.get_file_with_id(id).is_some(),
+        |id| Ok(foreign_server.get_file_details(id)?.size)
+    )?;
+    // The foreign server applies removals to whatever files it has, but the
+    // ones it removed itself are already gone
+    let local_removals = get_removed_files(&change_sets.local_changes);
+    let foreign = preview_side(
+        &change_sets.local_changes,
+        &local_removals,
+        |_| false,
+        |id| {
+            let file = fdb.get_file_with_id_result(id)?;
+            file_handler::stored_size(&file, fdb)
+        }
+    )?;
+
+    Ok(SyncPreview {
+        last_common_syncpoint: change_sets.last_common_syncpoint,
+        local: SyncSidePreview {
+            files_removed: change_sets.removed_files.into_iter()
+                .filter(|id| fdb.get_file_with_id(*id).is_some())
+                .fold(vec!(), add_unique),
+            ..local
+        },
+        foreign: SyncSidePreview {
+            files_removed: local_removals.into_iter().fold(vec!(), add_unique),
+            ..foreign
+        },
+        conflicts: change_sets.conflicts
+    })
+}
+
+fn add_unique<T: PartialEq>(mut list: Vec<T>, value: T) -> Vec<T> {
+    if !list.contains(&value) {
+        list.push(value);
+    }
+    list
+}
+
+/**
+  Summarises the effect of applying `changes` to a server. `exists` checks if
+  the server already has a file and `size` looks up how many bytes have to be
+  transferred to add or replace one. `files_removed` is left empty
+*/
+fn preview_side<E, S>(changes: &[Change], removed_files: &[i32], exists: E, size: S)
+    -> Result<SyncSidePreview>
+    where E: Fn(i32) -> bool,
+          S: Fn(i32) -> Result<Option<u64>>
+{
+    let mut preview = SyncSidePreview::default();
+
+    let changes = changes.iter()
+        .filter(|change| !removed_files.contains(&change.affected_file));
+
+    for change in changes {
+        let file_id = change.affected_file;
+        match change.change_type {
+            ChangeType::FileAdded => {
+                if !exists(file_id) && !preview.files_added.contains(&file_id) {
+                    preview.files_added.push(file_id);
+                    preview.bytes_to_transfer += size(file_id)?.unwrap_or(0);
+                }
+            }
+            ChangeType::Update(UpdateType::TagAdded(ref tag)) => {
+                preview.tags_added.push(TagChange{file_id, tag: tag.clone()})
+            }
+            ChangeType::Update(UpdateType::TagRemoved(ref tag)) => {
+                preview.tags_removed.push(TagChange{file_id, tag: tag.clone()})
+            }
+            ChangeType::Update(UpdateType::CreationDateChanged(date)) => {
+                preview.dates_changed.push(DateChange{file_id, date})
+            }
+            ChangeType::Update(UpdateType::ContentChanged(_)) => {
+                if !preview.contents_changed.contains(&file_id) {
+                    preview.contents_changed.push(file_id);
+                    preview.bytes_to_transfer += size(file_id)?.unwrap_or(0);
+                }
+            }
+            // Changes to removed files are filtered out above
+            ChangeType::FileRemoved => {}
+        }
+    }
+
+    Ok(preview)
 }
 
 
@@ -178,46 +677,169 @@ pub fn sync_with_foreign(
   Applies the specified changes to the database. Any changes affecting files in 
   the `removed_files` vec are ignored and the files are removed
 
+  The content of added and replaced files is fetched by `fetch_workers`
+  threads ahead of the changes that need it, while the changes themselves are
+  applied one by one in the order they are given.
+
   The function does not check for changes that are already in the database which
   means that such changes would be duplicated.
+
+  If a `filter` is specified, only the changes to files that are already stored
+  here or that match the filter on the foreign server are applied. All changes
+  are still added to the change log.
+
+  With a `receive_clock`, the changes are added to the change log with that
+  clock counter, which should be the one of the syncpoint the sync creates.
+  Their original counters may be smaller than the syncpoints shared with other
+  peers, which would stop the changes from being passed on to them.
+  Changes created from now on get larger counters
+
+  Files that start matching the filter are fetched even if they were added
+  before the synced changes.
+
+  With `lazy_originals`, only the thumbnails of new files are fetched. Their
+  originals are fetched by `originals::ensure_original` when they are requested.
+  Replaced content is only fetched for files whose originals are stored here
+  permanently
+
+  Returns the amount of files that were fetched from the foreign server
 */
-pub fn apply_changes(
+pub fn apply_changes_with_filter(
         fdb: &FileDatabase,
-        foreign_server: &ForeignServer,
+        foreign_server: &dyn ForeignServer,
         changes: &[Change],
         removed_files: &[i32],
+        options: &ApplyOptions,
         (job_id, progress_tx): &sp::LocalTxType,
-    ) -> Result<()>
+    ) -> Result<usize>
 {
-    let changes_to_be_applied = changes.iter().filter(|change| {
-        !removed_files.contains(&change.affected_file)
-    }).collect::<Vec<_>>();
-
-    let mut changes_left = changes_to_be_applied.len();
-    for change in changes_to_be_applied {
-        changes_left -= 1;
-        progress_tx.send((
-            *job_id,
-            sp::SyncUpdate::StartingToApplyChange(changes_left)
-        ))
-        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+    let ApplyOptions { fetch_workers, filter, lazy_originals, receive_clock } = *options;
 
-        apply_change(fdb, change, foreign_server)
-            .chain_err(|| {
-                format!(
-                    "Failed to apply change, affected file: {}",
-                    change.affected_file
-                )
-            })?;
+    if let Some(clock) = receive_clock {
+        fdb.reserve_clock(clock)?;
     }
 
+    let changes_to_be_applied = select_changes(
+        fdb,
+        foreign_server,
+        changes,
+        removed_files,
+        filter,
+        Some(*job_id)
+    )?;
+
+    let files_to_fetch = files_needing_content(fdb, &changes_to_be_applied);
+
+    let fetched_amount = files_to_fetch.len();
+
+    let save_path = fdb.get_file_save_path();
+    let progress = Mutex::new(sp::DownloadProgress {
+        in_flight: 0,
+        finished: 0,
+        total: files_to_fetch.len(),
+        bytes_received: 0,
+        bytes_total: 0
+    });
+    let last_byte_report = Mutex::new(Instant::now());
+    let cancelled = AtomicBool::new(false);
+
+    let mut fetched_files = HashMap::new();
+    let queue = Mutex::new(files_to_fetch.into_iter()
+        .map(|(id, is_new)| {
+            let (tx, rx) = sync_channel(1);
+            fetched_files.insert(id, rx);
+            // Replaced content is only fetched for files that store their original
+            (id, is_new && lazy_originals, tx)
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+    );
+
+    let result = thread::scope(|scope| {
+        for _ in 0..fetch_workers.max(1) {
+            scope.spawn(|| {
+                // The queue is in the order the files are needed which lets
+                // the changes be applied while later files are being fetched
+                while !cancelled.load(Ordering::SeqCst) {
+                    let next = queue.lock().unwrap().next();
+                    let (file_id, thumbnail_only, result_tx) = match next {
+                        Some(job) => job,
+                        None => break
+                    };
+
+                    update_download_progress(&progress, (*job_id, progress_tx), |p| p.in_flight += 1);
+                    // The remaining files fail right away once the job is stopped
+                    let fetched = sync_control::check_job(*job_id).and_then(|_| {
+                        fetch_file(foreign_server, file_id, &save_path, thumbnail_only, *job_id, &|event| {
+                            report_received_bytes(
+                                &progress,
+                                &last_byte_report,
+                                (*job_id, progress_tx),
+                                event
+                            )
+                        })
+                    });
+                    update_download_progress(&progress, (*job_id, progress_tx), |p| {
+                        p.in_flight -= 1;
+                        p.finished += 1;
+                    });
+
+                    // The receiver is gone if applying an earlier change failed
+                    result_tx.send(fetched).ok();
+                }
+            });
+        }
+
+        let mut changes_left = changes_to_be_applied.len();
+        for change in &changes_to_be_applied {
+            changes_left -= 1;
+            progress_tx.send((
+                *job_id,
+                sp::SyncUpdate::StartingToApplyChange(changes_left)
+            ))
+            .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
+            let fetched = match change.change_type {
+                ChangeType::FileAdded | ChangeType::Update(UpdateType::ContentChanged(_)) => {
+                    fetched_files.remove(&change.affected_file)
+                }
+                _ => None
+            }.map(|rx| rx.recv().expect("File fetching worker crashed"));
+
+            // The job is only stopped between changes. Changes are applied again
+            // by the next sync since no syncpoint is added
+            let result = sync_control::check_job(*job_id)
+                .and_then(|_| apply_change(fdb, change, fetched, foreign_server, lazy_originals, *job_id))
+                .chain_err(|| {
+                    format!(
+                        "Failed to apply change, affected file: {}",
+                        change.affected_file
+                    )
+                });
+
+            if result.is_err() {
+                // Stop the workers from starting downloads that will never be used
+                cancelled.store(true, Ordering::SeqCst);
+                return result;
+            }
+        }
+        Ok(())
+    });
+    result?;
+
     let mut changes_to_be_added = changes.len();
     for change in changes {
         changes_to_be_added -= 1;
         progress_tx.send((*job_id, sp::SyncUpdate::AddingChangeToDb(changes_to_be_added)))
             .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
-        fdb.add_change(change)?;
+        match receive_clock {
+            Some(counter) => {
+                let instance = change.clock.instance.clone();
+                fdb.add_change(&change.clone().with_clock(LogicalClock {counter, instance}))?
+            }
+            None => fdb.add_change(change)?
+        }
     }
 
     let mut files_to_remove = removed_files.len();
@@ -231,120 +853,572 @@ pub fn apply_changes(
         }
     }
 
-    Ok(())
+    Ok(fetched_amount)
 }
 
-fn fetch_with_retries(server: &ForeignServer, file_id: i32, max_retries: usize) -> Result<Vec<u8>> {
-    let mut last_err = None;
-    for _ in 0..max_retries+1 {
-        let file_bytes = server.get_file(file_id);
-        match file_bytes {
-            Ok(bytes) => {return Ok(bytes)}
-            Err(e) => {
-                println!("Warning: failed to fetch file, retrying");
-                println!("{}", e);
-                last_err = Some(e)
-            }
-        }
-    }
-    return Err(last_err.unwrap())
+/**
+  Returns the files that have to be fetched from the foreign server to apply
+  `changes`, in the order they are needed. See `apply_changes_with_filter`
+*/
+pub fn files_to_fetch(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    changes: &[Change],
+    removed_files: &[i32],
+    filter: Option<&SavedSearchQuery>
+) -> Result<Vec<i32>> {
+    let selected = select_changes(fdb, foreign_server, changes, removed_files, filter, None)?;
+
+    Ok(files_needing_content(fdb, &selected).into_iter().map(|(id, _)| id).collect())
 }
 
-fn apply_change(
+/**
+  Selects the changes that should be applied, leaving out changes to files
+  that are removed or don't match `filter`
+*/
+fn select_changes(
     fdb: &FileDatabase,
-    change: &Change,
-    foreign_server: &ForeignServer
-) -> Result<()> {
-    match change.change_type {
-        ChangeType::Update(ref update_type) => {
-            apply_file_update(&fdb, change.affected_file, update_type)?
-        }
-        ChangeType::FileAdded => {
-            // Check if the file is already in the database if it is, ignore it and print
-            // a warning
-            if fdb.get_file_with_id(change.affected_file) == None {
-                let file_details = foreign_server.get_file_details(change.affected_file)
-                    .chain_err(|| "Failed to get fille details")?;
-
-                let file = ByteSource::Memory(
-                    fetch_with_retries(foreign_server, change.affected_file, 1)?
-                );
+    foreign_server: &dyn ForeignServer,
+    changes: &[Change],
+    removed_files: &[i32],
+    filter: Option<&SavedSearchQuery>,
+    job_id: Option<usize>
+) -> Result<Vec<Change>> {
+    let selected = match filter {
+        Some(filter) => select_filtered_changes(fdb, foreign_server, changes, removed_files, filter, job_id)?,
+        None => changes.to_vec()
+    };
 
-                let thumbnail = {
-                    let from_server = foreign_server.get_thumbnail(change.affected_file)
-                        .unwrap_or_else(|e| {
-                            println!("Failed to get thumbnail, defaulting to None. Error: {:?}", e);
-                            None
-                        });
-
-                    match from_server {
-                        Some(data) =>
-                            ThumbnailStrategy::FromByteSource(ByteSource::Memory(data)),
-                        None => ThumbnailStrategy::None
-                    }
-                };
+    Ok(selected.into_iter()
+        .filter(|change| !removed_files.contains(&change.affected_file))
+        .collect())
+}
 
-                let file_timestamp = file_details.timestamp;
+/**
+  Returns the files whose content has to be fetched to apply `changes`,
+  together with whether they are new. These are the files that are added by
+  `changes` but not stored here and the stored files whose content was
+  replaced by different content, unless their originals are stored elsewhere.
+  Each file is only included once, even if several changes claim to add it
+  or its content was replaced several times
+*/
+fn files_needing_content(fdb: &FileDatabase, changes: &[Change]) -> Vec<(i32, bool)> {
+    let mut files = vec!();
+    for change in changes {
+        let file_id = change.affected_file;
+        if files.iter().any(|&(id, _)| id == file_id) {
+            continue;
+        }
 
-                file_handler::save_file(
-                            file,
-                            thumbnail,
-                            change.affected_file,
-                            &[],
-                            &fdb,
-                            &ChangeCreationPolicy::No,
-                            &file_details.extension,
-                            file_timestamp.timestamp() as u64
-                        ).chain_err(|| "Failed to save file")?;
-            }
-            else {
-                println!(
-                    "A file with id {} was already in the database. Ignoring",
-                    change.affected_file
-                );
+        let stored = fdb.get_file_with_id(file_id);
+        match (&change.change_type, stored) {
+            (&ChangeType::FileAdded, None) => files.push((file_id, true)),
+            (&ChangeType::Update(UpdateType::ContentChanged(ref hash)), Some(file)) => {
+                let original_stored = file.is_uploaded && file.original_accessed.is_none();
+                if original_stored && file.content_hash.as_ref() != Some(hash) {
+                    files.push((file_id, false));
+                }
             }
-        }
-        ChangeType::FileRemoved => {
-            file_handler::remove_file(change.affected_file, &fdb, &ChangeCreationPolicy::No)?;
+            _ => {}
         }
     }
-
-    Ok(())
+    files
 }
 
+/**
+  Selects the changes to apply when only the files matching `filter` should be
+  stored. Whether a file matches is decided by its current state on the foreign
+  server.
 
-fn apply_file_update(fdb: &FileDatabase, affected_file: i32, file_update: &UpdateType)
-    -> Result<()>
-{
-    let mut file = match fdb.get_file_with_id(affected_file) {
-        Some(id) => id,
-        None => return Err(ErrorKind::NoSuchFileInDatabase(affected_file).into())
-    };
+  If a file which is not stored here starts matching, the selected changes
+  start with unlogged changes that add the file and its current tags, since
+  the change that added it may have been synced before
+*/
+fn select_filtered_changes(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    changes: &[Change],
+    removed_files: &[i32],
+    filter: &SavedSearchQuery,
+    job_id: Option<usize>
+) -> Result<Vec<Change>> {
+    let retry_policy = job_id.map_or_else(RetryPolicy::default, sync_control::retry_policy);
+    let mut selected_files = HashMap::new();
+    let mut selected = vec!();
+    for change in changes {
+        let file_id = change.affected_file;
 
-    match *file_update {
-        UpdateType::TagAdded(ref tag) => {
-            if !file.tags.contains(&tag) {
-                file.tags.push(tag.clone())
+        if let Entry::Vacant(entry) = selected_files.entry(file_id) {
+            let is_selected = if removed_files.contains(&file_id)
+                || fdb.get_file_with_id(file_id).is_some()
+            {
+                true
             }
-        },
-        UpdateType::TagRemoved(ref tag) => {
-            file.tags = file.tags.into_iter().filter(|t| t != tag).collect()
+            else {
+                let details = with_retries(&retry_policy, job_id, || foreign_server.get_file_details(file_id))
+                    .chain_err(|| "Failed to get file details for filtering")?;
+
+                let matches = filter.matches(&details.tags, &details.timestamp);
+                if matches && change.change_type != ChangeType::FileAdded {
+                    selected.push(
+                        Change::new(change.timestamp, file_id, ChangeType::FileAdded)
+                            .with_clock(change.clock.clone())
+                    );
+                    for tag in details.tags {
+                        selected.push(
+                            Change::new(
+                                change.timestamp,
+                                file_id,
+                                ChangeType::Update(UpdateType::TagAdded(tag))
+                            ).with_clock(change.clock.clone())
+                        );
+                    }
+                }
+                matches
+            };
+            entry.insert(is_selected);
         }
-        UpdateType::CreationDateChanged(date) => file.creation_date = date
-    }
 
-    fdb.update_file_without_creating_change(&file)?;
-    Ok(())
+        if selected_files[&file_id] {
+            selected.push(change.clone());
+        }
+    }
+    Ok(selected)
 }
 
+/**
+  Removes the files in `candidates` which are stored here but don't match
+  `filter`, without creating changes for the removals. Files are only removed
+  if the foreign server stores the original with the same content, which makes
+  sure that nothing is lost. Foreign servers that only keep thumbnails or
+  fetch originals on demand, or are too old to tell, keep the files here.
 
-#[cfg(test)]
-mod sync_tests {
-    use super::*;
+  Returns the ids of the removed files
+*/
+pub fn evict_unmatched_files(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    candidates: &[i32],
+    filter: &SavedSearchQuery
+) -> Result<Vec<i32>> {
+    let mut evicted = vec!();
+    for id in candidates {
+        let file = match fdb.get_file_with_id(*id) {
+            Some(file) => file,
+            None => continue
+        };
 
-    use std::collections::HashMap;
+        if filter.matches(&file.tags, &file.creation_date) {
+            continue;
+        }
 
-    use file_database::db_test_helpers;
+        let details = match foreign_server.get_file_details(*id) {
+            Ok(details) => details,
+            Err(e) => {
+                println!("Keeping file {} which the foreign server can't provide: {}", id, e);
+                continue;
+            }
+        };
+
+        if !details.original_stored {
+            println!("Keeping file {} since the foreign server doesn't store its original", id);
+            continue;
+        }
+
+        // The content can only be compared if both sides know its hash
+        let same_content = match (&file.content_hash, &details.content_hash) {
+            (Some(own), Some(foreign)) => own == foreign,
+            _ => false
+        };
+        if !same_content {
+            println!("Keeping file {} since the foreign server may have different content", id);
+            continue;
+        }
+
+        remove_file(*id, fdb, &ChangeCreationPolicy::No)?;
+        evicted.push(*id);
+    }
+    Ok(evicted)
+}
+
+fn update_download_progress<F>(
+    progress: &Mutex<sp::DownloadProgress>,
+    (job_id, progress_tx): (usize, &sp::TxType),
+    update: F
+)
+    where F: FnOnce(&mut sp::DownloadProgress)
+{
+    let mut progress = progress.lock().unwrap();
+    update(&mut progress);
+    progress_tx.send((job_id, sp::SyncUpdate::Downloading(progress.clone())))
+        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+}
+
+/**
+  Progress of a single file download
+*/
+enum ByteProgress {
+    /// The size of the file and its sidecars is known
+    SizeKnown(u64),
+    /// The specified amount of bytes were received
+    Received(u64)
+}
+
+/**
+  Adds the size or received bytes of a file to the download progress. Updates
+  about received bytes are only sent every `BYTE_PROGRESS_INTERVAL` since
+  they arrive for every chunk of a download
+*/
+fn report_received_bytes(
+    progress: &Mutex<sp::DownloadProgress>,
+    last_report: &Mutex<Instant>,
+    reporter: (usize, &sp::TxType),
+    event: ByteProgress
+) {
+    match event {
+        ByteProgress::SizeKnown(size) => {
+            update_download_progress(progress, reporter, |p| p.bytes_total += size)
+        }
+        ByteProgress::Received(amount) => {
+            let mut last_report = last_report.lock().unwrap();
+            if last_report.elapsed() >= BYTE_PROGRESS_INTERVAL {
+                *last_report = Instant::now();
+                update_download_progress(progress, reporter, |p| p.bytes_received += amount);
+            }
+            else {
+                progress.lock().unwrap().bytes_received += amount;
+            }
+        }
+    }
+}
+
+/**
+  Everything needed to add a file from a foreign server to the database
+*/
+struct FetchedFile {
+    details: FileDetails,
+    /// Where the content of the file was downloaded to, `None` if only the
+    /// thumbnail was fetched
+    download_path: Option<PathBuf>,
+    thumbnail: ThumbnailStrategy,
+    sidecars: Vec<Sidecar>
+}
+
+/**
+  Fetches a file and everything that belongs to it from the foreign server
+  without touching the database, or only its details and thumbnail if
+  `thumbnail_only` is set. The size of the file and the bytes that are
+  received are reported to `on_progress`. Failed downloads are retried
+  according to the retry policy of the job `job_id`
+*/
+fn fetch_file(
+    foreign_server: &dyn ForeignServer,
+    file_id: i32,
+    save_path: &Path,
+    thumbnail_only: bool,
+    job_id: usize,
+    on_progress: &dyn Fn(ByteProgress)
+) -> Result<FetchedFile> {
+    let retry_policy = sync_control::retry_policy(job_id);
+    let details = with_retries(&retry_policy, Some(job_id), || foreign_server.get_file_details(file_id))
+        .chain_err(|| "Failed to get file details")?;
+
+    let download_path = if thumbnail_only {
+        None
+    }
+    else {
+        if let Some(size) = details.size {
+            on_progress(ByteProgress::SizeKnown(size));
+        }
+
+        // The file is downloaded into the storage folder so it can be moved into
+        // place once it is complete. Partial downloads are kept to be resumed later
+        let download_path = save_path.join(format!("{}.{}.download", file_id, details.extension));
+        download_with_retries(
+            foreign_server,
+            file_id,
+            &download_path,
+            &details.content_hash,
+            &sync_control::retry_policy(job_id),
+            Some(job_id),
+            &|amount| on_progress(ByteProgress::Received(amount))
+        )?;
+        Some(download_path)
+    };
+
+    let thumbnail = {
+        let from_server = foreign_server.get_thumbnail(file_id)
+            .unwrap_or_else(|e| {
+                println!("Failed to get thumbnail, defaulting to None. Error: {:?}", e);
+                None
+            });
+
+        match from_server {
+            Some(data) =>
+                ThumbnailStrategy::FromByteSource(ByteSource::Memory(data)),
+            None => ThumbnailStrategy::None
+        }
+    };
+
+    let mut sidecars = vec!();
+    for (index, extension) in details.sidecars.iter().enumerate().filter(|_| !thumbnail_only) {
+        // Sidecars have no content hash to check a resumed download against, so
+        // they are only resumed within this attempt to fetch the file
+        let download_path = save_path.join(format!("{}.sidecar{}.{}.download", file_id, index, extension));
+        if download_path.exists() {
+            fs::remove_file(&download_path)?;
+        }
+        with_retries(&retry_policy, Some(job_id), || {
+            foreign_server.download_sidecar(
+                file_id,
+                index,
+                &download_path,
+                &|amount| on_progress(ByteProgress::Received(amount))
+            )
+        })
+            .chain_err(|| "Failed to get sidecar")?;
+        sidecars.push(Sidecar{content: ByteSource::TempFile(download_path), extension: extension.clone()});
+    }
+
+    Ok(FetchedFile{details, download_path, thumbnail, sidecars})
+}
+
+/**
+  Downloads a file from the foreign server to `destination`, resuming the
+  download if it fails. If `expected_hash` is known, the downloaded file is
+  verified against it and removed if it doesn't match. If the download is
+  part of the job `job_id`, it stops waiting to retry when the job is stopped
+*/
+pub fn download_with_retries(
+    server: &dyn ForeignServer,
+    file_id: i32,
+    destination: &Path,
+    expected_hash: &Option<String>,
+    retry_policy: &RetryPolicy,
+    job_id: Option<usize>,
+    on_progress: &dyn Fn(u64)
+) -> Result<()> {
+    with_retries(retry_policy, job_id, || {
+        server.download_file(file_id, destination, on_progress)
+            .and_then(|_| verify_content_hash(file_id, destination, expected_hash))
+    })
+}
+
+/**
+  Makes a request to a foreign server until it succeeds or `retry_policy` runs
+  out of retries, waiting longer after each failed attempt. If the request is
+  part of the job `job_id`, it stops waiting to retry when the job is stopped
+*/
+pub fn with_retries<T, F>(retry_policy: &RetryPolicy, job_id: Option<usize>, mut request: F) -> Result<T>
+    where F: FnMut() -> Result<T>
+{
+    let mut last_error = None;
+    for attempt in 0..retry_policy.retries + 1 {
+        if attempt > 0 {
+            let backoff = retry_policy.backoff(attempt);
+            match job_id {
+                Some(job_id) => sync_control::wait(job_id, backoff)?,
+                None => thread::sleep(backoff)
+            }
+        }
+
+        match request() {
+            Ok(result) => return Ok(result),
+            Err(e) => last_error = Some(e)
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| "The request was never made".into()))
+}
+
+fn verify_content_hash(file_id: i32, path: &Path, expected_hash: &Option<String>) -> Result<()> {
+    if let Some(ref expected) = *expected_hash {
+        let actual = content_hash(path)?;
+        if &actual != expected {
+            // The content is broken so resuming from it would never succeed
+            fs::remove_file(path)?;
+            bail!(ErrorKind::ContentHashMismatch(file_id, expected.clone(), actual));
+        }
+    }
+    Ok(())
+}
+
+/**
+  Applies a single change. `fetched` is the content of the file for `FileAdded`
+  and `ContentChanged` changes if it has been fetched in advance, otherwise it
+  is fetched when needed as part of the job `job_id`. With `lazy_originals`,
+  only the thumbnail of added files is stored
+*/
+fn apply_change(
+    fdb: &FileDatabase,
+    change: &Change,
+    fetched: Option<Result<FetchedFile>>,
+    foreign_server: &dyn ForeignServer,
+    lazy_originals: bool,
+    job_id: usize
+) -> Result<()> {
+    match change.change_type {
+        ChangeType::Update(UpdateType::ContentChanged(ref hash)) => {
+            apply_content_change(fdb, change.affected_file, hash, fetched, foreign_server, job_id)?
+        }
+        ChangeType::Update(ref update_type) => {
+            apply_file_update(&fdb, change.affected_file, update_type)?
+        }
+        ChangeType::FileAdded => {
+            // Check if the file is already in the database if it is, ignore it and print
+            // a warning
+            if fdb.get_file_with_id(change.affected_file) == None {
+                let fetched = match fetched {
+                    Some(fetched) => fetched?,
+                    None => fetch_file(
+                        foreign_server,
+                        change.affected_file,
+                        &fdb.get_file_save_path(),
+                        lazy_originals,
+                        job_id,
+                        &|_| {}
+                    )?
+                };
+
+                let file_timestamp = fetched.details.timestamp;
+
+                match fetched.download_path {
+                    Some(download_path) => {
+                        file_handler::save_file(
+                            ByteSource::TempFile(download_path),
+                            fetched.sidecars,
+                            fetched.thumbnail,
+                            change.affected_file,
+                            &[],
+                            &fdb,
+                            &ChangeCreationPolicy::No,
+                            &fetched.details.extension,
+                            file_timestamp.timestamp() as u64
+                        ).chain_err(|| "Failed to save file")?;
+                    }
+                    None => {
+                        let thumbnail = match fetched.thumbnail {
+                            ThumbnailStrategy::FromByteSource(data) => Some(data),
+                            _ => None
+                        };
+
+                        file_handler::save_remote_file(
+                            thumbnail,
+                            &fetched.details.sidecars,
+                            change.affected_file,
+                            fdb,
+                            &fetched.details.extension,
+                            file_timestamp.timestamp() as u64,
+                            fetched.details.content_hash.as_deref()
+                        ).chain_err(|| "Failed to save file")?;
+                    }
+                }
+            }
+            else {
+                println!(
+                    "A file with id {} was already in the database. Ignoring",
+                    change.affected_file
+                );
+            }
+        }
+        ChangeType::FileRemoved => {
+            file_handler::remove_file(change.affected_file, &fdb, &ChangeCreationPolicy::No)?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+  Replaces the content of a file with the current content on the foreign
+  server, which has the hash `hash` unless it was replaced again since. The
+  file is left alone if it already has the content of the foreign server.
+
+  Only the thumbnail is replaced if the original is not stored here. Originals
+  that were fetched on demand are removed to be fetched again when needed
+*/
+fn apply_content_change(
+    fdb: &FileDatabase,
+    file_id: i32,
+    hash: &str,
+    fetched: Option<Result<FetchedFile>>,
+    foreign_server: &dyn ForeignServer,
+    job_id: usize
+) -> Result<()> {
+    let file = fdb.get_file_with_id_result(file_id)?;
+    let original_stored = file.is_uploaded && file.original_accessed.is_none();
+
+    let fetched = match fetched {
+        Some(fetched) => fetched?,
+        None => {
+            let retry_policy = sync_control::retry_policy(job_id);
+            let details = with_retries(&retry_policy, Some(job_id), || foreign_server.get_file_details(file_id))
+                .chain_err(|| "Failed to get file details")?;
+            if details.content_hash.is_some() && details.content_hash == file.content_hash {
+                return Ok(());
+            }
+
+            fetch_file(
+                foreign_server,
+                file_id,
+                &fdb.get_file_save_path(),
+                !original_stored,
+                job_id,
+                &|_| {}
+            )?
+        }
+    };
+
+    let content_hash = fetched.details.content_hash.unwrap_or_else(|| hash.to_string());
+
+    if file.is_uploaded && !original_stored {
+        originals::remove_original(fdb, &file)?;
+    }
+
+    file_handler::replace_file_content(
+        fdb,
+        &file,
+        fetched.download_path.map(ByteSource::TempFile),
+        &content_hash,
+        fetched.thumbnail,
+        &ChangeCreationPolicy::No
+    )
+}
+
+
+fn apply_file_update(fdb: &FileDatabase, affected_file: i32, file_update: &UpdateType)
+    -> Result<()>
+{
+    let mut file = match fdb.get_file_with_id(affected_file) {
+        Some(id) => id,
+        None => return Err(ErrorKind::NoSuchFileInDatabase(affected_file).into())
+    };
+
+    match *file_update {
+        UpdateType::TagAdded(ref tag) => {
+            if !file.tags.contains(&tag) {
+                file.tags.push(tag.clone())
+            }
+        },
+        UpdateType::TagRemoved(ref tag) => {
+            file.tags = file.tags.into_iter().filter(|t| t != tag).collect()
+        }
+        UpdateType::CreationDateChanged(date) => file.creation_date = date,
+        UpdateType::ContentChanged(_) => bail!("Content changes are applied by apply_content_change")
+    }
+
+    fdb.update_file_without_creating_change(&file)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod sync_tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use file_database::db_test_helpers;
 
     use test_macros::naive_datetime_from_date;
 
@@ -352,24 +1426,65 @@ mod sync_tests {
 
     use std::path::PathBuf;
 
-    use sync_progress::{SyncStatus, SyncUpdate};
+    use sync_progress::SyncUpdate;
 
     use foreign_server::{FileDetails};
 
+    use file_util::ThumbnailSettings;
+
     use chrono;
 
-    use std::sync::Mutex;
+    use std::sync::{Mutex, Condvar};
+    use std::io::Write;
+    use std::time::Duration;
+
+    use sha2::{Sha256, Digest};
+    use util::to_hex;
+    use conflicts::{discarded_content_path, ConflictKind, RemovalConflictPolicy, Side, TagConflictPolicy};
+    use sync_push::FilePart;
+    use originals;
+    use compaction;
+    use library_digest;
+    use library_digest::{LibraryDigest, FileDigest, DivergingFile, Divergence};
 
     fn create_change(date_string: &str) -> chrono::format::ParseResult<ChangeCreationPolicy> {
         Ok(ChangeCreationPolicy::Yes(naive_datetime_from_date(date_string)?))
     }
 
+    /**
+      Appends the part of `content` which is not already in `destination`, like
+      a server that resumes downloads
+    */
+    fn write_remaining(content: &[u8], destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+        let existing = fs::metadata(destination).map(|metadata| metadata.len() as usize).unwrap_or(0);
+
+        let remaining = content.get(existing..).unwrap_or(&[]);
+
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(destination)?;
+        file.write_all(remaining)?;
+        on_progress(remaining.len() as u64);
+        Ok(())
+    }
+
+    /// A part of a pushed file: the push id, file id, part, offset and content
+    type PushedPart = (usize, i32, FilePart, u64, Vec<u8>);
+
     struct MockForeignServer {
         file_data: HashMap<i32, (FileDetails, Vec<u8>, Option<Vec<u8>>)>,
         // This is a mutex to allow modification without the compiler getting
         // pissed because add_syncpoint isn't mut
         syncpoints: Mutex<Vec<SyncPoint>>,
-        changes: Vec<Change>
+        changes: Vec<Change>,
+        sidecar_data: HashMap<(i32, usize), Vec<u8>>,
+        /// Parts of files that were pushed and the push they belong to
+        pushed_parts: Mutex<Vec<PushedPart>>,
+        /// The push id the last changes were sent with
+        last_push_id: Option<usize>,
+        /// The prefixes library digests were requested for
+        digest_requests: Mutex<Vec<String>>,
+        handshake: Handshake,
+        /// The amount of chunk pushes that fail before they start to succeed
+        push_failures: Mutex<usize>
     }
 
     impl MockForeignServer {
@@ -385,9 +1500,20 @@ mod sync_tests {
             Self {
                 file_data,
                 syncpoints: Mutex::new(syncpoints),
-                changes
+                changes,
+                sidecar_data: HashMap::new(),
+                pushed_parts: Mutex::new(vec!()),
+                last_push_id: None,
+                digest_requests: Mutex::new(vec!()),
+                handshake: Handshake::new("mock".into()),
+                push_failures: Mutex::new(0)
             }
         }
+
+        pub fn with_sidecar(mut self, id: i32, index: usize, content: Vec<u8>) -> Self {
+            self.sidecar_data.insert((id, index), content);
+            self
+        }
     }
 
     impl ForeignServer for MockForeignServer {
@@ -396,9 +1522,9 @@ mod sync_tests {
         }
         fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
             match *starting_syncpoint {
-                Some(SyncPoint{last_change}) => {
+                Some(ref syncpoint) => {
                     Ok(self.changes.iter()
-                        .filter(|change| change.timestamp >= last_change)
+                        .filter(|change| change.clock.counter > syncpoint.clock)
                         .map(|change| change.clone())
                         .collect()
                     )
@@ -410,24 +1536,193 @@ mod sync_tests {
             Ok(self.file_data[&id].0.clone())
         }
 
-        fn send_changes(&mut self, data: &ChangeData, _port: u16) -> Result<usize> {
+        fn send_changes(&mut self, data: &ChangeData, _port: u16, push_id: Option<usize>)
+            -> Result<usize>
+        {
             let mut changes = data.changes.clone();
             self.changes.append(&mut changes);
+            self.last_push_id = push_id;
             Ok(0)
         }
-        fn get_file(&self, id: i32) -> Result<Vec<u8>> {
-            Ok(self.file_data[&id].1.clone())
+        fn get_needed_files(&self, data: &ChangeData, _port: u16) -> Result<Vec<i32>> {
+            Ok(data.changes.iter()
+                .filter(|change| change.change_type == ChangeType::FileAdded)
+                .map(|change| change.affected_file)
+                .filter(|id| !self.file_data.contains_key(id))
+                .collect())
+        }
+        fn push_file_part(&self, push_id: usize, file_id: i32, part: FilePart, offset: u64, content: &[u8])
+            -> Result<()>
+        {
+            let mut push_failures = self.push_failures.lock().unwrap();
+            if *push_failures > 0 {
+                *push_failures -= 1;
+                bail!(ErrorKind::Dummy);
+            }
+            self.pushed_parts.lock().unwrap().push((push_id, file_id, part, offset, content.to_vec()));
+            Ok(())
+        }
+        fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+            write_remaining(&self.file_data[&id].1, destination, on_progress)
+        }
+        fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+            -> Result<()>
+        {
+            write_remaining(&self.sidecar_data[&(id, index)], destination, on_progress)
         }
         fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
             Ok(self.file_data[&id].2.clone())
         }
-        fn get_sync_status(&self, _job_id: usize) -> Result<SyncStatus> {
-            Ok(SyncStatus{last_update: SyncUpdate::Done, foreign_job_id: None})
+        fn get_sync_updates(&self, _job_id: usize)
+            -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>>
+        {
+            Ok(Box::new(vec!(Ok(SyncUpdate::Done)).into_iter()))
         }
         fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()> {
             self.syncpoints.lock().unwrap().push(syncpoint.clone());
             Ok(())
         }
+        fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+            let mut files = self.file_data.iter()
+                .filter(|&(id, _)| library_digest::has_prefix(*id, prefix))
+                .map(|(id, (details, _, _))| FileDigest::new(*id, details))
+                .collect::<Vec<_>>();
+            files.sort_by_key(|file| file.id as u32);
+
+            self.digest_requests.lock().unwrap().push(prefix.to_string());
+            Ok(library_digest::bucket_digest(prefix, &files))
+        }
+        fn cancel_job(&self, _job_id: usize) -> Result<()> {
+            Ok(())
+        }
+        fn handshake(&self) -> Result<Handshake> {
+            Ok(self.handshake.clone())
+        }
+    }
+
+    /**
+      Server which makes every download wait until two are in progress at the
+      same time, to check that files are fetched concurrently
+    */
+    struct ConcurrentForeignServer {
+        inner: MockForeignServer,
+        /// The amount of downloads in progress and the highest amount seen
+        downloads: Mutex<(usize, usize)>,
+        download_started: Condvar
+    }
+
+    impl ConcurrentForeignServer {
+        pub fn new(inner: MockForeignServer) -> Self {
+            Self {
+                inner,
+                downloads: Mutex::new((0, 0)),
+                download_started: Condvar::new()
+            }
+        }
+
+        pub fn max_concurrent_downloads(&self) -> usize {
+            self.downloads.lock().unwrap().1
+        }
+    }
+
+    impl ForeignServer for ConcurrentForeignServer {
+        fn get_syncpoints(&self) -> Result<Vec<SyncPoint>>{
+            self.inner.get_syncpoints()
+        }
+        fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
+            self.inner.get_changes(starting_syncpoint)
+        }
+        fn get_file_details(&self, id: i32) -> Result<FileDetails> {
+            self.inner.get_file_details(id)
+        }
+        fn send_changes(&mut self, data: &ChangeData, port: u16, push_id: Option<usize>)
+            -> Result<usize>
+        {
+            self.inner.send_changes(data, port, push_id)
+        }
+        fn get_needed_files(&self, data: &ChangeData, port: u16) -> Result<Vec<i32>> {
+            self.inner.get_needed_files(data, port)
+        }
+        fn push_file_part(&self, push_id: usize, file_id: i32, part: FilePart, offset: u64, content: &[u8])
+            -> Result<()>
+        {
+            self.inner.push_file_part(push_id, file_id, part, offset, content)
+        }
+        fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+            {
+                let mut downloads = self.downloads.lock().unwrap();
+                downloads.0 += 1;
+                downloads.1 = downloads.1.max(downloads.0);
+                self.download_started.notify_all();
+
+                // The timeout makes the test fail rather than hang if downloads
+                // are sequential
+                let _ = self.download_started.wait_timeout_while(
+                    downloads,
+                    Duration::from_secs(5),
+                    |downloads| downloads.1 < 2
+                );
+            }
+            let result = self.inner.download_file(id, destination, on_progress);
+            self.downloads.lock().unwrap().0 -= 1;
+            result
+        }
+        fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+            -> Result<()>
+        {
+            self.inner.download_sidecar(id, index, destination, on_progress)
+        }
+        fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
+            self.inner.get_thumbnail(id)
+        }
+        fn get_sync_updates(&self, job_id: usize)
+            -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>>
+        {
+            self.inner.get_sync_updates(job_id)
+        }
+        fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()> {
+            self.inner.add_syncpoint(syncpoint)
+        }
+        fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+            self.inner.get_library_digest(prefix)
+        }
+        fn cancel_job(&self, job_id: usize) -> Result<()> {
+            self.inner.cancel_job(job_id)
+        }
+        fn handshake(&self) -> Result<Handshake> {
+            self.inner.handshake()
+        }
+    }
+
+    const DEFAULT_POLICIES: ConflictPolicies = ConflictPolicies {
+        tags: TagConflictPolicy::LastWriterWins,
+        removals: RemovalConflictPolicy::LastWriterWins
+    };
+
+    /**
+      Options for syncing all files with the default conflict policies
+    */
+    fn sync_options(transfer_mode: TransferMode) -> SyncOptions<'static> {
+        SyncOptions {
+            transfer_mode,
+            conflict_policies: &DEFAULT_POLICIES,
+            apply: ApplyOptions::unfiltered(2)
+        }
+    }
+
+    /**
+      Returns `changes` like they are stored after being received by the sync
+      which created the newest syncpoint in `fdb`
+    */
+    fn received_changes(fdb: &FileDatabase, changes: &[Change]) -> Vec<Change> {
+        let counter = fdb.get_syncpoints().unwrap().into_iter().map(|point| point.clock).max().unwrap();
+
+        changes.iter()
+            .map(|change| {
+                let instance = change.clock.instance.clone();
+                change.clone().with_clock(LogicalClock {counter, instance})
+            })
+            .collect()
     }
 
     #[test]
@@ -448,11 +1743,12 @@ mod sync_tests {
             );
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        apply_changes(
+        apply_changes_with_filter(
             &fdb,
             &MockForeignServer::new(vec!(), vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).unwrap();
 
@@ -483,11 +1779,12 @@ mod sync_tests {
             );
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        apply_changes(
+        apply_changes_with_filter(
             &fdb,
             &MockForeignServer::new(vec!(), vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).unwrap();
 
@@ -542,11 +1839,12 @@ mod sync_tests {
             );
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        apply_changes(
+        apply_changes_with_filter(
             &fdb,
             &MockForeignServer::new(vec!(), vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).unwrap();
 
@@ -585,11 +1883,12 @@ mod sync_tests {
             );
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        apply_changes(
+        apply_changes_with_filter(
             &fdb,
             &MockForeignServer::new(vec!(), vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).unwrap();
 
@@ -623,11 +1922,12 @@ mod sync_tests {
             );
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        assert_matches!(apply_changes(
+        assert_matches!(apply_changes_with_filter(
             &fdb,
             &MockForeignServer::new(vec!(), vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ), Ok(_));
     }
@@ -661,11 +1961,12 @@ mod sync_tests {
             );
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        apply_changes(
+        apply_changes_with_filter(
             &fdb,
             &MockForeignServer::new(vec!(), vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).unwrap();
 
@@ -685,7 +1986,8 @@ mod sync_tests {
 
         let (initial_file, worker_receiver) = file_handler::save_file(
             ByteSource::File(PathBuf::from(original_filename)),
-            ThumbnailStrategy::Generate,
+            vec!(),
+            ThumbnailStrategy::Generate(ThumbnailSettings::default()),
             1,
             &mapvec!(String::from: "things"),
             &fdb,
@@ -734,29 +2036,32 @@ mod sync_tests {
                 ),
             );
 
-        let mut all_changes = vec!();
-        for change in &remote_changes {
-            all_changes.push(change.clone());
-        }
-        for change in &fdb.get_all_changes().expect("Failed to get changes from db") {
-            all_changes.push(change.clone());
-        }
-        all_changes.sort_by_key(|change| change.timestamp);
+        let local_changes = fdb.get_all_changes().expect("Failed to get changes from db");
 
         // Set up the foreign server
         let mut foreign_server = MockForeignServer::new(
                 vec!(
                     (2, (FileDetails {
                         extension: "jpg".into(),
-                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!(),
+                        content_hash: None,
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
                     }, added_bytes, Some(added_thumbnail_bytes))),
                     (3, (FileDetails {
                         extension: "jpg".into(),
-                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!(),
+                        content_hash: None,
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
                     }, vec!(), None)),
                 ),
                 vec!(),
-                remote_changes
+                remote_changes.clone()
             );
 
 
@@ -764,12 +2069,14 @@ mod sync_tests {
         let (tx, _rx, _) = sp::setup_progress_datastructures();
 
         // Apply the changes
-        sync_with_foreign(&fdb, &mut foreign_server, 0, &(0, tx.clone()))
+        sync_with_foreign(&fdb, &mut foreign_server, &sync_options(TransferMode::Callback(0)), &(0, tx.clone()))
             .expect("Foreign server sync failed");
 
         // Assert that the local database now contains all changes
+        let mut all_changes = received_changes(&fdb, &remote_changes);
+        all_changes.extend(local_changes);
         assert_eq!(
-            all_changes,
+            ::changelog::sorted_changes(&all_changes),
             fdb.get_all_changes().expect("Failed to get changes from database")
         );
 
@@ -849,19 +2156,19 @@ mod sync_tests {
     #[test]
     fn last_common_syncpoint_works() {
         let side1 = vec!(
-            SyncPoint{last_change: naive_datetime_from_date("2018-01-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-02").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-03").unwrap()},
+            SyncPoint::from_timestamp(naive_datetime_from_date("2018-01-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-02").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-03").unwrap()),
         );
         let side2 = vec!(
-            SyncPoint{last_change: naive_datetime_from_date("2018-01-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-02").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-02-03").unwrap()},
+            SyncPoint::from_timestamp(naive_datetime_from_date("2018-01-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-02").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-02-03").unwrap()),
         );
 
         assert_eq!(
             last_common_syncpoint(&side1, &side2).unwrap(),
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-02").unwrap()}
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-02").unwrap())
         );
     }
 
@@ -870,7 +2177,7 @@ mod sync_tests {
         let fdb = db_test_helpers::get_database();
         let fdb = fdb.lock().unwrap();
         let common_syncpoint =
-            SyncPoint{last_change: NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1)};
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
 
         fdb.reset();
         fdb.add_syncpoint(
@@ -895,11 +2202,21 @@ mod sync_tests {
         let foreign_files = vec!(
             (2, (FileDetails {
                 extension: "jpg".into(),
-                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)
+                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                sidecars: vec!(),
+                content_hash: None,
+                size: None,
+                tags: vec!(),
+                original_stored: true
             }, vec!(), None)),
             (3, (FileDetails {
                 extension: "jpg".into(),
-                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)
+                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                sidecars: vec!(),
+                content_hash: None,
+                size: None,
+                tags: vec!(),
+                original_stored: true
             }, vec!(), None)),
         );
         let foreign_syncpoints = vec!(common_syncpoint);
@@ -916,39 +2233,1037 @@ mod sync_tests {
             )
         );
 
-        let mut server = MockForeignServer::new(
-                foreign_files,
-                foreign_syncpoints,
-                foreign_changes
-            );
+        let mut server = MockForeignServer::new(
+                foreign_files,
+                foreign_syncpoints,
+                foreign_changes
+            );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &sync_options(TransferMode::Callback(0)), &(0, tx)).unwrap();
+
+        assert!(fdb.get_file_with_id(2).is_none());
+        assert!(fdb.get_file_with_id(3).is_some());
+        assert_eq!(server.changes.len(), 3);
+    }
+
+    /**
+      A syncpoint created by a peer with a clock that was years ahead must not
+      hide changes made afterwards on peers with correct clocks
+    */
+    #[test]
+    fn changes_are_selected_by_logical_clock() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let skewed_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2030, 1, 1).and_hms(0,0,0));
+
+        fdb.reset();
+        fdb.add_syncpoint(&skewed_syncpoint).unwrap();
+        fdb.add_new_file(1, "", None, &[], 0, &create_change("2018-01-01").unwrap());
+
+        let details = FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+            sidecars: vec!(),
+            content_hash: None,
+            size: None,
+            tags: vec!(),
+            original_stored: true
+        };
+        let foreign_files = vec!(
+            (2, (details.clone(), vec!(), None)),
+            (3, (details, vec!(), None)),
+        );
+        let foreign_clock = LogicalClock {
+            counter: skewed_syncpoint.clock + 5,
+            instance: "foreign".into()
+        };
+        let foreign_changes = vec!(
+            Change::new(NaiveDate::from_ymd(2018, 1, 2).and_hms(0,0,0), 2, ChangeType::FileAdded)
+                .with_clock(foreign_clock.clone()),
+            // Made before the syncpoint even though its timestamp is later
+            Change::new(NaiveDate::from_ymd(2029, 1, 1).and_hms(0,0,0), 3, ChangeType::FileAdded)
+                .with_clock(LogicalClock {
+                    counter: skewed_syncpoint.clock - 5,
+                    instance: "foreign".into()
+                }),
+        );
+
+        let mut server = MockForeignServer::new(
+                foreign_files,
+                vec!(skewed_syncpoint.clone()),
+                foreign_changes
+            );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &sync_options(TransferMode::Callback(0)), &(0, tx)).unwrap();
+
+        assert!(fdb.get_file_with_id(2).is_some());
+        assert!(fdb.get_file_with_id(3).is_none());
+        // The local change was sent even though its timestamp is before the syncpoint
+        assert_eq!(server.changes.len(), 3);
+        assert_eq!(fdb.get_changes_after_clock(foreign_clock.counter - 1).unwrap().len(), 1);
+
+        // The new syncpoint comes after every change on both sides. The
+        // received changes are stored with its clock
+        let new_syncpoint = fdb.get_syncpoints().unwrap().into_iter().max().unwrap();
+        assert!(new_syncpoint.clock > foreign_clock.counter);
+        assert_eq!(
+            fdb.get_all_changes().unwrap().iter().map(|c| c.clock.counter).max().unwrap(),
+            new_syncpoint.clock
+        );
+        assert!(server.syncpoints.lock().unwrap().contains(&new_syncpoint));
+    }
+
+    /**
+      Three instances: `fdb` syncs with `foreign`, then with a new peer whose
+      clock is years behind. The changes received from that peer are older than
+      the syncpoint shared with `foreign` but must still be passed on to it
+    */
+    #[test]
+    fn received_changes_are_relayed_to_other_peers() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let foreign = db_test_helpers::get_foreign_database();
+        let foreign = foreign.lock().unwrap();
+        fdb.reset();
+        foreign.reset();
+
+        let sync_local = |fdb: &FileDatabase, foreign: &FileDatabase| {
+            let mut server = ::local_foreign_server::LocalForeignServer::new(foreign.reconnect().unwrap())
+                .receiving_from(fdb.reconnect().unwrap(), 2);
+            let (tx, _rx, _) = sp::setup_progress_datastructures();
+            sync_with_foreign(fdb, &mut server, &sync_options(TransferMode::Callback(0)), &(0, tx))
+                .expect("Failed to sync with the local foreign server")
+        };
+        sync_local(&fdb, &foreign);
+
+        // The peer has never synced with this instance and its clock is behind
+        let old_clock = SyncPoint::from_timestamp(NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)).clock;
+        let details = FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+            sidecars: vec!(),
+            content_hash: None,
+            size: None,
+            tags: vec!(),
+            original_stored: true
+        };
+        let peer_changes = vec!(
+            Change::new(NaiveDate::from_ymd(2016, 2, 1).and_hms(0,0,0), 60, ChangeType::FileAdded)
+                .with_clock(LogicalClock {counter: old_clock + 1, instance: "peer".into()}),
+            Change::new(NaiveDate::from_ymd(2016, 2, 1).and_hms(0,0,0), 60, ChangeType::Update(UpdateType::TagAdded("peer".into())))
+                .with_clock(LogicalClock {counter: old_clock + 2, instance: "peer".into()}),
+        );
+        let mut peer = MockForeignServer::new(
+                vec!((60, (details, vec!(6, 0), None))),
+                vec!(),
+                peer_changes
+            );
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut peer, &sync_options(TransferMode::Callback(0)), &(0, tx))
+            .expect("Failed to sync with the peer");
+
+        // The changes are stored with the clock of the new syncpoint so they
+        // are not sent back to the peer they came from
+        let peer_syncpoint = fdb.get_syncpoints().unwrap().into_iter().max().unwrap();
+        assert!(peer.syncpoints.lock().unwrap().contains(&peer_syncpoint));
+        assert!(fdb.get_changes_after_clock(peer_syncpoint.clock).unwrap().is_empty());
+
+        let counts = sync_local(&fdb, &foreign);
+        assert_eq!(counts.changes_sent, 2);
+        let file = foreign.get_file_with_id(60).expect("The file from the peer was not relayed");
+        assert_eq!(file.tags, vec!("peer".to_string()));
+    }
+
+    #[test]
+    fn sync_preview_summarises_changes_without_applying_them() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        // Added locally since the last sync
+        fdb.add_new_file(1, "1.jpg", None, &[], 0, &create_change("2017-02-02").unwrap());
+        fs::write(fdb.get_file_save_path().join("1.jpg"), vec!(0; 5)).unwrap();
+        fdb.add_change(&Change::new(
+            NaiveDate::from_ymd(2017, 3, 3).and_hms(0,0,0),
+            1,
+            ChangeType::Update(UpdateType::TagAdded("local".into()))
+        )).unwrap();
+        // Synced earlier and removed on the foreign server since
+        fdb.add_new_file(4, "4.jpg", None, &[], 0, &ChangeCreationPolicy::No);
+
+        let foreign_files = vec!(
+            (2, (FileDetails {
+                extension: "jpg".into(),
+                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                sidecars: vec!(),
+                content_hash: None,
+                size: Some(100),
+                tags: vec!(),
+                original_stored: true
+            }, vec!(), None)),
+        );
+        let new_date = NaiveDate::from_ymd(2015, 1, 1).and_hms(0,0,0);
+        let foreign_changes = vec!(
+            Change::new(
+                NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0),
+                2,
+                ChangeType::FileAdded
+            ),
+            Change::new(
+                NaiveDate::from_ymd(2018, 1, 2).and_hms(0,0,0),
+                2,
+                ChangeType::Update(UpdateType::TagAdded("foreign".into()))
+            ),
+            Change::new(
+                NaiveDate::from_ymd(2018, 1, 3).and_hms(0,0,0),
+                2,
+                ChangeType::Update(UpdateType::CreationDateChanged(new_date))
+            ),
+            Change::new(
+                NaiveDate::from_ymd(2018, 1, 4).and_hms(0,0,0),
+                4,
+                ChangeType::FileRemoved
+            ),
+        );
+
+        let server = MockForeignServer::new(
+                foreign_files,
+                vec!(common_syncpoint.clone()),
+                foreign_changes
+            );
+
+        let preview = preview_sync(&fdb, &server, &ConflictPolicies::default()).expect("Failed to preview sync");
+
+        assert_eq!(preview, SyncPreview {
+            last_common_syncpoint: Some(common_syncpoint),
+            local: SyncSidePreview {
+                files_added: vec!(2),
+                files_removed: vec!(4),
+                tags_added: vec!(TagChange{file_id: 2, tag: "foreign".into()}),
+                tags_removed: vec!(),
+                dates_changed: vec!(DateChange{file_id: 2, date: new_date}),
+                contents_changed: vec!(),
+                bytes_to_transfer: 100
+            },
+            foreign: SyncSidePreview {
+                files_added: vec!(1),
+                files_removed: vec!(),
+                tags_added: vec!(TagChange{file_id: 1, tag: "local".into()}),
+                tags_removed: vec!(),
+                dates_changed: vec!(),
+                contents_changed: vec!(),
+                bytes_to_transfer: 5
+            },
+            conflicts: vec!()
+        });
+
+        // Nothing should have been applied on either side
+        assert!(fdb.get_file_with_id(2).is_none());
+        assert!(fdb.get_file_with_id(4).is_some());
+        assert_eq!(fdb.get_syncpoints().unwrap().len(), 1);
+        assert_eq!(server.changes.len(), 4);
+        assert_eq!(server.syncpoints.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn conflicts_are_resolved_and_logged() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        let file = fdb.add_new_file(1, "1.jpg", None, &[], 0, &ChangeCreationPolicy::No);
+        fdb.change_file_tags(
+            &file,
+            &["things".into()],
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 2).and_hms(0,0,0))
+        ).unwrap();
+
+        let foreign_changes = vec!(
+            Change::new(
+                NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0),
+                1,
+                ChangeType::Update(UpdateType::TagRemoved("things".into()))
+            ),
+        );
+        let mut server = MockForeignServer::new(
+                vec!(),
+                vec!(common_syncpoint),
+                foreign_changes
+            );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &sync_options(TransferMode::Callback(0)), &(0, tx))
+            .expect("Failed to sync with foreign");
+
+        // The local change was made last, so the foreign removal is not applied
+        assert_eq!(fdb.get_file_with_id(1).unwrap().tags, vec!("things".to_owned()));
+
+        let conflicts = fdb.get_sync_conflicts().unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].resolution.kind, ConflictKind::Tag("things".into()));
+        assert_eq!(conflicts[0].resolution.kept, Side::Local);
+    }
+
+    #[test]
+    fn content_that_loses_a_conflict_is_kept() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+        let day = |day| NaiveDate::from_ymd(2018, 1, day).and_hms(0,0,0);
+        let hash = |content: &[u8]| to_hex(&Sha256::digest(content));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        let storage = fdb.get_file_save_path();
+        // Edited in order, so the clock of each edit is after the previous one
+        for (id, changed) in [(2, day(1)), (1, day(2))] {
+            let filename = format!("{}.jpg", id);
+            fs::write(storage.join(&filename), [id as u8]).unwrap();
+            let file = fdb.add_new_file(id, &filename, None, &[], 0, &ChangeCreationPolicy::No);
+            fdb.set_file_content_hash(&file, &hash(&[id as u8]), &ChangeCreationPolicy::Yes(changed))
+                .unwrap();
+        }
+
+        let details = |content: &[u8]| FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+            sidecars: vec!(),
+            content_hash: Some(hash(content)),
+            size: Some(content.len() as u64),
+            tags: vec!(),
+            original_stored: true
+        };
+        let foreign_changes = vec!(
+            Change::new(day(1), 1, ChangeType::Update(UpdateType::ContentChanged(hash(&[11])))),
+            Change::new(day(2), 2, ChangeType::Update(UpdateType::ContentChanged(hash(&[12])))),
+        );
+        let mut server = MockForeignServer::new(
+                vec!(
+                    (1, (details(&[11]), vec!(11), Some(vec!(0)))),
+                    (2, (details(&[12]), vec!(12), Some(vec!(0)))),
+                ),
+                vec!(common_syncpoint),
+                foreign_changes
+            );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &sync_options(TransferMode::Callback(0)), &(0, tx))
+            .expect("Failed to sync with foreign");
+
+        // The local content of file 1 was kept, the foreign one is downloaded
+        assert_eq!(fs::read(storage.join("1.jpg")).unwrap(), vec!(1));
+        assert_eq!(fs::read(discarded_content_path(&storage, &hash(&[11]))).unwrap(), vec!(11));
+        // The foreign content of file 2 was kept, the local one is copied
+        assert_eq!(fs::read(storage.join("2.jpg")).unwrap(), vec!(12));
+        assert_eq!(fs::read(discarded_content_path(&storage, &hash(&[2]))).unwrap(), vec!(2));
+    }
+
+    #[test]
+    fn sync_filters_select_and_evict_files() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        fs::write(fdb.get_file_save_path().join("1.jpg"), [1]).unwrap();
+        fdb.add_new_file(1, "1.jpg", None, &["work".into()], 0, &ChangeCreationPolicy::No);
+        fdb.set_content_hash(1, "hash1").unwrap();
+        fs::write(fdb.get_file_save_path().join("4.jpg"), [4]).unwrap();
+        fdb.add_new_file(4, "4.jpg", None, &["work".into()], 0, &ChangeCreationPolicy::No);
+
+        let details = |tags: Vec<&str>, content_hash: Option<&str>| FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+            sidecars: vec!(),
+            content_hash: content_hash.map(String::from),
+            size: None,
+            tags: tags.into_iter().map(String::from).collect(),
+            original_stored: true
+        };
+        let change_time = NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0);
+        let foreign_changes = vec!(
+            // File 1 stops matching
+            Change::new(change_time, 1, ChangeType::Update(UpdateType::TagRemoved("work".into()))),
+            // File 2 was added before the last sync and starts matching
+            Change::new(change_time, 2, ChangeType::Update(UpdateType::TagAdded("work".into()))),
+            // File 3 never matches
+            Change::new(change_time, 3, ChangeType::FileAdded),
+            // File 4 stops matching but its content can't be compared
+            Change::new(change_time, 4, ChangeType::Update(UpdateType::TagRemoved("work".into()))),
+        );
+        let mut server = MockForeignServer::new(
+                vec!(
+                    (1, (details(vec!(), Some("hash1")), vec!(1), None)),
+                    (2, (details(vec!("travel", "work"), None), vec!(2), None)),
+                    (3, (details(vec!(), None), vec!(3), None)),
+                    (4, (details(vec!(), Some("hash4")), vec!(4), None)),
+                ),
+                vec!(common_syncpoint),
+                foreign_changes.clone()
+            );
+
+        let filter = ::sync_filters::parse_filter_query("of work").unwrap();
+        let options = SyncOptions {
+            apply: ApplyOptions {
+                filter: Some(&filter),
+                lazy_originals: false,
+                .. ApplyOptions::unfiltered(2)
+            },
+            .. sync_options(TransferMode::Callback(0))
+        };
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &options, &(0, tx))
+            .expect("Failed to sync with foreign");
+
+        assert_eq!(fdb.get_file_with_id(1), None);
+        assert_eq!(
+            fdb.get_file_with_id(2).expect("Matching file was not fetched").tags,
+            vec!("travel".to_owned(), "work".to_owned())
+        );
+        assert_eq!(fdb.get_file_with_id(3), None);
+        assert!(fdb.get_file_with_id(4).is_some(), "File without a known content hash was evicted");
+
+        // The change log is complete even though not all changes were applied
+        assert_eq!(
+            fdb.get_all_changes().unwrap(),
+            ::changelog::sorted_changes(&received_changes(&fdb, &foreign_changes))
+        );
+    }
+
+    #[test]
+    fn files_are_not_evicted_if_the_foreign_server_lacks_the_original() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        fs::write(fdb.get_file_save_path().join("1.jpg"), [1]).unwrap();
+        fdb.add_new_file(1, "1.jpg", None, &["work".into()], 0, &ChangeCreationPolicy::No);
+
+        // The foreign server only stores a thumbnail, like lazy instances do
+        let details = FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+            sidecars: vec!(),
+            content_hash: None,
+            size: None,
+            tags: vec!(),
+            original_stored: false
+        };
+        let change_time = NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0);
+        let foreign_changes = vec!(
+            Change::new(change_time, 1, ChangeType::Update(UpdateType::TagRemoved("work".into()))),
+        );
+        let mut server = MockForeignServer::new(
+                vec!((1, (details, vec!(1), None))),
+                vec!(common_syncpoint),
+                foreign_changes
+            );
+
+        let filter = ::sync_filters::parse_filter_query("of work").unwrap();
+        let options = SyncOptions {
+            apply: ApplyOptions {
+                filter: Some(&filter),
+                lazy_originals: true,
+                .. ApplyOptions::unfiltered(2)
+            },
+            .. sync_options(TransferMode::Callback(0))
+        };
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &options, &(0, tx))
+            .expect("Failed to sync with foreign");
+
+        let file = fdb.get_file_with_id(1).expect("File without a stored original was evicted");
+        assert_eq!(file.tags, Vec::<String>::new());
+        assert!(fdb.get_file_save_path().join("1.jpg").exists());
+    }
+
+    #[test]
+    fn files_are_pushed_in_push_mode() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        fs::write(fdb.get_file_save_path().join("1.jpg"), [1, 2, 3]).unwrap();
+        fdb.add_new_file(
+            1,
+            "1.jpg",
+            None,
+            &["work".into()],
+            0,
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0))
+        );
+
+        let mut server = MockForeignServer::new(vec!(), vec!(common_syncpoint), vec!());
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let counts = sync_with_foreign(
+            &fdb,
+            &mut server,
+            &sync_options(TransferMode::Push(0)),
+            &(0, tx)
+        ).expect("Failed to sync with foreign");
+
+        assert_eq!(counts.files_sent, 1);
+
+        let pushed_parts = server.pushed_parts.lock().unwrap();
+        assert_eq!(pushed_parts.len(), 1);
+        let (push_id, file_id, part, offset, ref content) = pushed_parts[0];
+        assert_eq!((file_id, part, offset, content.clone()), (1, FilePart::Content, 0, vec!(1, 2, 3)));
+        // The changes refer to the pushed files
+        assert_eq!(server.last_push_id, Some(push_id));
+        assert_eq!(server.changes.len(), 2);
+    }
+
+    #[test]
+    fn large_files_are_pushed_in_chunks() {
+        use sync_push::PUSH_CHUNK_SIZE;
+        use sync_control::{JobGuard, JobLimits};
+
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        let content = vec!(7; PUSH_CHUNK_SIZE as usize + 1);
+        fs::write(fdb.get_file_save_path().join("1.jpg"), &content).unwrap();
+        fdb.add_new_file(
+            1,
+            "1.jpg",
+            None,
+            &[],
+            0,
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0))
+        );
+
+        // Chunks that fail are pushed again
+        let mut server = MockForeignServer::new(vec!(), vec!(common_syncpoint), vec!());
+        *server.push_failures.lock().unwrap() = 1;
+        let limits = JobLimits {
+            timeout: None,
+            retry_policy: RetryPolicy {
+                retries: 1,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1)
+            }
+        };
+        {
+            let _guard = JobGuard::register(1005, &limits);
+            let (tx, _rx, _) = sp::setup_progress_datastructures();
+            sync_with_foreign(&fdb, &mut server, &sync_options(TransferMode::Push(0)), &(1005, tx))
+                .expect("Failed to sync with foreign");
+        }
+
+        let pushed_parts = server.pushed_parts.lock().unwrap();
+        let chunks = pushed_parts.iter()
+            .map(|&(_, file_id, part, offset, ref content)| (file_id, part, offset, content.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec!(
+            (1, FilePart::Content, 0, PUSH_CHUNK_SIZE as usize),
+            (1, FilePart::Content, PUSH_CHUNK_SIZE, 1)
+        ));
+    }
+
+    #[test]
+    fn files_that_changed_while_pushing_are_not_pushed() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        let common_syncpoint =
+            SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(1,1,1));
+
+        fdb.reset();
+        fdb.add_syncpoint(&common_syncpoint).unwrap();
+        fs::write(fdb.get_file_save_path().join("1.jpg"), [1, 2, 3]).unwrap();
+        fdb.add_new_file(
+            1,
+            "1.jpg",
+            None,
+            &[],
+            0,
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0))
+        );
+        fdb.set_content_hash(1, "0123").unwrap();
+
+        let mut server = MockForeignServer::new(vec!(), vec!(common_syncpoint), vec!());
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let result = sync_with_foreign(&fdb, &mut server, &sync_options(TransferMode::Push(0)), &(0, tx));
+
+        assert!(
+            result.unwrap_err().iter().any(|e| e.to_string().contains("0123")),
+            "The content hash was not verified"
+        );
+        assert_eq!(server.last_push_id, None);
+    }
+
+    #[test]
+    fn sync_is_negotiated_through_handshakes() {
+        use sync_auth::PROTOCOL_VERSION;
+
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+
+        fdb.reset();
+        fs::write(fdb.get_file_save_path().join("54.jpg"), [1, 2, 3]).unwrap();
+        fdb.add_new_file(
+            54,
+            "54.jpg",
+            None,
+            &["work".into()],
+            0,
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0))
+        );
+
+        let sync = |server: &mut MockForeignServer| {
+            let (tx, _rx, _) = sp::setup_progress_datastructures();
+            sync_with_foreign(
+                &fdb,
+                server,
+                &sync_options(TransferMode::Push(0)),
+                &(0, tx)
+            )
+        };
+
+        // Peers that are too new are refused before anything is sent
+        let mut server = MockForeignServer::new(vec!(), vec!(), vec!());
+        server.handshake.protocol_version = PROTOCOL_VERSION + 1;
+        server.handshake.min_protocol_version = PROTOCOL_VERSION + 1;
+        assert_matches!(
+            sync(&mut server).err(),
+            Some(::error::Error(ErrorKind::IncompatibleProtocolVersion(_, _), _))
+        );
+        assert!(server.changes.is_empty());
+
+        // So are peers which can't apply the changes
+        let mut server = MockForeignServer::new(vec!(), vec!(), vec!());
+        server.handshake.change_types.retain(|name| name != "TagAdded");
+        assert_matches!(
+            sync(&mut server).err(),
+            Some(::error::Error(ErrorKind::UnsupportedChangeTypes(_), _))
+        );
+        assert!(server.changes.is_empty());
+        assert!(fdb.get_syncpoints().unwrap().is_empty());
+
+        // Peers without push support fetch the files instead
+        let mut server = MockForeignServer::new(vec!(), vec!(), vec!());
+        server.handshake.features.clear();
+        sync(&mut server).expect("Failed to sync without pushing files");
+        assert!(server.pushed_parts.lock().unwrap().is_empty());
+        assert_eq!(server.last_push_id, None);
+        assert_eq!(server.changes.len(), 2);
+
+        // Version 6 peers get the changes without the content changes they
+        // can't apply
+        let file = fdb.get_file_with_id(54).unwrap();
+        let content_hash = to_hex(&Sha256::digest([1, 2, 3]));
+        fdb.set_file_content_hash(
+            &file,
+            &content_hash,
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 2).and_hms(0,0,0))
+        ).unwrap();
+        let mut server = MockForeignServer::new(vec!(), vec!(), vec!());
+        server.handshake.protocol_version = 6;
+        server.handshake.change_types.retain(|name| name != "ContentChanged");
+        sync(&mut server).expect("Failed to sync with a version 6 peer");
+        assert_eq!(server.changes.len(), 2);
+        assert!(server.changes.iter().all(|change| change.change_type.name() != "ContentChanged"));
+    }
+
+    #[test]
+    fn lazy_originals_only_store_thumbnails() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let timestamp = NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0);
+        let mut server = MockForeignServer::new(
+                vec!(
+                    (43, (FileDetails {
+                        extension: "jpg".into(),
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!("nef".into()),
+                        content_hash: None,
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
+                    }, vec!(1, 2, 3), Some(vec!(4, 5)))),
+                ),
+                vec!(),
+                vec!(
+                    Change::new(timestamp, 43, ChangeType::FileAdded),
+                    Change::new(timestamp, 43, ChangeType::Update(UpdateType::TagAdded("work".into())))
+                )
+            )
+            .with_sidecar(43, 0, vec!(6, 7));
+
+        let options = SyncOptions {
+            apply: ApplyOptions {
+                lazy_originals: true,
+                .. ApplyOptions::unfiltered(2)
+            },
+            .. sync_options(TransferMode::Callback(0))
+        };
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(&fdb, &mut server, &options, &(0, tx)).expect("Failed to sync with foreign");
+
+        let storage = fdb.get_file_save_path();
+        let file = fdb.get_file_with_id(43).expect("The file was not added");
+        assert!(!file.is_uploaded);
+        assert_eq!(file.tags, vec!(String::from("work")));
+        assert_eq!(fs::read(storage.join(file.thumbnail_path.clone().unwrap())).unwrap(), vec!(4, 5));
+        assert!(!storage.join(&file.filename).exists());
+        assert!(!storage.join(&file.sidecars[0]).exists());
+
+        originals::fetch_original(&fdb, &file, &[&server], &RetryPolicy::default())
+            .expect("Failed to fetch original");
+        assert_eq!(fs::read(storage.join(&file.filename)).unwrap(), vec!(1, 2, 3));
+        assert_eq!(fs::read(storage.join(&file.sidecars[0])).unwrap(), vec!(6, 7));
+
+        fdb.set_original_stored(43, true, None).unwrap();
+        remove_file(43, &fdb, &ChangeCreationPolicy::No).expect("Failed to remove file");
+        assert!(!storage.join(&file.filename).exists());
+    }
+
+    #[test]
+    fn replaced_content_is_synced() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let storage = fdb.get_file_save_path();
+        let timestamp = NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0);
+
+        let (_, saving) = file_handler::save_file(
+            ByteSource::Memory(vec!(1, 2, 3)),
+            vec!(),
+            ThumbnailStrategy::FromByteSource(ByteSource::Memory(vec!(4))),
+            55,
+            &[],
+            &fdb,
+            &ChangeCreationPolicy::No,
+            "jpg",
+            0
+        ).expect("Failed to save file");
+        saving.file.recv().unwrap().expect("Failed to save file");
+        file_handler::save_remote_file(Some(ByteSource::Memory(vec!(5))), &[], 56, &fdb, "jpg", 0, Some("old"))
+            .expect("Failed to save remote file");
+
+        let new_content = vec!(7, 8, 9);
+        let new_hash = to_hex(&Sha256::digest(&new_content));
+        let details = FileDetails {
+            extension: "jpg".into(),
+            timestamp,
+            sidecars: vec!(),
+            content_hash: Some(new_hash.clone()),
+            size: Some(3),
+            tags: vec!(),
+            original_stored: true
+        };
+        let server = MockForeignServer::new(
+                vec!(
+                    (55, (details.clone(), new_content.clone(), Some(vec!(10)))),
+                    (56, (details, new_content.clone(), Some(vec!(11)))),
+                ),
+                vec!(),
+                vec!()
+            );
+        let changes = vec!(
+            Change::new(timestamp, 55, ChangeType::Update(UpdateType::ContentChanged(new_hash.clone()))),
+            Change::new(timestamp, 56, ChangeType::Update(UpdateType::ContentChanged(new_hash.clone())))
+        );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let fetched = apply_changes_with_filter(&fdb, &server, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx))
+            .expect("Failed to apply changes");
+        assert_eq!(fetched, 1);
+
+        let stored = fdb.get_file_with_id(55).unwrap();
+        assert_eq!(fs::read(storage.join(&stored.filename)).unwrap(), new_content);
+        assert_eq!(stored.content_hash, Some(new_hash.clone()));
+        assert_eq!(fs::read(storage.join(stored.thumbnail_path.unwrap())).unwrap(), vec!(10));
+
+        // Only the thumbnail is replaced for originals that are stored elsewhere
+        let remote = fdb.get_file_with_id(56).unwrap();
+        assert!(!remote.is_uploaded);
+        assert!(!storage.join(&remote.filename).exists());
+        assert_eq!(remote.content_hash, Some(new_hash.clone()));
+        assert_eq!(fs::read(storage.join(remote.thumbnail_path.unwrap())).unwrap(), vec!(11));
+
+        // Files which already have the new content are left alone
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        let fetched = apply_changes_with_filter(&fdb, &server, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx))
+            .expect("Failed to apply changes again");
+        assert_eq!(fetched, 0);
+        assert_eq!(fs::read(storage.join(&stored.filename)).unwrap(), new_content);
+    }
+
+    /**
+      Files, tags and creation dates of the files in the database, sorted by id
+    */
+    fn file_states(fdb: &FileDatabase) -> Vec<(i32, Vec<String>, NaiveDateTime)> {
+        let mut files = get_files_with_tags(fdb, vec!(), vec!()).into_iter()
+            .map(|file| {
+                let mut tags = file.tags;
+                tags.sort();
+                (file.id, tags, file.creation_date)
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn compacted_changes_are_equivalent() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let details = FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+            sidecars: vec!(),
+            content_hash: None,
+            size: None,
+            tags: vec!(),
+            original_stored: true
+        };
+        let files = vec!(
+            (44, (details.clone(), vec!(1), None)),
+            (45, (details.clone(), vec!(2), None)),
+            (46, (details.clone(), vec!(3), None)),
+        );
+
+        let day = |day| NaiveDate::from_ymd(2016, 1, day).and_hms(0,0,0);
+        let tag_added = |day_of_month, file, tag: &str| Change::new(
+            day(day_of_month),
+            file,
+            ChangeType::Update(UpdateType::TagAdded(tag.into()))
+        );
+        let tag_removed = |day_of_month, file, tag: &str| Change::new(
+            day(day_of_month),
+            file,
+            ChangeType::Update(UpdateType::TagRemoved(tag.into()))
+        );
+        let history = vec!(
+            Change::new(day(1), 44, ChangeType::FileAdded),
+            tag_added(2, 44, "a"),
+            tag_added(3, 44, "b"),
+            tag_removed(4, 44, "a"),
+            Change::new(day(5), 44, ChangeType::Update(UpdateType::CreationDateChanged(day(20)))),
+            Change::new(day(6), 44, ChangeType::Update(UpdateType::CreationDateChanged(day(21)))),
+            Change::new(day(1), 45, ChangeType::FileAdded),
+            tag_added(2, 45, "a"),
+            Change::new(day(3), 45, ChangeType::FileRemoved),
+            Change::new(day(1), 46, ChangeType::FileAdded),
+            tag_added(2, 46, "c"),
+            tag_removed(3, 46, "c"),
+            tag_added(4, 46, "c"),
+        );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        apply_changes_with_filter(
+            &fdb,
+            &MockForeignServer::new(files.clone(), vec!(), vec!()),
+            &history,
+            &[],
+            &ApplyOptions::unfiltered(2),
+            &(0, tx)
+        ).unwrap();
+        let expected_state = file_states(&fdb);
+        assert_eq!(expected_state.len(), 2);
+
+        let syncpoint = SyncPoint {
+            last_change: day(10),
+            clock: fdb.current_clock().unwrap()
+        };
+        fdb.add_syncpoint(&syncpoint).unwrap();
+        let mut server = MockForeignServer::new(
+            files.clone(),
+            vec!(syncpoint.clone()),
+            vec!(tag_added(1, 46, "d").with_clock(LogicalClock {
+                counter: syncpoint.clock + 1,
+                instance: "foreign".into()
+            }))
+        );
+
+        let compaction_point = compaction::compaction_syncpoint(&fdb, &[&server]).unwrap();
+        assert_eq!(compaction_point, Some(syncpoint.clone()));
+        assert_eq!(compaction::compact_changes(&fdb, &syncpoint).unwrap(), 8);
+        let compacted = fdb.get_all_changes().unwrap();
+        assert_eq!(compacted.len(), 5);
+
+        // Changes after the compacted history are still synced, and the
+        // compacted history is not sent again
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(
+            &fdb,
+            &mut server,
+            &sync_options(TransferMode::Callback(0)),
+            &(0, tx)
+        ).expect("Failed to sync after compaction");
+        assert_eq!(fdb.get_file_with_id(46).unwrap().tags.len(), 2);
+        assert_eq!(server.changes.len(), 1);
+
+        // Applying the compacted history gives the same files
+        remove_file(44, &fdb, &ChangeCreationPolicy::No).unwrap();
+        remove_file(46, &fdb, &ChangeCreationPolicy::No).unwrap();
+        fdb.reset();
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        apply_changes_with_filter(
+            &fdb,
+            &MockForeignServer::new(files, vec!(), vec!()),
+            &compacted,
+            &[],
+            &ApplyOptions::unfiltered(2),
+            &(0, tx)
+        ).unwrap();
+        assert_eq!(file_states(&fdb), expected_state);
+
+        remove_file(44, &fdb, &ChangeCreationPolicy::No).unwrap();
+        remove_file(46, &fdb, &ChangeCreationPolicy::No).unwrap();
+    }
+
+    #[test]
+    fn diverging_files_are_found_through_digests() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let details = |tag: &str| FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDateTime::from_timestamp(0, 0),
+            sidecars: vec!(),
+            content_hash: Some("abc".into()),
+            size: None,
+            tags: vec!(tag.into()),
+            original_stored: true
+        };
+
+        let mut foreign_files = vec!();
+        for id in 1..101 {
+            fdb.add_new_file(
+                id,
+                &format!("{}.jpg", id),
+                None,
+                &[String::from("things")],
+                0,
+                &ChangeCreationPolicy::No
+            );
+            fdb.set_content_hash(id, "abc").unwrap();
+
+            match id {
+                5 => foreign_files.push((id, (details("stuff"), vec!(), None))),
+                0x50 => {},
+                _ => foreign_files.push((id, (details("things"), vec!(), None)))
+            }
+        }
+        foreign_files.push((200, (details("things"), vec!(), None)));
+
+        let server = MockForeignServer::new(foreign_files, vec!(), vec!());
+
+        assert_eq!(
+            library_digest::find_diverging_files(&fdb, &server).unwrap(),
+            vec!(
+                DivergingFile{id: 5, divergence: Divergence::Different},
+                DivergingFile{id: 0x50, divergence: Divergence::OnlyLocal},
+                DivergingFile{id: 200, divergence: Divergence::OnlyForeign},
+            )
+        );
+
+        // Only the buckets containing the diverging files are compared
+        let requests = server.digest_requests.lock().unwrap().clone();
+        assert_eq!(
+            requests,
+            mapvec!(String::from:
+                "", "0", "00", "000", "0000", "00000", "000000",
+                "0000000", "0000005", "000000c"
+            )
+        );
 
-        let (tx, _rx, _) = sp::setup_progress_datastructures();
-        sync_with_foreign(&fdb, &mut server, 0, &(0, tx)).unwrap();
+        let identical = MockForeignServer::new(vec!(), vec!(), vec!());
+        fdb.reset();
+        assert!(library_digest::find_diverging_files(&fdb, &identical).unwrap().is_empty());
+        assert_eq!(identical.digest_requests.lock().unwrap().len(), 1);
+    }
 
-        assert!(fdb.get_file_with_id(2).is_none());
-        assert!(fdb.get_file_with_id(3).is_some());
-        assert_eq!(server.changes.len(), 3);
+    #[test]
+    fn diverging_files_with_negative_ids_are_found() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let details = |tag: &str| FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDateTime::from_timestamp(0, 0),
+            sidecars: vec!(),
+            content_hash: Some("abc".into()),
+            size: None,
+            tags: vec!(tag.into()),
+            original_stored: true
+        };
+
+        for id in &[7, -7, -1_000_000] {
+            let tags = vec!(String::from("things"));
+            fdb.add_new_file(*id, &format!("{}.jpg", id), None, &tags, 0, &ChangeCreationPolicy::No);
+            fdb.set_content_hash(*id, "abc").unwrap();
+        }
+        let server = MockForeignServer::new(
+            vec!(
+                (7, (details("things"), vec!(), None)),
+                (-7, (details("stuff"), vec!(), None)),
+                (-1_000_000, (details("things"), vec!(), None)),
+                (i32::MIN, (details("things"), vec!(), None))
+            ),
+            vec!(),
+            vec!()
+        );
+
+        assert_eq!(
+            library_digest::find_diverging_files(&fdb, &server).unwrap(),
+            vec!(
+                DivergingFile{id: i32::MIN, divergence: Divergence::OnlyForeign},
+                DivergingFile{id: -7, divergence: Divergence::Different},
+            )
+        );
     }
 
     #[test]
     fn last_common_syncpoint_considers_gaps() {
         let side1 = vec!(
-            SyncPoint{last_change: naive_datetime_from_date("2017-01-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2018-01-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2018-02-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-02").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-03").unwrap()},
+            SyncPoint::from_timestamp(naive_datetime_from_date("2017-01-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2018-01-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2018-02-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-02").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-03").unwrap()),
         );
         let side2 = vec!(
-            SyncPoint{last_change: naive_datetime_from_date("2017-01-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2018-01-01").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-01-02").unwrap()},
-            SyncPoint{last_change: naive_datetime_from_date("2019-02-03").unwrap()},
+            SyncPoint::from_timestamp(naive_datetime_from_date("2017-01-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2018-01-01").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-01-02").unwrap()),
+            SyncPoint::from_timestamp(naive_datetime_from_date("2019-02-03").unwrap()),
         );
 
         assert_eq!(
             last_common_syncpoint(&side1, &side2).unwrap(),
-            SyncPoint{last_change: naive_datetime_from_date("2018-01-01").unwrap()}
+            SyncPoint::from_timestamp(naive_datetime_from_date("2018-01-01").unwrap())
         );
     }
 
@@ -958,15 +3273,9 @@ mod sync_tests {
         let fdb = fdb.lock().unwrap();
         fdb.reset();
 
-        let common_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2016,1,1).and_hms(0,0,0)
-        };
-        let local_only_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0)
-        };
-        let remote_only_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0)
-        };
+        let common_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2016,1,1).and_hms(0,0,0));
+        let local_only_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0));
+        let remote_only_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0));
         fdb.add_syncpoint(&common_syncpoint).expect("failed to add syncpoint");
         fdb.add_syncpoint(&local_only_syncpoint).expect("failed to add syncpoint");
 
@@ -979,7 +3288,7 @@ mod sync_tests {
         sync_with_foreign(
             &fdb,
             &mut server,
-            0,
+            &sync_options(TransferMode::Callback(0)),
             &(0, tx)
         ).expect("Failed to sync with foreign");
 
@@ -1011,12 +3320,8 @@ mod sync_tests {
         let fdb = fdb.lock().unwrap();
         fdb.reset();
 
-        let local_only_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0)
-        };
-        let remote_only_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0)
-        };
+        let local_only_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0));
+        let remote_only_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0));
         fdb.add_syncpoint(&local_only_syncpoint).expect("failed to add syncpoint");
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
@@ -1028,7 +3333,7 @@ mod sync_tests {
         sync_with_foreign(
             &fdb,
             &mut server,
-            0,
+            &sync_options(TransferMode::Callback(0)),
             &(0, tx)
         ).expect("Failed to sync with foreign");
 
@@ -1055,15 +3360,9 @@ mod sync_tests {
 
     #[test]
     fn common_syncpoint_detection_works_if_unsorted() {
-        let sp1 = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0)
-        };
-        let sp2 = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0)
-        };
-        let sp3 = SyncPoint{
-            last_change: NaiveDate::from_ymd(2018, 2, 1).and_hms(0,0,0)
-        };
+        let sp1 = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0));
+        let sp2 = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0));
+        let sp3 = SyncPoint::from_timestamp(NaiveDate::from_ymd(2018, 2, 1).and_hms(0,0,0));
 
         let local = vec!(sp1.clone(), sp3.clone(), sp2.clone());
         let remote = vec!(sp3.clone(), sp2, sp1);
@@ -1079,18 +3378,10 @@ mod sync_tests {
         let fdb = fdb.lock().unwrap();
         fdb.reset();
 
-        let common_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2016,1,1).and_hms(0,0,0)
-        };
-        let local_only_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0)
-        };
-        let remote_only_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0)
-        };
-        let second_common_syncpoint = SyncPoint{
-            last_change: NaiveDate::from_ymd(2018,1,1).and_hms(0,0,0)
-        };
+        let common_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2016,1,1).and_hms(0,0,0));
+        let local_only_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 1, 1).and_hms(0,0,0));
+        let remote_only_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2017, 2, 1).and_hms(0,0,0));
+        let second_common_syncpoint = SyncPoint::from_timestamp(NaiveDate::from_ymd(2018,1,1).and_hms(0,0,0));
         fdb.add_syncpoint(&common_syncpoint).expect("failed to add syncpoint");
         fdb.add_syncpoint(&local_only_syncpoint).expect("failed to add syncpoint");
         fdb.add_syncpoint(&second_common_syncpoint).expect("failed to add syncpoint");
@@ -1108,7 +3399,7 @@ mod sync_tests {
         sync_with_foreign(
             &fdb,
             &mut server,
-            0,
+            &sync_options(TransferMode::Callback(0)),
             &(0, tx)
         ).expect("Failed to sync with foreign");
 
@@ -1167,9 +3458,9 @@ mod sync_tests {
         }
         fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
             match *starting_syncpoint {
-                Some(SyncPoint{last_change}) => {
+                Some(ref syncpoint) => {
                     Ok(self.changes.iter()
-                        .filter(|change| change.timestamp >= last_change)
+                        .filter(|change| change.clock.counter > syncpoint.clock)
                         .map(|change| change.clone())
                         .collect()
                     )
@@ -1181,21 +3472,47 @@ mod sync_tests {
             Ok(self.file_data[&id].0.clone())
         }
 
-        fn send_changes(&mut self, _data: &ChangeData, _port: u16) -> Result<usize> {
+        fn send_changes(&mut self, _data: &ChangeData, _port: u16, _push_id: Option<usize>)
+            -> Result<usize>
+        {
             Ok(0)
         }
-        fn get_file(&self, id: i32) -> Result<Vec<u8>> {
-            Ok(self.file_data[&id].1.clone())
+        fn get_needed_files(&self, _data: &ChangeData, _port: u16) -> Result<Vec<i32>> {
+            Ok(vec!())
+        }
+        fn push_file_part(&self, _push_id: usize, _file_id: i32, _part: FilePart, _offset: u64, _content: &[u8])
+            -> Result<()>
+        {
+            Ok(())
+        }
+        fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+            write_remaining(&self.file_data[&id].1, destination, on_progress)
+        }
+        fn download_sidecar(&self, _id: i32, _index: usize, _destination: &Path, _on_progress: &dyn Fn(u64))
+            -> Result<()>
+        {
+            Err(ErrorKind::Dummy.into())
         }
         fn get_thumbnail(&self, _id: i32) -> Result<Option<Vec<u8>>> {
             Err(ErrorKind::Dummy.into())
         }
-        fn get_sync_status(&self, _job_id: usize) -> Result<SyncStatus> {
-            Ok(SyncStatus{last_update: SyncUpdate::Done, foreign_job_id: None})
+        fn get_sync_updates(&self, _job_id: usize)
+            -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>>
+        {
+            Ok(Box::new(vec!(Ok(SyncUpdate::Done)).into_iter()))
         }
         fn add_syncpoint(&self, _syncpoint: &SyncPoint) -> Result<()> {
             Ok(())
         }
+        fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+            Ok(library_digest::bucket_digest(prefix, &[]))
+        }
+        fn cancel_job(&self, _job_id: usize) -> Result<()> {
+            Ok(())
+        }
+        fn handshake(&self) -> Result<Handshake> {
+            Ok(Handshake::new("thumbnail-error".into()))
+        }
     }
 
     #[test]
@@ -1218,18 +3535,24 @@ mod sync_tests {
                 vec!(
                     (2, (FileDetails {
                         extension: "jpg".into(),
-                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!(),
+                        content_hash: None,
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
                     }, vec!(0))),
                 ),
                 vec!(),
                 remote_changes
             );
 
-        apply_changes(
+        apply_changes_with_filter(
             &fdb,
             &foreign_server,
             &vec!(),
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).expect("Expected sync to work despite missing thumbnail");
     }
@@ -1264,9 +3587,9 @@ mod sync_tests {
         }
         fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
             match *starting_syncpoint {
-                Some(SyncPoint{last_change}) => {
+                Some(ref syncpoint) => {
                     Ok(self.changes.iter()
-                        .filter(|change| change.timestamp >= last_change)
+                        .filter(|change| change.clock.counter > syncpoint.clock)
                         .map(|change| change.clone())
                         .collect()
                     )
@@ -1278,28 +3601,57 @@ mod sync_tests {
             Ok(self.file_data[&id].0.clone())
         }
 
-        fn send_changes(&mut self, _data: &ChangeData, _port: u16) -> Result<usize> {
+        fn send_changes(&mut self, _data: &ChangeData, _port: u16, _push_id: Option<usize>)
+            -> Result<usize>
+        {
             Ok(0)
         }
-        fn get_file(&self, id: i32) -> Result<Vec<u8>> {
+        fn get_needed_files(&self, _data: &ChangeData, _port: u16) -> Result<Vec<i32>> {
+            Ok(vec!())
+        }
+        fn push_file_part(&self, _push_id: usize, _file_id: i32, _part: FilePart, _offset: u64, _content: &[u8])
+            -> Result<()>
+        {
+            Ok(())
+        }
+        fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
             let mut has_errored = self.file_data[&id].2.lock().unwrap();
             if *has_errored == true {
-                Ok(self.file_data[&id].1.clone())
+                write_remaining(&self.file_data[&id].1, destination, on_progress)
             }
             else {
+                // Fail half way through the download
                 *has_errored = true;
+                let content = &self.file_data[&id].1;
+                write_remaining(&content[..content.len() / 2], destination, on_progress)?;
                 Err(ErrorKind::Dummy.into())
             }
         }
+        fn download_sidecar(&self, _id: i32, _index: usize, _destination: &Path, _on_progress: &dyn Fn(u64))
+            -> Result<()>
+        {
+            Err(ErrorKind::Dummy.into())
+        }
         fn get_thumbnail(&self, _id: i32) -> Result<Option<Vec<u8>>> {
             Ok(None)
         }
-        fn get_sync_status(&self, _job_id: usize) -> Result<SyncStatus> {
-            Ok(SyncStatus{last_update: SyncUpdate::Done, foreign_job_id: None})
+        fn get_sync_updates(&self, _job_id: usize)
+            -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>>
+        {
+            Ok(Box::new(vec!(Ok(SyncUpdate::Done)).into_iter()))
         }
         fn add_syncpoint(&self, _syncpoint: &SyncPoint) -> Result<()> {
             Ok(())
         }
+        fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+            Ok(library_digest::bucket_digest(prefix, &[]))
+        }
+        fn cancel_job(&self, _job_id: usize) -> Result<()> {
+            Ok(())
+        }
+        fn handshake(&self) -> Result<Handshake> {
+            Ok(Handshake::new("unstable".into()))
+        }
     }
 
     #[test]
@@ -1320,17 +3672,392 @@ mod sync_tests {
             1,
             (FileDetails {
                 extension: "jpg".into(),
-                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0)
+                timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                sidecars: vec!(),
+                content_hash: None,
+                size: None,
+                tags: vec!(),
+                original_stored: true
             }, vec!(1,2,3))
         ));
 
         let (tx, _rx, _) = sp::setup_progress_datastructures();
-        assert!(apply_changes(
+        assert!(apply_changes_with_filter(
             &fdb,
             &UnstableForeignServer::new(foreign_files, vec!(), vec!()),
             &changes,
-            &vec!(),
+            &[],
+            &ApplyOptions::unfiltered(2),
             &(0, tx)
         ).is_ok());
+
+        // The second attempt should resume from the partially downloaded file
+        let storage = fdb.get_file_save_path();
+        assert_eq!(fs::read(storage.join("1.jpg")).unwrap(), vec!(1,2,3));
+        assert!(!storage.join("1.jpg.download").exists());
+    }
+
+    #[test]
+    fn requests_are_retried_until_the_retries_run_out() {
+        let retry_policy = RetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1)
+        };
+        let failing_until = |successful_attempt: usize| {
+            let mut attempts = 0;
+            with_retries(&retry_policy, None, || {
+                attempts += 1;
+                if attempts < successful_attempt {
+                    Err(ErrorKind::Dummy.into())
+                }
+                else {
+                    Ok(attempts)
+                }
+            })
+        };
+
+        assert_matches!(failing_until(3), Ok(3));
+        assert_matches!(failing_until(4), Err(Error(ErrorKind::Dummy, _)));
+    }
+
+    #[test]
+    fn files_with_wrong_content_hash_are_rejected() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let changes = vec!(
+                Change::new(
+                    naive_datetime_from_date("2017-01-01").unwrap(),
+                    1,
+                    ChangeType::FileAdded
+                ),
+            );
+
+        let foreign_server = MockForeignServer::new(
+                vec!(
+                    (1, (FileDetails {
+                        extension: "jpg".into(),
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!(),
+                        content_hash: Some(to_hex(&Sha256::digest([3,2,1]))),
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
+                    }, vec!(1,2,3), None)),
+                ),
+                vec!(),
+                vec!()
+            );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        assert!(apply_changes_with_filter(&fdb, &foreign_server, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx)).is_err());
+
+        assert_eq!(fdb.get_file_with_id(1), None);
+        assert!(!fdb.get_file_save_path().join("1.jpg.download").exists());
+    }
+
+    #[test]
+    fn files_with_correct_content_hash_are_accepted() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let changes = vec!(
+                Change::new(
+                    naive_datetime_from_date("2017-01-01").unwrap(),
+                    1,
+                    ChangeType::FileAdded
+                ),
+            );
+
+        let foreign_server = MockForeignServer::new(
+                vec!(
+                    (1, (FileDetails {
+                        extension: "jpg".into(),
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!(),
+                        content_hash: Some(to_hex(&Sha256::digest([1,2,3]))),
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
+                    }, vec!(1,2,3), None)),
+                ),
+                vec!(),
+                vec!()
+            );
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        apply_changes_with_filter(&fdb, &foreign_server, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx))
+            .expect("Failed to apply changes");
+
+        assert!(fdb.get_file_with_id(1).is_some());
+    }
+
+    #[test]
+    fn files_are_fetched_concurrently() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let changes = vec!(
+                Change::new(
+                    naive_datetime_from_date("2017-01-01").unwrap(),
+                    1,
+                    ChangeType::FileAdded
+                ),
+                Change::new(
+                    naive_datetime_from_date("2017-01-02").unwrap(),
+                    1,
+                    ChangeType::Update(UpdateType::TagAdded("things".into()))
+                ),
+                Change::new(
+                    naive_datetime_from_date("2017-01-03").unwrap(),
+                    2,
+                    ChangeType::FileAdded
+                ),
+                Change::new(
+                    naive_datetime_from_date("2017-01-04").unwrap(),
+                    3,
+                    ChangeType::FileAdded
+                ),
+            );
+
+        let foreign_files = (1..4)
+            .map(|id| {
+                (id, (FileDetails {
+                    extension: "jpg".into(),
+                    timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                    sidecars: vec!(),
+                    content_hash: None,
+                    size: Some(1),
+                    tags: vec!(),
+                    original_stored: true
+                }, vec!(id as u8), None))
+            })
+            .collect();
+
+        let foreign_server = ConcurrentForeignServer::new(
+            MockForeignServer::new(foreign_files, vec!(), vec!())
+        );
+
+        let (tx, rx, _) = sp::setup_progress_datastructures();
+        apply_changes_with_filter(&fdb, &foreign_server, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx))
+            .expect("Failed to apply changes");
+
+        assert_eq!(foreign_server.max_concurrent_downloads(), 2);
+
+        // Changes are still applied in order, so the tag is added after the file
+        assert_eq!(fdb.get_file_with_id(1).unwrap().tags, vec!("things".to_owned()));
+        for id in 1..4 {
+            let file = fdb.get_file_with_id(id).expect("File was not added");
+            assert_eq!(fs::read(fdb.get_file_save_path().join(file.filename)).unwrap(), vec!(id as u8));
+        }
+
+        let last_download_update = rx.try_iter()
+            .filter_map(|(_, update)| match update {
+                SyncUpdate::Downloading(progress) => Some(progress),
+                _ => None
+            })
+            .last();
+        assert_eq!(
+            last_download_update,
+            Some(sp::DownloadProgress {
+                in_flight: 0,
+                finished: 3,
+                total: 3,
+                bytes_received: 3,
+                bytes_total: 3
+            })
+        );
+    }
+
+    #[test]
+    fn sidecars_are_transferred() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let changes = vec!(
+                Change::new(
+                    naive_datetime_from_date("2017-01-01").unwrap(),
+                    1,
+                    ChangeType::FileAdded
+                ),
+            );
+
+        let foreign_server = MockForeignServer::new(
+                vec!(
+                    (1, (FileDetails {
+                        extension: "jpg".into(),
+                        timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0,0,0),
+                        sidecars: vec!("nef".into()),
+                        content_hash: None,
+                        size: None,
+                        tags: vec!(),
+                        original_stored: true
+                    }, vec!(1,2,3), None)),
+                ),
+                vec!(),
+                vec!()
+            ).with_sidecar(1, 0, vec!(4,5,6));
+
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        apply_changes_with_filter(&fdb, &foreign_server, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx))
+            .expect("Failed to apply changes");
+
+        let file = fdb.get_file_with_id(1).expect("File was not added");
+        assert_eq!(file.sidecars, vec!("1_0.nef".to_owned()));
+        assert_eq!(FileDetails::from(&file).sidecars, vec!("nef".to_owned()));
+    }
+
+    /**
+      Server whose jobs never finish, and which records the jobs it was asked
+      to cancel
+    */
+    struct EndlessForeignServer {
+        inner: MockForeignServer,
+        cancelled_jobs: Mutex<Vec<usize>>
+    }
+
+    impl EndlessForeignServer {
+        pub fn new(inner: MockForeignServer) -> Self {
+            Self {
+                inner,
+                cancelled_jobs: Mutex::new(vec!())
+            }
+        }
+    }
+
+    impl ForeignServer for EndlessForeignServer {
+        fn get_syncpoints(&self) -> Result<Vec<SyncPoint>>{
+            self.inner.get_syncpoints()
+        }
+        fn get_changes(&self, starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
+            self.inner.get_changes(starting_syncpoint)
+        }
+        fn get_file_details(&self, id: i32) -> Result<FileDetails> {
+            self.inner.get_file_details(id)
+        }
+        fn send_changes(&mut self, data: &ChangeData, port: u16, push_id: Option<usize>)
+            -> Result<usize>
+        {
+            self.inner.send_changes(data, port, push_id)
+        }
+        fn get_needed_files(&self, data: &ChangeData, port: u16) -> Result<Vec<i32>> {
+            self.inner.get_needed_files(data, port)
+        }
+        fn push_file_part(&self, push_id: usize, file_id: i32, part: FilePart, offset: u64, content: &[u8])
+            -> Result<()>
+        {
+            self.inner.push_file_part(push_id, file_id, part, offset, content)
+        }
+        fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+            self.inner.download_file(id, destination, on_progress)
+        }
+        fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+            -> Result<()>
+        {
+            self.inner.download_sidecar(id, index, destination, on_progress)
+        }
+        fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
+            self.inner.get_thumbnail(id)
+        }
+        fn get_sync_updates(&self, _job_id: usize)
+            -> Result<Box<dyn Iterator<Item=Result<SyncUpdate>>>>
+        {
+            Ok(Box::new((0..).map(|_| {
+                thread::sleep(Duration::from_millis(10));
+                Ok(SyncUpdate::WaitingForForeign)
+            })))
+        }
+        fn add_syncpoint(&self, syncpoint: &SyncPoint) -> Result<()> {
+            self.inner.add_syncpoint(syncpoint)
+        }
+        fn get_library_digest(&self, prefix: &str) -> Result<LibraryDigest> {
+            self.inner.get_library_digest(prefix)
+        }
+        fn cancel_job(&self, job_id: usize) -> Result<()> {
+            self.cancelled_jobs.lock().unwrap().push(job_id);
+            Ok(())
+        }
+        fn handshake(&self) -> Result<Handshake> {
+            self.inner.handshake()
+        }
+    }
+
+    #[test]
+    fn stopped_syncs_leave_both_sides_without_syncpoints() {
+        use sync_control::{JobGuard, JobLimits};
+
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        fdb.add_new_file(
+            53,
+            "53.jpg",
+            None,
+            &[],
+            0,
+            &ChangeCreationPolicy::Yes(NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0))
+        );
+
+        let sync = |server: &mut EndlessForeignServer, job_id| {
+            let (tx, _rx, _) = sp::setup_progress_datastructures();
+            sync_with_foreign(
+                &fdb,
+                server,
+                &sync_options(TransferMode::Callback(0)),
+                &(job_id, tx)
+            )
+        };
+
+        // A cancelled job stops before anything is sent
+        let mut server = EndlessForeignServer::new(MockForeignServer::new(vec!(), vec!(), vec!()));
+        {
+            let _guard = JobGuard::register(1003, &JobLimits::default());
+            sync_control::cancel_job(1003).unwrap();
+            assert_matches!(
+                sync(&mut server, 1003).err(),
+                Some(::error::Error(ErrorKind::JobCancelled(1003), _))
+            );
+        }
+        assert!(server.inner.changes.is_empty());
+        assert!(server.cancelled_jobs.lock().unwrap().is_empty());
+
+        // A job that times out while the foreign server applies the changes
+        // cancels the foreign job
+        let limits = JobLimits {
+            timeout: Some(Duration::from_millis(500)),
+            retry_policy: RetryPolicy {
+                retries: 0,
+                initial_backoff: Duration::from_millis(0),
+                max_backoff: Duration::from_millis(0)
+            }
+        };
+        {
+            let _guard = JobGuard::register(1004, &limits);
+            let error = sync(&mut server, 1004).expect_err("Expected the sync to time out");
+            assert!(::error::describe_chain(&error).contains("Job 1004 timed out"));
+        }
+        assert_eq!(server.inner.changes.len(), 1);
+        assert_eq!(*server.cancelled_jobs.lock().unwrap(), vec!(0));
+
+        assert!(fdb.get_syncpoints().unwrap().is_empty());
+        assert!(server.get_syncpoints().unwrap().is_empty());
+
+        // The stopped sync is resumed by the next one
+        let mut server = server.inner;
+        let (tx, _rx, _) = sp::setup_progress_datastructures();
+        sync_with_foreign(
+            &fdb,
+            &mut server,
+            &sync_options(TransferMode::Callback(0)),
+            &(0, tx)
+        ).expect("Failed to resume the sync");
+        assert_eq!(fdb.get_syncpoints().unwrap().len(), 1);
+        assert_eq!(server.get_syncpoints().unwrap().len(), 1);
     }
 }
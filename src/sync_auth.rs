@@ -0,0 +1,587 @@
+use iron::prelude::*;
+use iron::{AfterMiddleware, status};
+use iron::typemap::Key;
+use persistent;
+use mount::OriginalUrl;
+use reqwest::Url;
+
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+
+use chrono::Utc;
+
+use std::io::Read;
+
+use settings::Settings;
+use request_helpers::setup_db_connection;
+use util::to_hex;
+
+use error::{Result, ErrorKind};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header containing the unix timestamp of when a sync request was sent
+pub const TIMESTAMP_HEADER: &str = "X-Flash-Timestamp";
+/// Header containing the hex encoded HMAC-SHA256 signature of a sync request
+pub const SIGNATURE_HEADER: &str = "X-Flash-Signature";
+
+/// Header containing the sync protocol version of the instance that sent a
+/// request or response
+pub const PROTOCOL_VERSION_HEADER: &str = "X-Flash-Protocol-Version";
+
+/**
+  Version of the sync protocol. Peers only sync with peers using at least
+  `MIN_PROTOCOL_VERSION`, the rest of the differences are negotiated through
+  `/sync/handshake`.
+
+  1: Change ids were 32 bit hashes which depended on the rust version. Versions
+     before the header was added are treated as 1
+  2: Change ids are 64 bit hashes computed by `changelog::change_id`
+  3: Changes and syncpoints have logical clocks and changes are requested by
+     clock instead of timestamp
+  4: Sync jobs on the foreign server are followed through `/sync/progress/events`
+  5: Files can be pushed to the foreign server through `/sync/needed_files` and
+     `/sync/push`
+  6: Peers exchange the change types and features they support through
+     `/sync/handshake` and accept peers with newer versions
+  7: Replaced file content is synced through `ContentChanged` changes, which
+     are not sent to peers using version 6
+  8: Request signatures cover the host the request was sent to, and responses
+     to signed requests are signed. Applied changes are stored with the clock
+     of the sync that received them
+*/
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/**
+  The oldest version of the sync protocol that this instance accepts requests from
+*/
+pub const MIN_PROTOCOL_VERSION: u32 = 6;
+
+/**
+  The protocol version from which request signatures cover the host and
+  responses are signed. Requests from peers using this version or newer must
+  cover the host. This instance only sends requests to peers using at least
+  this version, older peers could not verify its signatures and would let
+  a host that received them replay them to other peers
+*/
+pub const SIGNED_HOST_VERSION: u32 = 8;
+
+/// The protocol version from which peers can apply `ContentChanged` changes
+pub const CONTENT_CHANGE_VERSION: u32 = 7;
+
+/// The amount of seconds a request timestamp may differ from the current time.
+/// This also limits how long a captured request can be replayed
+const MAX_CLOCK_DIFFERENCE: i64 = 300;
+
+/**
+  Returns the path and query of a url in the form that is signed. Empty
+  queries are left out to make `/a?` and `/a` equivalent
+*/
+pub fn canonical_path(path: &str, query: Option<&str>) -> String {
+    let path = format!("/{}", path.trim_start_matches('/'));
+
+    match query {
+        Some(query) if !query.is_empty() => format!("{}?{}", path, query),
+        _ => path
+    }
+}
+
+/**
+  Returns the host of `url` in the form that is signed, which is the form of
+  the `Host` header that is sent to it: the port is only included if it is not
+  the default port of the scheme
+*/
+pub fn signed_host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or("").to_lowercase();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host
+    }
+}
+
+/**
+  The parts of a request which are covered by its signature
+*/
+pub struct SignedContent<'a> {
+    pub method: &'a str,
+    /// The host the request was sent to, see `signed_host`. Peers using a
+    /// version older than `SIGNED_HOST_VERSION` leave it out
+    pub host: Option<&'a str>,
+    /// The path and query built by `canonical_path`
+    pub path: &'a str,
+    pub timestamp: i64,
+    pub body: &'a [u8]
+}
+
+/**
+  Builds the MAC of a request. The method, host, path, query, timestamp and a
+  hash of the body are all covered so none of them can be changed without
+  invalidating the signature. Covering the host stops an instance that
+  receives a request from replaying it to other peers
+*/
+fn request_mac(secret: &str, content: &SignedContent) -> HmacSha256 {
+    let mut mac = new_mac(secret);
+
+    if let Some(host) = content.host {
+        mac.update(format!("{}\n{}\n", content.method, host.to_lowercase()).as_bytes());
+    }
+    else {
+        mac.update(format!("{}\n", content.method).as_bytes());
+    }
+    mac.update(format!("{}\n{}\n", content.path, content.timestamp).as_bytes());
+    mac.update(to_hex(&Sha256::digest(content.body)).as_bytes());
+    mac
+}
+
+/**
+  Builds the MAC of the response to the request which was signed with
+  `request_signature`. `body_hash` is the hex encoded SHA-256 of the body.
+  Covering the request signature stops responses from being sent in reply to
+  other requests
+*/
+fn response_mac(secret: &str, request_signature: &str, body_hash: &str) -> HmacSha256 {
+    let mut mac = new_mac(secret);
+    mac.update(format!("response\n{}\n{}", request_signature, body_hash).as_bytes());
+    mac
+}
+
+fn new_mac(secret: &str) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC can take a key of any size")
+}
+
+/**
+  Returns the hex encoded signature of a request which should be sent in the
+  `SIGNATURE_HEADER`
+*/
+pub fn sign_request(secret: &str, content: &SignedContent) -> String {
+    to_hex(&request_mac(secret, content).finalize().into_bytes())
+}
+
+/**
+  Checks that `signature` was created by `sign_request` with the same secret
+  and request content, and that the request was sent at most
+  `MAX_CLOCK_DIFFERENCE` seconds from `now`
+*/
+pub fn verify_signature(secret: &str, content: &SignedContent, signature: &str, now: i64) -> Result<()> {
+    if (now - content.timestamp).abs() > MAX_CLOCK_DIFFERENCE {
+        bail!(ErrorKind::UnauthenticatedSyncRequest(
+            format!("The request timestamp differs from the current time by more than {}s", MAX_CLOCK_DIFFERENCE)
+        ));
+    }
+
+    let signature = from_hex(signature)
+        .ok_or_else(|| ErrorKind::UnauthenticatedSyncRequest("The signature is not valid hex".into()))?;
+
+    // verify_slice compares in constant time
+    request_mac(secret, content)
+        .verify_slice(&signature)
+        .map_err(|_| ErrorKind::UnauthenticatedSyncRequest("Invalid signature".into()))?;
+
+    Ok(())
+}
+
+/**
+  Returns the hex encoded signature of the response to the request which was
+  signed with `request_signature`, which is sent in the `SIGNATURE_HEADER`
+*/
+pub fn sign_response(secret: &str, request_signature: &str, body_hash: &str) -> String {
+    to_hex(&response_mac(secret, request_signature, body_hash).finalize().into_bytes())
+}
+
+/**
+  Checks that `signature` was created by `sign_response` for a response with
+  the body hash `body_hash` to the request signed with `request_signature`.
+  `signature` is `None` if the response was not signed
+*/
+pub fn verify_response(
+    secret: &str,
+    request_signature: &str,
+    body_hash: &str,
+    signature: Option<&str>
+) -> Result<()> {
+    let signature = signature.and_then(from_hex)
+        .ok_or_else(|| ErrorKind::UnauthenticatedSyncResponse("Missing or malformed signature".into()))?;
+
+    response_mac(secret, request_signature, body_hash)
+        .verify_slice(&signature)
+        .map_err(|_| ErrorKind::UnauthenticatedSyncResponse("Invalid signature".into()))?;
+
+    Ok(())
+}
+
+/**
+  The secret and signature of a request that was authenticated, which are
+  used to sign the response, and the protocol version of the peer that sent it
+*/
+#[derive(Clone)]
+pub struct VerifiedRequest {
+    pub secret: String,
+    pub signature: String,
+    pub protocol_version: u32
+}
+
+impl Key for VerifiedRequest {
+    type Value = VerifiedRequest;
+}
+
+/**
+  Builds a successful response with `body` which is signed if the request was
+  authenticated. Peers reject unsigned responses from this version on
+*/
+pub fn signed_response<B: Into<Vec<u8>>>(request: &Request, body: B) -> Response {
+    let body = body.into();
+    let body_hash = to_hex(&Sha256::digest(&body));
+
+    let mut response = Response::with((status::Ok, body));
+    sign_response_body(request, &mut response, &body_hash);
+    response
+}
+
+/**
+  Signs a response whose body has the hex encoded SHA-256 `body_hash`, for
+  bodies that are streamed. Does nothing if the request was not authenticated
+*/
+pub fn sign_response_body(request: &Request, response: &mut Response, body_hash: &str) {
+    if let Some(verified) = request.extensions.get::<VerifiedRequest>() {
+        let signature = sign_response(&verified.secret, &verified.signature, body_hash);
+        response.headers.set_raw(SIGNATURE_HEADER, vec!(signature.into_bytes()));
+    }
+}
+
+/**
+  The protocol version of the peer that sent an authenticated request
+*/
+pub fn peer_protocol_version(request: &Request) -> Option<u32> {
+    request.extensions.get::<VerifiedRequest>().map(|verified| verified.protocol_version)
+}
+
+/**
+  Checks that a request was signed by a peer which knows the sync secret and
+  that the peer uses the same protocol version.
+
+  The body of the request has to be read to check the signature so it is
+  returned for handlers that need it
+*/
+pub fn authenticate_request(request: &mut Request) -> Result<Vec<u8>> {
+    Ok(authenticate_request_with_secret(request)?.0)
+}
+
+/**
+  Like `authenticate_request` but also returns the secret that the request was
+  signed with. Requests may be signed with `FLASH_SYNC_SECRET` or with the
+  secret of any registered peer
+*/
+pub fn authenticate_request_with_secret(request: &mut Request) -> Result<(Vec<u8>, String)> {
+    let mut secrets = own_secret(request);
+    secrets.extend(setup_db_connection(request)?.get_peer_secrets()?);
+
+    verify_request(request, secrets)
+}
+
+/**
+  Checks that a request was signed with `FLASH_SYNC_SECRET`. Requests that
+  change which peers are trusted must use this since the secrets of peers are
+  only trusted because they were registered by someone who knows it
+*/
+pub fn authenticate_admin_request(request: &mut Request) -> Result<Vec<u8>> {
+    let secrets = own_secret(request);
+
+    Ok(verify_request(request, secrets)?.0)
+}
+
+fn own_secret(request: &mut Request) -> Vec<String> {
+    request.get::<persistent::Read<Settings>>().unwrap()
+        .get_sync_secret()
+        .into_iter()
+        .collect()
+}
+
+/**
+  Checks the signature and protocol version of a request against each of
+  `secrets`. Returns the body and the secret that the request was signed with,
+  which are also stored as a `VerifiedRequest` to sign the response.
+
+  The signature has to cover the `Host` header or the host of `FLASH_SYNC_URL`,
+  which is the one peers use if a proxy changes the header. Only peers using
+  a version older than `SIGNED_HOST_VERSION` may leave the host out
+*/
+fn verify_request(request: &mut Request, secrets: Vec<String>) -> Result<(Vec<u8>, String)> {
+    if secrets.is_empty() {
+        bail!(ErrorKind::SyncSecretMissing);
+    }
+
+    let peer_version = check_protocol_version(
+        request.headers.get_raw(PROTOCOL_VERSION_HEADER).map(|values| &values[0][..])
+    )?;
+    let hosts = if peer_version >= SIGNED_HOST_VERSION {
+        let sync_url = request.get::<persistent::Read<Settings>>().unwrap().get_sync_url();
+        get_header(request, "Host").ok().into_iter()
+            .chain(sync_url.and_then(|url| url_host(&url)))
+            .map(Some)
+            .collect()
+    }
+    else {
+        vec!(None)
+    };
+
+    let timestamp = get_header(request, TIMESTAMP_HEADER)?
+        .parse::<i64>()
+        .map_err(|_| ErrorKind::UnauthenticatedSyncRequest("The timestamp is not a number".into()))?;
+    let signature = get_header(request, SIGNATURE_HEADER)?;
+
+    // Mount removes the prefix that was used to find the handler from request.url
+    let path = {
+        let url = request.extensions.get::<OriginalUrl>().unwrap_or(&request.url);
+        canonical_path(&url.path().join("/"), url.query())
+    };
+
+    let mut body = vec!();
+    request.body.read_to_end(&mut body)?;
+
+    let now = Utc::now().timestamp();
+    let mut last_error = ErrorKind::UnauthenticatedSyncRequest("The Host header is missing".into()).into();
+    let mut used_secret = None;
+    'secrets: for secret in secrets {
+        for host in &hosts {
+            let content = SignedContent {
+                method: request.method.as_ref(),
+                host: host.as_ref().map(|host| host.as_str()),
+                path: &path,
+                timestamp,
+                body: &body
+            };
+
+            match verify_signature(&secret, &content, &signature, now) {
+                Ok(()) => {
+                    used_secret = Some(secret);
+                    break 'secrets;
+                }
+                Err(e) => last_error = e
+            }
+        }
+    }
+    let secret = match used_secret {
+        Some(secret) => secret,
+        None => return Err(last_error)
+    };
+
+    request.extensions.insert::<VerifiedRequest>(VerifiedRequest {
+        secret: secret.clone(),
+        signature,
+        protocol_version: peer_version
+    });
+
+    Ok((body, secret))
+}
+
+/**
+  Returns the host of a url like `https://flash.example.com` in the form that
+  is signed. Urls without a scheme are assumed to use http
+*/
+fn url_host(url: &str) -> Option<String> {
+    let url = if url.contains("://") {url.to_string()} else {format!("http://{}", url)};
+    Url::parse(&url).ok().map(|url| signed_host(&url))
+}
+
+/**
+  Checks that a peer uses a protocol version this instance can sync with.
+  `version` is the raw value of the `PROTOCOL_VERSION_HEADER` sent by the peer.
+  Returns the version of the peer.
+
+  Newer versions are accepted since the peer knows which versions it is
+  compatible with, and refuses to sync if this one is too old
+*/
+pub fn check_protocol_version(version: Option<&[u8]>) -> Result<u32> {
+    let peer_version = match version {
+        Some(version) => {
+            String::from_utf8_lossy(version).trim().parse::<u32>()
+                .map_err(|_| ErrorKind::UnauthenticatedSyncRequest(
+                    format!("{} is not a number", PROTOCOL_VERSION_HEADER)
+                ))?
+        }
+        None => 1
+    };
+
+    if peer_version < MIN_PROTOCOL_VERSION {
+        bail!(ErrorKind::IncompatibleProtocolVersion(PROTOCOL_VERSION, peer_version));
+    }
+    Ok(peer_version)
+}
+
+/**
+  Middleware which adds the `PROTOCOL_VERSION_HEADER` to all responses so
+  peers can check the version of this instance
+*/
+pub struct ProtocolVersionHeader;
+
+impl AfterMiddleware for ProtocolVersionHeader {
+    fn after(&self, _: &mut Request, mut response: Response) -> IronResult<Response> {
+        response.headers.set_raw(PROTOCOL_VERSION_HEADER, vec!(PROTOCOL_VERSION.to_string().into_bytes()));
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request, mut error: IronError) -> IronResult<Response> {
+        error.response = self.after(request, error.response)?;
+        Err(error)
+    }
+}
+
+fn get_header(request: &Request, name: &str) -> Result<String> {
+    match request.headers.get_raw(name) {
+        Some(values) if values.len() == 1 => {
+            String::from_utf8(values[0].clone())
+                .map_err(|_| ErrorKind::UnauthenticatedSyncRequest(format!("{} is not utf-8", name)).into())
+        }
+        _ => bail!(ErrorKind::UnauthenticatedSyncRequest(format!("Missing {} header", name)))
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod sync_auth_tests {
+    use super::*;
+    use error::Error;
+
+    const SECRET: &str = "shared secret";
+    const NOW: i64 = 1_500_000_000;
+    const HOST: &str = "flash.example.com";
+
+    fn content<'a>(path: &'a str, timestamp: i64, body: &'a [u8]) -> SignedContent<'a> {
+        SignedContent {method: "GET", host: Some(HOST), path, timestamp, body}
+    }
+
+    fn verify(secret: &str, path: &str, timestamp: i64, signature: &str, body: &[u8]) -> Result<()> {
+        verify_signature(secret, &content(path, timestamp, body), signature, NOW)
+    }
+
+    #[test]
+    fn signed_requests_are_accepted() {
+        let signature = sign_request(SECRET, &content("/sync/file?file_id=1", NOW, b"body"));
+
+        assert_matches!(verify(SECRET, "/sync/file?file_id=1", NOW, &signature, b"body"), Ok(()));
+        assert_matches!(verify(SECRET, "/sync/file?file_id=1", NOW + 10, &signature, b"body"), Err(_));
+    }
+
+    #[test]
+    fn modified_requests_are_rejected() {
+        let signature = sign_request(SECRET, &content("/sync/file?file_id=1", NOW, b"body"));
+
+        assert_matches!(verify("other secret", "/sync/file?file_id=1", NOW, &signature, b"body"), Err(_));
+        assert_matches!(verify(SECRET, "/sync/file?file_id=2", NOW, &signature, b"body"), Err(_));
+        assert_matches!(verify(SECRET, "/sync/file?file_id=1", NOW, &signature, b"other"), Err(_));
+        assert_matches!(
+            verify_signature(
+                SECRET,
+                &SignedContent {method: "POST", ..content("/sync/file?file_id=1", NOW, b"body")},
+                &signature,
+                NOW
+            ),
+            Err(_)
+        );
+        assert_matches!(verify(SECRET, "/sync/file?file_id=1", NOW, "not hex", b"body"), Err(_));
+        assert_matches!(verify(SECRET, "/sync/file?file_id=1", NOW, "", b"body"), Err(_));
+    }
+
+    #[test]
+    fn requests_to_other_hosts_are_rejected() {
+        let signature = sign_request(SECRET, &content("/sync/changes", NOW, b""));
+
+        let other_host = SignedContent {host: Some("other.example.com"), ..content("/sync/changes", NOW, b"")};
+        assert_matches!(verify_signature(SECRET, &other_host, &signature, NOW), Err(_));
+
+        let same_host = SignedContent {host: Some("Flash.Example.com"), ..content("/sync/changes", NOW, b"")};
+        assert_matches!(verify_signature(SECRET, &same_host, &signature, NOW), Ok(()));
+
+        // Signatures of old peers don't cover the host and are only valid without it
+        let legacy = SignedContent {host: None, ..content("/sync/changes", NOW, b"")};
+        let legacy_signature = sign_request(SECRET, &legacy);
+        assert_matches!(verify_signature(SECRET, &legacy, &legacy_signature, NOW), Ok(()));
+        assert_matches!(verify(SECRET, "/sync/changes", NOW, &legacy_signature, b""), Err(_));
+        assert_matches!(verify_signature(SECRET, &legacy, &signature, NOW), Err(_));
+    }
+
+    #[test]
+    fn signed_hosts() {
+        let host = |url: &str| signed_host(&Url::parse(url).unwrap());
+
+        assert_eq!(host("http://Flash.example.com/sync"), "flash.example.com");
+        assert_eq!(host("http://flash.example.com:80/sync"), "flash.example.com");
+        assert_eq!(host("https://flash.example.com:3000"), "flash.example.com:3000");
+        assert_eq!(url_host("192.168.1.2:3000"), Some("192.168.1.2:3000".to_string()));
+        assert_eq!(url_host("https://flash.example.com"), Some("flash.example.com".to_string()));
+    }
+
+    #[test]
+    fn response_signatures() {
+        let request_signature = sign_request(SECRET, &content("/sync/changes", NOW, b""));
+        let body_hash = to_hex(&Sha256::digest(b"[]"));
+        let signature = sign_response(SECRET, &request_signature, &body_hash);
+
+        assert_matches!(verify_response(SECRET, &request_signature, &body_hash, Some(&signature)), Ok(()));
+        assert_matches!(
+            verify_response(SECRET, &request_signature, &body_hash, None),
+            Err(Error(ErrorKind::UnauthenticatedSyncResponse(_), _))
+        );
+        assert_matches!(verify_response("other secret", &request_signature, &body_hash, Some(&signature)), Err(_));
+
+        let other_body = to_hex(&Sha256::digest(b"[{}]"));
+        assert_matches!(verify_response(SECRET, &request_signature, &other_body, Some(&signature)), Err(_));
+
+        // A response can't be replayed as the response to another request
+        let other_request = sign_request(SECRET, &content("/sync/changes", NOW + 1, b""));
+        assert_matches!(verify_response(SECRET, &other_request, &body_hash, Some(&signature)), Err(_));
+    }
+
+    #[test]
+    fn old_requests_are_rejected() {
+        let old = NOW - MAX_CLOCK_DIFFERENCE - 1;
+        let signature = sign_request(SECRET, &content("/sync/changes", old, b""));
+
+        assert_matches!(verify(SECRET, "/sync/changes", old, &signature, b""), Err(_));
+    }
+
+    #[test]
+    fn canonical_paths() {
+        assert_eq!(canonical_path("sync/syncpoints", Some("")), "/sync/syncpoints");
+        assert_eq!(canonical_path("/sync/syncpoints", None), "/sync/syncpoints");
+        assert_eq!(canonical_path("/sync/file", Some("file_id=1")), "/sync/file?file_id=1");
+    }
+
+    #[test]
+    fn protocol_versions_are_checked() {
+        let own_version = PROTOCOL_VERSION.to_string();
+
+        assert_matches!(check_protocol_version(Some(own_version.as_bytes())), Ok(PROTOCOL_VERSION));
+        assert_matches!(
+            check_protocol_version(None),
+            Err(Error(ErrorKind::IncompatibleProtocolVersion(PROTOCOL_VERSION, 1), _))
+        );
+        assert_matches!(
+            check_protocol_version(Some(b"1")),
+            Err(Error(ErrorKind::IncompatibleProtocolVersion(PROTOCOL_VERSION, 1), _))
+        );
+        assert_matches!(check_protocol_version(Some(b"two")), Err(_));
+
+        let newer_version = (PROTOCOL_VERSION + 1).to_string();
+        assert_matches!(check_protocol_version(Some(newer_version.as_bytes())), Ok(_));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec!(0, 15, 255)));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+}
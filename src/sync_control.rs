@@ -0,0 +1,196 @@
+use iron::prelude::*;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use request_helpers::{get_get_usize, to_json_with_result};
+use sync_auth::{authenticate_request, signed_response};
+
+use error::{Result, ErrorKind};
+
+/// How often a job that is waiting checks if it was stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/**
+  How requests to foreign servers are retried when they fail. The time between
+  attempts doubles after each attempt, up to `max_backoff`
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a request is retried after the first attempt
+    pub retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60)
+        }
+    }
+}
+
+impl RetryPolicy {
+    /**
+      The time to wait before retrying after `attempt` failed attempts
+    */
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+        self.initial_backoff.checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/**
+  The limits of a sync job
+*/
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct JobLimits {
+    /// The job is stopped if it takes longer than this
+    pub timeout: Option<Duration>,
+    pub retry_policy: RetryPolicy
+}
+
+struct RunningJob {
+    cancelled: bool,
+    deadline: Option<Instant>,
+    retry_policy: RetryPolicy
+}
+
+lazy_static! {
+    /// The jobs that are running on this instance. Jobs check if they should
+    /// stop between steps that leave the database consistent
+    static ref RUNNING: Mutex<HashMap<usize, RunningJob>> = Mutex::new(HashMap::new());
+}
+
+/**
+  Marks a job as running until it is dropped
+*/
+pub struct JobGuard(usize);
+
+impl JobGuard {
+    pub fn register(job_id: usize, limits: &JobLimits) -> Self {
+        RUNNING.lock().unwrap().insert(job_id, RunningJob {
+            cancelled: false,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            retry_policy: limits.retry_policy.clone()
+        });
+        JobGuard(job_id)
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/**
+  Fails if the job was cancelled or has run for too long. Jobs that were not
+  registered, like jobs in tests, are never stopped
+*/
+pub fn check_job(job_id: usize) -> Result<()> {
+    match RUNNING.lock().unwrap().get(&job_id) {
+        Some(job) if job.cancelled => bail!(ErrorKind::JobCancelled(job_id)),
+        Some(&RunningJob{deadline: Some(deadline), ..}) if Instant::now() >= deadline => {
+            bail!(ErrorKind::JobTimedOut(job_id))
+        }
+        _ => Ok(())
+    }
+}
+
+/**
+  The retry policy of a job, or the default policy if it was not registered
+*/
+pub fn retry_policy(job_id: usize) -> RetryPolicy {
+    RUNNING.lock().unwrap().get(&job_id)
+        .map(|job| job.retry_policy.clone())
+        .unwrap_or_default()
+}
+
+/**
+  Sleeps for `duration` unless the job is stopped before that
+*/
+pub fn wait(job_id: usize, duration: Duration) -> Result<()> {
+    let end = Instant::now() + duration;
+    loop {
+        check_job(job_id)?;
+
+        let now = Instant::now();
+        if now >= end {
+            return Ok(());
+        }
+        thread::sleep((end - now).min(STOP_CHECK_INTERVAL));
+    }
+}
+
+/**
+  Asks a running job to stop. The job stops the next time it checks, which is
+  before anything is changed that would leave the database inconsistent
+*/
+pub fn cancel_job(job_id: usize) -> Result<()> {
+    match RUNNING.lock().unwrap().get_mut(&job_id) {
+        Some(job) => {
+            job.cancelled = true;
+            Ok(())
+        }
+        None => bail!(ErrorKind::NoSuchJobId(job_id))
+    }
+}
+
+
+pub fn cancel_request_handler(request: &mut Request) -> IronResult<Response> {
+    // Peers cancel the jobs they started on this instance with signed requests
+    authenticate_request(request)?;
+
+    let job_id = get_get_usize(request, "job_id")?;
+
+    cancel_job(job_id)?;
+
+    Ok(signed_response(request, to_json_with_result(job_id)?))
+}
+
+
+#[cfg(test)]
+mod sync_control_tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000)
+        };
+
+        let backoffs = (1..7).map(|attempt| policy.backoff(attempt)).collect::<Vec<_>>();
+        assert_eq!(backoffs, mapvec!(Duration::from_millis: 100, 200, 400, 800, 1000, 1000));
+        assert_eq!(policy.backoff(100), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn running_jobs_can_be_stopped() {
+        assert_matches!(cancel_job(1001), Err(::error::Error(ErrorKind::NoSuchJobId(1001), _)));
+        // Unregistered jobs are never stopped
+        assert!(check_job(1001).is_ok());
+
+        {
+            let _guard = JobGuard::register(1001, &JobLimits::default());
+            assert!(check_job(1001).is_ok());
+
+            cancel_job(1001).unwrap();
+            assert_matches!(check_job(1001), Err(::error::Error(ErrorKind::JobCancelled(1001), _)));
+            assert!(wait(1001, Duration::from_secs(60)).is_err());
+        }
+        assert_matches!(cancel_job(1001), Err(::error::Error(ErrorKind::NoSuchJobId(1001), _)));
+
+        let limits = JobLimits{timeout: Some(Duration::from_millis(0)), .. JobLimits::default()};
+        let _guard = JobGuard::register(1002, &limits);
+        assert_matches!(check_job(1002), Err(::error::Error(ErrorKind::JobTimedOut(1002), _)));
+    }
+}
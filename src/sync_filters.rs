@@ -0,0 +1,124 @@
+use iron::prelude::*;
+use iron::status;
+
+use request_helpers::{to_json_with_result, get_get_variable, setup_db_connection};
+use schema::sync_filters;
+use search::{parse_search_query, SearchType, SavedSearchQuery};
+use sync_auth::authenticate_admin_request;
+
+use error::{Result, ErrorKind};
+
+/**
+  Limits the files this instance holds when syncing with the peer at
+  `foreign_url` to the ones found by `query`, a search in the same format as
+  the ones sent to `/search`, for example `of work, not private`.
+
+  Changes to other files are still stored so the change log stays complete,
+  but they are not applied and the files are not downloaded
+*/
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[table_name="sync_filters"]
+pub struct SyncFilter {
+    pub foreign_url: String,
+    pub query: String
+}
+
+/**
+  Parses the query of a sync filter. Path searches and queries that would
+  match every file are rejected since they are most likely mistakes
+*/
+pub fn parse_filter_query(query: &str) -> Result<SavedSearchQuery> {
+    match parse_search_query(query) {
+        SearchType::Saved(parsed) => {
+            let matches_everything = parsed.tags.is_empty()
+                && parsed.negated_tags.is_empty()
+                && parsed.date_constraints.intervals.is_empty()
+                && parsed.date_constraints.constraints.is_empty();
+
+            if matches_everything {
+                bail!(ErrorKind::InvalidSyncFilter(query.to_string()));
+            }
+            Ok(parsed)
+        }
+        SearchType::Path(_) => bail!(ErrorKind::InvalidSyncFilter(query.to_string()))
+    }
+}
+
+
+pub fn filters_request_handler(request: &mut Request) -> IronResult<Response> {
+    let fdb = setup_db_connection(request)?;
+
+    let filters = fdb.get_sync_filters()?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(filters)?)))
+}
+
+pub fn set_filter_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let foreign_url = get_get_variable(request, "foreign_url")?;
+    let query = get_get_variable(request, "query")?;
+
+    // Check the query now instead of failing the next sync
+    parse_filter_query(&query)?;
+
+    fdb.set_sync_filter(&SyncFilter{foreign_url, query})?;
+
+    Ok(Response::with((status::Ok, "Done")))
+}
+
+pub fn remove_filter_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let foreign_url = get_get_variable(request, "foreign_url")?;
+
+    fdb.remove_sync_filter(&foreign_url)?;
+
+    Ok(Response::with((status::Ok, "Done")))
+}
+
+
+#[cfg(test)]
+mod sync_filter_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    use error::Error;
+
+    #[test]
+    fn filter_queries_are_parsed() {
+        let query = parse_filter_query("of work, not private").unwrap();
+        assert_eq!(query.tags, vec!(String::from("work")));
+        assert_eq!(query.negated_tags, vec!(String::from("private")));
+
+        assert_matches!(
+            parse_filter_query("work"),
+            Err(Error(ErrorKind::InvalidSyncFilter(_), _))
+        );
+        assert_matches!(
+            parse_filter_query("/home/photos"),
+            Err(Error(ErrorKind::InvalidSyncFilter(_), _))
+        );
+    }
+
+    #[test]
+    fn filters_match_like_searches() {
+        let date = NaiveDate::from_ymd(2018, 6, 1).and_hms(0, 0, 0);
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+
+        let query = parse_filter_query("of work, not private").unwrap();
+        assert!(query.matches(&tags(&["work"]), &date));
+        assert!(query.matches(&tags(&["work", "travel"]), &date));
+        assert!(!query.matches(&tags(&["travel"]), &date));
+        assert!(!query.matches(&tags(&["work", "private"]), &date));
+
+        let query = parse_filter_query("from in june").unwrap();
+        assert!(query.matches(&tags(&[]), &date));
+        assert!(!query.matches(&tags(&[]), &NaiveDate::from_ymd(2018, 7, 1).and_hms(0, 0, 0)));
+    }
+}
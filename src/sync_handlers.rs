@@ -3,29 +3,71 @@ use persistent;
 
 use file_database::FileDatabase;
 
-use changelog::{Change, SyncPoint};
-use error::{Result, ResultExt};
+use changelog::{Change, ChangeType, UpdateType, SyncPoint};
+use error::{Result, ResultExt, ErrorKind, describe_chain};
 use request_helpers::{
     get_get_i64,
+    get_optional_get_i64,
+    get_get_usize,
     to_json_with_result,
     from_json_with_result,
     get_get_variable,
+    get_optional_get_variable,
     setup_db_connection
 };
 use settings::Settings;
+use file_handler::{stored_size, ensure_content_hash};
+use sync_auth::{
+    authenticate_request,
+    authenticate_request_with_secret,
+    authenticate_admin_request,
+    signed_response,
+    sign_response_body,
+    peer_protocol_version,
+    PROTOCOL_VERSION,
+    CONTENT_CHANGE_VERSION
+};
+use util::to_hex;
+use peers;
 
-use rand;
+use chrono::Utc;
 use serde_json;
-use chrono::NaiveDateTime;
+use sha2::{Sha256, Digest};
+
+use iron::headers::{
+    AcceptRanges,
+    ByteRangeSpec,
+    ContentLength,
+    ContentRange,
+    ContentRangeSpec,
+    ContentType,
+    Range,
+    RangeUnit
+};
 
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::thread;
 
 use foreign_server::{FileDetails, ChangeData, HttpForeignServer};
-use sync::{apply_changes, sync_with_foreign};
+use foreign_server::ForeignServer;
+use sync::{
+    apply_changes_with_filter,
+    evict_unmatched_files,
+    sync_with_foreign,
+    preview_sync,
+    ApplyOptions,
+    SyncOptions,
+    TransferMode
+};
+use sync_push::{PushedFiles, push_directory};
+use originals::ensure_original;
+use sync_filters::parse_filter_query;
 
 use sync_progress as sp;
+use sync_jobs::{record_job, JobKind, TransferCounts};
 
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -33,23 +75,49 @@ use sync_progress as sp;
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn sync_handler(own_port: u16, request: &mut Request, progress_tx: &sp::TxType) -> IronResult<Response> {
+    // Syncs are started by the owner of the instance, peers can't make it
+    // contact arbitrary servers
+    authenticate_admin_request(request)?;
+
     let settings = request.get::<persistent::Read<Settings>>().unwrap();
 
     let foreign_url = get_get_variable(request, "foreign_url")?;
 
-    let foreign_server = HttpForeignServer::new(foreign_url);
-    let job_id = handle_sync_request((*settings).clone(), foreign_server, own_port, progress_tx)?;
+    let fdb = setup_db_connection(request)?;
+    let foreign_server = peers::connect(&fdb, foreign_url.clone(), &settings)?;
+    let job_id = handle_sync_request(
+        (*settings).clone(),
+        foreign_url,
+        foreign_server,
+        own_port,
+        progress_tx
+    )?;
 
     Ok(Response::with((status::Ok, to_json_with_result(job_id)?)))
 }
 
+pub fn preview_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_admin_request(request)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+
+    let foreign_url = get_get_variable(request, "foreign_url")?;
+
+    let fdb = setup_db_connection(request)?;
+    let foreign_server = peers::connect(&fdb, foreign_url, &settings)?;
+
+    let preview = preview_sync(&fdb, &foreign_server, &settings.get_conflict_policies())?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(preview)?)))
+}
+
 pub fn syncpoint_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
     let fdb = setup_db_connection(request)?;
 
     match handle_syncpoint_request(&fdb) {
-        Ok(syncpoints) => Ok(Response::with(
-                (status::Ok, serde_json::to_string(&syncpoints).unwrap()),
-            )),
+        Ok(syncpoints) => Ok(signed_response(request, serde_json::to_string(&syncpoints).unwrap())),
         Err(e) => Ok(Response::with(
             (status::InternalServerError, format!("{:?}", e)),
         ))
@@ -57,26 +125,52 @@ pub fn syncpoint_request_handler(request: &mut Request) -> IronResult<Response>
 }
 
 pub fn change_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
     let fdb = setup_db_connection(request)?;
 
-    let timestamp = get_get_i64(request, "starting_timestamp")?;
+    let starting_clock = get_optional_get_i64(request, "starting_clock")?;
+    let peer_version = peer_protocol_version(request).unwrap_or(PROTOCOL_VERSION);
 
-    let changes = handle_change_request(&fdb, timestamp)?;
+    let changes = handle_change_request(&fdb, starting_clock, peer_version)?;
 
-    Ok(Response::with((status::Ok, to_json_with_result(changes)?)))
+    Ok(signed_response(request, to_json_with_result(changes)?))
 }
 
 pub fn file_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
     let fdb = setup_db_connection(request)?;
 
     let file_id = get_get_i64(request, "file_id")?;
 
-    let file = handle_file_request(&fdb, file_id as i32)?;
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+    ensure_original(&fdb, &settings, file_id as i32)?;
 
-    Ok(Response::with((status::Ok, file)))
+    let path = handle_file_request(&fdb, file_id as i32)?;
+
+    Ok(file_response(request, &path)?)
+}
+
+pub fn sidecar_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let file_id = get_get_i64(request, "file_id")?;
+    let index = get_get_usize(request, "index")?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+    ensure_original(&fdb, &settings, file_id as i32)?;
+
+    let path = handle_sidecar_request(&fdb, file_id as i32, index)?;
+
+    Ok(file_response(request, &path)?)
 }
 
 pub fn thumbnail_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
     let fdb = setup_db_connection(request)?;
 
     let file_id = get_get_i64(request, "file_id")?;
@@ -86,30 +180,23 @@ pub fn thumbnail_request_handler(request: &mut Request) -> IronResult<Response>
         None => vec!()
     };
 
-    Ok(Response::with((status::Ok, thumb)))
+    Ok(signed_response(request, thumb))
 }
 
 pub fn file_detail_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
     let file_id = get_get_i64(request, "file_id")?;
 
     let fdb = setup_db_connection(request)?;
     let file_details = handle_file_detail_request(&fdb, file_id as i32)?;
 
-    Ok(Response::with((status::Ok, to_json_with_result(&file_details)?)))
+    Ok(signed_response(request, to_json_with_result(&file_details)?))
 }
 
 pub fn syncpoint_add_handler(request: &mut Request) -> IronResult<Response> {
-    let mut syncpoint_str  = String::new();
-    // TODO Unduplicate this. Same code in change_application_handler
-    match request.body.read_to_string(&mut syncpoint_str) {
-        Ok(_) => {},
-        Err(e) => {
-            return Ok(Response::with((
-                status::PreconditionFailed,
-                format!("Failed to read body {:?}", e)
-            )));
-        }
-    }
+    let body = authenticate_request(request)?;
+    let syncpoint_str = String::from_utf8(body).chain_err(|| "Syncpoint was not valid utf-8")?;
 
     let syncpoint = from_json_with_result::<SyncPoint>(&syncpoint_str)
         .chain_err(|| {
@@ -118,31 +205,82 @@ pub fn syncpoint_add_handler(request: &mut Request) -> IronResult<Response> {
 
     let fdb = setup_db_connection(request)?;
     fdb.add_syncpoint(&syncpoint)?;
+    if let Some(foreign_url) = get_optional_get_variable(request, "url")? {
+        fdb.set_syncpoint_url(&[syncpoint], &foreign_url)?;
+    }
 
-    Ok(Response::with((status::Ok, "Done")))
+    Ok(signed_response(request, "Done"))
 }
 
 pub fn change_application_handler(request: &mut Request, progress_tx: &sp::TxType) -> IronResult<Response> {
+    let (body, secret) = authenticate_request_with_secret(request)?;
+    let body = String::from_utf8(body).chain_err(|| "Changes were not valid utf-8")?;
+
+    let change_data = from_json_with_result::<ChangeData>(&body)?;
+
     let settings = request.get::<persistent::Read<Settings>>().unwrap();
 
-    let remote_ip = request.remote_addr.ip();
-    let remote_port = get_get_i64(request, "port")? as u16;
+    let foreign_url = foreign_url(request)?;
 
-    let foreign_server = HttpForeignServer::new(format!("{}:{}", remote_ip, remote_port));
+    let fdb = setup_db_connection(request)?;
+    let filter = fdb.get_sync_filter(&foreign_url)?;
+    let lazy_originals = fdb.get_peer_with_url(&foreign_url)?
+        .is_some_and(|peer| peer.lazy_originals);
+
+    let job_id = match get_optional_get_i64(request, "push_id")? {
+        Some(push_id) => {
+            let directory = push_directory(&settings.get_file_storage_path(), push_id as usize);
+            let pushed_files = PushedFiles::new(change_data.file_details.clone(), directory);
+
+            handle_change_application(
+                change_data,
+                (*settings).clone(),
+                foreign_url,
+                pushed_files,
+                filter,
+                lazy_originals,
+                progress_tx
+            )
+        }
+        None => {
+            // The peer is contacted with the secret it used for this request
+            let foreign_server = HttpForeignServer::from_settings(
+                foreign_url.clone(),
+                Some(secret),
+                &settings
+            )?;
+
+            handle_change_application(
+                change_data,
+                (*settings).clone(),
+                foreign_url,
+                foreign_server,
+                filter,
+                lazy_originals,
+                progress_tx
+            )
+        }
+    };
+    Ok(signed_response(request, to_json_with_result(job_id)?))
+}
 
-    let mut body = String::new();
-    match request.body.read_to_string(&mut body) {
-        Ok(_) => {},
-        Err(e) => {
-            return Ok(Response::with((
-                status::PreconditionFailed,
-                format!("Failed to read body {:?}", e)
-            )));
+/**
+  Returns the url of the flash instance which sent a request. Instances with
+  `FLASH_SYNC_URL` set advertise their url with the request, otherwise it is
+  assumed that the instance listens on the `port` sent with the request at the
+  address the request came from. That address is wrong if the instance is
+  behind a proxy or NAT
+*/
+pub fn foreign_url(request: &mut Request) -> Result<String> {
+    match get_optional_get_variable(request, "url")? {
+        Some(url) => Ok(url),
+        None => {
+            let remote_ip = request.remote_addr.ip();
+            let remote_port = get_get_i64(request, "port")? as u16;
+
+            Ok(format!("{}:{}", remote_ip, remote_port))
         }
     }
-
-    let job_id = handle_change_application(&body, (*settings).clone(), foreign_server, progress_tx)?;
-    Ok(Response::with((status::Ok, to_json_with_result(job_id)?)))
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -153,29 +291,149 @@ fn handle_syncpoint_request(fdb: &FileDatabase) -> Result<Vec<SyncPoint>> {
     fdb.get_syncpoints()
 }
 
-fn handle_change_request(fdb: &FileDatabase, starting_timestamp: i64) -> Result<Vec<Change>> {
-    let starting_time = NaiveDateTime::from_timestamp(starting_timestamp, 0);
+/**
+  The changes after `starting_clock` which a peer using `peer_version` can
+  apply. Content changes are left out for peers that don't know them
+*/
+fn handle_change_request(fdb: &FileDatabase, starting_clock: Option<i64>, peer_version: u32)
+    -> Result<Vec<Change>>
+{
+    let changes = match starting_clock {
+        Some(clock) => fdb.get_changes_after_clock(clock)?,
+        None => fdb.get_all_changes()?
+    };
 
-    fdb.get_changes_after_timestamp(&starting_time)
+    if peer_version < CONTENT_CHANGE_VERSION {
+        Ok(changes.into_iter()
+            .filter(|change| !matches!(change.change_type, ChangeType::Update(UpdateType::ContentChanged(_))))
+            .collect())
+    }
+    else {
+        Ok(changes)
+    }
 }
 
 
-fn handle_file_request(fdb: &FileDatabase, id: i32) -> Result<Vec<u8>> {
+pub fn handle_file_request(fdb: &FileDatabase, id: i32) -> Result<PathBuf> {
     // Fetch the filename from the database
-    let filename = fdb.get_file_with_id_result(id)?.filename;
+    let file = fdb.get_file_with_id_result(id)?;
+
+    if !file.is_uploaded {
+        bail!(ErrorKind::OriginalNotStored(id));
+    }
 
     // Join the filename with the save path from the fdb
-    let file_path = fdb.get_file_save_path().join(filename);
+    Ok(fdb.get_file_save_path().join(file.filename))
+}
+
+/**
+  The part of a file which is sent in response to a request
+*/
+#[derive(Debug, PartialEq)]
+enum FileRange {
+    Whole,
+    /// Everything after the specified amount of bytes
+    From(u64),
+    /// The requested range is outside the file
+    Unsatisfiable
+}
+
+/**
+  Decides which part of a file of size `length` to send. Only ranges on the
+  form `bytes=<start>-` are supported since they are used to resume downloads,
+  the whole file is sent for other ranges
+*/
+fn requested_file_range(range: Option<&Range>, length: u64) -> FileRange {
+    match range {
+        Some(Range::Bytes(specs)) if specs.len() == 1 => {
+            match specs[0] {
+                ByteRangeSpec::AllFrom(start) if start < length => FileRange::From(start),
+                ByteRangeSpec::AllFrom(_) => FileRange::Unsatisfiable,
+                _ => FileRange::Whole
+            }
+        }
+        _ => FileRange::Whole
+    }
+}
+
+/**
+  Builds a response which streams the requested range of a file from disk
+  rather than reading it into memory
+*/
+fn file_response(request: &Request, path: &Path) -> Result<Response> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let range = request.headers.get::<Range>();
+
+    let mut response = Response::new();
+    response.headers.set(AcceptRanges(vec!(RangeUnit::Bytes)));
+    response.headers.set(ContentType("application/octet-stream".parse().unwrap()));
+
+    match requested_file_range(range, length) {
+        FileRange::Whole => {
+            response.status = Some(status::Ok);
+            response.headers.set(ContentLength(length));
+        }
+        FileRange::From(start) => {
+            file.seek(SeekFrom::Start(start))?;
+
+            response.status = Some(status::PartialContent);
+            response.headers.set(ContentLength(length - start));
+            response.headers.set(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, length - 1)),
+                instance_length: Some(length)
+            }));
+        }
+        FileRange::Unsatisfiable => {
+            response.status = Some(status::RangeNotSatisfiable);
+            response.headers.set(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(length)
+            }));
+            return Ok(response);
+        }
+    }
 
-    // Open the file for reading
-    let mut file = File::open(file_path)?;
-    let mut content = vec!();
-    file.read_to_end(&mut content)?;
+    let body_hash = hash_to_end(&mut file)?;
+    sign_response_body(request, &mut response, &body_hash);
 
-    Ok(content)
+    response.body = Some(Box::new(file));
+    Ok(response)
 }
 
-fn handle_thumbnail_request(fdb: &FileDatabase, id: i32) -> Result<Option<Vec<u8>>> {
+/**
+  Returns the hex encoded SHA-256 of the rest of `file` from its current
+  position, which is restored afterwards
+*/
+fn hash_to_end(file: &mut File) -> Result<String> {
+    let start = file.stream_position()?;
+
+    let mut hasher = Sha256::new();
+    ::std::io::copy(file, &mut hasher)?;
+
+    file.seek(SeekFrom::Start(start))?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/**
+  Returns the path of the sidecar with the specified index of a file
+*/
+pub fn handle_sidecar_request(fdb: &FileDatabase, id: i32, index: usize) -> Result<PathBuf> {
+    let file = fdb.get_file_with_id_result(id)?;
+
+    if !file.is_uploaded {
+        bail!(ErrorKind::OriginalNotStored(id));
+    }
+
+    let filename = match file.sidecars.into_iter().nth(index) {
+        Some(filename) => filename,
+        None => bail!(ErrorKind::NoSuchSidecar(id, index))
+    };
+
+    Ok(fdb.get_file_save_path().join(filename))
+}
+
+pub fn handle_thumbnail_request(fdb: &FileDatabase, id: i32) -> Result<Option<Vec<u8>>> {
     let thumbnail_path = fdb.get_file_with_id_result(id)?.thumbnail_path;
 
     if let Some(thumbnail_path) = thumbnail_path {
@@ -190,37 +448,77 @@ fn handle_thumbnail_request(fdb: &FileDatabase, id: i32) -> Result<Option<Vec<u8
     }
 }
 
-fn handle_file_detail_request(fdb: &FileDatabase, id: i32) -> Result<FileDetails> {
-    let file = fdb.get_file_with_id_result(id)?;
+pub fn handle_file_detail_request(fdb: &FileDatabase, id: i32) -> Result<FileDetails> {
+    let mut file = fdb.get_file_with_id_result(id)?;
+    ensure_content_hash(&mut file, fdb)?;
 
-    Ok(FileDetails::from(&file))
+    let mut details = FileDetails::from(&file);
+    details.size = stored_size(&file, fdb)?;
+    Ok(details)
 }
 
 
-fn handle_change_application(
-    body: &str,
+/**
+  Applies changes sent by the flash instance at `foreign_url` in the
+  background, getting the files from `foreign`. Returns the id of the job
+*/
+fn handle_change_application<F>(
+    change_data: ChangeData,
     settings: Settings,
-    foreign: HttpForeignServer,
+    foreign_url: String,
+    foreign: F,
+    filter: Option<String>,
+    lazy_originals: bool,
     progress_tx: &sp::TxType
-) -> Result<usize> {
-    let change_data = from_json_with_result::<ChangeData>(body)?;
-
-
-    let job_id = rand::random::<usize>();
+) -> usize
+    where F: ForeignServer + Send + 'static
+{
+    let job_id = sp::new_job_id();
 
     let progress_tx = progress_tx.clone();
+    progress_tx.send((job_id, sp::SyncUpdate::Started))
+        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
 
     thread::spawn(move || {
         let fdb = FileDatabase::new(&settings.database_url, settings.get_file_storage_path());
 
         let result = fdb.and_then(|fdb| {
-            apply_changes(
-                &fdb,
-                &foreign,
-                &change_data.changes,
-                &change_data.removed_files,
-                &(job_id, progress_tx.clone())
-            )
+            let history_days = settings.get_sync_history_days();
+            let limits = settings.get_job_limits();
+            record_job(&fdb, job_id, JobKind::Apply, &foreign_url, history_days, &limits, || {
+                let filter = match filter {
+                    Some(query) => Some(parse_filter_query(&query)?),
+                    None => None
+                };
+
+                let files_received = apply_changes_with_filter(
+                    &fdb,
+                    &foreign,
+                    &change_data.changes,
+                    &change_data.removed_files,
+                    &ApplyOptions {
+                        fetch_workers: settings.get_sync_workers(),
+                        filter: filter.as_ref(),
+                        lazy_originals,
+                        receive_clock: change_data.clock
+                    },
+                    &(job_id, progress_tx.clone())
+                )?;
+
+                // The files were received from the foreign server so it still has them
+                if let Some(filter) = filter {
+                    let changed_files = change_data.changes.iter()
+                        .map(|change| change.affected_file)
+                        .collect::<Vec<_>>();
+                    evict_unmatched_files(&fdb, &foreign, &changed_files, &filter)?;
+                }
+
+                Ok(TransferCounts {
+                    changes_received: change_data.changes.len(),
+                    files_received,
+                    .. TransferCounts::default()
+                })
+            })
         })
         .and_then(|_| {
             progress_tx.send((job_id, sp::SyncUpdate::Done))
@@ -229,43 +527,145 @@ fn handle_change_application(
         });
 
         if let Err(e) = result {
-            progress_tx.send((job_id, sp::SyncUpdate::Error(format!("{:?}", e))))
+            progress_tx.send((job_id, sp::SyncUpdate::Error(describe_chain(&e))))
                 .expect("Failed to send error from handle_change_application worker 
                         to sync progress manager. Did it crash?");
         }
     });
 
-    Ok(job_id)
+    job_id
 }
 
 
 fn handle_sync_request(
     settings: Settings,
-    mut foreign: HttpForeignServer,
+    foreign_url: String,
+    foreign: HttpForeignServer,
     own_port: u16,
     progress_tx: &sp::TxType
 ) -> Result<usize> {
-    let job_id = rand::random::<usize>();
+    let job_id = sp::new_job_id();
 
     let progress_tx = progress_tx.clone();
+    progress_tx.send((job_id, sp::SyncUpdate::Started))
+        .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
     thread::spawn(move || {
-        let fdb = FileDatabase::new(&settings.database_url, settings.get_file_storage_path());
+        // Errors are reported through progress_tx
+        run_sync_job(&settings, &foreign_url, foreign, own_port, &(job_id, progress_tx)).ok();
+    });
 
-        let result = fdb.and_then(|fdb| {
-            sync_with_foreign(
-                    &fdb,
-                    &mut foreign,
-                    own_port,
-                    &(job_id, progress_tx.clone())
-                )
+    Ok(job_id)
+}
+
+/**
+  Syncs with `foreign` which is reached at `foreign_url`, using the sync filter
+  for that url. Errors are reported through `progress_tx`. The job is stored
+  in the sync history and the result in the peer with the url if there is one
+*/
+pub fn run_sync_job(
+    settings: &Settings,
+    foreign_url: &str,
+    mut foreign: HttpForeignServer,
+    own_port: u16,
+    (job_id, progress_tx): &sp::LocalTxType
+) -> Result<TransferCounts> {
+    let fdb = FileDatabase::new(&settings.database_url, settings.get_file_storage_path());
+
+    let result = fdb.and_then(|fdb| {
+        let history_days = settings.get_sync_history_days();
+        let limits = settings.get_job_limits();
+        let result = record_job(&fdb, *job_id, JobKind::Sync, foreign_url, history_days, &limits, || {
+            let filter = match fdb.get_sync_filter(foreign_url)? {
+                Some(query) => Some(parse_filter_query(&query)?),
+                None => None
+            };
+            let lazy_originals = fdb.get_peer_with_url(foreign_url)?
+                .is_some_and(|peer| peer.lazy_originals);
+
+            let transfer_mode = if settings.get_sync_push() {
+                TransferMode::Push(own_port)
+            }
+            else {
+                TransferMode::Callback(own_port)
+            };
+
+            let options = SyncOptions {
+                transfer_mode,
+                conflict_policies: &settings.get_conflict_policies(),
+                apply: ApplyOptions {
+                    fetch_workers: settings.get_sync_workers(),
+                    filter: filter.as_ref(),
+                    lazy_originals,
+                    receive_clock: None
+                }
+            };
+            let counts = sync_with_foreign(&fdb, &mut foreign, &options, &(*job_id, progress_tx.clone()))?;
+
+            // Compaction has to ask every instance that shares syncpoints
+            // with this one, not just the registered peers
+            let shared = foreign.get_syncpoints()
+                .chain_err(|| "Failed to get syncpoints from the foreign server")?;
+            fdb.set_syncpoint_url(&shared, foreign_url)?;
+            Ok(counts)
         });
 
-        if let Err(e) = result {
-            progress_tx.send((job_id, sp::SyncUpdate::Error(format!("{:#?}", e))))
-                .expect("Failed to send error from handle_sync_request worker
-                        to sync progress manager. Did it crash?");
-        }
+        fdb.record_peer_sync(
+            foreign_url,
+            Utc::now().naive_utc(),
+            result.as_ref().err().map(describe_chain)
+        )?;
+        result
     });
 
-    Ok(job_id)
+    if let Err(ref e) = result {
+        progress_tx.send((*job_id, sp::SyncUpdate::Error(describe_chain(e))))
+            .expect("Failed to send error from sync job
+                    to sync progress manager. Did it crash?");
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod sync_handler_tests {
+    use super::*;
+
+    #[test]
+    fn file_ranges_are_parsed() {
+        let from = |start| Range::Bytes(vec!(ByteRangeSpec::AllFrom(start)));
+
+        assert_eq!(requested_file_range(None, 10), FileRange::Whole);
+        assert_eq!(requested_file_range(Some(&from(0)), 10), FileRange::From(0));
+        assert_eq!(requested_file_range(Some(&from(4)), 10), FileRange::From(4));
+        assert_eq!(requested_file_range(Some(&from(10)), 10), FileRange::Unsatisfiable);
+        assert_eq!(
+            requested_file_range(Some(&Range::Bytes(vec!(ByteRangeSpec::FromTo(0, 4)))), 10),
+            FileRange::Whole
+        );
+    }
+
+    #[test]
+    fn version_6_peers_do_not_get_content_changes() {
+        use chrono::NaiveDate;
+        use file_database::db_test_helpers;
+
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let timestamp = NaiveDate::from_ymd(2018, 1, 1).and_hms(0,0,0);
+        let changes = vec!(
+            Change::new(timestamp, 1, ChangeType::FileAdded),
+            Change::new(timestamp, 1, ChangeType::Update(UpdateType::ContentChanged("abc".into())))
+        );
+        for change in &changes {
+            fdb.add_change(change).unwrap();
+        }
+
+        assert_eq!(handle_change_request(&fdb, None, PROTOCOL_VERSION).unwrap().len(), 2);
+        let old_changes = handle_change_request(&fdb, None, 6).unwrap();
+        assert_eq!(old_changes.len(), 1);
+        assert_eq!(old_changes[0].change_type, ChangeType::FileAdded);
+    }
 }
@@ -0,0 +1,191 @@
+use iron::prelude::*;
+
+use changelog::{Change, CHANGE_TYPES, OPTIONAL_CHANGE_TYPES};
+use file_database::FileDatabase;
+use request_helpers::{to_json_with_result, setup_db_connection};
+use sync_auth::{authenticate_request, signed_response, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+
+use error::{Result, ErrorKind};
+
+/// Files can be pushed through `/sync/needed_files` and `/sync/push`
+pub const FEATURE_PUSH: &str = "push";
+/// Jobs can be stopped through `/sync/jobs/cancel`
+pub const FEATURE_CANCEL: &str = "cancel";
+
+/**
+  The optional features of the sync protocol that this instance supports.
+  Peers fall back to doing without a feature if the other side lacks it
+*/
+pub const FEATURES: &[&str] = &[FEATURE_PUSH, FEATURE_CANCEL];
+
+/**
+  What an instance tells its peers about itself before they sync
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    /// The oldest protocol version the instance can sync with
+    pub min_protocol_version: u32,
+    pub instance_id: String,
+    /// The names of the change types the instance can apply, see `changelog::CHANGE_TYPES`
+    pub change_types: Vec<String>,
+    pub features: Vec<String>
+}
+
+impl Handshake {
+    /**
+      The handshake of this version of flash for the instance `instance_id`
+    */
+    pub fn new(instance_id: String) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            instance_id,
+            change_types: CHANGE_TYPES.iter().map(|name| name.to_string()).collect(),
+            features: FEATURES.iter().map(|name| name.to_string()).collect()
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    /**
+      Fails if this instance and the peer that sent the handshake can't sync
+      because one of them is too old for the other
+    */
+    pub fn check_compatible(&self) -> Result<()> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION || self.min_protocol_version > PROTOCOL_VERSION {
+            bail!(ErrorKind::IncompatibleProtocolVersion(PROTOCOL_VERSION, self.protocol_version));
+        }
+        Ok(())
+    }
+
+    fn can_apply(&self, change: &Change) -> bool {
+        let name = change.change_type.name();
+        self.change_types.iter().any(|supported| supported == name)
+    }
+
+    /**
+      The changes which should be sent to the peer. Changes of the
+      `OPTIONAL_CHANGE_TYPES` that the peer can't apply are left out, the sync
+      fails if the peer can't apply some of the other changes
+    */
+    pub fn supported_changes(&self, changes: Vec<Change>) -> Result<Vec<Change>> {
+        let mut unsupported = changes.iter()
+            .filter(|change| !self.can_apply(change))
+            .map(|change| change.change_type.name())
+            .filter(|name| !OPTIONAL_CHANGE_TYPES.contains(name))
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        unsupported.sort();
+        unsupported.dedup();
+
+        if !unsupported.is_empty() {
+            bail!(ErrorKind::UnsupportedChangeTypes(unsupported));
+        }
+        Ok(changes.into_iter().filter(|change| self.can_apply(change)).collect())
+    }
+}
+
+/**
+  The handshake of this instance
+*/
+pub fn own_handshake(fdb: &FileDatabase) -> Result<Handshake> {
+    Ok(Handshake::new(fdb.get_instance_id()?))
+}
+
+
+pub fn handshake_request_handler(request: &mut Request) -> IronResult<Response> {
+    authenticate_request(request)?;
+
+    let fdb = setup_db_connection(request)?;
+
+    let handshake = own_handshake(&fdb)?;
+
+    Ok(signed_response(request, to_json_with_result(handshake)?))
+}
+
+
+#[cfg(test)]
+mod sync_handshake_tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    use changelog::{ChangeType, UpdateType};
+
+    #[test]
+    fn peers_are_checked_for_compatibility() {
+        let own = Handshake::new("peer".into());
+        assert!(own.check_compatible().is_ok());
+        assert!(own.supports(FEATURE_PUSH));
+
+        let newer = Handshake {
+            protocol_version: PROTOCOL_VERSION + 1,
+            .. own.clone()
+        };
+        assert!(newer.check_compatible().is_ok());
+
+        let too_new = Handshake {
+            protocol_version: PROTOCOL_VERSION + 1,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            .. own.clone()
+        };
+        assert_matches!(
+            too_new.check_compatible(),
+            Err(::error::Error(ErrorKind::IncompatibleProtocolVersion(PROTOCOL_VERSION, _), _))
+        );
+
+        let too_old = Handshake {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            .. own.clone()
+        };
+        assert!(too_old.check_compatible().is_err());
+    }
+
+    #[test]
+    fn changes_the_peer_does_not_know_are_found() {
+        let timestamp = NaiveDate::from_ymd(2017, 1, 1).and_hms(0, 0, 0);
+        let changes = vec!(
+            Change::new(timestamp, 1, ChangeType::FileAdded),
+            Change::new(timestamp, 1, ChangeType::Update(UpdateType::TagAdded("a".into()))),
+            Change::new(timestamp, 2, ChangeType::Update(UpdateType::TagAdded("b".into()))),
+            Change::new(timestamp, 2, ChangeType::FileRemoved)
+        );
+
+        assert_eq!(Handshake::new("peer".into()).supported_changes(changes.clone()).unwrap(), changes);
+
+        let peer = Handshake {
+            change_types: vec!("FileAdded".into(), "TagRemoved".into()),
+            .. Handshake::new("peer".into())
+        };
+        assert_matches!(
+            peer.supported_changes(changes.clone()),
+            Err(::error::Error(ErrorKind::UnsupportedChangeTypes(ref types), _))
+                if types == &vec!("FileRemoved".to_string(), "TagAdded".to_string())
+        );
+        assert_eq!(peer.supported_changes(changes[..1].to_vec()).unwrap(), &changes[..1]);
+    }
+
+    #[test]
+    fn content_changes_are_left_out_for_peers_that_do_not_know_them() {
+        let timestamp = NaiveDate::from_ymd(2017, 1, 1).and_hms(0, 0, 0);
+        let changes = vec!(
+            Change::new(timestamp, 1, ChangeType::Update(UpdateType::ContentChanged("abc".into()))),
+            Change::new(timestamp, 1, ChangeType::Update(UpdateType::TagAdded("a".into())))
+        );
+
+        // Version 6 peers only know the change types from before version 7
+        let old_peer = Handshake {
+            protocol_version: 6,
+            change_types: CHANGE_TYPES.iter()
+                .filter(|name| **name != "ContentChanged")
+                .map(|name| name.to_string())
+                .collect(),
+            .. Handshake::new("peer".into())
+        };
+        assert!(old_peer.check_compatible().is_ok());
+        assert_eq!(old_peer.supported_changes(changes.clone()).unwrap(), &changes[1..]);
+    }
+}
@@ -0,0 +1,243 @@
+use iron::prelude::*;
+use iron::status;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use file_database::FileDatabase;
+use request_helpers::{to_json_with_result, get_get_usize, get_optional_get_i64, setup_db_connection};
+use schema::sync_jobs;
+use sync_control::{JobGuard, JobLimits};
+use sync_progress::{SyncStatus, SyncUpdate};
+
+use error::{Result, describe_chain};
+
+/// The amount of jobs returned by `/sync/jobs` if no limit is specified
+const DEFAULT_JOB_LIMIT: i64 = 50;
+
+/// What a sync job did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobKind {
+    /// A sync started by this instance
+    Sync,
+    /// Changes sent by a peer that started a sync
+    Apply,
+    /// Changes and files written to a bundle
+    Export,
+    /// Changes read from a bundle
+    Import,
+    /// A sync with the backup library of this instance
+    Backup
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            JobKind::Sync => "sync",
+            JobKind::Apply => "apply",
+            JobKind::Export => "export",
+            JobKind::Import => "import",
+            JobKind::Backup => "backup"
+        }
+    }
+}
+
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_DONE: &str = "done";
+pub const STATUS_FAILED: &str = "failed";
+
+/**
+  The amount of changes and files that a sync job moved in each direction
+*/
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct TransferCounts {
+    pub changes_sent: usize,
+    pub changes_received: usize,
+    /// Files that the peer fetches from this instance
+    pub files_sent: usize,
+    /// Files that were fetched from the peer
+    pub files_received: usize
+}
+
+/**
+  A sync job as it is stored in the database. Jobs are stored when they start,
+  the counts are filled in once they are done
+*/
+#[derive(Queryable, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncJob {
+    pub id: i64,
+    pub kind: String,
+    pub peer: String,
+    pub started_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+    pub status: String,
+    pub changes_sent: i32,
+    pub changes_received: i32,
+    pub files_sent: i32,
+    pub files_received: i32,
+    /// Every error in the chain of the error that stopped the job, one per line
+    pub error: Option<String>
+}
+
+impl SyncJob {
+    /**
+      The status `/sync/progress` reports for a job which is no longer tracked
+      in memory. Returns `None` for jobs that are still running
+    */
+    pub fn final_status(&self) -> Option<SyncStatus> {
+        let last_update = match self.status.as_str() {
+            STATUS_DONE => SyncUpdate::Done,
+            STATUS_FAILED => SyncUpdate::Error(self.error.clone().unwrap_or_default()),
+            _ => return None
+        };
+
+        Some(SyncStatus{last_update, foreign_job_id: None, downloads: None})
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="sync_jobs"]
+pub struct NewSyncJob {
+    pub id: i64,
+    pub kind: String,
+    pub peer: String,
+    pub started_at: NaiveDateTime,
+    pub status: String
+}
+
+/**
+  Stores the job `job_id` while `job` runs, along with what it moved or the
+  error that stopped it. Jobs that started more than `history_days` ago are
+  removed first. The job can be cancelled while it runs and is stopped if it
+  exceeds `limits`
+*/
+pub fn record_job<F>(
+    fdb: &FileDatabase,
+    job_id: usize,
+    kind: JobKind,
+    peer: &str,
+    history_days: i64,
+    limits: &JobLimits,
+    job: F
+) -> Result<TransferCounts>
+    where F: FnOnce() -> Result<TransferCounts>
+{
+    let started_at = Utc::now().naive_utc();
+    fdb.remove_sync_jobs_before(started_at - Duration::days(history_days))?;
+
+    fdb.add_sync_job(&NewSyncJob{
+        id: job_id as i64,
+        kind: kind.as_str().to_string(),
+        peer: peer.to_string(),
+        started_at,
+        status: STATUS_RUNNING.to_string()
+    })?;
+
+    let result = {
+        let _guard = JobGuard::register(job_id, limits);
+        job()
+    };
+
+    fdb.finish_sync_job(
+        job_id,
+        Utc::now().naive_utc(),
+        &result.as_ref().ok().cloned().unwrap_or_default(),
+        result.as_ref().err().map(describe_chain)
+    )?;
+
+    result
+}
+
+
+pub fn jobs_request_handler(request: &mut Request) -> IronResult<Response> {
+    let fdb = setup_db_connection(request)?;
+
+    let limit = get_optional_get_i64(request, "limit")?.unwrap_or(DEFAULT_JOB_LIMIT);
+
+    let jobs = fdb.get_sync_jobs(limit)?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(jobs)?)))
+}
+
+pub fn job_request_handler(request: &mut Request) -> IronResult<Response> {
+    let fdb = setup_db_connection(request)?;
+
+    let job_id = get_get_usize(request, "job_id")?;
+
+    let job = fdb.get_sync_job(job_id)?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(job)?)))
+}
+
+
+#[cfg(test)]
+mod sync_job_tests {
+    use super::*;
+
+    use file_database::db_test_helpers;
+
+    use error::{Error, ErrorKind, ResultExt};
+
+    #[test]
+    fn jobs_are_recorded() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let counts = TransferCounts{
+            changes_sent: 1,
+            changes_received: 2,
+            files_sent: 3,
+            files_received: 4
+        };
+        let limits = JobLimits::default();
+        record_job(&fdb, 1, JobKind::Sync, "localhost:3000", 90, &limits, || Ok(counts.clone()))
+            .unwrap();
+        let result = record_job(&fdb, 2, JobKind::Apply, "localhost:3000", 90, &limits, || {
+            Err(Error::from(ErrorKind::NoSuchPeer(5))).chain_err(|| "Failed to sync")
+        });
+        assert!(result.is_err());
+
+        let jobs = fdb.get_sync_jobs(10).unwrap();
+        assert_eq!(jobs.len(), 2);
+
+        let failed = &jobs[0];
+        assert_eq!(failed.kind, "apply");
+        assert_eq!(failed.status, STATUS_FAILED);
+        assert_eq!(failed.error, Some("Failed to sync\nCaused by: No peer with id 5".into()));
+        assert_matches!(
+            failed.final_status().unwrap().last_update,
+            SyncUpdate::Error(_)
+        );
+
+        let done = fdb.get_sync_job(1).unwrap();
+        assert_eq!(done.status, STATUS_DONE);
+        assert_eq!(
+            (done.changes_sent, done.changes_received, done.files_sent, done.files_received),
+            (1, 2, 3, 4)
+        );
+        assert!(done.finished_at.is_some());
+        assert_matches!(done.final_status().unwrap().last_update, SyncUpdate::Done);
+    }
+
+    #[test]
+    fn old_jobs_are_removed() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        fdb.add_sync_job(&NewSyncJob{
+            id: 1,
+            kind: JobKind::Sync.as_str().into(),
+            peer: "localhost:3000".into(),
+            started_at: Utc::now().naive_utc() - Duration::days(100),
+            status: STATUS_DONE.into()
+        }).unwrap();
+
+        let limits = JobLimits::default();
+        record_job(&fdb, 2, JobKind::Sync, "localhost:3000", 90, &limits, || Ok(TransferCounts::default()))
+            .unwrap();
+
+        let ids = fdb.get_sync_jobs(10).unwrap().iter().map(|job| job.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec!(2));
+    }
+}
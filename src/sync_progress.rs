@@ -1,23 +1,41 @@
-use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, RecvTimeoutError, channel, sync_channel};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{BufRead, Write};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use request_helpers::{to_json_with_result, get_get_usize};
+use file_database::FileDatabase;
+use request_helpers::{to_json_with_result, get_get_usize, setup_db_connection};
+
+use rand;
+use serde_json;
 
 use iron::prelude::*;
 use iron::status;
+use iron::response::WriteBody;
+use iron::headers::{CacheControl, CacheDirective, ContentType};
 
 use error::{Result, ErrorKind};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum SyncUpdate {
+    /// The job was created. This is sent before the id of the job is returned
+    /// so that the job can be followed right away
+    Started,
     /// Done gathering data
     GatheredData,
+    /// Pushing a file to the foreign server. There are usize files left to push
+    PushingFile(usize),
+    /// Writing a file to a bundle. There are usize files left to write
+    ExportingFile(usize),
     /// Done sending to the foreign server. The request has the specified ID
     SentToForeign(usize),
     /// Starting to apply a new change. There are usize changes left
     StartingToApplyChange(usize),
+    /// A file download started or finished
+    Downloading(DownloadProgress),
     /// Adding a change to the database. There are usize changes left
     AddingChangeToDb(usize),
     /// Removing the specified file from the database. There are usize files left to remove
@@ -32,58 +50,138 @@ pub enum SyncUpdate {
     Error(String)
 }
 
+/**
+  Progress of the files that are downloaded while changes are being applied
+*/
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DownloadProgress {
+    /// Downloads that have started but not finished
+    pub in_flight: usize,
+    /// Downloads that have finished, successfully or not
+    pub finished: usize,
+    pub total: usize,
+    /// Bytes of file content and sidecars that have been received
+    pub bytes_received: u64,
+    /// The combined size of the files whose downloads have started. The size
+    /// of a file is only known once its download starts
+    pub bytes_total: u64
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SyncStatus {
     pub last_update: SyncUpdate,
-    pub foreign_job_id: Option<usize>
+    pub foreign_job_id: Option<usize>,
+    /// The latest download progress. It is kept when other updates arrive
+    #[serde(default)]
+    pub downloads: Option<DownloadProgress>
 }
 
 pub type RxType = Receiver<(usize, SyncUpdate)>;
 pub type TxType = SyncSender<(usize, SyncUpdate)>;
 pub type LocalTxType = (usize, TxType);
-pub type StorageType = Arc<Mutex<HashMap<usize, SyncStatus>>>;
+pub type StorageType = Arc<Mutex<ProgressStorage>>;
+
+/// How long finished jobs are kept in memory. Their result is read from the
+/// database after that
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(600);
+/// How often a comment is sent on idle event streams. This keeps proxies from
+/// closing the connection and detects clients that went away
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/**
+  The status of the jobs that are tracked in memory along with the clients
+  that follow their updates
+*/
+#[derive(Default)]
+pub struct ProgressStorage {
+    statuses: HashMap<usize, SyncStatus>,
+    listeners: HashMap<usize, Vec<Sender<SyncUpdate>>>
+}
+
+impl SyncUpdate {
+    /**
+      Returns true if this is the last update of a job
+    */
+    pub fn is_final(&self) -> bool {
+        matches!(*self, SyncUpdate::Done | SyncUpdate::Error(_))
+    }
+}
+
+/**
+  Returns a random id for a sync job. Ids fit in an i64 so they can be stored
+  in the database
+*/
+pub fn new_job_id() -> usize {
+    rand::random::<usize>() & (i64::MAX as usize)
+}
 
 /**
   Creates a channel for sending updates and a hash map where they can be read from
 */
 pub fn setup_progress_datastructures() -> (TxType, RxType, StorageType) {
     let (tx, rx) = sync_channel(32);
-    let storage = Arc::new(Mutex::new(HashMap::new()));
+    let storage = Arc::new(Mutex::new(ProgressStorage::default()));
 
     (tx, rx, storage)
 }
 
 /**
   Starts a tread that listens for updates on `update_rx` and inserts
-  them into the corresponding spot in the hash map
+  them into the corresponding spot in the hash map. The updates are also
+  passed on to the listeners of the job
 */
 pub fn run_sync_tracking_thread(
     update_rx: Receiver<(usize, SyncUpdate)>,
     storage: StorageType
 ) {
     thread::spawn(move || {
+        let mut finished_jobs: VecDeque<(Instant, usize)> = VecDeque::new();
         loop {
             let (id, update) = update_rx.recv()
                 .expect("Failed to read sync update, sender disconnected");
 
             let mut storage = storage.lock().unwrap();
 
+            while finished_jobs.front()
+                .map(|&(finished, _)| finished.elapsed() > FINISHED_JOB_RETENTION)
+                .unwrap_or(false)
+            {
+                let (_, finished_id) = finished_jobs.pop_front().unwrap();
+                storage.statuses.remove(&finished_id);
+            }
+
             println!("got_update: {:?}", update);
 
             // Fetch the old status from storage
             let mut new_status = storage
+                .statuses
                 .get(&id)
                 .map(|x| (*x).clone())
-                .unwrap_or(SyncStatus{last_update: update.clone(), foreign_job_id: None});
+                .unwrap_or(SyncStatus{last_update: update.clone(), foreign_job_id: None, downloads: None});
 
 
             // Update it to reflect what has changed
-            if let SyncUpdate::SentToForeign(id) = update {
-                new_status.foreign_job_id = Some(id);
+            match update {
+                SyncUpdate::SentToForeign(id) => new_status.foreign_job_id = Some(id),
+                SyncUpdate::Downloading(ref progress) => new_status.downloads = Some(progress.clone()),
+                SyncUpdate::Done | SyncUpdate::Error(_) => finished_jobs.push_back((Instant::now(), id)),
+                _ => {}
+            }
+
+            // Listeners that went away are dropped, and nothing is sent
+            // after the last update of a job
+            if update.is_final() {
+                for listener in storage.listeners.remove(&id).unwrap_or_default() {
+                    listener.send(update.clone()).ok();
+                }
+            }
+            else if let Some(listeners) = storage.listeners.get_mut(&id) {
+                listeners.retain(|listener| listener.send(update.clone()).is_ok());
             }
+
             new_status.last_update = update;
 
-            storage.insert(id, new_status);
+            storage.statuses.insert(id, new_status);
         }
     });
 }
@@ -93,17 +191,200 @@ pub fn progress_request_handler(request: &mut Request, storage: &StorageType)
 {
     let job_id = get_get_usize(request, "job_id")?;
 
-    let result = handle_progress_request(job_id, storage)?;
+    let fdb = setup_db_connection(request)?;
+    let result = handle_progress_request(job_id, storage, &fdb)?;
 
     Ok(Response::with((status::Ok, to_json_with_result(result)?)))
 }
 
-fn handle_progress_request(job_id: usize, storage: &StorageType) -> Result<SyncStatus> {
-    let storage = storage.lock().unwrap();
+/**
+  Returns the status of a job. Jobs that finished a while ago are only stored
+  in the database, see `sync_jobs`
+*/
+fn handle_progress_request(job_id: usize, storage: &StorageType, fdb: &FileDatabase)
+    -> Result<SyncStatus>
+{
+    if let Some(val) = storage.lock().unwrap().statuses.get(&job_id) {
+        return Ok((*val).clone());
+    }
 
-    match storage.get(&job_id) {
-        Some(val) => Ok((*val).clone()),
+    match fdb.get_sync_job(job_id)?.final_status() {
+        Some(status) => Ok(status),
         None => bail!(ErrorKind::NoSuchJobId(job_id))
     }
 }
 
+
+
+/**
+  Starts following the updates of a job. The latest download progress and
+  update of the job are received first if it is tracked, followed by every
+  update until the job is done
+*/
+pub fn listen(job_id: usize, storage: &StorageType) -> Receiver<SyncUpdate> {
+    let (tx, rx) = channel();
+
+    let mut storage = storage.lock().unwrap();
+    if let Some(status) = storage.statuses.get(&job_id).cloned() {
+        if let Some(downloads) = status.downloads {
+            tx.send(SyncUpdate::Downloading(downloads)).ok();
+        }
+        let is_final = status.last_update.is_final();
+        tx.send(status.last_update).ok();
+
+        if is_final {
+            return rx;
+        }
+    }
+
+    storage.listeners.entry(job_id).or_default().push(tx);
+    rx
+}
+
+/**
+  Streams the updates of a job as server-sent events until the job is done.
+  Each event contains a json encoded `SyncUpdate`
+*/
+pub fn events_request_handler(request: &mut Request, storage: &StorageType)
+    -> IronResult<Response>
+{
+    let job_id = get_get_usize(request, "job_id")?;
+
+    let fdb = setup_db_connection(request)?;
+    let updates = handle_events_request(job_id, storage, &fdb)?;
+
+    let mut response = Response::with((status::Ok, Box::new(EventStream{updates}) as Box<dyn WriteBody>));
+    response.headers.set(ContentType("text/event-stream".parse().unwrap()));
+    response.headers.set(CacheControl(vec!(CacheDirective::NoCache)));
+    Ok(response)
+}
+
+fn handle_events_request(job_id: usize, storage: &StorageType, fdb: &FileDatabase)
+    -> Result<Receiver<SyncUpdate>>
+{
+    let is_tracked = storage.lock().unwrap().statuses.contains_key(&job_id);
+
+    // Jobs that are not tracked have either finished a while ago or not sent
+    // their first update yet
+    if !is_tracked {
+        if let Some(status) = fdb.get_sync_job(job_id)?.final_status() {
+            let (tx, rx) = channel();
+            tx.send(status.last_update).ok();
+            return Ok(rx);
+        }
+    }
+
+    Ok(listen(job_id, storage))
+}
+
+/**
+  Response body which writes updates as server-sent events as they arrive
+*/
+struct EventStream {
+    updates: Receiver<SyncUpdate>
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        loop {
+            match self.updates.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(update) => {
+                    write!(out, "data: {}\n\n", serde_json::to_string(&update)?)?;
+                    if update.is_final() {
+                        return out.flush();
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => write!(out, ": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return out.flush()
+            }
+            out.flush()?;
+        }
+    }
+}
+
+/**
+  Reads the updates from a stream of server-sent events written by
+  `events_request_handler`. Comments and other fields are skipped
+*/
+pub fn read_events<R: BufRead>(reader: R) -> impl Iterator<Item=Result<SyncUpdate>> {
+    reader.lines()
+        .filter_map(|line| {
+            match line {
+                Ok(line) => {
+                    line.strip_prefix("data:")
+                        .map(|data| serde_json::from_str(data.trim()).map_err(|e| e.into()))
+                }
+                Err(e) => Some(Err(e.into()))
+            }
+        })
+}
+
+
+#[cfg(test)]
+mod sync_progress_tests {
+    use super::*;
+
+    #[test]
+    fn listeners_get_updates_until_the_job_is_done() {
+        let (tx, rx, storage) = setup_progress_datastructures();
+        run_sync_tracking_thread(rx, storage.clone());
+
+        let downloads = DownloadProgress {
+            in_flight: 1,
+            finished: 0,
+            total: 2,
+            bytes_received: 10,
+            bytes_total: 100
+        };
+        tx.send((1, SyncUpdate::GatheredData)).unwrap();
+        tx.send((1, SyncUpdate::Downloading(downloads.clone()))).unwrap();
+        tx.send((1, SyncUpdate::StartingToApplyChange(3))).unwrap();
+
+        // Wait for the updates to be tracked
+        while storage.lock().unwrap().statuses.get(&1).map(|s| s.downloads.is_none()).unwrap_or(true) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let updates = listen(1, &storage);
+        let late_updates = listen(1, &storage);
+        tx.send((1, SyncUpdate::AddingSyncpoint)).unwrap();
+        tx.send((1, SyncUpdate::Done)).unwrap();
+        drop(late_updates);
+
+        let received = updates.iter().collect::<Vec<_>>();
+        assert_eq!(received.len(), 4);
+        assert_matches!(received[0], SyncUpdate::Downloading(ref d) if *d == downloads);
+        assert_matches!(received[1], SyncUpdate::StartingToApplyChange(3));
+        assert_matches!(received[2], SyncUpdate::AddingSyncpoint);
+        assert_matches!(received[3], SyncUpdate::Done);
+
+        // Listening to a finished job gives its last update
+        let finished = listen(1, &storage).iter().collect::<Vec<_>>();
+        assert_matches!(finished.last(), Some(SyncUpdate::Done));
+    }
+
+    #[test]
+    fn events_are_written_and_read() {
+        let (tx, rx) = channel();
+        tx.send(SyncUpdate::GatheredData).unwrap();
+        tx.send(SyncUpdate::Error("Failed\nto sync".into())).unwrap();
+        tx.send(SyncUpdate::AddingSyncpoint).unwrap();
+
+        let mut out = vec!();
+        EventStream{updates: rx}.write_body(&mut out).unwrap();
+
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.starts_with("data: \"GatheredData\"\n\n"));
+
+        // Nothing is written after the last update
+        let events = read_events(&out[..]).collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_matches!(events[0], Ok(SyncUpdate::GatheredData));
+        assert_matches!(events[1], Ok(SyncUpdate::Error(ref e)) if e == "Failed\nto sync");
+
+        let with_comments = ": keep-alive\n\nevent: update\ndata: \"Done\"\n\n";
+        let events = read_events(with_comments.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_matches!(events[0], Ok(SyncUpdate::Done));
+    }
+}
@@ -0,0 +1,656 @@
+use iron::prelude::*;
+use iron::headers::ContentLength;
+use persistent;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use changelog::{Change, SyncPoint};
+use file_database::FileDatabase;
+use foreign_server::{ForeignServer, ChangeData, FileDetails};
+use library_digest::LibraryDigest;
+use request_helpers::{
+    get_get_i64,
+    get_optional_get_i64,
+    get_get_usize,
+    get_get_variable,
+    to_json_with_result,
+    from_json_with_result,
+    setup_db_connection
+};
+use search::SavedSearchQuery;
+use settings::Settings;
+use sync::{files_to_fetch, with_retries};
+use sync_auth::{authenticate_request, signed_response};
+use sync_filters::parse_filter_query;
+use sync_handlers::{
+    foreign_url,
+    handle_file_request,
+    handle_file_detail_request,
+    handle_sidecar_request,
+    handle_thumbnail_request
+};
+use sync_handshake::Handshake;
+use sync_progress as sp;
+use sync_control::{self, RetryPolicy};
+
+use file_handler::ensure_content_hash;
+use util::to_hex;
+
+use sha2::{Sha256, Digest};
+
+use error::{Result, ErrorKind, ResultExt};
+
+/// The folder in the file storage where pushed files are kept until the
+/// changes they belong to have been applied
+const PUSH_FOLDER: &str = "sync_push";
+
+/// The size of the chunks files are pushed in. Larger requests are rejected
+/// since their body has to be kept in memory to check the signature
+pub const PUSH_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Pushes whose parts have not been written to for this long are assumed to
+/// be abandoned by the instance that pushed them and are removed
+pub const PUSH_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/**
+  A part of a file which is pushed to a foreign server
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilePart {
+    Content,
+    Thumbnail,
+    /// The sidecar with the index
+    Sidecar(usize)
+}
+
+impl FilePart {
+    /**
+      The query variables which identify the part in a request to `/sync/push`
+    */
+    pub fn query(&self) -> Vec<(String, String)> {
+        match *self {
+            FilePart::Content => vec!((String::from("part"), String::from("content"))),
+            FilePart::Thumbnail => vec!((String::from("part"), String::from("thumbnail"))),
+            FilePart::Sidecar(index) => vec!(
+                (String::from("part"), String::from("sidecar")),
+                (String::from("index"), format!("{}", index))
+            )
+        }
+    }
+
+    fn from_request(request: &mut Request) -> Result<Self> {
+        match get_get_variable(request, "part")?.as_str() {
+            "content" => Ok(FilePart::Content),
+            "thumbnail" => Ok(FilePart::Thumbnail),
+            "sidecar" => Ok(FilePart::Sidecar(get_get_usize(request, "index")?)),
+            _ => bail!(ErrorKind::InvalidVariableType(
+                "part".into(),
+                "content, thumbnail or sidecar".into()
+            ))
+        }
+    }
+
+    /**
+      The name of the file the part is stored in until it is used
+    */
+    pub fn filename(&self, file_id: i32) -> String {
+        match *self {
+            FilePart::Content => format!("{}.content", file_id),
+            FilePart::Thumbnail => format!("{}.thumbnail", file_id),
+            FilePart::Sidecar(index) => format!("{}.sidecar.{}", file_id, index)
+        }
+    }
+}
+
+/**
+  Returns the folder where the parts pushed with `push_id` are stored
+*/
+pub fn push_directory(storage_path: &Path, push_id: usize) -> PathBuf {
+    storage_path.join(PUSH_FOLDER).join(format!("{}", push_id))
+}
+
+/**
+  Stores a chunk of a part of a file that was pushed by a foreign server at
+  `offset`. Anything stored after the offset is replaced, which happens when
+  a chunk is sent again because the response to the first attempt was lost.
+
+  Fails if the chunks before `offset` have not been stored
+*/
+pub fn store_pushed_part(
+    storage_path: &Path,
+    push_id: usize,
+    file_id: i32,
+    part: FilePart,
+    offset: u64,
+    content: &[u8]
+) -> Result<()> {
+    let directory = push_directory(storage_path, push_id);
+    if !directory.exists() {
+        if let Err(e) = remove_stale_pushes(storage_path, PUSH_MAX_AGE) {
+            println!("Warning: Failed to remove stale pushes: {}", e);
+        }
+    }
+    fs::create_dir_all(&directory)?;
+
+    let path = directory.join(part.filename(file_id));
+    let mut file = if offset == 0 {
+        File::create(path)?
+    }
+    else {
+        let mut file = OpenOptions::new().write(true).open(path)
+            .chain_err(|| ErrorKind::PushedChunkMissing(file_id, offset, 0))?;
+
+        let stored = file.metadata()?.len();
+        if stored < offset {
+            bail!(ErrorKind::PushedChunkMissing(file_id, offset, stored));
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file
+    };
+    file.write_all(content)?;
+    Ok(())
+}
+
+/**
+  Removes the parts of pushes which have not been written to for `max_age`.
+  They are left behind when the instance that pushed them stops before
+  applying the changes. Returns the amount of removed pushes
+*/
+pub fn remove_stale_pushes(storage_path: &Path, max_age: Duration) -> Result<usize> {
+    let pushes = match fs::read_dir(storage_path.join(PUSH_FOLDER)) {
+        Ok(pushes) => pushes,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into())
+    };
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for push in pushes {
+        let directory = push?.path();
+        let mut last_write = fs::metadata(&directory)?.modified()?;
+        for part in fs::read_dir(&directory)? {
+            last_write = last_write.max(part?.metadata()?.modified()?);
+        }
+
+        if now.duration_since(last_write).unwrap_or_default() > max_age {
+            fs::remove_dir_all(&directory)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                  Receiving side
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  The files pushed by a foreign server which this instance can't connect to.
+  It stands in for the foreign server while its changes are applied, so only
+  the methods for getting files are available.
+
+  The pushed files are removed when this is dropped
+*/
+pub struct PushedFiles {
+    details: HashMap<i32, FileDetails>,
+    /// Where the pushed parts are stored. There are no parts when deciding
+    /// which files are needed
+    directory: Option<PathBuf>
+}
+
+impl PushedFiles {
+    pub fn new(details: HashMap<i32, FileDetails>, directory: PathBuf) -> Self {
+        Self { details, directory: Some(directory) }
+    }
+
+    /**
+      Files which only have details, used to find out which files are needed
+    */
+    pub fn details_only(details: HashMap<i32, FileDetails>) -> Self {
+        Self { details, directory: None }
+    }
+
+    fn part_path(&self, file_id: i32, part: FilePart) -> Result<PathBuf> {
+        match self.directory {
+            Some(ref directory) => Ok(directory.join(part.filename(file_id))),
+            None => bail!(ErrorKind::FileNotPushed(file_id))
+        }
+    }
+
+    fn read_part(&self, file_id: i32, part: FilePart) -> Result<Vec<u8>> {
+        let mut content = vec!();
+        File::open(self.part_path(file_id, part)?)
+            .and_then(|mut file| file.read_to_end(&mut content))
+            .chain_err(|| ErrorKind::FileNotPushed(file_id))?;
+        Ok(content)
+    }
+}
+
+impl Drop for PushedFiles {
+    fn drop(&mut self) {
+        if let Some(ref directory) = self.directory {
+            fs::remove_dir_all(directory).unwrap_or_else(|e| {
+                if e.kind() != ::std::io::ErrorKind::NotFound {
+                    println!("Warning: Failed to remove pushed files in {:?}: {}", directory, e)
+                }
+            });
+        }
+    }
+}
+
+impl ForeignServer for PushedFiles {
+    fn get_syncpoints(&self) -> Result<Vec<SyncPoint>> {
+        bail!("Syncpoints can't be requested from pushed files")
+    }
+    fn get_changes(&self, _starting_syncpoint: &Option<SyncPoint>) -> Result<Vec<Change>> {
+        bail!("Changes can't be requested from pushed files")
+    }
+    fn get_file_details(&self, id: i32) -> Result<FileDetails> {
+        match self.details.get(&id) {
+            Some(details) => Ok(details.clone()),
+            None => bail!(ErrorKind::FileNotPushed(id))
+        }
+    }
+    fn send_changes(&mut self, _changes: &ChangeData, _own_port: u16, _push_id: Option<usize>)
+        -> Result<usize>
+    {
+        bail!("Changes can't be sent to pushed files")
+    }
+    fn get_needed_files(&self, _changes: &ChangeData, _own_port: u16) -> Result<Vec<i32>> {
+        bail!("Changes can't be sent to pushed files")
+    }
+    fn push_file_part(&self, _push_id: usize, _file_id: i32, _part: FilePart, _offset: u64, _content: &[u8])
+        -> Result<()>
+    {
+        bail!("Files can't be pushed to pushed files")
+    }
+    fn download_file(&self, id: i32, destination: &Path, on_progress: &dyn Fn(u64)) -> Result<()> {
+        let copied = fs::copy(self.part_path(id, FilePart::Content)?, destination)
+            .chain_err(|| ErrorKind::FileNotPushed(id))?;
+        on_progress(copied);
+        Ok(())
+    }
+    fn download_sidecar(&self, id: i32, index: usize, destination: &Path, on_progress: &dyn Fn(u64))
+        -> Result<()>
+    {
+        let copied = fs::copy(self.part_path(id, FilePart::Sidecar(index))?, destination)
+            .chain_err(|| ErrorKind::FileNotPushed(id))?;
+        on_progress(copied);
+        Ok(())
+    }
+    fn get_thumbnail(&self, id: i32) -> Result<Option<Vec<u8>>> {
+        // Files without thumbnails don't push one
+        match self.part_path(id, FilePart::Thumbnail)? {
+            ref path if path.exists() => Ok(Some(self.read_part(id, FilePart::Thumbnail)?)),
+            _ => Ok(None)
+        }
+    }
+    fn get_sync_updates(&self, _job_id: usize) -> Result<Box<dyn Iterator<Item=Result<sp::SyncUpdate>>>> {
+        bail!("Pushed files don't have sync jobs")
+    }
+    fn add_syncpoint(&self, _syncpoint: &SyncPoint) -> Result<()> {
+        bail!("Syncpoints can't be added to pushed files")
+    }
+    fn get_library_digest(&self, _prefix: &str) -> Result<LibraryDigest> {
+        bail!("Library digests can't be requested from pushed files")
+    }
+    fn cancel_job(&self, _job_id: usize) -> Result<()> {
+        bail!("Pushed files don't have sync jobs")
+    }
+    fn handshake(&self) -> Result<Handshake> {
+        bail!("Pushed files can't be synced with")
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                  Pushing side
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  Sends changes to a foreign server along with the files it needs to apply
+  them, so that the foreign server never has to connect to this instance.
+  `own_port` is only used to identify this instance.
+
+  Returns the id of the job which applies the changes on the foreign server
+  and the amount of files that were pushed
+*/
+pub fn push_changes(
+    fdb: &FileDatabase,
+    foreign_server: &mut dyn ForeignServer,
+    change_data: ChangeData,
+    own_port: u16,
+    (job_id, progress_tx): &sp::LocalTxType
+) -> Result<(usize, usize)> {
+    let mut file_details = HashMap::new();
+    for change in &change_data.changes {
+        let id = change.affected_file;
+        if !file_details.contains_key(&id)
+            && !change_data.removed_files.contains(&id)
+            && fdb.get_file_with_id(id).is_some()
+        {
+            file_details.insert(id, handle_file_detail_request(fdb, id)?);
+        }
+    }
+    let change_data = ChangeData{file_details, ..change_data};
+
+    let needed_files = foreign_server.get_needed_files(&change_data, own_port)
+        .chain_err(|| "Failed to get the files needed by the foreign server")?;
+
+    let push_id = sp::new_job_id();
+    let retry_policy = sync_control::retry_policy(*job_id);
+    let mut files_left = needed_files.len();
+    for file_id in &needed_files {
+        files_left -= 1;
+        progress_tx.send((*job_id, sp::SyncUpdate::PushingFile(files_left)))
+            .unwrap_or_else(|_e| println!("Warning: Sync progress listener crashed"));
+
+        push_file(fdb, foreign_server, push_id, *file_id, &retry_policy, *job_id)
+            .chain_err(|| format!("Failed to push file {}", file_id))?;
+    }
+
+    let foreign_job_id = with_retries(&retry_policy, Some(*job_id), || {
+        foreign_server.send_changes(&change_data, own_port, Some(push_id))
+    })?;
+
+    Ok((foreign_job_id, needed_files.len()))
+}
+
+/**
+  Pushes the content, thumbnail and sidecars of a file as part of the job
+  `job_id`. The content is read and pushed in chunks of `PUSH_CHUNK_SIZE` and
+  fails if it doesn't match the content hash of the file, which happens if the
+  file is replaced while it is being pushed
+*/
+fn push_file(
+    fdb: &FileDatabase,
+    foreign_server: &dyn ForeignServer,
+    push_id: usize,
+    file_id: i32,
+    retry_policy: &RetryPolicy,
+    job_id: usize
+) -> Result<()> {
+    let push_part = |part, content: &mut dyn Read| {
+        push_part(foreign_server, push_id, file_id, part, content, retry_policy, job_id)
+    };
+
+    let mut file = fdb.get_file_with_id_result(file_id)?;
+    ensure_content_hash(&mut file, fdb)?;
+
+    let mut hasher = Sha256::new();
+    let content = File::open(handle_file_request(fdb, file_id)?)?;
+    push_part(FilePart::Content, &mut HashingReader{
+        inner: content,
+        hasher: &mut hasher
+    })?;
+
+    let actual = to_hex(&hasher.finalize());
+    if let Some(expected) = file.content_hash {
+        if expected != actual {
+            bail!(ErrorKind::ContentHashMismatch(file_id, expected, actual));
+        }
+    }
+
+    if let Some(thumbnail) = handle_thumbnail_request(fdb, file_id)? {
+        push_part(FilePart::Thumbnail, &mut thumbnail.as_slice())?;
+    }
+
+    for index in 0..file.sidecars.len() {
+        let mut sidecar = File::open(handle_sidecar_request(fdb, file_id, index)?)?;
+        push_part(FilePart::Sidecar(index), &mut sidecar)?;
+    }
+    Ok(())
+}
+
+/**
+  Pushes everything that can be read from `content` in chunks of
+  `PUSH_CHUNK_SIZE`. Empty parts are pushed as a single empty chunk.
+
+  Chunks are retried according to `retry_policy` since the foreign server
+  replaces chunks that are pushed again, and the push stops once the job is
+  stopped
+*/
+fn push_part(
+    foreign_server: &dyn ForeignServer,
+    push_id: usize,
+    file_id: i32,
+    part: FilePart,
+    content: &mut dyn Read,
+    retry_policy: &RetryPolicy,
+    job_id: usize
+) -> Result<()> {
+    let mut content = content.take(0);
+    let mut offset = 0;
+    loop {
+        sync_control::check_job(job_id)?;
+
+        let mut chunk = vec!();
+        content.set_limit(PUSH_CHUNK_SIZE);
+        content.read_to_end(&mut chunk)?;
+
+        if chunk.is_empty() && offset != 0 {
+            return Ok(());
+        }
+        with_retries(retry_policy, Some(job_id), || {
+            foreign_server.push_file_part(push_id, file_id, part, offset, &chunk)
+        })?;
+
+        if (chunk.len() as u64) < PUSH_CHUNK_SIZE {
+            return Ok(());
+        }
+        offset += chunk.len() as u64;
+    }
+}
+
+/**
+  Reader which hashes everything that is read through it
+*/
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256
+}
+
+impl<'a, R: Read> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+
+////////////////////////////////////////////////////////////////////////////////
+//                  Request handlers
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn needed_files_handler(request: &mut Request) -> IronResult<Response> {
+    let body = authenticate_request(request)?;
+    let body = String::from_utf8(body).chain_err(|| "Changes were not valid utf-8")?;
+    let change_data = from_json_with_result::<ChangeData>(&body)?;
+
+    let fdb = setup_db_connection(request)?;
+    let filter = match fdb.get_sync_filter(&foreign_url(request)?)? {
+        Some(query) => Some(parse_filter_query(&query)?),
+        None => None
+    };
+
+    let needed_files = handle_needed_files_request(&fdb, change_data, filter.as_ref())?;
+
+    Ok(signed_response(request, to_json_with_result(needed_files)?))
+}
+
+pub fn push_handler(request: &mut Request) -> IronResult<Response> {
+    // Checked before the body is read to authenticate the request
+    if let Some(&ContentLength(length)) = request.headers.get::<ContentLength>() {
+        check_chunk_size(length)?;
+    }
+    let content = authenticate_request(request)?;
+    check_chunk_size(content.len() as u64)?;
+
+    let settings = request.get::<persistent::Read<Settings>>().unwrap();
+
+    let push_id = get_get_usize(request, "push_id")?;
+    let file_id = get_get_i64(request, "file_id")? as i32;
+    let part = FilePart::from_request(request)?;
+    let offset = get_optional_get_i64(request, "offset")?.unwrap_or(0) as u64;
+
+    store_pushed_part(&settings.get_file_storage_path(), push_id, file_id, part, offset, &content)?;
+
+    Ok(signed_response(request, "Done"))
+}
+
+fn check_chunk_size(size: u64) -> Result<()> {
+    if size > PUSH_CHUNK_SIZE {
+        bail!(ErrorKind::PushedChunkTooLarge(size, PUSH_CHUNK_SIZE));
+    }
+    Ok(())
+}
+
+fn handle_needed_files_request(
+    fdb: &FileDatabase,
+    change_data: ChangeData,
+    filter: Option<&SavedSearchQuery>
+) -> Result<Vec<i32>> {
+    let pushed_files = PushedFiles::details_only(change_data.file_details);
+
+    files_to_fetch(
+        fdb,
+        &pushed_files,
+        &change_data.changes,
+        &change_data.removed_files,
+        filter
+    )
+}
+
+
+#[cfg(test)]
+mod sync_push_tests {
+    use super::*;
+
+    use changelog::ChangeType;
+    use file_database::db_test_helpers;
+    use sync::{apply_changes_with_filter, ApplyOptions};
+
+    use chrono::NaiveDate;
+
+    fn details(sidecars: Vec<String>) -> FileDetails {
+        FileDetails {
+            extension: "jpg".into(),
+            timestamp: NaiveDate::from_ymd(2016, 1, 1).and_hms(0, 0, 0),
+            sidecars,
+            content_hash: None,
+            size: None,
+            tags: vec!(),
+            original_stored: true
+        }
+    }
+
+    #[test]
+    fn pushed_files_are_applied() {
+        let fdb = db_test_helpers::get_database();
+        let fdb = fdb.lock().unwrap();
+        fdb.reset();
+
+        let timestamp = NaiveDate::from_ymd(2017, 1, 1).and_hms(0, 0, 0);
+        let changes = vec!(Change::new(timestamp, 1, ChangeType::FileAdded));
+        let mut file_details = HashMap::new();
+        file_details.insert(1, details(vec!("nef".into())));
+        let change_data = ChangeData{
+            changes: changes.clone(),
+            removed_files: vec!(),
+            file_details: file_details.clone(),
+            clock: None
+        };
+
+        assert_eq!(handle_needed_files_request(&fdb, change_data, None).unwrap(), vec!(1));
+
+        let storage = fdb.get_file_save_path();
+        store_pushed_part(&storage, 5, 1, FilePart::Content, 0, &[1, 2, 3]).unwrap();
+        store_pushed_part(&storage, 5, 1, FilePart::Sidecar(0), 0, &[4, 5, 6]).unwrap();
+
+        let directory = push_directory(&storage, 5);
+        {
+            let pushed_files = PushedFiles::new(file_details.clone(), directory.clone());
+            assert_matches!(pushed_files.get_thumbnail(1), Ok(None));
+
+            let (tx, _rx, _) = sp::setup_progress_datastructures();
+            apply_changes_with_filter(&fdb, &pushed_files, &changes, &[], &ApplyOptions::unfiltered(2), &(0, tx))
+                .expect("Failed to apply pushed changes");
+        }
+        assert!(!directory.exists());
+
+        let file = fdb.get_file_with_id(1).expect("Pushed file was not added");
+        assert_eq!(fs::read(storage.join(&file.filename)).unwrap(), vec!(1, 2, 3));
+        assert_eq!(fs::read(storage.join(&file.sidecars[0])).unwrap(), vec!(4, 5, 6));
+
+        // Files that are already stored are not needed again
+        let change_data = ChangeData{changes, removed_files: vec!(), file_details, clock: None};
+        assert!(handle_needed_files_request(&fdb, change_data, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pushed_chunks_are_stored_at_their_offsets() {
+        let storage = ::std::env::temp_dir().join("flash_pushed_chunks");
+        let path = push_directory(&storage, 3).join(FilePart::Content.filename(1));
+
+        store_pushed_part(&storage, 3, 1, FilePart::Content, 0, &[1, 2]).unwrap();
+        store_pushed_part(&storage, 3, 1, FilePart::Content, 2, &[3]).unwrap();
+        // Chunks that are sent again replace the previous attempt
+        store_pushed_part(&storage, 3, 1, FilePart::Content, 2, &[3, 4]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec!(1, 2, 3, 4));
+
+        assert_matches!(
+            store_pushed_part(&storage, 3, 1, FilePart::Content, 10, &[5]).map_err(|e| e.0),
+            Err(ErrorKind::PushedChunkMissing(1, 10, 4))
+        );
+        assert_matches!(
+            store_pushed_part(&storage, 3, 1, FilePart::Thumbnail, 2, &[5]).map_err(|e| e.0),
+            Err(ErrorKind::PushedChunkMissing(1, 2, 0))
+        );
+        assert_eq!(fs::read(&path).unwrap(), vec!(1, 2, 3, 4));
+
+        fs::remove_dir_all(&storage).unwrap();
+    }
+
+    #[test]
+    fn stale_pushes_are_removed() {
+        let storage = ::std::env::temp_dir().join("flash_stale_pushes");
+        assert_eq!(remove_stale_pushes(&storage, PUSH_MAX_AGE).unwrap(), 0);
+
+        store_pushed_part(&storage, 4, 1, FilePart::Content, 0, &[1]).unwrap();
+        store_pushed_part(&storage, 5, 1, FilePart::Content, 0, &[1]).unwrap();
+        assert_eq!(remove_stale_pushes(&storage, PUSH_MAX_AGE).unwrap(), 0);
+        assert!(push_directory(&storage, 4).exists());
+
+        assert_eq!(remove_stale_pushes(&storage, Duration::from_secs(0)).unwrap(), 2);
+        assert!(!push_directory(&storage, 4).exists());
+        assert!(!push_directory(&storage, 5).exists());
+
+        fs::remove_dir_all(&storage).unwrap();
+    }
+
+    #[test]
+    fn files_without_pushed_content_fail() {
+        let mut file_details = HashMap::new();
+        file_details.insert(1, details(vec!()));
+
+        let pushed_files = PushedFiles::details_only(file_details);
+
+        assert!(pushed_files.get_file_details(1).is_ok());
+        assert_matches!(
+            pushed_files.get_file_details(2).err().map(|e| e.0),
+            Some(ErrorKind::FileNotPushed(2))
+        );
+        assert_matches!(
+            pushed_files.download_file(1, Path::new("/tmp/flash_not_pushed"), &|_| {}),
+            Err(::error::Error(ErrorKind::FileNotPushed(1), _))
+        );
+    }
+}
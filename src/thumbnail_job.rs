@@ -0,0 +1,396 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::collections::HashMap;
+use std::thread;
+use std::cmp;
+
+use iron::prelude::*;
+use iron::status;
+use persistent::Read;
+
+use rand;
+
+use file_database::{FileDatabase, File};
+use file_handler::{regenerate_thumbnails, ensure_content_hash};
+use file_util::{rendition_sizes, is_readable_image, ThumbnailSettings, RENDITION_VERSION};
+use request_helpers::{to_json_with_result, get_get_usize, setup_db_connection};
+use search::SavedSearchQuery;
+use settings::Settings;
+
+use error_chain::ChainedError;
+
+use error::{Result, ErrorKind};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ThumbnailJobUpdate {
+    /// Looking for files with missing or outdated thumbnails
+    FindingFiles,
+    /// Regenerating thumbnails. The first usize files out of the second usize are done
+    Regenerating(usize, usize),
+    /// All files have been processed
+    Done,
+    /// An error occured which stopped the job
+    Error(String)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThumbnailJobStatus {
+    pub last_update: ThumbnailJobUpdate,
+    /// IDs of the files where regeneration failed along with the reason
+    pub failed_files: Vec<(i32, String)>
+}
+
+impl ThumbnailJobStatus {
+    fn is_running(&self) -> bool {
+        !matches!(self.last_update, ThumbnailJobUpdate::Done | ThumbnailJobUpdate::Error(_))
+    }
+}
+
+pub type StorageType = Arc<Mutex<HashMap<usize, ThumbnailJobStatus>>>;
+
+pub fn setup_storage() -> StorageType {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+/**
+  Returns true if the thumbnail or renditions of `file` are missing, unreadable,
+  generated by an old version or don't match the sizes in `settings`. Files
+  without a perceptual hash also need their renditions regenerated since the
+  hash is computed at the same time
+*/
+pub fn needs_regeneration(fdb: &FileDatabase, file: &File, settings: &ThumbnailSettings)
+    -> Result<bool>
+{
+    let thumbnail_path = match file.thumbnail_path {
+        Some(ref path) => path,
+        None => return Ok(true)
+    };
+    if file.perceptual_hash.is_none() {
+        return Ok(true)
+    }
+
+    let renditions = fdb.get_renditions(file.id)?;
+
+    if !renditions.iter().any(|rendition| &rendition.filename == thumbnail_path) {
+        return Ok(true)
+    }
+    if renditions.iter().any(|rendition| rendition.version < RENDITION_VERSION) {
+        return Ok(true)
+    }
+
+    let expected_sizes = match (file.width, file.height) {
+        (Some(width), Some(height)) => {
+            rendition_sizes(settings, cmp::max(width, height) as u32)
+        }
+        _ => return Ok(true)
+    };
+    let mut sizes = renditions.iter().map(|rendition| rendition.size as u32).collect::<Vec<_>>();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    if sizes != expected_sizes {
+        return Ok(true)
+    }
+
+    let storage_path = fdb.get_file_save_path();
+    Ok(!renditions.iter()
+        .all(|rendition| is_readable_image(&storage_path.join(&rendition.filename))))
+}
+
+/**
+  Returns all files in the database which need new thumbnails or lack a
+  content hash. Files whose originals are stored on another instance are left
+  out since both are computed from the original
+*/
+pub fn find_outdated_files(fdb: &FileDatabase, settings: &ThumbnailSettings) -> Result<Vec<File>> {
+    let mut result = vec!();
+    for file in fdb.search_files(SavedSearchQuery::empty()) {
+        if file.is_uploaded && (file.content_hash.is_none() || needs_regeneration(fdb, &file, settings)?) {
+            result.push(file);
+        }
+    }
+    Ok(result)
+}
+
+/**
+  Computes the content hash of a file if it is missing and regenerates its
+  thumbnails if they are outdated
+*/
+fn update_file(fdb: &FileDatabase, mut file: File, settings: &ThumbnailSettings) -> Result<()> {
+    ensure_content_hash(&mut file, fdb)?;
+
+    if needs_regeneration(fdb, &file, settings)? {
+        regenerate_thumbnails(fdb, &file, settings)?;
+    }
+    Ok(())
+}
+
+/**
+  Regenerates the thumbnails and computes the missing content hashes of
+  `files` using at most `worker_amount` threads.
+  `on_progress` is called with the amount of processed files each time a file
+  is done.
+
+  Returns the IDs of the files where regeneration failed along with the reason
+*/
+pub fn regenerate_files<F>(
+    fdb: &FileDatabase,
+    files: Vec<File>,
+    settings: &ThumbnailSettings,
+    worker_amount: usize,
+    mut on_progress: F
+) -> Result<Vec<(i32, String)>>
+    where F: FnMut(usize)
+{
+    let file_amount = files.len();
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+
+    let (result_tx, result_rx) = channel();
+
+    for _ in 0..cmp::min(cmp::max(worker_amount, 1), file_amount) {
+        let worker_fdb = fdb.reconnect()?;
+        let queue = queue.clone();
+        let result_tx = result_tx.clone();
+        let settings = settings.clone();
+
+        thread::spawn(move || {
+            loop {
+                // The lock has to be released before the file is processed
+                let next = queue.lock().unwrap().next();
+                let file = match next {
+                    Some(file) => file,
+                    None => break
+                };
+
+                let id = file.id;
+                let result = update_file(&worker_fdb, file, &settings);
+
+                // The receiver only goes away if the job was aborted
+                if result_tx.send((id, result)).is_err() {
+                    break
+                }
+            }
+        });
+    }
+    // Only the workers should keep the channel open
+    drop(result_tx);
+
+    let mut failed_files = vec!();
+    for done in 1..=file_amount {
+        let (id, result) = match result_rx.recv() {
+            Ok(result) => result,
+            Err(_) => bail!("All thumbnail workers stopped before processing all files")
+        };
+
+        if let Err(e) = result {
+            failed_files.push((id, format!("{}", e.display())));
+        }
+
+        on_progress(done);
+    }
+
+    Ok(failed_files)
+}
+
+/**
+  Finds all files that need new thumbnails and regenerates them, storing the
+  progress of the job in `storage`
+*/
+fn run_regeneration_job(
+    fdb: &FileDatabase,
+    settings: &ThumbnailSettings,
+    worker_amount: usize,
+    job_id: usize,
+    storage: &StorageType
+) -> Result<()> {
+    let update = |update: ThumbnailJobUpdate| {
+        let mut storage = storage.lock().unwrap();
+        if let Some(status) = storage.get_mut(&job_id) {
+            status.last_update = update;
+        }
+    };
+
+    let files = find_outdated_files(fdb, settings)?;
+    let file_amount = files.len();
+    update(ThumbnailJobUpdate::Regenerating(0, file_amount));
+
+    let failed_files = regenerate_files(
+        fdb,
+        files,
+        settings,
+        worker_amount,
+        |done| update(ThumbnailJobUpdate::Regenerating(done, file_amount))
+    )?;
+
+    let mut storage = storage.lock().unwrap();
+    if let Some(status) = storage.get_mut(&job_id) {
+        status.failed_files = failed_files;
+        status.last_update = ThumbnailJobUpdate::Done;
+    }
+    Ok(())
+}
+
+/**
+  Starts a regeneration job unless one is already running. Returns the id
+  of the job that is running
+*/
+fn handle_regeneration_request(
+    fdb: FileDatabase,
+    settings: &Settings,
+    storage: &StorageType
+) -> usize {
+    let job_id = {
+        let mut storage = storage.lock().unwrap();
+
+        let running = storage.iter()
+            .find(|&(_, status)| status.is_running())
+            .map(|(id, _)| *id);
+        if let Some(id) = running {
+            return id;
+        }
+
+        let job_id = rand::random::<usize>();
+        storage.insert(job_id, ThumbnailJobStatus {
+            last_update: ThumbnailJobUpdate::FindingFiles,
+            failed_files: vec!()
+        });
+        job_id
+    };
+
+    let thumbnail_settings = settings.get_thumbnail_settings();
+    let worker_amount = settings.get_thumbnail_workers();
+    let storage = storage.clone();
+    thread::spawn(move || {
+        let result = run_regeneration_job(
+            &fdb,
+            &thumbnail_settings,
+            worker_amount,
+            job_id,
+            &storage
+        );
+
+        if let Err(e) = result {
+            let mut storage = storage.lock().unwrap();
+            if let Some(status) = storage.get_mut(&job_id) {
+                status.last_update = ThumbnailJobUpdate::Error(format!("{}", e.display()));
+            }
+        }
+    });
+
+    job_id
+}
+
+pub fn regeneration_request_handler(request: &mut Request, storage: &StorageType)
+    -> IronResult<Response>
+{
+    let fdb = setup_db_connection(request)?;
+    let settings = request.get::<Read<Settings>>().unwrap();
+
+    let job_id = handle_regeneration_request(fdb, &settings, storage);
+
+    Ok(Response::with((status::Ok, to_json_with_result(job_id)?)))
+}
+
+pub fn progress_request_handler(request: &mut Request, storage: &StorageType)
+    -> IronResult<Response>
+{
+    let job_id = get_get_usize(request, "job_id")?;
+
+    let result = handle_progress_request(job_id, storage)?;
+
+    Ok(Response::with((status::Ok, to_json_with_result(result)?)))
+}
+
+fn handle_progress_request(job_id: usize, storage: &StorageType) -> Result<ThumbnailJobStatus> {
+    let storage = storage.lock().unwrap();
+
+    match storage.get(&job_id) {
+        Some(val) => Ok((*val).clone()),
+        None => bail!(ErrorKind::NoSuchJobId(job_id))
+    }
+}
+
+
+#[cfg(test)]
+mod thumbnail_job_tests {
+    use super::*;
+
+    use std::fs;
+
+    use changelog::ChangeCreationPolicy;
+
+    /**
+      Adds a file without a thumbnail to the database and copies `source`
+      into the storage folder as its content
+    */
+    fn add_file_without_thumbnail(fdb: &FileDatabase, id: i32, source: &str) -> File {
+        let filename = format!("{}.png", id);
+        fs::copy(source, fdb.get_file_save_path().join(&filename))
+            .expect("Failed to copy test file");
+
+        fdb.add_new_file(id, &filename, None, &[], 0, &ChangeCreationPolicy::No)
+    }
+
+    db_test!(missing_thumbnails_are_regenerated(fdb) {
+        let settings = ThumbnailSettings{sizes: vec!(100, 300, 1000), quality: 80};
+
+        let file = add_file_without_thumbnail(fdb, 1, "test/media/512x512.png");
+        let other = add_file_without_thumbnail(fdb, 2, "test/media/10x10.png");
+
+        let outdated = find_outdated_files(fdb, &settings).unwrap();
+        assert_eq!(outdated.len(), 2);
+
+        let mut progress = vec!();
+        let failed = regenerate_files(fdb, outdated, &settings, 2, |done| progress.push(done))
+            .unwrap();
+        assert_eq!(failed, vec!());
+        assert_eq!(progress, vec!(1, 2));
+
+        let file = fdb.get_file_with_id(file.id).unwrap();
+        assert_eq!(file.thumbnail_path, Some("thumb_1_100.jpg".to_owned()));
+        assert_eq!((file.width, file.height), (Some(512), Some(512)));
+        assert!(file.perceptual_hash.is_some());
+        assert_eq!(
+            fdb.get_renditions(file.id).unwrap().iter().map(|r| r.size).collect::<Vec<_>>(),
+            vec!(100, 300)
+        );
+        let other = fdb.get_file_with_id(other.id).unwrap();
+        assert_eq!((other.width, other.height), (Some(10), Some(10)));
+        // The content hashes are computed by the job as well
+        assert!(file.content_hash.is_some() && other.content_hash.is_some());
+
+        assert_eq!(find_outdated_files(fdb, &settings).unwrap(), vec!());
+    });
+
+    db_test!(changed_and_unreadable_thumbnails_need_regeneration(fdb) {
+        let settings = ThumbnailSettings{sizes: vec!(100, 300), quality: 80};
+
+        let file = add_file_without_thumbnail(fdb, 1, "test/media/512x512.png");
+        regenerate_files(fdb, vec!(file.clone()), &settings, 1, |_| {}).unwrap();
+        let file = fdb.get_file_with_id(file.id).unwrap();
+        assert!(!needs_regeneration(fdb, &file, &settings).unwrap());
+
+        // New sizes
+        let new_settings = ThumbnailSettings{sizes: vec!(100, 400), quality: 80};
+        assert!(needs_regeneration(fdb, &file, &new_settings).unwrap());
+
+        // Broken rendition
+        fs::write(fdb.get_file_save_path().join("thumb_1_300.jpg"), b"not an image").unwrap();
+        assert!(needs_regeneration(fdb, &file, &settings).unwrap());
+
+        // Regenerating with new settings removes the old renditions
+        regenerate_files(fdb, vec!(file.clone()), &new_settings, 1, |_| {}).unwrap();
+        let file = fdb.get_file_with_id(file.id).unwrap();
+        assert!(!needs_regeneration(fdb, &file, &new_settings).unwrap());
+        assert!(!fdb.get_file_save_path().join("thumb_1_300.jpg").exists());
+        assert!(fdb.get_file_save_path().join("thumb_1_400.jpg").exists());
+    });
+
+    db_test!(failures_are_reported(fdb) {
+        let settings = ThumbnailSettings::default();
+
+        let file = fdb.add_new_file(1, "missing.png", None, &[], 0, &ChangeCreationPolicy::No);
+
+        let failed = regenerate_files(fdb, vec!(file), &settings, 1, |_| {}).unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 1);
+    });
+}
@@ -7,3 +7,10 @@ pub fn merge_vectors<T>(v1: &[T], v2: &[T]) -> Vec<T>
         .cloned()
         .collect()
 }
+
+/**
+  Encodes bytes as a lowercase hex string
+*/
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -44,6 +44,7 @@ done
 export DATABASE_URL="${DATABASE_FOREIGN_URL}_${FLASH_PORT}"
 export FILE_STORAGE_PATH="/tmp/flash_sync_${FLASH_PORT}"
 export FILE_READ_PATH=test/media/foreign
+export FLASH_SYNC_SECRET=flash_sync_test_secret
 
 # Log data to this file
 export LOG_FILE="${FILE_STORAGE_PATH}/log_${FLASH_PORT}"